@@ -1,72 +1,23 @@
 #!/bin/bash
+# Import a binary execution trace (written with --trace) into SQLite.
+# Usage: ./log_to_database.sh [trace file] [database] [ELF for symbols]...
 
-# Input log file
-LOG_FILE="log/output_kernel.log"
+set -euo pipefail
 
-# Database file
-DB_FILE="emulator_logs.db"
-
-# Reset the SQLite database and optimize settings
-sqlite3 "$DB_FILE" <<EOF
-PRAGMA journal_mode=WAL;           -- Enable faster concurrent writes
-PRAGMA synchronous=OFF;            -- Disable sync for better performance
-DROP TABLE IF EXISTS logs;         -- Reset the table (delete all existing logs)
-CREATE TABLE logs (
-    id INTEGER PRIMARY KEY AUTOINCREMENT,
-    timestamp TEXT NOT NULL,
-    level TEXT NOT NULL,
-    module TEXT NOT NULL,
-    message TEXT NOT NULL
-);
-EOF
-
-# Buffer to batch inserts (for performance)
-BUFFER_SIZE=1000
-BUFFER=""
-LINE_NUM=0
-
-# Process the log file
-while IFS= read -r line; do
-
-    ((LINE_NUM++))
+# Input trace file
+TRACE_FILE="${1:-log/trace.bin}"
 
-    # Skip the first 49 lines
-    if (( LINE_NUM <= 49 )); then
-        continue
-    fi
-
-
-    # Parse log fields
-    timestamp=$(echo "$line" | awk '{print substr($1, 2, length($1) - 2)}')
-    level=$(echo "$line" | awk '{print $2}')
-    module=$(echo "$line" | awk '{print substr($3, 1, length($3) - 1)}')
-    message=$(echo "$line" | sed -E 's/.*\] //')
-
-    # Validate parsed fields
-    if [[ -n "$timestamp" && -n "$level" && -n "$module" && -n "$message" ]]; then
-        # Add to buffer
-        # BUFFER+=$(echo "INSERT INTO logs (timestamp, level, module, message) VALUES ($timestamp, $level, $module, $message);")
-        # echo "INSERT INTO logs (timestamp, level, module, message) VALUES ($timestamp, $level, $module, $message);"
-        sqlite3 emulator_logs.db "INSERT INTO logs (timestamp, level, module, message) VALUES ('$timestamp', '$level', '$module', '$message');"
-        # echo $BUFFER | wc -l
-    fi
+# Database file
+DB_FILE="${2:-emulator_logs.db}"
 
-    # # Flush to the database when buffer is full
-    # if [[ $(echo "$BUFFER" | wc -l) -ge $BUFFER_SIZE ]]; then
-    #     echo "BEGIN TRANSACTION;" > batch.sql
-    #     echo "$BUFFER" >> batch.sql
-    #     echo "COMMIT;" >> batch.sql
-    #     sqlite3 -batch "$DB_FILE" < batch.sql
-    #     BUFFER=""
-    # fi
-done < "$LOG_FILE"
+SYMBOL_ARGS=()
+for elf in "${@:3}"; do
+    SYMBOL_ARGS+=(--symbols "$elf")
+done
 
-# Insert remaining logs
-if [[ -n "$BUFFER" ]]; then
-    echo "BEGIN TRANSACTION;" > batch.sql
-    echo "$BUFFER" >> batch.sql
-    echo "COMMIT;" >> batch.sql
-    sqlite3 -batch "$DB_FILE" < batch.sql
-fi
+# The converter emits the schema (blocks, traps, mmio, csr_writes) and all
+# rows in one transaction, replacing the tables from a previous import
+cargo run --release --quiet -- trace-convert --format sql "${SYMBOL_ARGS[@]}" "$TRACE_FILE" \
+    | sqlite3 -cmd "PRAGMA journal_mode=WAL;" -cmd "PRAGMA synchronous=OFF;" "$DB_FILE" > /dev/null
 
-echo "✅ Log file imported into $DB_FILE"
+echo "✅ Trace $TRACE_FILE imported into $DB_FILE"
@@ -64,6 +64,14 @@ impl Bus {
         Err(Exception::LoadAccessFault)
     }
 
+    /// Whether `load` and `store` send an access to `addr` to the UART or
+    /// the virtio disk rather than DRAM.
+    pub fn is_device(&self, addr: u64) -> bool {
+        addr < self.dram.dram_base
+            && (self.uart.is_accessible(addr)
+                || self.virtio.as_ref().is_some_and(|v| v.is_accessible(addr)))
+    }
+
     /// Load from a PLIC address, passing the CPU's interrupt list for CLAIM_COMPLETE handling.
     pub fn plic_load(
         &mut self,
@@ -73,15 +81,28 @@ impl Bus {
     ) -> Result<u64, Exception> {
         let ret_val = self.plic.load(addr, size, interrupts);
         debug!(
-            "load plic addr:{:x}, size:{}, value:{}(0x{:x})",
-            addr,
-            size,
-            ret_val.as_ref().unwrap(),
-            ret_val.as_ref().unwrap()
+            "load plic addr:{:x}, size:{}, value:{:x?}",
+            addr, size, ret_val
         );
         ret_val
     }
 
+    /// Store to a PLIC address, passing the CPU's interrupt list so that
+    /// enable, threshold and completion writes update the external interrupt lines.
+    pub fn plic_store(
+        &mut self,
+        addr: u64,
+        size: u64,
+        value: u64,
+        interrupts: &mut BTreeSet<Interrupt>,
+    ) -> Result<(), Exception> {
+        debug!(
+            "store plic addr:{:x}, size:{}, value:{}(0x{:x})",
+            addr, size, value, value
+        );
+        self.plic.store(addr, size, value, interrupts)
+    }
+
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
         if self.dram.dram_base <= addr {
             return self.dram.store(addr, size, value);
@@ -93,9 +114,6 @@ impl Bus {
         if self.uart.is_accessible(addr) {
             return self.uart.store(addr, size, value);
         }
-        if self.plic.is_accessible(addr) {
-            return self.plic.store(addr, size, value);
-        }
         if let Some(ref mut virtio) = self.virtio {
             if virtio.is_accessible(addr) {
                 return virtio.store(addr, size, value);
@@ -1,79 +1,115 @@
+use crate::cpu::CPU_FREQUENCY;
+use crate::csr::TIMER_FREQ;
 use crate::interrupt::*;
-use log::info;
-// use std::sync::{mpsc, Arc};
-// use std::time::Duration;
+use log::{debug, warn};
 use serde::{Deserialize, Serialize};
 
-const FREQUENCY: u64 = 10000000; // clock frequency: 10MHz
-const MTIMECMP: usize = 0x4000;
-const MTIME: usize = 0xBFF8;
+// register offsets for hart 0
+const MSIP: u64 = 0x0;
+const MTIMECMP: u64 = 0x4000;
+const MTIME: u64 = 0xBFF8;
 
+/// mtime as derived from the number of emulated cycles.
+pub fn mtime_from_cycle(cycle: u64) -> u64 {
+    cycle / (CPU_FREQUENCY / TIMER_FREQ)
+}
+
+/// Core-local interruptor for a single hart.
+/// `mtime` is not stored but computed from the cycle count; a write to it
+/// only records the offset from the cycle-derived value.
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Clint {
     start_addr: u64,
     size: u64,
-    registers: Vec<u64>,
-    // a thread to emulate the timer interrupt
-    // timer thread triggers an interrupt after a certain time
-    // as a function call
-    // thread: Option<std::thread::JoinHandle<()>>,
-    // duration_sender: mpsc::Sender<Option<Duration>>,
+    msip: u32,
+    mtimecmp: u64,
+    mtime_offset: u64,
 }
 
 impl Clint {
-    pub fn new(
-        _start_addr: u64,
-        _size: u64,
-        // _interrupt_sender: Arc<mpsc::Sender<Interrupt>>,
-    ) -> Clint {
-        // let (sender, receiver) = mpsc::channel();
-        // let thread = std::thread::spawn(move || {
-        //     Self::timer_thread(receiver, _interrupt_sender);
-        // });
+    pub fn new(start_addr: u64, size: u64) -> Clint {
         Self {
-            start_addr: _start_addr,
-            size: _size, // size is in bytes, but we store u64
-            registers: vec![0; (_size / 8) as usize],
-            // thread: Some(thread),
-            // duration_sender: sender,
+            start_addr,
+            size,
+            msip: 0,
+            mtimecmp: u64::MAX,
+            mtime_offset: 0,
         }
     }
 
+    pub fn start_addr(&self) -> u64 {
+        self.start_addr
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
     pub fn is_accessible(&self, addr: u64) -> bool {
         (addr >= self.start_addr) && (addr < self.start_addr + self.size)
     }
 
-    pub fn load(&self, _addr: u64, _size: u64) -> Result<u64, Exception> {
-        info!("clint: load: addr: {:#x}", _addr);
-        Ok(0x0)
+    pub fn mtime(&self, cycle: u64) -> u64 {
+        mtime_from_cycle(cycle).wrapping_add(self.mtime_offset)
     }
 
-    pub fn store(&mut self, _addr: u64, _size: u64, _value: u64) -> Result<(), Exception> {
-        info!("clint: store: addr: {:#x}, value: {:#x}", _addr, _value);
-        let offset = (_addr - self.start_addr) as usize;
-        self.registers[offset / 8] = _value;
-        if offset == MTIMECMP {
-            self.set_mtimecmp(_value);
-        }
-        Ok(())
+    pub fn mtime_offset(&self) -> u64 {
+        self.mtime_offset
     }
 
-    fn set_mtimecmp(&mut self, value: u64) {
-        let current_counter = self.registers[MTIME / 8];
-        let target_counter = value;
-        let wait_time_ms = ((target_counter - current_counter) * 1000 / FREQUENCY) as u64;
-        info!("clint: store: mtimecmp: wait_time_ms: {}", wait_time_ms);
+    /// MTIP: mtime has reached mtimecmp.
+    pub fn timer_pending(&self, cycle: u64) -> bool {
+        self.mtime(cycle) >= self.mtimecmp
+    }
 
-        // let sleep_duration = Option::Some(Duration::from_millis(wait_time_ms));
-        // self.duration_sender.send(sleep_duration).unwrap();
+    /// MSIP: bit 0 of the msip register.
+    pub fn software_pending(&self) -> bool {
+        self.msip & 0x1 != 0
     }
-}
 
-impl Drop for Clint {
-    fn drop(&mut self) {
-        // self.duration_sender.send(Option::None).unwrap();
-        // if let Some(thread) = self.thread.take() {
-        //     thread.join().unwrap();
-        // }
+    // every register is accessed through the naturally aligned doubleword containing it
+    fn read_register(&self, offset: u64, cycle: u64) -> u64 {
+        match offset & !0x7 {
+            MSIP => self.msip as u64,
+            MTIMECMP => self.mtimecmp,
+            MTIME => self.mtime(cycle),
+            _ => 0,
+        }
+    }
+
+    pub fn load(&self, addr: u64, size: u64, cycle: u64) -> Result<u64, Exception> {
+        let offset = addr - self.start_addr;
+        if !offset.is_multiple_of(size / 8) {
+            return Err(Exception::LoadAddressMissaligned);
+        }
+        let shift = (offset & 0x7) * 8;
+        let value = self.read_register(offset, cycle) >> shift;
+        Ok(if size == 64 {
+            value
+        } else {
+            value & ((1 << size) - 1)
+        })
+    }
+
+    pub fn store(&mut self, addr: u64, size: u64, value: u64, cycle: u64) -> Result<(), Exception> {
+        debug!("clint: store: addr: {:#x}, value: {:#x}", addr, value);
+        let offset = addr - self.start_addr;
+        if !offset.is_multiple_of(size / 8) {
+            return Err(Exception::StoreAMOAddressMisaligned);
+        }
+        let shift = (offset & 0x7) * 8;
+        let mask = if size == 64 {
+            u64::MAX
+        } else {
+            ((1 << size) - 1) << shift
+        };
+        let merged = (self.read_register(offset, cycle) & !mask) | ((value << shift) & mask);
+        match offset & !0x7 {
+            MSIP => self.msip = merged as u32 & 0x1,
+            MTIMECMP => self.mtimecmp = merged,
+            MTIME => self.mtime_offset = merged.wrapping_sub(mtime_from_cycle(cycle)),
+            _ => warn!("clint: store to unimplemented register {:#x}", offset),
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,151 @@
+//! Instruction commit trace in the format of Spike's `--log-commits`, so a run
+//! can be diffed line by line against the reference simulator (see
+//! `cargo xtask cosim`). Each retired instruction produces one line:
+//!
+//! ```text
+//! core   0: 3 0x0000000080000004 (0x00a2b023) mem 0x0000000080001000 0x0000000000000000
+//! ```
+//!
+//! holding the privilege mode it ran in, its pc and encoding, then the
+//! register it wrote, the CSR written by a Zicsr instruction, the addresses
+//! it loaded from and the stores it made. Instructions that trap are not
+//! logged, as in Spike.
+
+use crate::disasm;
+use crate::instruction::RegDest;
+use std::io::{self, Write};
+
+/// A retired instruction and the register writes it made.
+pub struct Retired {
+    pub mode: u64,
+    pub pc: u64,
+    pub inst: u32,
+    pub len: u64,
+    pub reg: Option<(RegDest, u64)>,
+    pub csr: Option<(usize, u64)>,
+}
+
+pub struct CommitLog {
+    out: Box<dyn Write>,
+    // memory accesses of the instruction being executed
+    loads: Vec<u64>,
+    stores: Vec<(u64, u64, u64)>,
+}
+
+impl CommitLog {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        Self {
+            out,
+            loads: Vec::new(),
+            stores: Vec::new(),
+        }
+    }
+
+    pub(crate) fn record_load(&mut self, addr: u64) {
+        self.loads.push(addr);
+    }
+
+    pub(crate) fn record_store(&mut self, addr: u64, size: u64, value: u64) {
+        self.stores.push((addr, size, value));
+    }
+
+    /// Drops the accesses of an instruction that trapped.
+    pub(crate) fn discard(&mut self) {
+        self.loads.clear();
+        self.stores.clear();
+    }
+
+    pub(crate) fn commit(&mut self, retired: &Retired) -> io::Result<()> {
+        let line = self.format(retired);
+        self.discard();
+        writeln!(self.out, "{}", line)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    fn format(&self, retired: &Retired) -> String {
+        let mut line = format!("core   0: {} 0x{:016x} ", retired.mode, retired.pc);
+        if retired.len == 2 {
+            line += &format!("(0x{:04x})", retired.inst & 0xffff);
+        } else {
+            line += &format!("(0x{:08x})", retired.inst);
+        }
+        match retired.reg {
+            // x0 is never written
+            Some((RegDest::X(0), _)) | None => {}
+            Some((RegDest::X(rd), value)) => line += &format!(" x{:<2} 0x{:016x}", rd, value),
+            Some((RegDest::F(rd), value)) => line += &format!(" f{:<2} 0x{:016x}", rd, value),
+        }
+        if let Some((csr, value)) = retired.csr {
+            let name = disasm::csr_name(csr).unwrap_or("unknown");
+            line += &format!(" c{}_{} 0x{:016x}", csr, name, value);
+        }
+        for addr in &self.loads {
+            line += &format!(" mem 0x{:016x}", addr);
+        }
+        for (addr, size, value) in &self.stores {
+            let digits = (*size / 4) as usize;
+            line += &format!(
+                " mem 0x{:016x} 0x{:0digits$x}",
+                addr,
+                value,
+                digits = digits
+            );
+        }
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let mut log = CommitLog::new(Box::new(io::sink()));
+        let auipc = Retired {
+            mode: 3,
+            pc: 0x8000_0000,
+            inst: 0x0000_0297,
+            len: 4,
+            reg: Some((RegDest::X(5), 0x8000_0000)),
+            csr: None,
+        };
+        assert_eq!(
+            log.format(&auipc),
+            "core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000"
+        );
+
+        log.record_store(0x8000_1000, 32, 0x2a);
+        let sw = Retired {
+            mode: 1,
+            pc: 0x8000_0004,
+            inst: 0xc04a,
+            len: 2,
+            reg: None,
+            csr: None,
+        };
+        assert_eq!(
+            log.format(&sw),
+            "core   0: 1 0x0000000080000004 (0xc04a) mem 0x0000000080001000 0x0000002a"
+        );
+        log.discard();
+
+        log.record_load(0x8000_2000);
+        let csrrw = Retired {
+            mode: 3,
+            pc: 0x8000_0006,
+            inst: 0x3005_1573,
+            len: 4,
+            reg: Some((RegDest::X(10), 0x1800)),
+            csr: Some((0x300, 0xa)),
+        };
+        assert_eq!(
+            log.format(&csrrw),
+            "core   0: 3 0x0000000080000006 (0x30051573) x10 0x0000000000001800 \
+             c768_mstatus 0x000000000000000a mem 0x0000000080002000"
+        );
+    }
+}
@@ -0,0 +1,278 @@
+//! Expect-style scripts that drive the guest console through the UART.
+//!
+//! One command per line; `#` starts a comment. Text is double-quoted and
+//! understands `\n`, `\r`, `\t`, `\"` and `\\`.
+//!
+//! ```text
+//! timeout CYCLES          default timeout of the following `expect`s
+//! expect "TEXT" [CYCLES]  run until the console prints TEXT
+//! send "TEXT"             type TEXT on the console
+//! fail "TEXT"             fail as soon as the console prints TEXT
+//! run CYCLES              run for CYCLES without waiting for anything
+//! ```
+
+use crate::emu::Emu;
+use log::info;
+
+const DEFAULT_TIMEOUT: u64 = 100_000_000;
+// how many cycles to run between looks at the console output
+const POLL_INTERVAL: u64 = 100_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Command {
+    Timeout(u64),
+    Expect(Vec<u8>, Option<u64>),
+    Send(Vec<u8>),
+    Fail(Vec<u8>),
+    Run(u64),
+}
+
+pub struct ConsoleScript {
+    // (line number, command)
+    commands: Vec<(usize, Command)>,
+}
+
+/// Split a line into words, keeping quoted text (with escapes resolved) as one word.
+fn tokenize(line: &str) -> Result<Vec<Vec<u8>>, String> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '#' {
+            break;
+        } else if c == '"' {
+            chars.next();
+            let mut word = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => word.push('\n'),
+                        Some('r') => word.push('\r'),
+                        Some('t') => word.push('\t'),
+                        Some(other) => word.push(other),
+                        None => return Err("unterminated escape".to_string()),
+                    },
+                    Some(other) => word.push(other),
+                    None => return Err("unterminated string".to_string()),
+                }
+            }
+            words.push(word.into_bytes());
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            words.push(word.into_bytes());
+        }
+    }
+    Ok(words)
+}
+
+fn parse_cycles(word: &[u8]) -> Result<u64, String> {
+    let text = String::from_utf8_lossy(word).replace('_', "");
+    text.parse()
+        .map_err(|_| format!("invalid cycle count: {}", text))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
+    }
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+impl ConsoleScript {
+    pub fn parse(source: &str) -> Result<ConsoleScript, String> {
+        let mut commands = Vec::new();
+        for (index, line) in source.lines().enumerate() {
+            let line_number = index + 1;
+            let words = tokenize(line).map_err(|e| format!("line {}: {}", line_number, e))?;
+            let Some((name, args)) = words.split_first() else {
+                continue;
+            };
+            let command = match (name.as_slice(), args) {
+                (b"timeout", [cycles]) => parse_cycles(cycles).map(Command::Timeout),
+                (b"expect", [text]) => Ok(Command::Expect(text.clone(), None)),
+                (b"expect", [text, cycles]) => {
+                    parse_cycles(cycles).map(|cycles| Command::Expect(text.clone(), Some(cycles)))
+                }
+                (b"send", [text]) => Ok(Command::Send(text.clone())),
+                (b"fail", [text]) => Ok(Command::Fail(text.clone())),
+                (b"run", [cycles]) => parse_cycles(cycles).map(Command::Run),
+                _ => Err(format!("invalid command: {}", line.trim())),
+            }
+            .map_err(|e| format!("line {}: {}", line_number, e))?;
+            commands.push((line_number, command));
+        }
+        Ok(ConsoleScript { commands })
+    }
+
+    pub fn from_file(path: &std::path::Path) -> Result<ConsoleScript, String> {
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        Self::parse(&source)
+    }
+
+    /// Run the script against `emu`. The error describes the command that
+    /// failed together with the console output it was looking at.
+    pub fn run(&self, emu: &mut Emu) -> Result<(), String> {
+        let mut runner = Runner {
+            emu,
+            output: Vec::new(),
+            matched_up_to: 0,
+            checked_up_to: 0,
+            fail_patterns: Vec::new(),
+        };
+        runner.emu.bus.uart.start_capture();
+        let mut timeout = DEFAULT_TIMEOUT;
+        for (line, command) in &self.commands {
+            let result = match command {
+                Command::Timeout(cycles) => {
+                    timeout = *cycles;
+                    Ok(())
+                }
+                Command::Expect(text, cycles) => runner.expect(text, cycles.unwrap_or(timeout)),
+                Command::Send(text) => {
+                    runner.emu.bus.uart.send_input(text);
+                    Ok(())
+                }
+                Command::Fail(text) => {
+                    runner.fail_patterns.push(text.clone());
+                    runner.check_fail_patterns()
+                }
+                Command::Run(cycles) => runner.run(*cycles),
+            };
+            result.map_err(|e| format!("line {}: {}", line, e))?;
+        }
+        info!("console script passed at cycle {}", runner.emu.cpu.cycle);
+        Ok(())
+    }
+}
+
+struct Runner<'a> {
+    emu: &'a mut Emu,
+    // everything the guest has printed so far
+    output: Vec<u8>,
+    // `expect` only looks at output after the previous match
+    matched_up_to: usize,
+    // output before this has been checked against the fail patterns
+    checked_up_to: usize,
+    fail_patterns: Vec<Vec<u8>>,
+}
+
+impl Runner<'_> {
+    fn step(&mut self, until: u64) -> Result<(), String> {
+        let target = until.min(self.emu.cpu.cycle + POLL_INTERVAL);
+        self.emu.run_for(target);
+        let captured = self.emu.bus.uart.take_captured();
+        self.output.extend_from_slice(&captured);
+        self.check_fail_patterns()?;
+        if let Some(reset) = self.emu.cpu.system_reset() {
+            return Err(format!(
+                "guest requested a system reset ({:?}) at cycle {}{}",
+                reset,
+                self.emu.cpu.cycle,
+                self.recent_output()
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_fail_patterns(&mut self) -> Result<(), String> {
+        for pattern in &self.fail_patterns {
+            // patterns may straddle the previously checked boundary
+            let from = self
+                .checked_up_to
+                .saturating_sub(pattern.len().saturating_sub(1));
+            if find(&self.output[from..], pattern).is_some() {
+                return Err(format!(
+                    "console printed \"{}\" at cycle {}{}",
+                    String::from_utf8_lossy(pattern).escape_debug(),
+                    self.emu.cpu.cycle,
+                    self.recent_output()
+                ));
+            }
+        }
+        self.checked_up_to = self.output.len();
+        Ok(())
+    }
+
+    fn expect(&mut self, text: &[u8], timeout: u64) -> Result<(), String> {
+        let deadline = self.emu.cpu.cycle.saturating_add(timeout);
+        loop {
+            if let Some(pos) = find(&self.output[self.matched_up_to..], text) {
+                self.matched_up_to += pos + text.len();
+                return Ok(());
+            }
+            if self.emu.cpu.cycle >= deadline {
+                return Err(format!(
+                    "timed out after {} cycles waiting for \"{}\"{}",
+                    timeout,
+                    String::from_utf8_lossy(text).escape_debug(),
+                    self.recent_output()
+                ));
+            }
+            self.step(deadline)?;
+        }
+    }
+
+    fn run(&mut self, cycles: u64) -> Result<(), String> {
+        let deadline = self.emu.cpu.cycle.saturating_add(cycles);
+        while self.emu.cpu.cycle < deadline {
+            self.step(deadline)?;
+        }
+        Ok(())
+    }
+
+    fn recent_output(&self) -> String {
+        const TAIL: usize = 512;
+        let unmatched = &self.output[self.matched_up_to..];
+        let tail = &unmatched[unmatched.len().saturating_sub(TAIL)..];
+        format!(
+            "; recent console output:\n{}",
+            String::from_utf8_lossy(tail)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let script = ConsoleScript::parse(
+            "# boot\n\
+             timeout 1_000\n\
+             expect \"$ \"\n\
+             send \"echo \\\"hi\\\"\\n\" # comment\n\
+             fail \"panic\"\n\
+             expect \"hi\" 20\n\
+             run 5\n",
+        )
+        .unwrap();
+        let commands: Vec<Command> = script.commands.into_iter().map(|(_, c)| c).collect();
+        assert_eq!(
+            commands,
+            vec![
+                Command::Timeout(1000),
+                Command::Expect(b"$ ".to_vec(), None),
+                Command::Send(b"echo \"hi\"\n".to_vec()),
+                Command::Fail(b"panic".to_vec()),
+                Command::Expect(b"hi".to_vec(), Some(20)),
+                Command::Run(5),
+            ]
+        );
+        assert!(ConsoleScript::parse("expect").is_err());
+        assert!(ConsoleScript::parse("send \"open").is_err());
+        assert!(ConsoleScript::parse("timeout soon").is_err());
+    }
+}
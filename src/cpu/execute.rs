@@ -1,7 +1,74 @@
 use super::*;
+use crate::commit_log::Retired;
+use crate::softfloat::{Format, F32, F64};
 
 impl Cpu {
     pub fn execute(&mut self, bus: &mut Bus, inst: &DecodedInstr) -> Result<(), Exception> {
+        if self.commit_log.is_none() && self.trace.is_none() && self.stats.is_none() {
+            return self.execute_instr(bus, inst);
+        }
+        let (pc, mode) = (self.pc, self.mode);
+        let result = self.execute_instr(bus, inst);
+        if result.is_ok() {
+            if let Some(stats) = &mut self.stats {
+                stats.instruction(mode, inst);
+            }
+        }
+        if result.is_ok() && self.trace.is_some() {
+            if let Some(csr) = inst.csr_write() {
+                self.record(Record::CsrWrite {
+                    cycle: self.cycle,
+                    csr: csr as u16,
+                    value: self.csr.load_csrs(csr, self.cycle, &self.interrupt_list),
+                });
+            }
+        }
+        if self.commit_log.is_some() {
+            self.log_commit(pc, mode, inst, result.is_ok());
+        }
+        result
+    }
+
+    // a PMP change can take execute permission away from decoded blocks
+    fn write_csr(&mut self, csr: usize, value: u64) {
+        self.csr.store_csrs(csr, value);
+        if (PMPCFG0..=PMPCFG2).contains(&csr) || (PMPADDR0..=PMPADDR15).contains(&csr) {
+            self.clear_block_cache();
+        }
+    }
+
+    fn log_commit(&mut self, pc: u64, mode: u64, inst: &DecodedInstr, retired: bool) {
+        // ecall traps from inside execute_instr; Spike logs no commit for it
+        if !retired || matches!(inst, DecodedInstr::Ecall { .. }) {
+            if let Some(log) = &mut self.commit_log {
+                log.discard();
+            }
+            return;
+        }
+        let retired = Retired {
+            mode,
+            pc,
+            inst: inst.raw(),
+            len: inst.inst_len(),
+            reg: inst.destination().map(|dest| match dest {
+                RegDest::X(rd) => (dest, self.regs[rd]),
+                RegDest::F(rd) => (dest, self.fregs[rd]),
+            }),
+            csr: inst.csr_write().map(|csr| {
+                (
+                    csr,
+                    self.csr.load_csrs(csr, self.cycle, &self.interrupt_list),
+                )
+            }),
+        };
+        if let Some(log) = &mut self.commit_log {
+            if let Err(e) = log.commit(&retired) {
+                error!("Failed to write the commit log: {}", e);
+            }
+        }
+    }
+
+    fn execute_instr(&mut self, bus: &mut Bus, inst: &DecodedInstr) -> Result<(), Exception> {
         self.clear_reg_marks();
         match *inst {
             DecodedInstr::Add {
@@ -334,7 +401,7 @@ impl Cpu {
                 imm,
             } => {
                 let shamt = (imm & 0x3f) as u64;
-                let logical_shift = imm >> 5;
+                let logical_shift = (imm >> 10) & 0x1;
                 if logical_shift == 0 {
                     self.regs[rd] = (self.regs[rs1] as u64) >> shamt;
                 } else {
@@ -484,8 +551,8 @@ impl Cpu {
                 Ok(())
             }
             DecodedInstr::Jal { raw: _, rd, imm } => {
-                self.regs[rd] = self.pc.wrapping_add(4);
-                self.pc = self.pc.wrapping_add(imm as u64).wrapping_sub(4);
+                self.regs[rd] = self.pc.wrapping_add(inst.inst_len());
+                self.pc = self.pc.wrapping_add(imm).wrapping_sub(inst.inst_len());
                 self.mark_as_dest(rd);
                 Ok(())
             }
@@ -495,8 +562,8 @@ impl Cpu {
                 rs1,
                 imm,
             } => {
-                let return_addr = self.pc.wrapping_add(4);
-                let next_pc = self.regs[rs1].wrapping_add(imm as u64).wrapping_sub(4);
+                let return_addr = self.pc.wrapping_add(inst.inst_len());
+                let next_pc = (self.regs[rs1].wrapping_add(imm) & !1).wrapping_sub(inst.inst_len());
                 self.regs[rd] = return_addr;
                 self.pc = next_pc;
                 self.mark_as_dest(rd);
@@ -562,7 +629,7 @@ impl Cpu {
                 imm,
             } => {
                 if self.regs[rs1] == self.regs[rs2] {
-                    self.pc = self.pc.wrapping_add(imm as u64).wrapping_sub(4);
+                    self.pc = self.pc.wrapping_add(imm).wrapping_sub(inst.inst_len());
                 }
                 self.mark_as_src1(rs1);
                 self.mark_as_src2(rs2);
@@ -575,7 +642,7 @@ impl Cpu {
                 imm,
             } => {
                 if self.regs[rs1] != self.regs[rs2] {
-                    self.pc = self.pc.wrapping_add(imm as u64).wrapping_sub(4);
+                    self.pc = self.pc.wrapping_add(imm).wrapping_sub(inst.inst_len());
                 }
                 self.mark_as_src1(rs1);
                 self.mark_as_src2(rs2);
@@ -588,7 +655,7 @@ impl Cpu {
                 imm,
             } => {
                 if (self.regs[rs1] as i64) < (self.regs[rs2] as i64) {
-                    self.pc = self.pc.wrapping_add(imm as u64).wrapping_sub(4);
+                    self.pc = self.pc.wrapping_add(imm).wrapping_sub(inst.inst_len());
                 }
                 self.mark_as_src1(rs1);
                 self.mark_as_src2(rs2);
@@ -601,7 +668,7 @@ impl Cpu {
                 imm,
             } => {
                 if (self.regs[rs1] as i64) >= (self.regs[rs2] as i64) {
-                    self.pc = self.pc.wrapping_add(imm as u64).wrapping_sub(4);
+                    self.pc = self.pc.wrapping_add(imm).wrapping_sub(inst.inst_len());
                 }
                 self.mark_as_src1(rs1);
                 self.mark_as_src2(rs2);
@@ -614,7 +681,7 @@ impl Cpu {
                 imm,
             } => {
                 if self.regs[rs1] < self.regs[rs2] {
-                    self.pc = self.pc.wrapping_add(imm as u64).wrapping_sub(4);
+                    self.pc = self.pc.wrapping_add(imm).wrapping_sub(inst.inst_len());
                 }
                 self.mark_as_src1(rs1);
                 self.mark_as_src2(rs2);
@@ -627,7 +694,7 @@ impl Cpu {
                 imm,
             } => {
                 if self.regs[rs1] >= self.regs[rs2] {
-                    self.pc = self.pc.wrapping_add(imm as u64).wrapping_sub(4);
+                    self.pc = self.pc.wrapping_add(imm).wrapping_sub(inst.inst_len());
                 }
                 self.mark_as_src1(rs1);
                 self.mark_as_src2(rs2);
@@ -777,7 +844,9 @@ impl Cpu {
                 info!("ecall instruction from mode {}", self.mode);
                 match self.mode {
                     M_MODE => Exception::EnvironmentalCallFromMMode.take_trap(self),
-                    S_MODE => Exception::EnvironmentalCallFromSMode.take_trap(self),
+                    S_MODE => {
+                        Exception::EnvironmentalCallFromSMode.take_trap_or_call_sbi(self, bus)
+                    }
                     U_MODE => Exception::EnvironmentalCallFromUMode.take_trap(self),
                     _ => panic!("ecall is executed with mode: {}", self.mode),
                 }
@@ -799,256 +868,324 @@ impl Cpu {
                 Ok(())
             }
             DecodedInstr::Wfi { raw: _ } => Ok(()),
-            DecodedInstr::Csrrw {
-                raw: _,
-                rd,
-                rs1,
-                csr,
-            } => {
+            DecodedInstr::Csrrw { raw, rd, rs1, csr } => {
+                self.require_fpu_for_csr(raw, csr)?;
                 if rd != 0 {
                     self.regs[rd] = self.csr.load_csrs(csr, self.cycle, &self.interrupt_list);
                 }
-                self.csr.store_csrs(csr, self.regs[rs1]);
+                self.write_csr(csr, self.regs[rs1]);
                 self.mark_as_dest(rd);
                 self.mark_as_src1(rs1);
                 Ok(())
             }
-            DecodedInstr::Csrrs {
-                raw: _,
-                rd,
-                rs1,
-                csr,
-            } => {
+            DecodedInstr::Csrrs { raw, rd, rs1, csr } => {
+                self.require_fpu_for_csr(raw, csr)?;
                 let old = self.csr.load_csrs(csr, self.cycle, &self.interrupt_list);
                 self.regs[rd] = old;
                 if rs1 != 0 {
-                    self.csr.store_csrs(csr, self.regs[rs1] | old);
+                    self.write_csr(csr, self.regs[rs1] | old);
                 }
                 self.mark_as_dest(rd);
                 self.mark_as_src1(rs1);
                 Ok(())
             }
-            DecodedInstr::Csrrc {
-                raw: _,
-                rd,
-                rs1,
-                csr,
-            } => {
+            DecodedInstr::Csrrc { raw, rd, rs1, csr } => {
+                self.require_fpu_for_csr(raw, csr)?;
                 let old = self.csr.load_csrs(csr, self.cycle, &self.interrupt_list);
                 self.regs[rd] = old;
                 if rs1 != 0 {
-                    self.csr.store_csrs(csr, self.regs[rs1] & !old);
+                    self.write_csr(csr, self.regs[rs1] & !old);
                 }
                 self.mark_as_dest(rd);
                 self.mark_as_src1(rs1);
                 Ok(())
             }
             DecodedInstr::Csrrwi {
-                raw: _,
+                raw,
                 rd,
                 rs1,
                 csr,
                 uimm,
             } => {
+                self.require_fpu_for_csr(raw, csr)?;
                 if rd != 0 {
                     self.regs[rd] = self.csr.load_csrs(csr, self.cycle, &self.interrupt_list);
                 }
-                self.csr.store_csrs(csr, uimm as u64);
+                self.write_csr(csr, uimm as u64);
                 self.mark_as_dest(rd);
                 self.mark_as_src1(rs1);
                 Ok(())
             }
             DecodedInstr::Csrrsi {
-                raw: _,
+                raw,
                 rd,
                 rs1,
                 csr,
                 uimm,
             } => {
+                self.require_fpu_for_csr(raw, csr)?;
                 let old_val = self.csr.load_csrs(csr, self.cycle, &self.interrupt_list);
                 self.regs[rd] = old_val;
                 if rs1 != 0 {
-                    self.csr.store_csrs(csr, uimm as u64 | old_val);
+                    self.write_csr(csr, uimm as u64 | old_val);
                 }
                 self.mark_as_dest(rd);
                 self.mark_as_src1(rs1);
                 Ok(())
             }
             DecodedInstr::Csrrci {
-                raw: _,
+                raw,
                 rd,
                 rs1,
                 csr,
                 uimm,
             } => {
+                self.require_fpu_for_csr(raw, csr)?;
                 let old_val = self.csr.load_csrs(csr, self.cycle, &self.interrupt_list);
                 self.regs[rd] = old_val;
                 if rs1 != 0 {
-                    self.csr.store_csrs(csr, uimm as u64 & !old_val);
+                    self.write_csr(csr, uimm as u64 & !old_val);
                 }
                 self.mark_as_dest(rd);
                 self.mark_as_src1(rs1);
                 Ok(())
             }
-            DecodedInstr::Sfence { raw: _ } => {
-                self.address_translation_cache.clear();
-                self.block_cache.clear();
+            DecodedInstr::Sfence { raw } => {
+                // rs1 selects a virtual address and rs2 an ASID; x0 selects all
+                let rs1 = ((raw >> 15) & 0x1f) as usize;
+                let rs2 = ((raw >> 20) & 0x1f) as usize;
+                let va = Some(self.regs[rs1]).filter(|_| rs1 != 0);
+                let asid =
+                    Some(self.regs[rs2] & (SATP_ASID >> SATP_ASID_SHIFT)).filter(|_| rs2 != 0);
+                self.tlb.flush(va, asid);
+                self.clear_block_cache();
                 Ok(())
             }
             DecodedInstr::Fence { raw: _ } => {
-                self.address_translation_cache.clear();
-                self.block_cache.clear();
+                self.clear_block_cache();
                 Ok(())
             }
-            DecodedInstr::Amoswap {
-                raw: _,
-                rd,
-                rs1,
-                rs2,
-            } => {
-                let addr = self.regs[rs1];
-                let val = self.load(bus, addr, 32)?;
-                let src = self.regs[rs2];
-                self.regs[rd] = val;
-                self.store(bus, addr, 32, src)?;
+            DecodedInstr::Amoswap { raw, rd, rs1, rs2 } => {
+                self.atomic_rmw(bus, raw, rd, rs1, rs2, |_, src| src)
+            }
+            DecodedInstr::Amoadd { raw, rd, rs1, rs2 } => {
+                self.atomic_rmw(bus, raw, rd, rs1, rs2, |val, src| val.wrapping_add(src))
+            }
+            DecodedInstr::Amoxor { raw, rd, rs1, rs2 } => {
+                self.atomic_rmw(bus, raw, rd, rs1, rs2, |val, src| val ^ src)
+            }
+            DecodedInstr::Amoand { raw, rd, rs1, rs2 } => {
+                self.atomic_rmw(bus, raw, rd, rs1, rs2, |val, src| val & src)
+            }
+            DecodedInstr::Amoor { raw, rd, rs1, rs2 } => {
+                self.atomic_rmw(bus, raw, rd, rs1, rs2, |val, src| val | src)
+            }
+            DecodedInstr::Amomin { raw, rd, rs1, rs2 } => {
+                self.atomic_rmw(bus, raw, rd, rs1, rs2, |val, src| {
+                    cmp::min(val as i64, src as i64) as u64
+                })
+            }
+            DecodedInstr::Amomax { raw, rd, rs1, rs2 } => {
+                self.atomic_rmw(bus, raw, rd, rs1, rs2, |val, src| {
+                    cmp::max(val as i64, src as i64) as u64
+                })
+            }
+            DecodedInstr::Amominu { raw, rd, rs1, rs2 } => {
+                self.atomic_rmw(bus, raw, rd, rs1, rs2, cmp::min)
+            }
+            DecodedInstr::Amomaxu { raw, rd, rs1, rs2 } => {
+                self.atomic_rmw(bus, raw, rd, rs1, rs2, cmp::max)
+            }
+            DecodedInstr::Lrw { raw: _, rd, rs1 } => {
+                let val = self.load_reserved(bus, self.regs[rs1], 32)?;
+                self.regs[rd] = val as i32 as i64 as u64;
                 self.mark_as_dest(rd);
                 self.mark_as_src1(rs1);
-                self.mark_as_src2(rs2);
                 Ok(())
             }
-            DecodedInstr::Amoadd {
-                raw: _,
-                rd,
-                rs1,
-                rs2,
-            } => {
-                let addr = self.regs[rs1];
-                let val = self.load(bus, addr, 32)?;
-                let result = val.wrapping_add(self.regs[rs2]);
+            DecodedInstr::Lrd { raw: _, rd, rs1 } => {
+                let val = self.load_reserved(bus, self.regs[rs1], 64)?;
                 self.regs[rd] = val;
-                self.store(bus, addr, 32, result)?;
                 self.mark_as_dest(rd);
                 self.mark_as_src1(rs1);
-                self.mark_as_src2(rs2);
                 Ok(())
             }
-            DecodedInstr::Amoxor {
+            DecodedInstr::Scw {
                 raw: _,
                 rd,
                 rs1,
                 rs2,
             } => {
-                let addr = self.regs[rs1];
-                let val = self.load(bus, addr, 32)?;
-                let result = val ^ self.regs[rs2];
-                self.regs[rd] = val;
-                self.store(bus, addr, 32, result)?;
+                let result = self.store_conditional(bus, self.regs[rs1], 32, self.regs[rs2])?;
+                self.regs[rd] = result;
                 self.mark_as_dest(rd);
                 self.mark_as_src1(rs1);
                 self.mark_as_src2(rs2);
                 Ok(())
             }
-            DecodedInstr::Amoand {
+            DecodedInstr::Scd {
                 raw: _,
                 rd,
                 rs1,
                 rs2,
             } => {
-                let addr = self.regs[rs1];
-                let val = self.load(bus, addr, 32)?;
-                let result = val & self.regs[rs2];
-                self.regs[rd] = val;
-                self.store(bus, addr, 32, result)?;
+                let result = self.store_conditional(bus, self.regs[rs1], 64, self.regs[rs2])?;
+                self.regs[rd] = result;
                 self.mark_as_dest(rd);
                 self.mark_as_src1(rs1);
                 self.mark_as_src2(rs2);
                 Ok(())
             }
-            DecodedInstr::Amoor {
-                raw: _,
+            DecodedInstr::Flw { raw, rd, rs1, imm } => self.fp_load(bus, F32, raw, rd, rs1, imm),
+            DecodedInstr::Fld { raw, rd, rs1, imm } => self.fp_load(bus, F64, raw, rd, rs1, imm),
+            DecodedInstr::Fsw { raw, rs1, rs2, imm } => self.fp_store(bus, F32, raw, rs1, rs2, imm),
+            DecodedInstr::Fsd { raw, rs1, rs2, imm } => self.fp_store(bus, F64, raw, rs1, rs2, imm),
+            DecodedInstr::Fmadd {
+                raw,
                 rd,
                 rs1,
                 rs2,
-            } => {
-                let addr = self.regs[rs1];
-                let val = self.load(bus, addr, 32)?;
-                let result = val | self.regs[rs2];
-                self.regs[rd] = val;
-                self.store(bus, addr, 32, result)?;
-                self.mark_as_dest(rd);
-                self.mark_as_src1(rs1);
-                self.mark_as_src2(rs2);
-                Ok(())
-            }
-            DecodedInstr::Amomin {
-                raw: _,
+                rs3,
+                rm,
+            } => self.fp_fused(raw, rd, rs1, rs2, rs3, rm, false, false),
+            DecodedInstr::Fmsub {
+                raw,
                 rd,
                 rs1,
                 rs2,
-            } => {
-                let addr = self.regs[rs1];
-                let loaded_value = self.load(bus, addr, 32)? as i32 as i64 as u64;
-                let src_value = self.regs[rs2];
-                self.regs[rd] = loaded_value;
-                let result = cmp::min(loaded_value as i64, src_value as i64) as u64;
-                self.store(bus, addr, 32, result)?;
-                self.mark_as_dest(rd);
-                self.mark_as_src1(rs1);
-                self.mark_as_src2(rs2);
-                Ok(())
-            }
-            DecodedInstr::Amomax {
-                raw: _,
+                rs3,
+                rm,
+            } => self.fp_fused(raw, rd, rs1, rs2, rs3, rm, false, true),
+            DecodedInstr::Fnmsub {
+                raw,
                 rd,
                 rs1,
                 rs2,
-            } => {
-                let addr = self.regs[rs1];
-                let loaded_value = self.load(bus, addr, 32)? as i32 as i64 as u64;
-                let src_value = self.regs[rs2];
-                self.regs[rd] = loaded_value;
-                let result = cmp::max(loaded_value as i64, src_value as i64) as u64;
-                self.store(bus, addr, 32, result)?;
-                self.mark_as_dest(rd);
-                self.mark_as_src1(rs1);
-                self.mark_as_src2(rs2);
-                Ok(())
-            }
-            DecodedInstr::Amominu {
-                raw: _,
+                rs3,
+                rm,
+            } => self.fp_fused(raw, rd, rs1, rs2, rs3, rm, true, false),
+            DecodedInstr::Fnmadd {
+                raw,
                 rd,
                 rs1,
                 rs2,
-            } => {
-                let addr = self.regs[rs1];
-                let loaded_value = self.load(bus, addr, 32)? as i32 as i64 as u64;
-                let src_value = self.regs[rs2];
-                self.regs[rd] = loaded_value;
-                let result = cmp::min(loaded_value, src_value);
-                self.store(bus, addr, 32, result)?;
-                self.mark_as_dest(rd);
-                self.mark_as_src1(rs1);
-                self.mark_as_src2(rs2);
-                Ok(())
-            }
-            DecodedInstr::Amomaxu {
-                raw: _,
+                rs3,
+                rm,
+            } => self.fp_fused(raw, rd, rs1, rs2, rs3, rm, true, true),
+            DecodedInstr::Fadd {
+                raw,
                 rd,
                 rs1,
                 rs2,
-            } => {
-                let addr = self.regs[rs1];
-                let loaded_value = self.load(bus, addr, 32)? as i32 as i64 as u64;
-                let src_value = self.regs[rs2];
-                self.regs[rd] = loaded_value;
-                let result = cmp::max(loaded_value, src_value);
-                self.store(bus, addr, 32, result)?;
-                self.mark_as_dest(rd);
-                self.mark_as_src1(rs1);
-                self.mark_as_src2(rs2);
-                Ok(())
+                rm,
+            } => self.fp_arith(raw, rd, rs1, rs2, rm, Format::add),
+            DecodedInstr::Fsub {
+                raw,
+                rd,
+                rs1,
+                rs2,
+                rm,
+            } => self.fp_arith(raw, rd, rs1, rs2, rm, Format::sub),
+            DecodedInstr::Fmul {
+                raw,
+                rd,
+                rs1,
+                rs2,
+                rm,
+            } => self.fp_arith(raw, rd, rs1, rs2, rm, Format::mul),
+            DecodedInstr::Fdiv {
+                raw,
+                rd,
+                rs1,
+                rs2,
+                rm,
+            } => self.fp_arith(raw, rd, rs1, rs2, rm, Format::div),
+            DecodedInstr::Fsqrt { raw, rd, rs1, rm } => self.fp_sqrt(raw, rd, rs1, rm),
+            DecodedInstr::Fsgnj { raw, rd, rs1, rs2 } => {
+                self.fp_sign_inject(raw, rd, rs1, rs2, |_, b| b)
+            }
+            DecodedInstr::Fsgnjn { raw, rd, rs1, rs2 } => {
+                self.fp_sign_inject(raw, rd, rs1, rs2, |_, b| !b)
             }
+            DecodedInstr::Fsgnjx { raw, rd, rs1, rs2 } => {
+                self.fp_sign_inject(raw, rd, rs1, rs2, |a, b| a ^ b)
+            }
+            DecodedInstr::Fmin { raw, rd, rs1, rs2 } => {
+                self.fp_min_max(raw, rd, rs1, rs2, Format::min)
+            }
+            DecodedInstr::Fmax { raw, rd, rs1, rs2 } => {
+                self.fp_min_max(raw, rd, rs1, rs2, Format::max)
+            }
+            DecodedInstr::FcvtW { raw, rd, rs1, rm } => self.fp_to_int(raw, rd, rs1, rm, true, 32),
+            DecodedInstr::FcvtWu { raw, rd, rs1, rm } => {
+                self.fp_to_int(raw, rd, rs1, rm, false, 32)
+            }
+            DecodedInstr::FcvtL { raw, rd, rs1, rm } => self.fp_to_int(raw, rd, rs1, rm, true, 64),
+            DecodedInstr::FcvtLu { raw, rd, rs1, rm } => {
+                self.fp_to_int(raw, rd, rs1, rm, false, 64)
+            }
+            DecodedInstr::FcvtFromW { raw, rd, rs1, rm } => {
+                self.fp_from_int(raw, rd, rs1, rm, true, 32)
+            }
+            DecodedInstr::FcvtFromWu { raw, rd, rs1, rm } => {
+                self.fp_from_int(raw, rd, rs1, rm, false, 32)
+            }
+            DecodedInstr::FcvtFromL { raw, rd, rs1, rm } => {
+                self.fp_from_int(raw, rd, rs1, rm, true, 64)
+            }
+            DecodedInstr::FcvtFromLu { raw, rd, rs1, rm } => {
+                self.fp_from_int(raw, rd, rs1, rm, false, 64)
+            }
+            DecodedInstr::FcvtSD { raw, rd, rs1, rm } => {
+                self.fp_convert(raw, rd, rs1, rm, F64, F32)
+            }
+            DecodedInstr::FcvtDS { raw, rd, rs1, rm } => {
+                self.fp_convert(raw, rd, rs1, rm, F32, F64)
+            }
+            DecodedInstr::FmvXW { raw, rd, rs1 } => self.fp_move_to_int(raw, rd, rs1, F32),
+            DecodedInstr::FmvXD { raw, rd, rs1 } => self.fp_move_to_int(raw, rd, rs1, F64),
+            DecodedInstr::FmvWX { raw, rd, rs1 } => self.fp_move_from_int(raw, rd, rs1, F32),
+            DecodedInstr::FmvDX { raw, rd, rs1 } => self.fp_move_from_int(raw, rd, rs1, F64),
+            DecodedInstr::Feq { raw, rd, rs1, rs2 } => {
+                self.fp_compare(raw, rd, rs1, rs2, Format::eq)
+            }
+            DecodedInstr::Flt { raw, rd, rs1, rs2 } => {
+                self.fp_compare(raw, rd, rs1, rs2, Format::lt)
+            }
+            DecodedInstr::Fle { raw, rd, rs1, rs2 } => {
+                self.fp_compare(raw, rd, rs1, rs2, Format::le)
+            }
+            DecodedInstr::Fclass { raw, rd, rs1 } => self.fp_classify(raw, rd, rs1),
             DecodedInstr::IllegalInstruction { inst } => Err(Exception::IllegalInstruction(inst)),
         }
     }
+
+    /// Read-modify-write for the AMO* instructions. The width comes from funct3
+    /// (0x2: .w, 0x3: .d); word operands are sign-extended before `op` is applied
+    /// and the loaded value is written to rd.
+    fn atomic_rmw(
+        &mut self,
+        bus: &mut Bus,
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+        op: impl Fn(u64, u64) -> u64,
+    ) -> Result<(), Exception> {
+        let addr = self.regs[rs1];
+        let val = if (raw >> 12) & 0x7 == 0x3 {
+            let src = self.regs[rs2];
+            self.read_modify_write(bus, addr, 64, |val| op(val, src))?
+        } else {
+            let src = self.regs[rs2] as i32 as i64 as u64;
+            let val =
+                self.read_modify_write(bus, addr, 32, |val| op(val as i32 as i64 as u64, src))?;
+            val as i32 as i64 as u64
+        };
+        self.regs[rd] = val;
+        self.mark_as_dest(rd);
+        self.mark_as_src1(rs1);
+        self.mark_as_src2(rs2);
+        Ok(())
+    }
 }
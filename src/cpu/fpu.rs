@@ -0,0 +1,353 @@
+use super::*;
+use crate::softfloat::*;
+
+// upper half of a NaN-boxed single-precision value
+const NAN_BOX: u64 = 0xffff_ffff_0000_0000;
+
+/// The format selected by the fmt field (bits 25-26) of an FP instruction.
+fn format_of(raw: u32) -> Format {
+    if (raw >> 25) & 0x3 == 0x1 {
+        F64
+    } else {
+        F32
+    }
+}
+
+impl Cpu {
+    /// FP instructions and FP CSR accesses are illegal while mstatus.FS is Off.
+    pub(crate) fn require_fpu(&self, raw: u32) -> Result<(), Exception> {
+        if self.csr.fs() == FS_OFF {
+            return Err(Exception::IllegalInstruction(raw));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn require_fpu_for_csr(&self, raw: u32, csr: usize) -> Result<(), Exception> {
+        match csr {
+            FFLAGS | FRM | FCSR => self.require_fpu(raw),
+            _ => Ok(()),
+        }
+    }
+
+    /// Resolve a static or dynamic rounding mode; reserved encodings are illegal.
+    fn rounding_mode(&self, raw: u32, rm: u64) -> Result<u64, Exception> {
+        let rm = if rm == RM_DYN { self.csr.frm() } else { rm };
+        if rm > RM_RMM {
+            return Err(Exception::IllegalInstruction(raw));
+        }
+        Ok(rm)
+    }
+
+    /// Read an FP register as `fmt`. A single that is not properly NaN-boxed
+    /// reads as the canonical NaN.
+    pub(crate) fn read_freg(&self, fmt: Format, reg: usize) -> u64 {
+        let val = self.fregs[reg];
+        if fmt == F64 {
+            val
+        } else if val & NAN_BOX == NAN_BOX {
+            val & 0xffff_ffff
+        } else {
+            F32.canonical_nan()
+        }
+    }
+
+    pub(crate) fn write_freg(&mut self, fmt: Format, reg: usize, val: u64) {
+        self.fregs[reg] = if fmt == F64 { val } else { val | NAN_BOX };
+        self.csr.set_fs(FS_DIRTY);
+    }
+
+    pub(crate) fn fp_load(
+        &mut self,
+        bus: &mut Bus,
+        fmt: Format,
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        imm: u64,
+    ) -> Result<(), Exception> {
+        self.require_fpu(raw)?;
+        let addr = self.regs[rs1].wrapping_add(imm);
+        let size = if fmt == F64 { 64 } else { 32 };
+        let val = self.load(bus, addr, size)?;
+        self.write_freg(fmt, rd, val);
+        self.mark_as_src1(rs1);
+        Ok(())
+    }
+
+    pub(crate) fn fp_store(
+        &mut self,
+        bus: &mut Bus,
+        fmt: Format,
+        raw: u32,
+        rs1: usize,
+        rs2: usize,
+        imm: u64,
+    ) -> Result<(), Exception> {
+        self.require_fpu(raw)?;
+        let addr = self.regs[rs1].wrapping_add(imm);
+        // stores move the raw bits without checking the NaN box
+        let (size, val) = if fmt == F64 {
+            (64, self.fregs[rs2])
+        } else {
+            (32, self.fregs[rs2] & 0xffff_ffff)
+        };
+        self.store(bus, addr, size, val)?;
+        self.mark_as_src1(rs1);
+        Ok(())
+    }
+
+    /// fmadd/fmsub/fnmsub/fnmadd: `(+/-)(rs1 * rs2) (+/-) rs3`.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn fp_fused(
+        &mut self,
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+        rs3: usize,
+        rm: u64,
+        negate_product: bool,
+        negate_addend: bool,
+    ) -> Result<(), Exception> {
+        self.require_fpu(raw)?;
+        let rm = self.rounding_mode(raw, rm)?;
+        let fmt = format_of(raw);
+        let mut a = self.read_freg(fmt, rs1);
+        let mut c = self.read_freg(fmt, rs3);
+        if negate_product {
+            a = fmt.negate(a);
+        }
+        if negate_addend {
+            c = fmt.negate(c);
+        }
+        let mut flags = 0;
+        let result = fmt.mul_add(a, self.read_freg(fmt, rs2), c, rm, &mut flags);
+        self.csr.accrue_fflags(flags);
+        self.write_freg(fmt, rd, result);
+        Ok(())
+    }
+
+    pub(crate) fn fp_arith(
+        &mut self,
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+        rm: u64,
+        op: impl Fn(Format, u64, u64, u64, &mut u64) -> u64,
+    ) -> Result<(), Exception> {
+        self.require_fpu(raw)?;
+        let rm = self.rounding_mode(raw, rm)?;
+        let fmt = format_of(raw);
+        let mut flags = 0;
+        let result = op(
+            fmt,
+            self.read_freg(fmt, rs1),
+            self.read_freg(fmt, rs2),
+            rm,
+            &mut flags,
+        );
+        self.csr.accrue_fflags(flags);
+        self.write_freg(fmt, rd, result);
+        Ok(())
+    }
+
+    pub(crate) fn fp_sqrt(
+        &mut self,
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rm: u64,
+    ) -> Result<(), Exception> {
+        self.require_fpu(raw)?;
+        let rm = self.rounding_mode(raw, rm)?;
+        let fmt = format_of(raw);
+        let mut flags = 0;
+        let result = fmt.sqrt(self.read_freg(fmt, rs1), rm, &mut flags);
+        self.csr.accrue_fflags(flags);
+        self.write_freg(fmt, rd, result);
+        Ok(())
+    }
+
+    /// fsgnj/fsgnjn/fsgnjx: the magnitude of rs1 with a sign computed from
+    /// the signs of rs1 and rs2.
+    pub(crate) fn fp_sign_inject(
+        &mut self,
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+        sign: impl Fn(u64, u64) -> u64,
+    ) -> Result<(), Exception> {
+        self.require_fpu(raw)?;
+        let fmt = format_of(raw);
+        let sign_bit = if fmt == F64 { 1 << 63 } else { 1 << 31 };
+        let a = self.read_freg(fmt, rs1);
+        let b = self.read_freg(fmt, rs2);
+        let result = (a & !sign_bit) | (sign(a, b) & sign_bit);
+        self.write_freg(fmt, rd, result);
+        Ok(())
+    }
+
+    pub(crate) fn fp_min_max(
+        &mut self,
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+        op: impl Fn(Format, u64, u64, &mut u64) -> u64,
+    ) -> Result<(), Exception> {
+        self.require_fpu(raw)?;
+        let fmt = format_of(raw);
+        let mut flags = 0;
+        let result = op(
+            fmt,
+            self.read_freg(fmt, rs1),
+            self.read_freg(fmt, rs2),
+            &mut flags,
+        );
+        self.csr.accrue_fflags(flags);
+        self.write_freg(fmt, rd, result);
+        Ok(())
+    }
+
+    /// feq/flt/fle write 1 or 0 to the integer register rd.
+    pub(crate) fn fp_compare(
+        &mut self,
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+        op: impl Fn(Format, u64, u64, &mut u64) -> bool,
+    ) -> Result<(), Exception> {
+        self.require_fpu(raw)?;
+        let fmt = format_of(raw);
+        let mut flags = 0;
+        let result = op(
+            fmt,
+            self.read_freg(fmt, rs1),
+            self.read_freg(fmt, rs2),
+            &mut flags,
+        );
+        self.csr.accrue_fflags(flags);
+        self.regs[rd] = result as u64;
+        self.mark_as_dest(rd);
+        Ok(())
+    }
+
+    pub(crate) fn fp_classify(&mut self, raw: u32, rd: usize, rs1: usize) -> Result<(), Exception> {
+        self.require_fpu(raw)?;
+        let fmt = format_of(raw);
+        self.regs[rd] = fmt.classify(self.read_freg(fmt, rs1));
+        self.mark_as_dest(rd);
+        Ok(())
+    }
+
+    /// fcvt.{w,wu,l,lu}.{s,d}: FP register to integer register.
+    pub(crate) fn fp_to_int(
+        &mut self,
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rm: u64,
+        signed: bool,
+        width: u32,
+    ) -> Result<(), Exception> {
+        self.require_fpu(raw)?;
+        let rm = self.rounding_mode(raw, rm)?;
+        let fmt = format_of(raw);
+        let mut flags = 0;
+        let result = fmt.to_int(self.read_freg(fmt, rs1), signed, width, rm, &mut flags);
+        self.csr.accrue_fflags(flags);
+        self.regs[rd] = result;
+        self.mark_as_dest(rd);
+        Ok(())
+    }
+
+    /// fcvt.{s,d}.{w,wu,l,lu}: integer register to FP register.
+    pub(crate) fn fp_from_int(
+        &mut self,
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rm: u64,
+        signed: bool,
+        width: u32,
+    ) -> Result<(), Exception> {
+        self.require_fpu(raw)?;
+        let rm = self.rounding_mode(raw, rm)?;
+        let fmt = format_of(raw);
+        let src = self.regs[rs1];
+        let (negative, magnitude) = match (signed, width) {
+            (true, 32) => {
+                let v = src as i32 as i64;
+                (v < 0, v.unsigned_abs())
+            }
+            (true, _) => ((src as i64) < 0, (src as i64).unsigned_abs()),
+            (false, 32) => (false, src & 0xffff_ffff),
+            (false, _) => (false, src),
+        };
+        let mut flags = 0;
+        let result = fmt.int_to_float(negative, magnitude, rm, &mut flags);
+        self.csr.accrue_fflags(flags);
+        self.write_freg(fmt, rd, result);
+        self.mark_as_src1(rs1);
+        Ok(())
+    }
+
+    /// fcvt.s.d and fcvt.d.s.
+    pub(crate) fn fp_convert(
+        &mut self,
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rm: u64,
+        from: Format,
+        to: Format,
+    ) -> Result<(), Exception> {
+        self.require_fpu(raw)?;
+        let rm = self.rounding_mode(raw, rm)?;
+        let mut flags = 0;
+        let result = to.convert_from(from, self.read_freg(from, rs1), rm, &mut flags);
+        self.csr.accrue_fflags(flags);
+        self.write_freg(to, rd, result);
+        Ok(())
+    }
+
+    /// fmv.x.w/fmv.x.d: copy raw bits to an integer register, sign-extending singles.
+    pub(crate) fn fp_move_to_int(
+        &mut self,
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        fmt: Format,
+    ) -> Result<(), Exception> {
+        self.require_fpu(raw)?;
+        self.regs[rd] = if fmt == F64 {
+            self.fregs[rs1]
+        } else {
+            self.fregs[rs1] as u32 as i32 as i64 as u64
+        };
+        self.mark_as_dest(rd);
+        Ok(())
+    }
+
+    /// fmv.w.x/fmv.d.x: copy raw bits from an integer register.
+    pub(crate) fn fp_move_from_int(
+        &mut self,
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        fmt: Format,
+    ) -> Result<(), Exception> {
+        self.require_fpu(raw)?;
+        let val = if fmt == F64 {
+            self.regs[rs1]
+        } else {
+            self.regs[rs1] & 0xffff_ffff
+        };
+        self.write_freg(fmt, rd, val);
+        self.mark_as_src1(rs1);
+        Ok(())
+    }
+}
@@ -1,12 +1,106 @@
 use super::*;
 
+// PTE bits above the ppn: Svnapot's N, Svpbmt's memory type, and bits
+// reserved for future use
+const PTE_N: u64 = 1 << 63;
+const PTE_PBMT_SHIFT: u64 = 61;
+const PTE_RESERVED: u64 = 0x7f << 54;
+
+/// Optional page-table extensions, all off by default.
+#[derive(Clone, Copy, Default)]
+pub struct PagingExtensions {
+    /// Leaf PTEs with the N bit set map naturally aligned 64 KiB ranges.
+    pub svnapot: bool,
+    /// PTE bits 62:61 select a page-based memory type while menvcfg.PBMTE
+    /// is set, as it is at reset.
+    pub svpbmt: bool,
+    /// A clear A bit, or a clear D bit on a store, raises a page fault
+    /// instead of being set by the walker. Without it the hart has Svadu,
+    /// and the walker sets them while menvcfg.ADUE is set.
+    pub svade: bool,
+}
+
+// Svpbmt memory types; the emulator has no caches and does not reorder
+// accesses, so NC and IO pages behave like PMA ones
+#[derive(Debug)]
+enum MemoryType {
+    Pma,
+    NonCacheable,
+    Io,
+}
+
+// who a translation is for, and so which state it may update
+#[derive(Clone, Copy, PartialEq)]
+enum Walk {
+    // a guest access: counted, cached in the TLB and setting A/D bits
+    Access,
+    // the emulator's bookkeeping: TLB hits are not counted
+    Quiet,
+    // diagnostics: no side effects at all
+    Inspect,
+}
+
+fn page_fault(acc: AccessMode, va: u64) -> Exception {
+    match acc {
+        AccessMode::Fetch => Exception::InstructionPageFault(va),
+        AccessMode::Load => Exception::LoadPageFault(va),
+        AccessMode::Store => Exception::StoreAMOPageFault(va),
+    }
+}
+
 impl Cpu {
-    /// SV39 page-table walk + permission check + A/D handling + simple TLB keyed by (satp_ppn, asid, va_page).
+    // whether the R/W/X and U bits of a leaf PTE allow the access
+    fn leaf_permits(&self, pte: u64, acc: AccessMode) -> bool {
+        let permitted = match acc {
+            AccessMode::Fetch => bit(pte, 3) == 1,
+            AccessMode::Load => bit(pte, 1) == 1,
+            AccessMode::Store => bit(pte, 2) == 1,
+        };
+        permitted && !(self.mode == U_MODE && bit(pte, 4) == 0)
+    }
+
+    /// Sv39/Sv48/Sv57 page-table walk + permission check + A/D handling, with
+    /// leaf translations cached in the TLB.
     pub(crate) fn translate(
         &mut self,
         bus: &mut Bus,
         va: u64,
         acc: AccessMode,
+    ) -> Result<u64, Exception> {
+        self.walk(bus, va, acc, Walk::Access)
+    }
+
+    /// `translate` for the emulator's own bookkeeping, such as finding the
+    /// cached block at pc: a TLB hit is not counted in the TLB or the
+    /// statistics. A miss walks the page table as usual, and counts, since
+    /// the guest's own access would have to as well.
+    pub(crate) fn translate_quietly(
+        &mut self,
+        bus: &mut Bus,
+        va: u64,
+        acc: AccessMode,
+    ) -> Result<u64, Exception> {
+        self.walk(bus, va, acc, Walk::Quiet)
+    }
+
+    /// `translate` for diagnostics such as backtraces: nothing the guest or
+    /// the statistics can see changes. The TLB is neither counted nor
+    /// filled and the A and D bits are left as they are.
+    pub(crate) fn translate_for_inspection(
+        &mut self,
+        bus: &mut Bus,
+        va: u64,
+        acc: AccessMode,
+    ) -> Result<u64, Exception> {
+        self.walk(bus, va, acc, Walk::Inspect)
+    }
+
+    fn walk(
+        &mut self,
+        bus: &mut Bus,
+        va: u64,
+        acc: AccessMode,
+        walk: Walk,
     ) -> Result<u64, Exception> {
         const PAGESIZE: u64 = 4096;
         const PTESIZE: u64 = 8;
@@ -23,100 +117,125 @@ impl Cpu {
         }
 
         let satp = self.csr.load_csrs(SATP, self.cycle, &self.interrupt_list);
-        let mode = (satp >> 60) & 0xF;
-        let asid = (satp >> 44) & 0xFFFF;
-        let satp_ppn = satp & ((1u64 << 44) - 1);
+        let mode = satp >> SATP_MODE_SHIFT;
+        let asid = (satp & SATP_ASID) >> SATP_ASID_SHIFT;
+        let satp_ppn = satp & SATP_PPN;
 
-        if mode == 0 {
-            return Ok(va);
-        }
-        if mode != 8 {
-            return match acc {
-                AccessMode::Fetch => Err(Exception::InstructionPageFault(va as u32)),
-                AccessMode::Load => Err(Exception::LoadPageFault(va as u32)),
-                AccessMode::Store => Err(Exception::StoreAMOPageFault(va as u32)),
-            };
-        }
+        // satp only holds supported modes, see Csr::store_csrs
+        let levels = match mode {
+            SATP_MODE_BARE => return Ok(va),
+            SATP_MODE_SV39 => 3,
+            SATP_MODE_SV48 => 4,
+            SATP_MODE_SV57 => 5,
+            _ => return Err(page_fault(acc, va)),
+        };
 
-        let sign = (va >> 38) & 1;
-        let upper = va >> 39;
-        if (sign == 0 && upper != 0) || (sign == 1 && upper != ((1u64 << 25) - 1)) {
-            return match acc {
-                AccessMode::Fetch => Err(Exception::InstructionPageFault(va as u32)),
-                AccessMode::Load => Err(Exception::LoadPageFault(va as u32)),
-                AccessMode::Store => Err(Exception::StoreAMOPageFault(va as u32)),
-            };
+        // the bits above the virtual address must all equal its top bit
+        let unused_bits = 64 - (12 + 9 * levels);
+        if ((va << unused_bits) as i64 >> unused_bits) as u64 != va {
+            return Err(page_fault(acc, va));
         }
 
-        let va_page = va >> 12;
-        let tlb_key = (satp_ppn, asid, va_page);
-        if let Some(&pa_page) = self.address_translation_cache.get(&tlb_key) {
-            return Ok((pa_page << 12) | (va & 0xFFF));
+        let root = satp & !SATP_ASID;
+        let store = matches!(acc, AccessMode::Store);
+        let cached = match walk {
+            Walk::Access => self.tlb.lookup(va, root, asid, store),
+            Walk::Quiet => match self.tlb.probe(va, root, asid, store) {
+                Some(entry) => Some(entry),
+                None => return self.translate(bus, va, acc),
+            },
+            Walk::Inspect => self.tlb.probe(va, root, asid, store),
+        };
+        if let Some(entry) = cached {
+            if let (Walk::Access, Some(stats)) = (walk, &mut self.stats) {
+                stats.translation_cache_hit();
+            }
+            if !self.leaf_permits(entry.flags, acc) {
+                return Err(page_fault(acc, va));
+            }
+            return Ok(entry.translate(va));
+        }
+        if let (Walk::Access | Walk::Quiet, Some(stats)) = (walk, &mut self.stats) {
+            stats.page_walk();
         }
 
-        let vpn0 = (va >> 12) & 0x1FF;
-        let vpn1 = (va >> 21) & 0x1FF;
-        let vpn2 = (va >> 30) & 0x1FF;
-        let vpn = [vpn0, vpn1, vpn2];
-
+        let menvcfg = self
+            .csr
+            .load_csrs(MENVCFG, self.cycle, &self.interrupt_list);
+        let pbmte = menvcfg & MENVCFG_PBMTE != 0;
         let mut a = satp_ppn * PAGESIZE;
-        let mut level: i32 = 2;
-
-        let mut pte_addr: u64 = 0;
-        let mut pte: u64 = 0;
+        let mut level = levels - 1;
+        // a G bit anywhere on the way makes every mapping below it global
+        let mut global = false;
 
         loop {
-            pte_addr = a + vpn[level as usize] * PTESIZE;
-            pte = bus.load(pte_addr, 64).map_err(|_| match acc {
-                AccessMode::Fetch => Exception::InstructionPageFault(va as u32),
-                AccessMode::Load => Exception::LoadPageFault(va as u32),
-                AccessMode::Store => Exception::StoreAMOPageFault(va as u32),
-            })?;
+            let vpn = (va >> (12 + 9 * level)) & 0x1FF;
+            let pte_addr = a + vpn * PTESIZE;
+            // the walk's own accesses are checked as S-mode ones
+            self.pmp_check(pte_addr, 64, AccessMode::Load, S_MODE)
+                .map_err(|_| pmp::access_fault(acc))?;
+            let pte = bus.load(pte_addr, 64).map_err(|_| page_fault(acc, va))?;
 
             let v = bit(pte, 0);
             let r = bit(pte, 1);
             let w = bit(pte, 2);
             let x = bit(pte, 3);
-            let u = bit(pte, 4);
             let a_bit = bit(pte, 6);
             let d_bit = bit(pte, 7);
 
-            if v == 0 || (r == 0 && w == 1) {
-                return match acc {
-                    AccessMode::Fetch => Err(Exception::InstructionPageFault(va as u32)),
-                    AccessMode::Load => Err(Exception::LoadPageFault(va as u32)),
-                    AccessMode::Store => Err(Exception::StoreAMOPageFault(va as u32)),
-                };
+            if v == 0 || (r == 0 && w == 1) || pte & PTE_RESERVED != 0 {
+                return Err(page_fault(acc, va));
             }
 
+            global |= bit(pte, 5) == 1;
             let is_leaf = (r == 1) || (x == 1);
+            let napot = pte & PTE_N != 0;
+            let memory_type = match (pte >> PTE_PBMT_SHIFT) & 0b11 {
+                0 => MemoryType::Pma,
+                1 if pbmte => MemoryType::NonCacheable,
+                2 if pbmte => MemoryType::Io,
+                // reserved unless menvcfg.PBMTE is set, which needs Svpbmt,
+                // or the reserved encoding
+                _ => return Err(page_fault(acc, va)),
+            };
+            // N and the memory type are reserved in pointers to the next level
+            if !is_leaf && (napot || !matches!(memory_type, MemoryType::Pma)) {
+                return Err(page_fault(acc, va));
+            }
             if is_leaf {
-                match acc {
-                    AccessMode::Fetch => {
-                        if x == 0 {
-                            return Err(Exception::InstructionPageFault(va as u32));
-                        }
-                    }
-                    AccessMode::Load => {
-                        if r == 0 {
-                            return Err(Exception::LoadPageFault(va as u32));
-                        }
-                    }
-                    AccessMode::Store => {
-                        if w == 0 {
-                            return Err(Exception::StoreAMOPageFault(va as u32));
-                        }
+                if !self.leaf_permits(pte, acc) {
+                    return Err(page_fault(acc, va));
+                }
+
+                let mut ppn = (pte >> 10) & SATP_PPN;
+                // a superpage must be aligned to its size
+                let superpage_mask = (1u64 << (9 * level)) - 1;
+                if ppn & superpage_mask != 0 {
+                    return Err(page_fault(acc, va));
+                }
+                let mut page_shift = 12 + 9 * level as u32;
+                if napot {
+                    // 64 KiB pages are the only NAPOT size defined, encoded
+                    // by ppn[3:0] = 0b1000 in a level 0 PTE
+                    if !self.paging.svnapot || level != 0 || ppn & 0xf != 0b1000 {
+                        return Err(page_fault(acc, va));
                     }
+                    ppn &= !0xf;
+                    page_shift = 16;
                 }
 
-                if self.mode == U_MODE && u == 0 {
-                    return match acc {
-                        AccessMode::Fetch => Err(Exception::InstructionPageFault(va as u32)),
-                        AccessMode::Load => Err(Exception::LoadPageFault(va as u32)),
-                        AccessMode::Store => Err(Exception::StoreAMOPageFault(va as u32)),
-                    };
+                // the low ppn bits of a superpage come from the virtual address
+                let pa = (ppn << 12) | (va & ((1 << page_shift) - 1));
+                if walk == Walk::Inspect {
+                    return Ok(pa);
                 }
 
+                let needs_update = a_bit == 0 || (matches!(acc, AccessMode::Store) && d_bit == 0);
+                // Svadu updates them while menvcfg.ADUE is set, which it never
+                // is under Svade
+                if needs_update && menvcfg & MENVCFG_ADUE == 0 {
+                    return Err(page_fault(acc, va));
+                }
                 let mut new_pte = pte;
                 if a_bit == 0 {
                     new_pte |= 1 << 6;
@@ -125,67 +244,326 @@ impl Cpu {
                     new_pte |= 1 << 7;
                 }
                 if new_pte != pte {
-                    bus.store(pte_addr, 64, new_pte).map_err(|_| match acc {
-                        AccessMode::Fetch => Exception::InstructionPageFault(va as u32),
-                        AccessMode::Load => Exception::LoadPageFault(va as u32),
-                        AccessMode::Store => Exception::StoreAMOPageFault(va as u32),
-                    })?;
-                    pte = new_pte;
+                    self.pmp_check(pte_addr, 64, AccessMode::Store, S_MODE)
+                        .map_err(|_| pmp::access_fault(acc))?;
+                    bus.store(pte_addr, 64, new_pte)
+                        .map_err(|_| page_fault(acc, va))?;
                 }
 
-                let ppn0 = (pte >> 10) & 0x1FF;
-                let ppn1 = (pte >> 19) & 0x1FF;
-                let ppn2 = (pte >> 28) & 0x3FF_FFFF;
-                if level == 2 {
-                    if ppn0 != 0 || ppn1 != 0 {
-                        return match acc {
-                            AccessMode::Fetch => Err(Exception::InstructionPageFault(va as u32)),
-                            AccessMode::Load => Err(Exception::LoadPageFault(va as u32)),
-                            AccessMode::Store => Err(Exception::StoreAMOPageFault(va as u32)),
-                        };
-                    }
-                } else if level == 1 {
-                    if ppn0 != 0 {
-                        return match acc {
-                            AccessMode::Fetch => Err(Exception::InstructionPageFault(va as u32)),
-                            AccessMode::Load => Err(Exception::LoadPageFault(va as u32)),
-                            AccessMode::Store => Err(Exception::StoreAMOPageFault(va as u32)),
-                        };
-                    }
-                }
+                trace!("0x{:x} maps to 0x{:x} as {:?} memory", va, pa, memory_type);
 
-                let page_off = va & 0xFFF;
-                let pa: u64 = match level {
-                    0 => {
-                        let ppn = (pte >> 10) & ((1u64 << 44) - 1);
-                        (ppn << 12) | page_off
-                    }
-                    1 => {
-                        let ppn = ((ppn2 << 18) | (ppn1 << 9) | vpn0) & ((1u64 << 44) - 1);
-                        (ppn << 12) | page_off
-                    }
-                    2 => {
-                        let ppn = ((ppn2 << 18) | (vpn1 << 9) | vpn0) & ((1u64 << 44) - 1);
-                        (ppn << 12) | page_off
-                    }
-                    _ => unreachable!(),
-                };
-
-                self.address_translation_cache.insert(tlb_key, pa >> 12);
+                let flags = (new_pte & 0xff) | ((global as u64) << 5);
+                self.tlb.insert(va, pa, root, asid, page_shift, flags);
                 return Ok(pa);
             }
 
             if level == 0 {
-                return match acc {
-                    AccessMode::Fetch => Err(Exception::InstructionPageFault(va as u32)),
-                    AccessMode::Load => Err(Exception::LoadPageFault(va as u32)),
-                    AccessMode::Store => Err(Exception::StoreAMOPageFault(va as u32)),
-                };
+                return Err(page_fault(acc, va));
             }
 
-            let next_ppn = (pte >> 10) & ((1u64 << 44) - 1);
+            let next_ppn = (pte >> 10) & SATP_PPN;
             a = next_ppn * PAGESIZE;
             level -= 1;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT: u64 = 0x8010_0000;
+    const V: u64 = 1 << 0;
+    const RWX: u64 = 0b1110;
+
+    // builds page tables in DRAM, allocating them after the root
+    struct PageTables {
+        levels: u64,
+        next_table: u64,
+    }
+
+    impl PageTables {
+        fn new(levels: u64) -> Self {
+            Self {
+                levels,
+                next_table: ROOT + 0x1000,
+            }
+        }
+
+        // returns the address of the leaf PTE
+        fn map(&mut self, bus: &mut Bus, va: u64, pa: u64, leaf_level: u64) -> u64 {
+            let mut table = ROOT;
+            for level in (leaf_level + 1..self.levels).rev() {
+                let pte_addr = table + ((va >> (12 + 9 * level)) & 0x1FF) * 8;
+                let pte = bus.load(pte_addr, 64).unwrap();
+                table = if pte & V != 0 {
+                    (pte >> 10) << 12
+                } else {
+                    let next = self.next_table;
+                    self.next_table += 0x1000;
+                    bus.store(pte_addr, 64, ((next >> 12) << 10) | V).unwrap();
+                    next
+                };
+            }
+            let pte_addr = table + ((va >> (12 + 9 * leaf_level)) & 0x1FF) * 8;
+            bus.store(pte_addr, 64, ((pa >> 12) << 10) | RWX | V)
+                .unwrap();
+            pte_addr
+        }
+    }
+
+    fn set_bits(bus: &mut Bus, pte_addr: u64, bits: u64) {
+        let pte = bus.load(pte_addr, 64).unwrap();
+        bus.store(pte_addr, 64, pte | bits).unwrap();
+    }
+
+    // an S-mode hart translating through the tables at ROOT
+    fn s_mode(satp_mode: u64) -> (Cpu, Bus) {
+        let bus = Bus::new(Vec::new(), 0x8000_0000);
+        let mut cpu = Cpu::new(0x8000_0000, 0);
+        cpu.mode = S_MODE;
+        cpu.csr.store_csrs(PMPADDR0, u64::MAX);
+        cpu.csr
+            .store_csrs(PMPCFG0, PMP_NAPOT | PMP_X | PMP_W | PMP_R);
+        cpu.csr
+            .store_csrs(SATP, (satp_mode << SATP_MODE_SHIFT) | (ROOT >> 12));
+        (cpu, bus)
+    }
+
+    #[test]
+    fn test_sv39_sv48_sv57() {
+        for (mode, levels) in [
+            (SATP_MODE_SV39, 3),
+            (SATP_MODE_SV48, 4),
+            (SATP_MODE_SV57, 5),
+        ] {
+            let (mut cpu, mut bus) = s_mode(mode);
+            assert_eq!(cpu.csr.load_csrs(SATP, 0, &cpu.interrupt_list) >> 60, mode);
+
+            let mut tables = PageTables::new(levels);
+            // the lowest page of the upper half of the address space
+            let va_bits = 12 + 9 * levels;
+            let high = u64::MAX << (va_bits - 1);
+            tables.map(&mut bus, high, 0x8020_0000, 0);
+            // a 2 MiB megapage, and one whose physical address is misaligned
+            tables.map(&mut bus, 0x4020_0000, 0x8040_0000, 1);
+            tables.map(&mut bus, 0x4040_0000, 0x8060_1000, 1);
+
+            let mut translate = |va, acc| cpu.translate(&mut bus, va, acc);
+            assert_eq!(
+                translate(high | 0x678, AccessMode::Load).unwrap(),
+                0x8020_0678
+            );
+            assert_eq!(
+                translate(0x4021_2345, AccessMode::Store).unwrap(),
+                0x8041_2345
+            );
+            assert!(matches!(
+                translate(0x4040_0000, AccessMode::Load),
+                Err(Exception::LoadPageFault(0x4040_0000))
+            ));
+            // an address that is not sign-extended from its top bit
+            let non_canonical = high ^ (1 << va_bits);
+            assert!(matches!(
+                translate(non_canonical, AccessMode::Fetch),
+                Err(Exception::InstructionPageFault(va)) if va == non_canonical
+            ));
+        }
+    }
+
+    #[test]
+    fn test_svnapot_and_svpbmt() {
+        let (mut cpu, mut bus) = s_mode(SATP_MODE_SV39);
+        let mut tables = PageTables::new(3);
+        // one of the 16 PTEs of a 64 KiB page at 0x10000 -> 0x80300000
+        let napot = tables.map(&mut bus, 0x1_5000, 0x8030_8000, 0);
+        set_bits(&mut bus, napot, PTE_N);
+        let io = tables.map(&mut bus, 0x2_0000, 0x8040_0000, 0);
+        set_bits(&mut bus, io, 2 << PTE_PBMT_SHIFT);
+        let reserved = tables.map(&mut bus, 0x3_0000, 0x8050_0000, 0);
+        set_bits(&mut bus, reserved, 3 << PTE_PBMT_SHIFT);
+
+        let is_page_fault = |result| matches!(result, Err(Exception::LoadPageFault(_)));
+        // N and PBMT are reserved bits until the extensions are enabled
+        assert!(is_page_fault(cpu.translate(
+            &mut bus,
+            0x1_5678,
+            AccessMode::Load
+        )));
+        assert!(is_page_fault(cpu.translate(
+            &mut bus,
+            0x2_0000,
+            AccessMode::Load
+        )));
+
+        cpu.set_paging_extensions(PagingExtensions {
+            svnapot: true,
+            svpbmt: true,
+            svade: false,
+        });
+        assert_eq!(
+            cpu.translate(&mut bus, 0x1_5678, AccessMode::Load).unwrap(),
+            0x8030_5678
+        );
+        assert_eq!(
+            cpu.translate(&mut bus, 0x2_0010, AccessMode::Load).unwrap(),
+            0x8040_0010
+        );
+        assert!(is_page_fault(cpu.translate(
+            &mut bus,
+            0x3_0000,
+            AccessMode::Load
+        )));
+
+        // the memory types are reserved again once menvcfg.PBMTE is cleared
+        cpu.csr.store_csrs(MENVCFG, MENVCFG_ADUE);
+        cpu.tlb.flush(None, None);
+        assert!(is_page_fault(cpu.translate(
+            &mut bus,
+            0x2_0010,
+            AccessMode::Load
+        )));
+
+        // and PBMTE reads as zero without Svpbmt
+        cpu.set_paging_extensions(PagingExtensions::default());
+        cpu.csr.store_csrs(MENVCFG, MENVCFG_PBMTE);
+        assert_eq!(cpu.csr.load_csrs(MENVCFG, 0, &cpu.interrupt_list), 0);
+    }
+
+    #[test]
+    fn test_svade() {
+        let (mut cpu, mut bus) = s_mode(SATP_MODE_SV39);
+        let mut tables = PageTables::new(3);
+        let pte = tables.map(&mut bus, 0x1_0000, 0x8030_0000, 0);
+        let hardware = tables.map(&mut bus, 0x2_0000, 0x8040_0000, 0);
+        cpu.set_paging_extensions(PagingExtensions {
+            svade: true,
+            ..Default::default()
+        });
+
+        // the guest sets A and D itself after the faults
+        assert!(matches!(
+            cpu.translate(&mut bus, 0x1_0000, AccessMode::Load),
+            Err(Exception::LoadPageFault(0x1_0000))
+        ));
+        set_bits(&mut bus, pte, 1 << 6);
+        assert!(matches!(
+            cpu.translate(&mut bus, 0x1_0008, AccessMode::Store),
+            Err(Exception::StoreAMOPageFault(0x1_0008))
+        ));
+        set_bits(&mut bus, pte, 1 << 7);
+        assert_eq!(
+            cpu.translate(&mut bus, 0x1_0008, AccessMode::Store)
+                .unwrap(),
+            0x8030_0008
+        );
+
+        // under Svade menvcfg.ADUE reads as zero
+        cpu.csr.store_csrs(MENVCFG, MENVCFG_ADUE);
+        assert_eq!(cpu.csr.load_csrs(MENVCFG, 0, &cpu.interrupt_list), 0);
+
+        // with Svadu the walker sets them while ADUE is set
+        cpu.set_paging_extensions(PagingExtensions::default());
+        assert!(matches!(
+            cpu.translate(&mut bus, 0x2_0000, AccessMode::Load),
+            Err(Exception::LoadPageFault(0x2_0000))
+        ));
+        cpu.csr.store_csrs(MENVCFG, MENVCFG_ADUE);
+        cpu.translate(&mut bus, 0x2_0000, AccessMode::Store)
+            .unwrap();
+        assert_eq!(bus.load(hardware, 64).unwrap() & 0xc0, 0xc0);
+    }
+
+    #[test]
+    fn test_inspection_has_no_side_effects() {
+        let (mut cpu, mut bus) = s_mode(SATP_MODE_SV39);
+        let mut tables = PageTables::new(3);
+        let pte = tables.map(&mut bus, 0x1_0000, 0x8030_0000, 0);
+        cpu.set_stats(crate::stats::Stats::new(false, None));
+
+        assert_eq!(
+            cpu.translate_for_inspection(&mut bus, 0x1_0010, AccessMode::Load)
+                .unwrap(),
+            0x8030_0010
+        );
+        assert_eq!(bus.load(pte, 64).unwrap() & 0xc0, 0, "A and D untouched");
+        assert!(cpu.tlb.report().contains("; 0 lookups"));
+        assert!(cpu
+            .stats
+            .as_ref()
+            .unwrap()
+            .to_json()
+            .contains("\"page_walks\":0,\"translation_cache_hits\":0"));
+    }
+
+    #[test]
+    fn test_tlb_hits_are_permission_checked() {
+        let (mut cpu, mut bus) = s_mode(SATP_MODE_SV39);
+        let mut tables = PageTables::new(3);
+        let pte = tables.map(&mut bus, 0x1_0000, 0x8030_0000, 0);
+        // read-only
+        let read_only = bus.load(pte, 64).unwrap() & !0b1100;
+        bus.store(pte, 64, read_only).unwrap();
+
+        assert_eq!(
+            cpu.translate(&mut bus, 0x1_0010, AccessMode::Load).unwrap(),
+            0x8030_0010
+        );
+        assert!(matches!(
+            cpu.translate(&mut bus, 0x1_0010, AccessMode::Store),
+            Err(Exception::StoreAMOPageFault(0x1_0010))
+        ));
+        assert!(matches!(
+            cpu.translate(&mut bus, 0x1_0010, AccessMode::Fetch),
+            Err(Exception::InstructionPageFault(0x1_0010))
+        ));
+        cpu.mode = U_MODE;
+        assert!(matches!(
+            cpu.translate(&mut bus, 0x1_0010, AccessMode::Load),
+            Err(Exception::LoadPageFault(0x1_0010))
+        ));
+        cpu.mode = S_MODE;
+
+        // a remapping is seen once the page is flushed
+        tables.map(&mut bus, 0x1_0000, 0x8040_0000, 0);
+        assert_eq!(
+            cpu.translate(&mut bus, 0x1_0010, AccessMode::Load).unwrap(),
+            0x8030_0010
+        );
+        cpu.tlb.flush(Some(0x1_0000), None);
+        assert_eq!(
+            cpu.translate(&mut bus, 0x1_0010, AccessMode::Store)
+                .unwrap(),
+            0x8040_0010
+        );
+    }
+
+    #[test]
+    fn test_global_pointer_makes_mappings_global() {
+        let (mut cpu, mut bus) = s_mode(SATP_MODE_SV39);
+        let mut tables = PageTables::new(3);
+        let pte = tables.map(&mut bus, 0x1_0000, 0x8030_0000, 0);
+        // G in the root entry pointing towards the leaf
+        set_bits(&mut bus, ROOT, 1 << 5);
+        cpu.translate(&mut bus, 0x1_0000, AccessMode::Load).unwrap();
+
+        // flushing the address space keeps the global translation
+        bus.store(pte, 64, 0).unwrap();
+        cpu.tlb.flush(None, Some(0));
+        assert_eq!(
+            cpu.translate(&mut bus, 0x1_0010, AccessMode::Load).unwrap(),
+            0x8030_0010
+        );
+    }
+
+    #[test]
+    fn test_satp_ignores_unsupported_modes() {
+        let mut cpu = Cpu::new(0x8000_0000, 0);
+        let sv48 = (SATP_MODE_SV48 << SATP_MODE_SHIFT) | (ROOT >> 12);
+        cpu.csr.store_csrs(SATP, sv48);
+        // Sv64 is not defined, and mode 1-7 are reserved
+        cpu.csr.store_csrs(SATP, (11 << SATP_MODE_SHIFT) | 0x1234);
+        cpu.csr.store_csrs(SATP, 1 << SATP_MODE_SHIFT);
+        assert_eq!(cpu.csr.load_csrs(SATP, 0, &cpu.interrupt_list), sv48);
+    }
+}
@@ -1,12 +1,24 @@
 mod execute;
+mod fpu;
 mod mmu;
+mod pmp;
+pub mod tlb;
+
+pub use mmu::PagingExtensions;
+pub use tlb::Tlb;
 
 use crate::bus::*;
 use crate::clint::*;
+use crate::commit_log::CommitLog;
 use crate::csr::*;
 use crate::dram::*;
 use crate::instruction::*;
 use crate::interrupt::*;
+use crate::profiler::Profiler;
+use crate::sbi::{Sbi, SystemReset};
+use crate::stats::Stats;
+use crate::symbols::SymbolTable;
+use crate::trace::{Record, TraceWriter};
 
 use log::{debug, error, info, trace};
 
@@ -23,7 +35,7 @@ pub const U_MODE: u64 = 0b00;
 
 pub const CPU_FREQUENCY: u64 = 200_000_000; // 200MHz
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq)]
 pub(crate) enum AccessMode {
     Fetch,
     Load,
@@ -37,17 +49,21 @@ pub(crate) fn bit(integer: u64, bit: u64) -> u64 {
 #[derive(Serialize, Deserialize)]
 pub struct CpuSnapshot {
     pub regs: [u64; 32],
+    pub fregs: [u64; 32],
     pub pc: u64,
     pub csr: CsrSnapshot,
     pub mode: u64,
     pub cycle: u64,
     pub clint: Clint,
     pub interrupt_list: BTreeSet<Interrupt>,
-    pub address_translation_cache: FxHashMap<(u64, u64, u64), u64>,
+    pub tlb: Tlb,
+    pub reservation: Option<u64>,
+    pub sbi: Option<Sbi>,
 }
 
 pub struct Cpu {
     pub regs: [u64; 32],
+    pub fregs: [u64; 32],
     pub pc: u64,
     pub csr: Csr,
     pub(crate) dest: usize,
@@ -60,16 +76,50 @@ pub struct Cpu {
     pub cycle: u64,
     pub(crate) clint: Clint,
     pub interrupt_list: BTreeSet<Interrupt>,
-    pub(crate) address_translation_cache: FxHashMap<(u64, u64, u64), u64>,
-    pub(crate) block_cache: FxHashMap<u64, Rc<BasicBlock>>,
+    pub(crate) tlb: Tlb,
+    // optional page-table extensions; machine configuration, not part of snapshots
+    pub(crate) paging: PagingExtensions,
+    // each block with the physical pages its code was fetched from
+    pub(crate) block_cache: FxHashMap<BlockKey, (Rc<BasicBlock>, Vec<u64>)>,
+    // physical page -> the cached blocks with code in it
+    pub(crate) code_pages: FxHashMap<u64, Vec<BlockKey>>,
+    // physical address reserved by the last lr.w/lr.d, if any
+    pub(crate) reservation: Option<u64>,
+    // built-in SBI firmware servicing ecalls from S-mode, if enabled
+    pub(crate) sbi: Option<Sbi>,
+    // symbols used to annotate traces and crash reports; not part of snapshots
+    pub symbols: SymbolTable,
+    // Spike-style trace of retired instructions, if enabled; not part of snapshots
+    pub(crate) commit_log: Option<CommitLog>,
+    // binary trace of blocks, traps, MMIO and CSR writes, if enabled
+    pub(crate) trace: Option<TraceWriter>,
+    // guest profile of the blocks run, if enabled
+    pub(crate) profiler: Option<Profiler>,
+    // instruction, trap and address translation counts, if enabled
+    pub(crate) stats: Option<Stats>,
+}
+
+/// Identifies a cached basic block: its virtual and physical start address,
+/// and the privilege mode and address space it was decoded in.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct BlockKey {
+    pc: u64,
+    pa: u64,
+    mode: u64,
+    // satp while translation is on, else 0
+    satp: u64,
 }
 
+// frames beyond this are not followed when walking the frame pointer chain
+const MAX_BACKTRACE_DEPTH: usize = 64;
+
 impl Cpu {
     pub fn new(base_addr: u64, dump_count: u64) -> Self {
         let mut regs = [0; 32];
         regs[2] = DRAM_SIZE;
         Self {
             regs,
+            fregs: [0; 32],
             pc: base_addr,
             csr: Csr::new(),
             dest: REG_NUM,
@@ -82,31 +132,40 @@ impl Cpu {
             clint: Clint::new(0x200_0000, 0x10000),
             cycle: 0,
             interrupt_list: BTreeSet::new(),
-            address_translation_cache: FxHashMap::default(),
+            tlb: Tlb::default(),
+            paging: PagingExtensions::default(),
             block_cache: FxHashMap::default(),
+            code_pages: FxHashMap::default(),
+            reservation: None,
+            sbi: None,
+            symbols: SymbolTable::default(),
+            commit_log: None,
+            trace: None,
+            profiler: None,
+            stats: None,
         }
     }
 
     pub fn to_snapshot(&self) -> CpuSnapshot {
         CpuSnapshot {
             regs: self.regs,
+            fregs: self.fregs,
             pc: self.pc,
             csr: self.csr.to_snapshot(),
             mode: self.mode,
             cycle: self.cycle,
             clint: self.clint.clone(),
             interrupt_list: self.interrupt_list.clone(),
-            address_translation_cache: self
-                .address_translation_cache
-                .iter()
-                .map(|(&k, &v)| (k, v))
-                .collect(),
+            tlb: self.tlb.clone(),
+            reservation: self.reservation,
+            sbi: self.sbi.clone(),
         }
     }
 
     pub fn from_snapshot(snapshot: CpuSnapshot) -> Self {
         let mut cpu = Self {
             regs: snapshot.regs,
+            fregs: snapshot.fregs,
             pc: snapshot.pc,
             csr: Csr::from_snapshot(snapshot.csr),
             dest: REG_NUM,
@@ -119,18 +178,48 @@ impl Cpu {
             clint: snapshot.clint,
             cycle: snapshot.cycle,
             interrupt_list: snapshot.interrupt_list,
-            address_translation_cache: snapshot.address_translation_cache.into_iter().collect(),
+            tlb: snapshot.tlb,
+            paging: PagingExtensions::default(),
             block_cache: FxHashMap::default(),
+            code_pages: FxHashMap::default(),
+            reservation: snapshot.reservation,
+            sbi: snapshot.sbi,
+            symbols: SymbolTable::default(),
+            commit_log: None,
+            trace: None,
+            profiler: None,
+            stats: None,
         };
         cpu.clear_reg_marks();
         cpu
     }
 
+    /// Fetch one instruction. A 16-bit RVC instruction is returned in the lower half;
+    /// a 32-bit instruction crossing a page boundary is fetched in two halves.
     pub fn fetch(&mut self, bus: &mut Bus, addr: u64) -> Result<u32, Exception> {
-        match self.translate(bus, addr, AccessMode::Fetch) {
-            Ok(pa) => bus.load(pa, 32).map(|v| v as u32),
-            Err(e) => Err(e),
+        let pa = self.translate(bus, addr, AccessMode::Fetch)?;
+        self.pmp_check(pa, 16, AccessMode::Fetch, self.mode)?;
+        if addr & 0xfff != 0xffe {
+            let inst = bus.load(pa, 32)? as u32;
+            if inst & 0b11 != 0b11 {
+                return Ok(inst & 0xffff);
+            }
+            self.pmp_check(pa, 32, AccessMode::Fetch, self.mode)?;
+            return Ok(inst);
         }
+        let low = bus.load(pa, 16)? as u32;
+        if low & 0b11 != 0b11 {
+            return Ok(low);
+        }
+        let pa_high = self.translate(bus, addr.wrapping_add(2), AccessMode::Fetch)?;
+        self.pmp_check(pa_high, 16, AccessMode::Fetch, self.mode)?;
+        let high = bus.load(pa_high, 16)? as u32;
+        Ok(low | (high << 16))
+    }
+
+    /// The system reset requested through the SBI firmware, if any.
+    pub fn system_reset(&self) -> Option<SystemReset> {
+        self.sbi.as_ref().and_then(|sbi| sbi.reset)
     }
 
     pub fn set_dump_count(&mut self, count: u64) {
@@ -138,6 +227,91 @@ impl Cpu {
         self.dump_interval = count;
     }
 
+    pub fn set_paging_extensions(&mut self, paging: PagingExtensions) {
+        self.paging = paging;
+        // Svade and Svadu are the two ways of handling A and D
+        self.csr.set_svadu(!paging.svade);
+        self.csr.set_svpbmt(paging.svpbmt);
+    }
+
+    /// Replaces the TLB, dropping the translations it held.
+    pub fn set_tlb(&mut self, tlb: Tlb) {
+        self.tlb = tlb;
+    }
+
+    pub fn set_commit_log(&mut self, log: CommitLog) {
+        self.commit_log = Some(log);
+    }
+
+    pub fn set_trace(&mut self, trace: TraceWriter) {
+        self.trace = Some(trace);
+    }
+
+    pub fn set_profiler(&mut self, profiler: Profiler) {
+        self.profiler = Some(profiler);
+    }
+
+    pub fn set_stats(&mut self, stats: Stats) {
+        self.stats = Some(stats);
+    }
+
+    /// Flushes the commit log and the trace and writes the profile and the
+    /// statistics, before the process exits.
+    pub fn flush_logs(&mut self) {
+        if let Some(log) = &mut self.commit_log {
+            if let Err(e) = log.flush() {
+                error!("Failed to write the commit log: {}", e);
+            }
+        }
+        if let Some(trace) = &mut self.trace {
+            if let Err(e) = trace.flush() {
+                error!("Failed to write the trace: {}", e);
+            }
+        }
+        if let Some(profiler) = &self.profiler {
+            if let Err(e) = profiler.write(&self.symbols) {
+                error!("Failed to write the profile: {}", e);
+            }
+        }
+        if let Some(stats) = &self.stats {
+            if let Err(e) = stats.write() {
+                error!("Failed to write the statistics: {}", e);
+            }
+        }
+        info!("{}", self.tlb.report().trim_end());
+    }
+
+    // feed the profiler a block that ran `executed` of its instructions
+    fn profile_block(&mut self, block: &BasicBlock, mode: u64, executed: u64) {
+        let Some(profiler) = &mut self.profiler else {
+            return;
+        };
+        profiler.block(mode, block.start_pc, executed, self.cycle);
+        if executed != block.instrs.len() as u64 {
+            return;
+        }
+        // blocks end at jumps, so only the last instruction can call or return
+        match block.instrs.last() {
+            Some(&DecodedInstr::Jal { rd, .. }) | Some(&DecodedInstr::Jalr { rd, .. })
+                if rd == 1 || rd == 5 =>
+            {
+                profiler.call(self.mode, self.pc, self.regs[rd]);
+            }
+            Some(&DecodedInstr::Jalr { rd: 0, rs1, .. }) if rs1 == 1 || rs1 == 5 => {
+                profiler.ret(self.mode, self.pc);
+            }
+            _ => {}
+        }
+    }
+
+    pub(crate) fn record(&mut self, record: Record) {
+        if let Some(trace) = &mut self.trace {
+            if let Err(e) = trace.write(&record) {
+                error!("Failed to write the trace: {}", e);
+            }
+        }
+    }
+
     pub(crate) fn mark_as_dest(&mut self, reg: usize) {
         self.dest = reg;
     }
@@ -158,18 +332,37 @@ impl Cpu {
 
     pub fn load(&mut self, bus: &mut Bus, va: u64, size: u64) -> Result<u64, Exception> {
         trace!("Load access to 0x{:x}", va);
-        match self.translate(bus, va, AccessMode::Load) {
-            Ok(pa) => {
-                if self.clint.is_accessible(pa) {
-                    self.clint.load(pa, size)
-                } else if bus.plic.is_accessible(pa) {
-                    bus.plic_load(pa, size, &mut self.interrupt_list)
-                } else {
-                    bus.load(pa, size)
-                }
-            }
-            Err(e) => Err(e),
+        let pa = self.translate(bus, va, AccessMode::Load)?;
+        self.pmp_check(pa, size, AccessMode::Load, self.data_access_mode())?;
+        if let Some(log) = &mut self.commit_log {
+            log.record_load(va);
         }
+        self.load_physical(bus, pa, size)
+    }
+
+    // whether an access to `pa` is handled by a device rather than DRAM
+    fn is_mmio(&self, bus: &Bus, pa: u64) -> bool {
+        self.clint.is_accessible(pa) || bus.plic.is_accessible(pa) || bus.is_device(pa)
+    }
+
+    fn load_physical(&mut self, bus: &mut Bus, pa: u64, size: u64) -> Result<u64, Exception> {
+        let value = if self.clint.is_accessible(pa) {
+            self.clint.load(pa, size, self.cycle)
+        } else if bus.plic.is_accessible(pa) {
+            bus.plic_load(pa, size, &mut self.interrupt_list)
+        } else {
+            bus.load(pa, size)
+        }?;
+        if self.trace.is_some() && self.is_mmio(bus, pa) {
+            self.record(Record::Mmio {
+                cycle: self.cycle,
+                addr: pa,
+                size: size as u8,
+                write: false,
+                value,
+            });
+        }
+        Ok(value)
     }
 
     pub fn store(
@@ -179,16 +372,121 @@ impl Cpu {
         size: u64,
         value: u64,
     ) -> Result<(), Exception> {
-        match self.translate(bus, va, AccessMode::Store) {
-            Ok(pa) => {
-                if self.clint.is_accessible(pa) {
-                    self.clint.store(pa, size, value)
-                } else {
-                    bus.store(pa, size, value)
-                }
-            }
-            Err(e) => Err(e),
+        let pa = self.translate(bus, va, AccessMode::Store)?;
+        self.pmp_check(pa, size, AccessMode::Store, self.data_access_mode())?;
+        if let Some(log) = &mut self.commit_log {
+            log.record_store(va, size, value);
+        }
+        self.store_physical(bus, pa, size, value)
+    }
+
+    fn store_physical(
+        &mut self,
+        bus: &mut Bus,
+        pa: u64,
+        size: u64,
+        value: u64,
+    ) -> Result<(), Exception> {
+        // any store overlapping the reserved doubleword breaks the reservation
+        if self.reservation == Some(pa & !0x7) {
+            self.reservation = None;
+        }
+        if self.trace.is_some() && self.is_mmio(bus, pa) {
+            self.record(Record::Mmio {
+                cycle: self.cycle,
+                addr: pa,
+                size: size as u8,
+                write: true,
+                value,
+            });
+        }
+        if self.clint.is_accessible(pa) {
+            self.clint.store(pa, size, value, self.cycle)?;
+            // keep the time CSR in step with writes to mtime and make
+            // mip reflect the new msip/mtimecmp right away
+            self.csr.set_time_offset(self.clint.mtime_offset());
+            self.update_pending_interrupts();
+            Ok(())
+        } else if bus.plic.is_accessible(pa) {
+            bus.plic_store(pa, size, value, &mut self.interrupt_list)
+        } else {
+            bus.store(pa, size, value)
+        }
+    }
+
+    /// lr.w/lr.d: load a naturally aligned value and reserve its address.
+    pub(crate) fn load_reserved(
+        &mut self,
+        bus: &mut Bus,
+        va: u64,
+        size: u64,
+    ) -> Result<u64, Exception> {
+        if !va.is_multiple_of(size / 8) {
+            return Err(Exception::LoadAddressMissaligned);
+        }
+        let pa = self.translate(bus, va, AccessMode::Load)?;
+        self.pmp_check(pa, size, AccessMode::Load, self.data_access_mode())?;
+        if let Some(log) = &mut self.commit_log {
+            log.record_load(va);
+        }
+        let val = self.load_physical(bus, pa, size)?;
+        self.reservation = Some(pa & !0x7);
+        Ok(val)
+    }
+
+    /// sc.w/sc.d: store only if the reservation on the address is still valid.
+    /// Returns the value for rd: 0 on success, 1 on failure.
+    /// The reservation is cleared in either case.
+    pub(crate) fn store_conditional(
+        &mut self,
+        bus: &mut Bus,
+        va: u64,
+        size: u64,
+        value: u64,
+    ) -> Result<u64, Exception> {
+        if !va.is_multiple_of(size / 8) {
+            return Err(Exception::StoreAMOAddressMisaligned);
+        }
+        let pa = self.translate(bus, va, AccessMode::Store)?;
+        self.pmp_check(pa, size, AccessMode::Store, self.data_access_mode())?;
+        let reserved = self.reservation.take() == Some(pa & !0x7);
+        if !reserved {
+            return Ok(1);
         }
+        if let Some(log) = &mut self.commit_log {
+            log.record_store(va, size, value);
+        }
+        self.store_physical(bus, pa, size, value)?;
+        Ok(0)
+    }
+
+    /// AMOs: read the value at `va`, write back `op` of it and return the
+    /// value read. The access is translated once as a store, so a fault on
+    /// either half is a store/AMO page or access fault.
+    pub(crate) fn read_modify_write(
+        &mut self,
+        bus: &mut Bus,
+        va: u64,
+        size: u64,
+        op: impl FnOnce(u64) -> u64,
+    ) -> Result<u64, Exception> {
+        let pa = self.translate(bus, va, AccessMode::Store)?;
+        let mode = self.data_access_mode();
+        self.pmp_check(pa, size, AccessMode::Load, mode)
+            .and_then(|_| self.pmp_check(pa, size, AccessMode::Store, mode))
+            .map_err(|_| Exception::StoreAMOAccessFault)?;
+        if let Some(log) = &mut self.commit_log {
+            log.record_load(va);
+        }
+        let val = self
+            .load_physical(bus, pa, size)
+            .map_err(|_| Exception::StoreAMOAccessFault)?;
+        let result = op(val);
+        if let Some(log) = &mut self.commit_log {
+            log.record_store(va, size, result);
+        }
+        self.store_physical(bus, pa, size, result)?;
+        Ok(val)
     }
 
     // get the takable pending interrupt with the highest priority
@@ -270,7 +568,12 @@ impl Cpu {
             " a1 ", " a2 ", " a3 ", " a4 ", " a5 ", " a6 ", " a7 ", " s2 ", " s3 ", " s4 ", " s5 ",
             " s6 ", " s7 ", " s8 ", " s9 ", " s10", " s11", " t3 ", " t4 ", " t5 ", " t6 ",
         ];
-        let mut output = format!("pc={:>#18x}\n{}", self.pc, self.inst_string);
+        let mut output = format!(
+            "pc={:>#18x}{}\n{}",
+            self.pc,
+            self.symbol_suffix(self.pc),
+            self.inst_string
+        );
         const SEQ_RED: &str = "\x1b[91m";
         const SEQ_GREEN: &str = "\x1b[92m";
         const SEQ_CLEAR: &str = "\x1b[0m";
@@ -299,9 +602,83 @@ impl Cpu {
                 )
             )
         }
+        if let Some(location) = self.symbols.lookup(self.regs[1]) {
+            output = format!("{}ra -> {}\n", output, location);
+        }
         output
     }
 
+    /// `inst` at `pc` in assembler syntax, with the symbol of a branch target.
+    pub fn disassemble(&self, pc: u64, inst: &DecodedInstr) -> String {
+        let target = inst
+            .branch_target(pc)
+            .map(|target| self.symbol_suffix(target));
+        format!("{}{}", inst.at(pc), target.unwrap_or_default())
+    }
+
+    // the instruction shown under the pc in the register dump
+    fn set_last_inst(&mut self, pc: u64, inst: &DecodedInstr) {
+        self.inst_string = format!("{:x}: {}\n", pc, self.disassemble(pc, inst));
+    }
+
+    /// " <symbol+offset>" for an address with a known symbol, "" otherwise.
+    pub(crate) fn symbol_suffix(&self, addr: u64) -> String {
+        self.symbols
+            .lookup(addr)
+            .map(|location| format!(" <{}>", location))
+            .unwrap_or_default()
+    }
+
+    /// Return addresses found by walking the frame pointer chain from s0,
+    /// innermost first. Code must keep frame pointers
+    /// (`-fno-omit-frame-pointer`), where the return address and the
+    /// caller's frame pointer are saved just below the frame pointer. A
+    /// leaf function that saves neither leaves its caller out.
+    pub fn backtrace(&mut self, bus: &mut Bus) -> Vec<u64> {
+        let mut frames = Vec::new();
+        let mut fp = self.regs[8];
+        while frames.len() < MAX_BACKTRACE_DEPTH && fp != 0 && fp.is_multiple_of(8) {
+            let (Some(ra), Some(prev_fp)) = (
+                self.read_stack(bus, fp.wrapping_sub(8)),
+                self.read_stack(bus, fp.wrapping_sub(16)),
+            ) else {
+                break;
+            };
+            if ra == 0 {
+                break;
+            }
+            frames.push(ra);
+            // the stack grows down, so callers' frames are at higher addresses
+            if prev_fp <= fp {
+                break;
+            }
+            fp = prev_fp;
+        }
+        frames
+    }
+
+    // a doubleword of the stack, read without touching devices
+    fn read_stack(&mut self, bus: &mut Bus, va: u64) -> Option<u64> {
+        let pa = self
+            .translate_for_inspection(bus, va, AccessMode::Load)
+            .ok()?;
+        let dram_base = bus.dram.dram_base;
+        if pa < dram_base || pa.checked_add(8)? > dram_base + DRAM_SIZE {
+            return None;
+        }
+        bus.load(pa, 64).ok()
+    }
+
+    /// Registers, CSRs and the backtrace, with addresses resolved to symbols.
+    pub fn crash_report(&mut self, bus: &mut Bus) -> String {
+        let mut report = format!("{}{}\nbacktrace:\n", self.dump_registers(), self.csr.dump());
+        report += &format!("  #0 {}\n", self.symbols.describe(self.pc));
+        for (i, ra) in self.backtrace(bus).into_iter().enumerate() {
+            report += &format!("  #{} {}\n", i + 1, self.symbols.describe(ra));
+        }
+        report
+    }
+
     pub fn trap_interrupt(&mut self, bus: &mut Bus) {
         self.cycle += 1;
         if self.cycle % 1000000 == 0 {
@@ -320,15 +697,55 @@ impl Cpu {
         }
     }
 
+    fn block_key(&mut self, bus: &mut Bus) -> Result<BlockKey, Exception> {
+        let pa = self.translate_quietly(bus, self.pc, AccessMode::Fetch)?;
+        let satp = self.csr.load_csrs(SATP, self.cycle, &self.interrupt_list);
+        let translated = self.mode != M_MODE && satp >> SATP_MODE_SHIFT != SATP_MODE_BARE;
+        Ok(BlockKey {
+            pc: self.pc,
+            pa,
+            mode: self.mode,
+            satp: if translated { satp } else { 0 },
+        })
+    }
+
+    /// Drops every decoded block, e.g. on `fence.i`.
+    pub(crate) fn clear_block_cache(&mut self) {
+        self.block_cache.clear();
+        self.code_pages.clear();
+    }
+
+    // drop the blocks whose code was written since the last block was built
+    fn invalidate_written_code(&mut self, bus: &mut Bus) {
+        for page in bus.dram.take_written_code_pages() {
+            for key in self.code_pages.remove(&page).unwrap_or_default() {
+                let Some((_, pages)) = self.block_cache.remove(&key) else {
+                    continue;
+                };
+                // forget the block in the other pages it spans too
+                for other in pages.iter().filter(|&&other| other != page) {
+                    if let Some(keys) = self.code_pages.get_mut(other) {
+                        keys.retain(|&k| k != key);
+                    }
+                }
+            }
+        }
+    }
+
     pub fn build_basic_block(&mut self, bus: &mut Bus) -> Result<Rc<BasicBlock>, Exception> {
         let pc = self.pc;
 
-        if let Some(block) = self.block_cache.get(&pc) {
+        self.invalidate_written_code(bus);
+        let key = self.block_key(bus)?;
+        if let Some((block, _)) = self.block_cache.get(&key) {
             return Ok(Rc::clone(block));
         }
 
         let mut instrs = Vec::with_capacity(16);
         let mut cur_pc = pc;
+        // the physical pages the block was fetched from
+        let mut pages = vec![key.pa & !0xfff];
+        let mut va_page = pc >> 12;
 
         loop {
             let inst = match self.fetch(bus, cur_pc) {
@@ -343,11 +760,22 @@ impl Cpu {
             };
             let decoded_inst = DecodedInstr::decode(inst);
             let is_end = decoded_inst.is_building_block_end();
+            let inst_len = decoded_inst.inst_len();
+            let last_byte = cur_pc.wrapping_add(inst_len - 1);
+            if last_byte >> 12 != va_page {
+                // the fetch went through, so the next page is mapped
+                va_page = last_byte >> 12;
+                if let Ok(pa) = self.translate_quietly(bus, last_byte, AccessMode::Fetch) {
+                    if !pages.contains(&(pa & !0xfff)) {
+                        pages.push(pa & !0xfff);
+                    }
+                }
+            }
             instrs.push(decoded_inst);
             if is_end {
                 break;
             }
-            cur_pc = cur_pc.wrapping_add(4);
+            cur_pc = cur_pc.wrapping_add(inst_len);
         }
 
         let block = Rc::new(BasicBlock {
@@ -355,31 +783,57 @@ impl Cpu {
             end_pc: cur_pc,
             instrs,
         });
-        self.block_cache.insert(pc, Rc::clone(&block));
+        for &page in &pages {
+            bus.dram.mark_code(page);
+            self.code_pages.entry(page).or_default().push(key);
+        }
+        self.block_cache.insert(key, (Rc::clone(&block), pages));
         Ok(block)
     }
 
     pub fn run_block(&mut self, bus: &mut Bus, block: &BasicBlock) -> u64 {
         self.pc = block.start_pc;
+        let mode = self.mode;
         let mut cycle: u64 = 0;
+        if self.trace.is_some() {
+            self.record(Record::Block {
+                cycle: self.cycle,
+                start_pc: block.start_pc,
+                end_pc: block.end_pc,
+                mode: mode as u8,
+            });
+        }
         trace!(
-            "Block execution: 0x{:x} to 0x{:x}",
+            "Block execution: 0x{:x} to 0x{:x}{}",
             block.start_pc,
-            block.end_pc
+            block.end_pc,
+            self.symbol_suffix(block.start_pc)
         );
         for instr in &block.instrs {
+            let pc = self.pc;
+            trace!(
+                "{}: {}",
+                self.symbols.describe(pc),
+                self.disassemble(pc, instr)
+            );
             let result = self.execute(bus, instr);
             if let Err(e) = result {
                 error!(
-                    "Execution failed in block at pc={:x}: {:?}, mode={}",
-                    self.pc, e, self.mode
+                    "Execution failed in block at pc={}: {:?}, mode={}",
+                    self.symbols.describe(self.pc),
+                    e,
+                    self.mode
                 );
+                if e.is_unhandled(self) {
+                    self.set_last_inst(pc, instr);
+                    error!("{}", self.crash_report(bus));
+                }
                 e.take_trap(self);
                 self.pc = self.pc.wrapping_add(4);
                 break;
             }
             self.regs[0] = 0;
-            self.pc = self.pc.wrapping_add(4);
+            self.pc = self.pc.wrapping_add(instr.inst_len());
             self.cycle += 1;
             if self.dump_count > 0 {
                 self.dump_count -= 1;
@@ -389,45 +843,56 @@ impl Cpu {
                         "Block executed up to pc={:x}, cycle={}",
                         self.pc, self.cycle
                     );
+                    self.set_last_inst(pc, instr);
                     debug!("{}", self.dump_registers());
                     debug!("CSR: {}", self.csr.dump());
                 }
             }
             cycle += 1;
         }
+        if self.profiler.is_some() {
+            self.profile_block(block, mode, cycle);
+        }
         cycle
     }
 
     pub fn step_run(&mut self, bus: &mut Bus) -> u64 {
-        trace!("pc={:>#18x}", self.pc);
-
         self.trap_interrupt(bus);
 
-        let inst = match self.fetch(bus, self.pc) {
+        let pc = self.pc;
+        let inst = match self.fetch(bus, pc) {
             Ok(inst) => inst,
             Err(_) => return 0x0,
         };
 
         let decoded_inst = DecodedInstr::decode(inst);
+        trace!(
+            "{}: {}",
+            self.symbols.describe(pc),
+            self.disassemble(pc, &decoded_inst)
+        );
 
-        let result = self
-            .execute(bus, &decoded_inst)
-            .map_err(|e| e.take_trap(self));
+        let result = self.execute(bus, &decoded_inst);
         if let Err(e) = result {
-            error!("Execution failed!");
-            error!("Exception: {:?}", e);
-            error!("pc=0x{:x}", self.pc);
-            error!("{}", self.dump_registers());
-            error!("{}", self.csr.dump());
+            if e.is_unhandled(self) {
+                error!("Execution failed!");
+                error!("Exception: {:?}", e);
+                error!("pc={}", self.symbols.describe(self.pc));
+                self.set_last_inst(pc, &decoded_inst);
+                error!("{}", self.crash_report(bus));
+            }
+            e.take_trap(self);
+            self.pc = self.pc.wrapping_add(4);
+        } else {
+            self.pc = self.pc.wrapping_add(decoded_inst.inst_len());
         }
         self.regs[0] = 0;
 
-        self.pc = self.pc.wrapping_add(4);
-
         if self.dump_count > 0 {
             self.dump_count -= 1;
             if self.dump_count == 0 {
                 self.dump_count = self.dump_interval;
+                self.set_last_inst(pc, &decoded_inst);
                 info!("{}", self.dump_registers());
                 debug!("CSR: {}", self.csr.dump());
             }
@@ -436,30 +901,45 @@ impl Cpu {
         if self.pc == 0 {
             info!("{}", self.dump_registers());
             info!("Program finished!");
+            self.flush_logs();
             std::process::exit(0);
         }
         self.pc
     }
 
     fn update_pending_interrupts(&mut self) {
+        let mtime = self.clint.mtime(self.cycle);
+        self.set_pending(
+            Interrupt::MachineTimerInterrupt,
+            self.clint.timer_pending(self.cycle),
+        );
+        self.set_pending(
+            Interrupt::MachineSoftwareInterrupt,
+            self.clint.software_pending(),
+        );
+
+        // SSIP and STIP can also be raised by M-mode software writing mip
+        let software = self.csr.software_pending_bits();
+        self.set_pending(
+            Interrupt::SupervisorSoftwareInterrupt,
+            software & MIP_SSIP != 0,
+        );
+
+        // Sstc: STIP is pending while time >= stimecmp
         let stimecmp = self
             .csr
             .load_csrs(STIMECMP, self.cycle, &self.interrupt_list);
-        let current_counter = self.cycle * TIMER_FREQ / CPU_FREQUENCY;
-        if current_counter % 10000 == 0 {
-            if current_counter % 1000000 == 0 {
-                debug!(
-                    "stimecmp: {}, current_counter: {}",
-                    stimecmp, current_counter
-                );
-            }
-            if (stimecmp > 0) && (current_counter >= stimecmp) {
-                self.interrupt_list
-                    .insert(Interrupt::SupervisorTimerInterrupt);
-            } else {
-                self.interrupt_list
-                    .remove(&Interrupt::SupervisorTimerInterrupt);
-            }
+        self.set_pending(
+            Interrupt::SupervisorTimerInterrupt,
+            software & MIP_STIP != 0 || ((stimecmp > 0) && (mtime >= stimecmp)),
+        );
+    }
+
+    fn set_pending(&mut self, interrupt: Interrupt, pending: bool) {
+        if pending {
+            self.interrupt_list.insert(interrupt);
+        } else {
+            self.interrupt_list.remove(&interrupt);
         }
     }
 }
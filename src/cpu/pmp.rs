@@ -0,0 +1,147 @@
+use super::*;
+
+pub(crate) fn access_fault(acc: AccessMode) -> Exception {
+    match acc {
+        AccessMode::Fetch => Exception::InstructionAccessFault,
+        AccessMode::Load => Exception::LoadAccessFault,
+        AccessMode::Store => Exception::StoreAMOAccessFault,
+    }
+}
+
+impl Cpu {
+    /// Physical memory protection check of a `size`-bit access at `pa` made
+    /// in privilege mode `mode`. The lowest-numbered entry matching any byte
+    /// decides and must cover all of them. M-mode is held only to locked
+    /// entries; S- and U-mode accesses matching no entry fail.
+    pub(crate) fn pmp_check(
+        &self,
+        pa: u64,
+        size: u64,
+        acc: AccessMode,
+        mode: u64,
+    ) -> Result<(), Exception> {
+        if mode == M_MODE && !self.csr.pmp_locked() {
+            return Ok(());
+        }
+        let end = pa.saturating_add(size / 8);
+        for i in 0..PMP_ENTRIES {
+            let cfg = self.csr.pmp_cfg(i);
+            let Some((start, limit)) = self.pmp_range(i, cfg) else {
+                continue;
+            };
+            if end <= start || pa >= limit {
+                continue;
+            }
+            let permission = match acc {
+                AccessMode::Fetch => PMP_X,
+                AccessMode::Load => PMP_R,
+                AccessMode::Store => PMP_W,
+            };
+            let allowed = pa >= start
+                && end <= limit
+                && ((mode == M_MODE && cfg & PMP_L == 0) || cfg & permission != 0);
+            return if allowed {
+                Ok(())
+            } else {
+                Err(access_fault(acc))
+            };
+        }
+        if mode == M_MODE {
+            Ok(())
+        } else {
+            Err(access_fault(acc))
+        }
+    }
+
+    // the [start, limit) range of PMP entry `i`, if it is enabled
+    fn pmp_range(&self, i: usize, cfg: u64) -> Option<(u64, u64)> {
+        let addr = self.csr.pmp_addr(i);
+        match cfg & PMP_A {
+            PMP_TOR => {
+                let start = if i == 0 {
+                    0
+                } else {
+                    self.csr.pmp_addr(i - 1) << 2
+                };
+                Some((start, addr << 2)).filter(|(start, limit)| start < limit)
+            }
+            PMP_NA4 => Some((addr << 2, (addr << 2) + 4)),
+            PMP_NAPOT => {
+                // the trailing ones encode a size of 2^(ones + 3) bytes
+                let ones = addr.trailing_ones();
+                let start = (addr & !((1 << ones) - 1)) << 2;
+                Some((start, start + (1 << (ones + 3))))
+            }
+            _ => None,
+        }
+    }
+
+    /// The privilege mode loads and stores are checked in: with
+    /// mstatus.MPRV set, M-mode accesses memory as the mode in MPP.
+    pub(crate) fn data_access_mode(&self) -> u64 {
+        if self.mode == M_MODE && self.csr.get_mstatus_bit(MASK_MPRV, BIT_MPRV) == 1 {
+            self.csr.get_mstatus_bit(MASK_MPP, BIT_MPP)
+        } else {
+            self.mode
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cpu_with_pmp(cfg0: u64, addrs: &[u64]) -> Cpu {
+        let mut cpu = Cpu::new(0x8000_0000, 0);
+        for (i, &addr) in addrs.iter().enumerate() {
+            cpu.csr.store_csrs(PMPADDR0 + i, addr);
+        }
+        cpu.csr.store_csrs(PMPCFG0, cfg0);
+        cpu
+    }
+
+    #[test]
+    fn test_pmp_matching() {
+        // entry 0: NA4 read-only at 0x1000; entry 1: TOR up to 0x2000,
+        // read-write; entry 2: NAPOT 64 KiB at 0x8000_0000, execute-only
+        let cpu = cpu_with_pmp(
+            (PMP_NAPOT | PMP_X) << 16 | (PMP_TOR | PMP_R | PMP_W) << 8 | (PMP_NA4 | PMP_R),
+            &[0x1000 >> 2, 0x2000 >> 2, (0x8000_0000 >> 2) | 0x1fff],
+        );
+        let check = |pa, size, acc| cpu.pmp_check(pa, size, acc, U_MODE).is_ok();
+        assert!(check(0x1000, 32, AccessMode::Load));
+        assert!(!check(0x1000, 32, AccessMode::Store));
+        // entry 0 matches the first bytes but does not cover the rest
+        assert!(!check(0x1002, 32, AccessMode::Load));
+        assert!(check(0x1004, 64, AccessMode::Store));
+        // S- and U-mode accesses matching no entry fail
+        assert!(!check(0x0, 8, AccessMode::Store));
+        assert!(!check(0x1ffc, 64, AccessMode::Load));
+        assert!(check(0x8000_fffc, 32, AccessMode::Fetch));
+        assert!(!check(0x8000_0000, 32, AccessMode::Load));
+        assert!(!check(0x8001_0000, 32, AccessMode::Fetch));
+        // M-mode ignores unlocked entries
+        assert!(cpu
+            .pmp_check(0x8000_0000, 32, AccessMode::Store, M_MODE)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_pmp_lock() {
+        let mut cpu = cpu_with_pmp(PMP_L | PMP_TOR | PMP_R, &[0x1000 >> 2]);
+        assert!(matches!(
+            cpu.pmp_check(0x800, 64, AccessMode::Store, M_MODE),
+            Err(Exception::StoreAMOAccessFault)
+        ));
+        assert!(cpu.pmp_check(0x800, 64, AccessMode::Load, M_MODE).is_ok());
+        assert!(cpu.pmp_check(0x1000, 64, AccessMode::Store, M_MODE).is_ok());
+        // neither the locked entry nor its address can be changed
+        cpu.csr.store_csrs(PMPCFG0, PMP_TOR | PMP_R | PMP_W);
+        cpu.csr.store_csrs(PMPADDR0, 0);
+        assert_eq!(cpu.csr.pmp_cfg(0), PMP_L | PMP_TOR | PMP_R);
+        assert_eq!(cpu.csr.pmp_addr(0), 0x1000 >> 2);
+        // W without R reads back as neither
+        cpu.csr.store_csrs(PMPCFG0, PMP_W << 8);
+        assert_eq!(cpu.csr.pmp_cfg(1), 0);
+    }
+}
@@ -0,0 +1,272 @@
+//! Translation lookaside buffer.
+//!
+//! A set-associative cache of leaf translations with LRU replacement. Entries
+//! are tagged with the ASID and the root page table they were walked from;
+//! global mappings match in every address space. Translations from superpages
+//! are cached one 4 KiB page at a time, so every address is found in the set
+//! its own page number selects, but they remember the size of the page they
+//! belong to so that `sfence.vma` on any address in it removes them all.
+
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_ENTRIES: usize = 1024;
+pub const DEFAULT_WAYS: usize = 4;
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct TlbEntry {
+    va_page: u64,
+    pa_page: u64,
+    // satp mode and root ppn of the walk; not compared for global mappings
+    root: u64,
+    asid: u64,
+    global: bool,
+    // log2 of the size of the page the translation belongs to
+    page_shift: u32,
+    /// V, R, W, X, U, G, A and D of the leaf PTE.
+    pub(crate) flags: u64,
+    last_used: u64,
+}
+
+impl TlbEntry {
+    pub(crate) fn translate(&self, va: u64) -> u64 {
+        (self.pa_page << 12) | (va & 0xfff)
+    }
+
+    // whether `va` lies in the whole page the translation belongs to
+    fn maps(&self, va: u64) -> bool {
+        (va ^ (self.va_page << 12)) >> self.page_shift == 0
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Tlb {
+    ways: usize,
+    // the ways of each set are adjacent
+    entries: Vec<Option<TlbEntry>>,
+    // advances on every hit and fill, to find the least recently used way
+    clock: u64,
+    hits: u64,
+    misses: u64,
+    flushes: u64,
+}
+
+impl Default for Tlb {
+    fn default() -> Self {
+        Self::new(DEFAULT_ENTRIES, DEFAULT_WAYS).unwrap()
+    }
+}
+
+impl Tlb {
+    pub fn new(entries: usize, ways: usize) -> Result<Self, String> {
+        if ways == 0 || entries == 0 || !entries.is_multiple_of(ways) {
+            return Err(format!(
+                "a TLB of {} entries cannot be split into sets of {} ways",
+                entries, ways
+            ));
+        }
+        Ok(Self {
+            ways,
+            entries: vec![None; entries],
+            clock: 0,
+            hits: 0,
+            misses: 0,
+            flushes: 0,
+        })
+    }
+
+    fn set(&mut self, va_page: u64) -> &mut [Option<TlbEntry>] {
+        let sets = self.entries.len() / self.ways;
+        let start = (va_page % sets as u64) as usize * self.ways;
+        &mut self.entries[start..start + self.ways]
+    }
+
+    // the entry caching `va_page` in the address space, if any; a store
+    // misses on a translation whose D bit is clear
+    fn find(&mut self, va_page: u64, root: u64, asid: u64, store: bool) -> Option<&mut TlbEntry> {
+        self.set(va_page).iter_mut().flatten().find(|entry| {
+            entry.va_page == va_page
+                && (entry.global || (entry.asid == asid && entry.root == root))
+                && (!store || entry.flags & (1 << 7) != 0)
+        })
+    }
+
+    /// The cached translation of `va` in the address space of `root` and
+    /// `asid`. A store misses on a translation whose D bit is clear, so that
+    /// the walk sets it or faults.
+    pub(crate) fn lookup(
+        &mut self,
+        va: u64,
+        root: u64,
+        asid: u64,
+        store: bool,
+    ) -> Option<TlbEntry> {
+        self.clock += 1;
+        let clock = self.clock;
+        match self.find(va >> 12, root, asid, store) {
+            Some(entry) => {
+                entry.last_used = clock;
+                let entry = *entry;
+                self.hits += 1;
+                Some(entry)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Like `lookup`, but leaves the counters and the replacement order
+    /// alone.
+    pub(crate) fn probe(&mut self, va: u64, root: u64, asid: u64, store: bool) -> Option<TlbEntry> {
+        self.find(va >> 12, root, asid, store).copied()
+    }
+
+    /// Caches the translation of the 4 KiB page of `va` to that of `pa`, from
+    /// a leaf PTE with `flags` mapping a page of `1 << page_shift` bytes.
+    pub(crate) fn insert(
+        &mut self,
+        va: u64,
+        pa: u64,
+        root: u64,
+        asid: u64,
+        page_shift: u32,
+        flags: u64,
+    ) {
+        let va_page = va >> 12;
+        self.clock += 1;
+        let entry = TlbEntry {
+            va_page,
+            pa_page: pa >> 12,
+            root,
+            asid,
+            global: flags & (1 << 5) != 0,
+            page_shift,
+            flags,
+            last_used: self.clock,
+        };
+        let set = self.set(va_page);
+        // replace a stale copy of this translation, else an empty way, else
+        // the least recently used one
+        let way = set
+            .iter()
+            .position(|e| {
+                e.is_some_and(|e| {
+                    e.va_page == va_page
+                        && e.global == entry.global
+                        && e.asid == asid
+                        && e.root == root
+                })
+            })
+            .or_else(|| set.iter().position(|e| e.is_none()))
+            .unwrap_or_else(|| {
+                (0..set.len())
+                    .min_by_key(|&i| set[i].map_or(0, |e| e.last_used))
+                    .unwrap_or(0)
+            });
+        set[way] = Some(entry);
+    }
+
+    /// `sfence.vma`: drops the translations of the page holding `va`, or of
+    /// all pages, in the address space `asid`, or in all of them. Global
+    /// mappings are kept when an ASID is given.
+    pub(crate) fn flush(&mut self, va: Option<u64>, asid: Option<u64>) {
+        self.flushes += 1;
+        for slot in self.entries.iter_mut() {
+            let Some(entry) = slot else {
+                continue;
+            };
+            let in_range = va.is_none_or(|va| entry.maps(va));
+            let in_space = asid.is_none_or(|asid| !entry.global && entry.asid == asid);
+            if in_range && in_space {
+                *slot = None;
+            }
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let lookups = self.hits + self.misses;
+        format!(
+            "TLB: {} entries, {}-way; {} lookups, {} hits ({:.2}%), {} misses, {} flushes\n",
+            self.entries.len(),
+            self.ways,
+            lookups,
+            self.hits,
+            100.0 * self.hits as f64 / lookups.max(1) as f64,
+            self.misses,
+            self.flushes
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RW: u64 = 0b1100_0111;
+    const GLOBAL: u64 = 1 << 5;
+
+    #[test]
+    fn test_lookup_and_replacement() {
+        let mut tlb = Tlb::new(4, 2).unwrap();
+        tlb.insert(0x1000, 0x8000_1000, 0, 1, 12, RW);
+        assert_eq!(
+            tlb.lookup(0x1234, 0, 1, true).unwrap().translate(0x1234),
+            0x8000_1234
+        );
+        // another address space, or another root with the same ASID
+        assert!(tlb.lookup(0x1234, 0, 2, false).is_none());
+        assert!(tlb.lookup(0x1234, 7, 1, false).is_none());
+        // a clean page misses on stores
+        tlb.insert(0x5000, 0x8000_5000, 0, 1, 12, RW & !(1 << 7));
+        assert!(tlb.lookup(0x5000, 0, 1, false).is_some());
+        assert!(tlb.lookup(0x5000, 0, 1, true).is_none());
+
+        // 0x1000, 0x5000 and 0x9000 share a set: the least recently used goes
+        tlb.lookup(0x1000, 0, 1, false);
+        tlb.insert(0x9000, 0x8000_9000, 0, 1, 12, RW);
+        assert!(tlb.lookup(0x1000, 0, 1, false).is_some());
+        assert!(tlb.lookup(0x5000, 0, 1, false).is_none());
+        assert!(tlb.lookup(0x9000, 0, 1, false).is_some());
+        assert!(tlb
+            .report()
+            .starts_with("TLB: 4 entries, 2-way; 9 lookups, 5 hits"));
+        assert!(Tlb::new(6, 4).is_err());
+    }
+
+    #[test]
+    fn test_sfence_vma() {
+        let mut tlb = Tlb::new(64, 4).unwrap();
+        // two pages of a 2 MiB megapage, a page in each of two address
+        // spaces, and a global page
+        tlb.insert(0x20_0000, 0x8020_0000, 0, 1, 21, RW);
+        tlb.insert(0x3f_f000, 0x803f_f000, 0, 1, 21, RW);
+        tlb.insert(0x1000, 0x8000_1000, 0, 1, 12, RW);
+        tlb.insert(0x1000, 0x8000_2000, 0, 2, 12, RW);
+        tlb.insert(0x4000, 0x8000_4000, 0, 1, 12, RW | GLOBAL);
+        let cached = |tlb: &mut Tlb, va, asid| tlb.lookup(va, 0, asid, false).is_some();
+
+        // an address in the megapage drops all of it
+        tlb.flush(Some(0x30_0000), None);
+        assert!(!cached(&mut tlb, 0x20_0000, 1));
+        assert!(!cached(&mut tlb, 0x3f_f000, 1));
+        assert!(cached(&mut tlb, 0x1000, 1));
+
+        // an ASID keeps global mappings and other address spaces
+        tlb.flush(None, Some(1));
+        assert!(!cached(&mut tlb, 0x1000, 1));
+        assert!(cached(&mut tlb, 0x1000, 2));
+        assert!(cached(&mut tlb, 0x4000, 3));
+        tlb.flush(Some(0x4000), Some(3));
+        assert!(cached(&mut tlb, 0x4000, 3));
+
+        // the last page of the address space
+        tlb.insert(u64::MAX, 0x8000_5000, 0, 1, 12, RW);
+        tlb.flush(Some(u64::MAX), None);
+        assert!(!cached(&mut tlb, u64::MAX, 1));
+
+        tlb.flush(None, None);
+        assert!(!cached(&mut tlb, 0x1000, 2));
+        assert!(!cached(&mut tlb, 0x4000, 3));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::cpu::CPU_FREQUENCY;
+use crate::clint::mtime_from_cycle;
 use crate::interrupt::*;
 use log::trace;
 use serde::{Deserialize, Serialize};
@@ -13,8 +13,17 @@ pub struct CsrSnapshot {
 
 pub struct Csr {
     csr: [u64; 4096],
+    // Svadu and Svpbmt are implemented, so menvcfg.ADUE and menvcfg.PBMTE
+    // are writable; machine configuration, not part of snapshots
+    svadu: bool,
+    svpbmt: bool,
 }
 
+// floating-point accrued exceptions, rounding mode and their combination
+pub const FFLAGS: usize = 0x001;
+pub const FRM: usize = 0x002;
+pub const FCSR: usize = 0x003;
+
 pub const SSTATUS: usize = 0x100;
 pub const SIE: usize = 0x104;
 pub const STVEC: usize = 0x105;
@@ -36,14 +45,25 @@ pub const MEPC: usize = 0x341;
 pub const MCAUSE: usize = 0x342;
 pub const MTVAL: usize = 0x343;
 pub const MIP: usize = 0x344;
+pub const MENVCFG: usize = 0x30a;
+
+// physical memory protection: 16 entries, eight configuration bytes to
+// each of pmpcfg0 and pmpcfg2
+pub const PMPCFG0: usize = 0x3a0;
+pub const PMPCFG2: usize = 0x3a2;
+pub const PMPADDR0: usize = 0x3b0;
+pub const PMPADDR15: usize = 0x3bf;
+pub const PMP_ENTRIES: usize = 16;
 
 pub const TIME: usize = 0xc01;
 
+pub const BIT_SD: u64 = 63;
 pub const BIT_SXL: u64 = 34;
 pub const BIT_TSR: u64 = 22;
 pub const BIT_TW: u64 = 21;
 pub const BIT_TVM: u64 = 20;
 pub const BIT_MPRV: u64 = 17;
+pub const BIT_FS: u64 = 13;
 pub const BIT_MPP: u64 = 11;
 pub const BIT_MPIE: u64 = 7;
 pub const BIT_MIE: u64 = 3;
@@ -51,6 +71,7 @@ pub const BIT_SPP: u64 = 8;
 pub const BIT_SPIE: u64 = 5;
 pub const BIT_SIE: u64 = 1;
 
+pub const MASK_SD: u64 = 0b1 << BIT_SD;
 pub const MASK_SXL: u64 = 0b11 << BIT_SXL;
 pub const MASK_TSR: u64 = 0b1 << BIT_TSR;
 pub const MASK_TW: u64 = 0b1 << BIT_TW;
@@ -59,6 +80,7 @@ pub const MASK_SPP: u64 = 0b11 << BIT_SPP;
 pub const MASK_SPIE: u64 = 0b1 << BIT_SPIE;
 pub const MASK_SIE: u64 = 0b1 << BIT_SIE;
 pub const MASK_MPRV: u64 = 0b1 << BIT_MPRV;
+pub const MASK_FS: u64 = 0b11 << BIT_FS;
 pub const MASK_MPP: u64 = 0b11 << BIT_MPP;
 pub const MASK_MPIE: u64 = 0b1 << BIT_MPIE;
 pub const MASK_MIE: u64 = 0b1 << BIT_MIE;
@@ -72,11 +94,59 @@ const SSTATUS_MASK: u64 = !(MASK_SXL
     | MASK_MPIE
     | MASK_MIE);
 
+// satp fields and the translation modes it accepts
+pub const SATP_MODE_SHIFT: u64 = 60;
+pub const SATP_ASID_SHIFT: u64 = 44;
+pub const SATP_ASID: u64 = 0xFFFF << SATP_ASID_SHIFT;
+pub const SATP_PPN: u64 = (1 << SATP_ASID_SHIFT) - 1;
+pub const SATP_MODE_BARE: u64 = 0;
+pub const SATP_MODE_SV39: u64 = 8;
+pub const SATP_MODE_SV48: u64 = 9;
+pub const SATP_MODE_SV57: u64 = 10;
+
 pub const TIMER_FREQ: u64 = 10000000; // 10 MHz
 
+// mstatus.FS states
+pub const FS_OFF: u64 = 0b00;
+pub const FS_INITIAL: u64 = 0b01;
+pub const FS_DIRTY: u64 = 0b11;
+
+// interrupt-pending bits that software may set in mip
+pub const MIP_SSIP: u64 = 1 << 1;
+pub const MIP_STIP: u64 = 1 << 5;
+
+// menvcfg.ADUE: Svadu's hardware updating of PTE A and D bits
+pub const MENVCFG_ADUE: u64 = 1 << 61;
+// menvcfg.PBMTE: Svpbmt's memory types in PTE bits 62:61
+pub const MENVCFG_PBMTE: u64 = 1 << 62;
+
+// pmpNcfg fields; A selects how pmpaddrN is matched
+pub const PMP_R: u64 = 0x01;
+pub const PMP_W: u64 = 0x02;
+pub const PMP_X: u64 = 0x04;
+pub const PMP_A: u64 = 0x18;
+pub const PMP_TOR: u64 = 0x08;
+pub const PMP_NA4: u64 = 0x10;
+pub const PMP_NAPOT: u64 = 0x18;
+pub const PMP_L: u64 = 0x80;
+// pmpaddr holds bits 55:2 of an address
+const PMPADDR_MASK: u64 = (1 << 54) - 1;
+
+const FFLAGS_MASK: u64 = 0x1f;
+const FRM_MASK: u64 = 0x7 << 5;
+
 impl Csr {
     pub fn new() -> Self {
-        Self { csr: [0; 4096] }
+        let mut csr = [0; 4096];
+        // the FPU starts out enabled so that bare-metal hard-float programs can run
+        csr[MSTATUS] = FS_INITIAL << BIT_FS;
+        // the page walker updates A and D unless the guest turns it off
+        csr[MENVCFG] = MENVCFG_ADUE;
+        Self {
+            csr,
+            svadu: true,
+            svpbmt: false,
+        }
     }
 
     pub fn to_snapshot(&self) -> CsrSnapshot {
@@ -84,12 +154,39 @@ impl Csr {
     }
 
     pub fn from_snapshot(snapshot: CsrSnapshot) -> Self {
-        Self { csr: snapshot.csr }
+        Self {
+            csr: snapshot.csr,
+            svadu: true,
+            svpbmt: false,
+        }
+    }
+
+    /// Whether Svadu is implemented. Without it menvcfg.ADUE reads as zero.
+    pub fn set_svadu(&mut self, implemented: bool) {
+        self.svadu = implemented;
+        if !implemented {
+            self.csr[MENVCFG] &= !MENVCFG_ADUE;
+        }
+    }
+
+    /// Whether Svpbmt is implemented. With it menvcfg.PBMTE is set, as
+    /// firmware would leave it for the kernel; without it PBMTE reads as zero.
+    pub fn set_svpbmt(&mut self, implemented: bool) {
+        self.svpbmt = implemented;
+        if implemented {
+            self.csr[MENVCFG] |= MENVCFG_PBMTE;
+        } else {
+            self.csr[MENVCFG] &= !MENVCFG_PBMTE;
+        }
     }
 
     pub fn load_csrs(&self, addr: usize, cycle: u64, interrupts: &BTreeSet<Interrupt>) -> u64 {
         match addr {
-            SSTATUS => self.csr[MSTATUS] & SSTATUS_MASK,
+            FFLAGS => self.csr[FCSR] & FFLAGS_MASK,
+            FRM => (self.csr[FCSR] & FRM_MASK) >> 5,
+            FCSR => self.csr[FCSR] & (FRM_MASK | FFLAGS_MASK),
+            SSTATUS => self.mstatus() & SSTATUS_MASK,
+            MSTATUS => self.mstatus(),
             SIE => self.csr[MIE] & self.csr[MIDELEG],
             SIP => {
                 let mut sip = 0u64;
@@ -98,7 +195,8 @@ impl Csr {
                 }
                 sip & self.csr[MIDELEG]
             }
-            TIME => cycle * 1000 / CPU_FREQUENCY * TIMER_FREQ / 1000,
+            // time is read-only, so its slot holds the offset written through mtime
+            TIME => mtime_from_cycle(cycle).wrapping_add(self.csr[TIME]),
             MIP => {
                 let mut mip = 0u64;
                 for interrupt in interrupts.iter() {
@@ -113,18 +211,90 @@ impl Csr {
     pub fn store_csrs(&mut self, addr: usize, val: u64) {
         trace!("store: addr:{:#x}, val:{:#x}", addr, val);
         match addr {
+            FFLAGS => {
+                self.csr[FCSR] = (self.csr[FCSR] & FRM_MASK) | (val & FFLAGS_MASK);
+                self.set_fs(FS_DIRTY);
+            }
+            FRM => {
+                self.csr[FCSR] = (self.csr[FCSR] & FFLAGS_MASK) | ((val << 5) & FRM_MASK);
+                self.set_fs(FS_DIRTY);
+            }
+            FCSR => {
+                self.csr[FCSR] = val & (FRM_MASK | FFLAGS_MASK);
+                self.set_fs(FS_DIRTY);
+            }
             SSTATUS => {
-                self.csr[MSTATUS] = val & SSTATUS_MASK;
+                // SD is read-only and derived from FS
+                self.csr[MSTATUS] = val & SSTATUS_MASK & !MASK_SD;
+            }
+            MSTATUS => {
+                self.csr[MSTATUS] = val & !MASK_SD;
             }
             SIE => {
                 self.csr[MIE] = val & self.csr[MIDELEG];
             }
             SIP => {
-                self.csr[MIP] = val & self.csr[MIDELEG];
+                // only SSIP is writable through sip
+                let mask = MIP_SSIP & self.csr[MIDELEG];
+                self.csr[MIP] = (self.csr[MIP] & !mask) | (val & mask);
+            }
+            MIP => {
+                self.csr[MIP] = val & (MIP_SSIP | MIP_STIP);
             }
             STIMECMP => {
                 self.csr[STIMECMP] = val;
             }
+            SATP => {
+                // a write selecting an unsupported mode has no effect at all
+                let mode = val >> SATP_MODE_SHIFT;
+                if matches!(
+                    mode,
+                    SATP_MODE_BARE | SATP_MODE_SV39 | SATP_MODE_SV48 | SATP_MODE_SV57
+                ) {
+                    self.csr[SATP] = val;
+                }
+            }
+            PMPCFG0 | PMPCFG2 => {
+                let first = (addr - PMPCFG0) / 2 * 8;
+                let mut cfgs = self.csr[addr];
+                for j in 0..8 {
+                    // locked entries ignore writes until reset
+                    if self.pmp_cfg(first + j) & PMP_L != 0 {
+                        continue;
+                    }
+                    let mut cfg = (val >> (j * 8)) & (PMP_L | PMP_A | PMP_X | PMP_W | PMP_R);
+                    // W without R is reserved
+                    if cfg & PMP_R == 0 {
+                        cfg &= !PMP_W;
+                    }
+                    cfgs = (cfgs & !(0xff << (j * 8))) | (cfg << (j * 8));
+                }
+                self.csr[addr] = cfgs;
+            }
+            PMPADDR0..=PMPADDR15 => {
+                let i = addr - PMPADDR0;
+                // a locked TOR entry also locks the address below it
+                let locked = self.pmp_cfg(i) & PMP_L != 0
+                    || (i + 1 < PMP_ENTRIES
+                        && self.pmp_cfg(i + 1) & (PMP_L | PMP_A) == PMP_L | PMP_TOR);
+                if !locked {
+                    self.csr[addr] = val & PMPADDR_MASK;
+                }
+            }
+            MENVCFG => {
+                let mut writable = !(MENVCFG_ADUE | MENVCFG_PBMTE);
+                if self.svadu {
+                    writable |= MENVCFG_ADUE;
+                }
+                if self.svpbmt {
+                    writable |= MENVCFG_PBMTE;
+                }
+                self.csr[MENVCFG] = val & writable;
+            }
+            MEPC | SEPC => {
+                // IALIGN=16 with the C extension: only bit 0 is hardwired to zero
+                self.csr[addr] = val & !0x1;
+            }
             _ => {
                 self.csr[addr] = val;
             }
@@ -155,6 +325,60 @@ impl Csr {
         (status & mask) >> bit
     }
 
+    /// mstatus with the read-only SD bit summarizing a dirty FS field.
+    fn mstatus(&self) -> u64 {
+        let mstatus = self.csr[MSTATUS];
+        if mstatus & MASK_FS == FS_DIRTY << BIT_FS {
+            mstatus | MASK_SD
+        } else {
+            mstatus
+        }
+    }
+
+    /// The configuration byte of PMP entry `i`.
+    pub fn pmp_cfg(&self, i: usize) -> u64 {
+        (self.csr[PMPCFG0 + i / 8 * 2] >> (i % 8 * 8)) & 0xff
+    }
+
+    pub fn pmp_addr(&self, i: usize) -> u64 {
+        self.csr[PMPADDR0 + i] & PMPADDR_MASK
+    }
+
+    /// Whether any PMP entry is locked, and so applies to M-mode as well.
+    pub fn pmp_locked(&self) -> bool {
+        (self.csr[PMPCFG0] | self.csr[PMPCFG2]) & 0x8080_8080_8080_8080 != 0
+    }
+
+    /// Pending bits written to mip/sip by software, as opposed to those
+    /// driven by devices.
+    pub fn software_pending_bits(&self) -> u64 {
+        self.csr[MIP]
+    }
+
+    pub fn set_time_offset(&mut self, offset: u64) {
+        self.csr[TIME] = offset;
+    }
+
+    pub fn fs(&self) -> u64 {
+        self.get_mstatus_bit(MASK_FS, BIT_FS)
+    }
+
+    pub fn set_fs(&mut self, fs: u64) {
+        self.csr[MSTATUS] = (self.csr[MSTATUS] & !MASK_FS) | ((fs << BIT_FS) & MASK_FS);
+    }
+
+    pub fn frm(&self) -> u64 {
+        (self.csr[FCSR] & FRM_MASK) >> 5
+    }
+
+    /// OR exception flags raised by an FP instruction into fflags.
+    pub fn accrue_fflags(&mut self, flags: u64) {
+        if flags != 0 {
+            self.csr[FCSR] |= flags & FFLAGS_MASK;
+            self.set_fs(FS_DIRTY);
+        }
+    }
+
     pub fn dump(&self) -> String {
         let mut result = String::new();
         for i in 0..4096 {
@@ -6,6 +6,9 @@ use std::net::{TcpListener, TcpStream};
 use crate::emu::{Emu, Event, ExecMode, RunEvent};
 
 use gdbstub::common::Signal;
+use gdbstub::target::ext::base::single_register_access::{
+    SingleRegisterAccess, SingleRegisterAccessOps,
+};
 use gdbstub::target::ext::base::singlethread::{
     SingleThreadBase, SingleThreadResume, SingleThreadSingleStep,
 };
@@ -13,7 +16,12 @@ use gdbstub::target::ext::base::singlethread::{SingleThreadResumeOps, SingleThre
 use gdbstub::target::ext::base::BaseOps;
 use gdbstub::target::ext::breakpoints::{Breakpoints, SwBreakpoint};
 use gdbstub::target::ext::breakpoints::{BreakpointsOps, SwBreakpointOps};
+use gdbstub::target::ext::monitor_cmd::{outputln, ConsoleOutput, MonitorCmd, MonitorCmdOps};
+use gdbstub::target::ext::target_description_xml_override::{
+    TargetDescriptionXmlOverride, TargetDescriptionXmlOverrideOps,
+};
 use gdbstub::target::{Target, TargetError, TargetResult};
+use gdbstub_arch::riscv::reg::id::RiscvRegId;
 
 use gdbstub::conn::{Connection, ConnectionExt}; // note the use of `ConnectionExt`
 use gdbstub::stub::run_blocking;
@@ -33,6 +41,64 @@ impl Target for Emu {
     fn support_breakpoints(&mut self) -> Option<BreakpointsOps<Self>> {
         Some(self)
     }
+
+    // the default rv64i description has no FP registers
+    #[inline(always)]
+    fn support_target_description_xml_override(
+        &mut self,
+    ) -> Option<TargetDescriptionXmlOverrideOps<'_, Self>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn support_monitor_cmd(&mut self) -> Option<MonitorCmdOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+const TARGET_XML: &str = include_str!("target.xml");
+
+impl TargetDescriptionXmlOverride for Emu {
+    fn target_description_xml(
+        &self,
+        annex: &[u8],
+        offset: u64,
+        length: usize,
+        buf: &mut [u8],
+    ) -> TargetResult<usize, Self> {
+        if annex != b"target.xml" {
+            return Err(TargetError::NonFatal);
+        }
+        let xml = TARGET_XML.as_bytes();
+        let start = (offset as usize).min(xml.len());
+        let end = (start + length).min(xml.len()).min(start + buf.len());
+        buf[..end - start].copy_from_slice(&xml[start..end]);
+        Ok(end - start)
+    }
+}
+
+impl MonitorCmd for Emu {
+    fn handle_monitor_cmd(&mut self, cmd: &[u8], mut out: ConsoleOutput<'_>) -> Result<(), ()> {
+        let cmd = String::from_utf8_lossy(cmd);
+        if cmd.trim() == "tlb" {
+            outputln!(out, "{}", self.cpu.tlb.report().trim_end());
+            return Ok(());
+        }
+        let Some(stats) = &self.cpu.stats else {
+            outputln!(out, "statistics are off; run the emulator with --stats");
+            return Ok(());
+        };
+        match cmd.trim() {
+            "stats" => {
+                for line in stats.report().lines() {
+                    outputln!(out, "{}", line);
+                }
+            }
+            "stats json" => outputln!(out, "{}", stats.to_json()),
+            _ => outputln!(out, "commands: stats, stats json, tlb"),
+        }
+        Ok(())
+    }
 }
 
 impl SingleThreadBase for Emu {
@@ -103,6 +169,12 @@ impl SingleThreadBase for Emu {
         Ok(())
     }
 
+    // FP registers and CSRs are not part of the 'g' packet, gdb reads them one by one
+    #[inline(always)]
+    fn support_single_register_access(&mut self) -> Option<SingleRegisterAccessOps<'_, (), Self>> {
+        Some(self)
+    }
+
     // most targets will want to support at resumption as well...
 
     #[inline(always)]
@@ -111,6 +183,54 @@ impl SingleThreadBase for Emu {
     }
 }
 
+impl SingleRegisterAccess<()> for Emu {
+    fn read_register(
+        &mut self,
+        _tid: (),
+        reg_id: RiscvRegId<u64>,
+        buf: &mut [u8],
+    ) -> TargetResult<usize, Self> {
+        let val = match reg_id {
+            RiscvRegId::Gpr(n) => self.cpu.regs[n as usize],
+            RiscvRegId::Fpr(n) => self.cpu.fregs[n as usize],
+            RiscvRegId::Pc => self.cpu.pc,
+            RiscvRegId::Csr(csr) => {
+                self.cpu
+                    .csr
+                    .load_csrs(csr as usize, self.cpu.cycle, &self.cpu.interrupt_list)
+            }
+            RiscvRegId::Priv => self.cpu.mode,
+            _ => return Err(TargetError::NonFatal),
+        };
+        let bytes = val.to_le_bytes();
+        let len = buf.len().min(bytes.len());
+        buf[..len].copy_from_slice(&bytes[..len]);
+        Ok(len)
+    }
+
+    fn write_register(
+        &mut self,
+        _tid: (),
+        reg_id: RiscvRegId<u64>,
+        val: &[u8],
+    ) -> TargetResult<(), Self> {
+        let mut bytes = [0u8; 8];
+        let len = val.len().min(bytes.len());
+        bytes[..len].copy_from_slice(&val[..len]);
+        let val = u64::from_le_bytes(bytes);
+        match reg_id {
+            RiscvRegId::Gpr(0) => {}
+            RiscvRegId::Gpr(n) => self.cpu.regs[n as usize] = val,
+            RiscvRegId::Fpr(n) => self.cpu.fregs[n as usize] = val,
+            RiscvRegId::Pc => self.cpu.pc = val,
+            RiscvRegId::Csr(csr) => self.cpu.csr.store_csrs(csr as usize, val),
+            RiscvRegId::Priv => self.cpu.mode = val & 0b11,
+            _ => return Err(TargetError::NonFatal),
+        }
+        Ok(())
+    }
+}
+
 impl SingleThreadResume for Emu {
     fn resume(&mut self, _signal: Option<Signal>) -> Result<(), Self::Error> {
         self.exec_mode = ExecMode::Continue;
@@ -0,0 +1,766 @@
+//! Disassembly of decoded instructions in standard assembler syntax.
+//!
+//! Registers are printed with their ABI names and the usual aliases (`li`,
+//! `mv`, `ret`, `beqz`, `csrr`, ...) are used where they apply. Compressed
+//! instructions are printed as the base instructions they expand to.
+//! Branch and jump targets are absolute when the instruction's address is
+//! known (`inst.at(pc)`) and relative to `.` otherwise.
+
+use crate::instruction::DecodedInstr;
+use crate::symbols::SymbolTable;
+use std::fmt;
+
+const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+const FP_ABI_NAMES: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2",
+    "fa3", "fa4", "fa5", "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9",
+    "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+const CSR_NAMES: [(usize, &str); 39] = [
+    (0x001, "fflags"),
+    (0x002, "frm"),
+    (0x003, "fcsr"),
+    (0x100, "sstatus"),
+    (0x104, "sie"),
+    (0x105, "stvec"),
+    (0x106, "scounteren"),
+    (0x10a, "senvcfg"),
+    (0x140, "sscratch"),
+    (0x141, "sepc"),
+    (0x142, "scause"),
+    (0x143, "stval"),
+    (0x144, "sip"),
+    (0x14d, "stimecmp"),
+    (0x180, "satp"),
+    (0x300, "mstatus"),
+    (0x301, "misa"),
+    (0x302, "medeleg"),
+    (0x303, "mideleg"),
+    (0x304, "mie"),
+    (0x305, "mtvec"),
+    (0x306, "mcounteren"),
+    (0x30a, "menvcfg"),
+    (0x340, "mscratch"),
+    (0x341, "mepc"),
+    (0x342, "mcause"),
+    (0x343, "mtval"),
+    (0x344, "mip"),
+    (0x3a0, "pmpcfg0"),
+    (0x3a2, "pmpcfg2"),
+    (0x3b0, "pmpaddr0"),
+    (0x3b1, "pmpaddr1"),
+    (0xb00, "mcycle"),
+    (0xb02, "minstret"),
+    (0xc00, "cycle"),
+    (0xc01, "time"),
+    (0xc02, "instret"),
+    (0xf11, "mvendorid"),
+    (0xf14, "mhartid"),
+];
+
+const ROUNDING_MODES: [&str; 8] = ["rne", "rtz", "rdn", "rup", "rmm", "", "", "dyn"];
+
+fn x(reg: usize) -> &'static str {
+    ABI_NAMES[reg]
+}
+
+fn f(reg: usize) -> &'static str {
+    FP_ABI_NAMES[reg]
+}
+
+/// The assembler name of a CSR, for the ones the emulator implements.
+pub fn csr_name(csr: usize) -> Option<&'static str> {
+    CSR_NAMES
+        .iter()
+        .find(|(number, _)| *number == csr)
+        .map(|(_, name)| *name)
+}
+
+fn csr(csr: usize) -> String {
+    match csr_name(csr) {
+        Some(name) => name.to_string(),
+        None => format!("0x{:x}", csr),
+    }
+}
+
+// ".s" or ".d" from the fmt field of an FP instruction
+fn fmt_suffix(raw: u32) -> &'static str {
+    if (raw >> 25) & 0x3 == 0x1 {
+        "d"
+    } else {
+        "s"
+    }
+}
+
+// the rounding mode operand, omitted when it is the dynamic one
+fn rounding(rm: u64) -> String {
+    match rm {
+        7 => String::new(),
+        _ => format!(", {}", ROUNDING_MODES[rm as usize & 0x7]),
+    }
+}
+
+// ".aq", ".rl" or ".aqrl" from the ordering bits of an atomic instruction
+fn ordering(raw: u32) -> &'static str {
+    match (raw >> 25) & 0x3 {
+        0b10 => ".aq",
+        0b01 => ".rl",
+        0b11 => ".aqrl",
+        _ => "",
+    }
+}
+
+fn atomic_width(raw: u32) -> &'static str {
+    if (raw >> 12) & 0x7 == 0x3 {
+        "d"
+    } else {
+        "w"
+    }
+}
+
+fn fence_set(bits: u32) -> String {
+    let set: String = "iorw"
+        .chars()
+        .enumerate()
+        .filter(|(i, _)| bits & (0x8 >> i) != 0)
+        .map(|(_, c)| c)
+        .collect();
+    if set.is_empty() {
+        "0".to_string()
+    } else {
+        set
+    }
+}
+
+/// An instruction at a known address, printed with absolute branch targets.
+pub struct Disassembly<'a> {
+    inst: &'a DecodedInstr,
+    pc: u64,
+}
+
+impl DecodedInstr {
+    pub fn at(&self, pc: u64) -> Disassembly<'_> {
+        Disassembly { inst: self, pc }
+    }
+
+    /// Where a taken branch or a `jal` at `pc` goes.
+    pub fn branch_target(&self, pc: u64) -> Option<u64> {
+        match *self {
+            DecodedInstr::Jal { imm, .. }
+            | DecodedInstr::Beq { imm, .. }
+            | DecodedInstr::Bne { imm, .. }
+            | DecodedInstr::Blt { imm, .. }
+            | DecodedInstr::Bge { imm, .. }
+            | DecodedInstr::Bltu { imm, .. }
+            | DecodedInstr::Bgeu { imm, .. } => Some(pc.wrapping_add(imm)),
+            _ => None,
+        }
+    }
+
+    fn disassemble(&self, pc: Option<u64>) -> String {
+        let target = |imm: u64| match pc {
+            Some(pc) => format!("0x{:x}", pc.wrapping_add(imm)),
+            None if (imm as i64) < 0 => format!(". - {}", (imm as i64).unsigned_abs()),
+            None => format!(". + {}", imm),
+        };
+        let simm = |imm: u64| imm as i64;
+        let r = |name: &str, rd: usize, rs1: usize, rs2: usize| {
+            format!("{} {}, {}, {}", name, x(rd), x(rs1), x(rs2))
+        };
+        let i = |name: &str, rd: usize, rs1: usize, imm: u64| {
+            format!("{} {}, {}, {}", name, x(rd), x(rs1), simm(imm))
+        };
+        let load = |name: &str, rd: &str, rs1: usize, imm: u64| {
+            format!("{} {}, {}({})", name, rd, simm(imm), x(rs1))
+        };
+        let branch = |name: &str, rs1: usize, rs2: usize, imm: u64| match (rs1, rs2) {
+            (_, 0) if matches!(name, "beq" | "bne" | "blt" | "bge") => {
+                let alias = match name {
+                    "beq" => "beqz",
+                    "bne" => "bnez",
+                    "blt" => "bltz",
+                    _ => "bgez",
+                };
+                format!("{} {}, {}", alias, x(rs1), target(imm))
+            }
+            (0, _) if matches!(name, "blt" | "bge") => {
+                let alias = if name == "blt" { "bgtz" } else { "blez" };
+                format!("{} {}, {}", alias, x(rs2), target(imm))
+            }
+            _ => format!("{} {}, {}, {}", name, x(rs1), x(rs2), target(imm)),
+        };
+        let amo = |name: &str, raw: u32, rd: usize, rs1: usize, rs2: usize| {
+            format!(
+                "{}.{}{} {}, {}, ({})",
+                name,
+                atomic_width(raw),
+                ordering(raw),
+                x(rd),
+                x(rs2),
+                x(rs1)
+            )
+        };
+        let fp3 = |name: &str, raw: u32, rd: usize, rs1: usize, rs2: usize, rm: &str| {
+            format!(
+                "{}.{} {}, {}, {}{}",
+                name,
+                fmt_suffix(raw),
+                f(rd),
+                f(rs1),
+                f(rs2),
+                rm
+            )
+        };
+        let fused = |name: &str, raw: u32, regs: [usize; 4], rm: u64| {
+            format!(
+                "{}.{} {}, {}, {}, {}{}",
+                name,
+                fmt_suffix(raw),
+                f(regs[0]),
+                f(regs[1]),
+                f(regs[2]),
+                f(regs[3]),
+                rounding(rm)
+            )
+        };
+
+        match *self {
+            DecodedInstr::Add {
+                rd, rs1: 0, rs2, ..
+            } => format!("mv {}, {}", x(rd), x(rs2)),
+            DecodedInstr::Add { rd, rs1, rs2, .. } => r("add", rd, rs1, rs2),
+            DecodedInstr::Sub {
+                rd, rs1: 0, rs2, ..
+            } => format!("neg {}, {}", x(rd), x(rs2)),
+            DecodedInstr::Sub { rd, rs1, rs2, .. } => r("sub", rd, rs1, rs2),
+            DecodedInstr::Sll { rd, rs1, rs2, .. } => r("sll", rd, rs1, rs2),
+            DecodedInstr::Slt { rd, rs1, rs2, .. } => r("slt", rd, rs1, rs2),
+            DecodedInstr::Sltu {
+                rd, rs1: 0, rs2, ..
+            } => format!("snez {}, {}", x(rd), x(rs2)),
+            DecodedInstr::Sltu { rd, rs1, rs2, .. } => r("sltu", rd, rs1, rs2),
+            DecodedInstr::Xor { rd, rs1, rs2, .. } => r("xor", rd, rs1, rs2),
+            DecodedInstr::Srl { rd, rs1, rs2, .. } => r("srl", rd, rs1, rs2),
+            DecodedInstr::Sra { rd, rs1, rs2, .. } => r("sra", rd, rs1, rs2),
+            DecodedInstr::Or { rd, rs1, rs2, .. } => r("or", rd, rs1, rs2),
+            DecodedInstr::And { rd, rs1, rs2, .. } => r("and", rd, rs1, rs2),
+            DecodedInstr::Mul { rd, rs1, rs2, .. } => r("mul", rd, rs1, rs2),
+            DecodedInstr::Mulh { rd, rs1, rs2, .. } => r("mulh", rd, rs1, rs2),
+            DecodedInstr::Mulhsu { rd, rs1, rs2, .. } => r("mulhsu", rd, rs1, rs2),
+            DecodedInstr::Mulhu { rd, rs1, rs2, .. } => r("mulhu", rd, rs1, rs2),
+            DecodedInstr::Div { rd, rs1, rs2, .. } => r("div", rd, rs1, rs2),
+            DecodedInstr::Divu { rd, rs1, rs2, .. } => r("divu", rd, rs1, rs2),
+            DecodedInstr::Rem { rd, rs1, rs2, .. } => r("rem", rd, rs1, rs2),
+            DecodedInstr::Remu { rd, rs1, rs2, .. } => r("remu", rd, rs1, rs2),
+            DecodedInstr::Addi {
+                rd: 0,
+                rs1: 0,
+                imm: 0,
+                ..
+            } => "nop".to_string(),
+            DecodedInstr::Addi {
+                rd, rs1: 0, imm, ..
+            } => format!("li {}, {}", x(rd), simm(imm)),
+            DecodedInstr::Addi {
+                rd, rs1, imm: 0, ..
+            } => format!("mv {}, {}", x(rd), x(rs1)),
+            DecodedInstr::Addi { rd, rs1, imm, .. } => i("addi", rd, rs1, imm),
+            DecodedInstr::Slti { rd, rs1, imm, .. } => i("slti", rd, rs1, imm),
+            DecodedInstr::Sltiu {
+                rd, rs1, imm: 1, ..
+            } => {
+                format!("seqz {}, {}", x(rd), x(rs1))
+            }
+            DecodedInstr::Sltiu { rd, rs1, imm, .. } => i("sltiu", rd, rs1, imm),
+            DecodedInstr::Xori { rd, rs1, imm, .. } if simm(imm) == -1 => {
+                format!("not {}, {}", x(rd), x(rs1))
+            }
+            DecodedInstr::Xori { rd, rs1, imm, .. } => i("xori", rd, rs1, imm),
+            DecodedInstr::Ori { rd, rs1, imm, .. } => i("ori", rd, rs1, imm),
+            DecodedInstr::Andi { rd, rs1, imm, .. } => i("andi", rd, rs1, imm),
+            DecodedInstr::Slli { rd, rs1, imm, .. } => i("slli", rd, rs1, imm & 0x3f),
+            DecodedInstr::Srli { rd, rs1, imm, .. } if (imm >> 10) & 0x1 == 1 => {
+                i("srai", rd, rs1, imm & 0x3f)
+            }
+            DecodedInstr::Srli { rd, rs1, imm, .. } => i("srli", rd, rs1, imm & 0x3f),
+            DecodedInstr::Lb { rd, rs1, imm, .. } => load("lb", x(rd), rs1, imm),
+            DecodedInstr::Lh { rd, rs1, imm, .. } => load("lh", x(rd), rs1, imm),
+            DecodedInstr::Lw { rd, rs1, imm, .. } => load("lw", x(rd), rs1, imm),
+            DecodedInstr::Ld { rd, rs1, imm, .. } => load("ld", x(rd), rs1, imm),
+            DecodedInstr::Lbu { rd, rs1, imm, .. } => load("lbu", x(rd), rs1, imm),
+            DecodedInstr::Lhu { rd, rs1, imm, .. } => load("lhu", x(rd), rs1, imm),
+            DecodedInstr::Lwu { rd, rs1, imm, .. } => load("lwu", x(rd), rs1, imm),
+            DecodedInstr::Sb { rs1, rs2, imm, .. } => load("sb", x(rs2), rs1, imm),
+            DecodedInstr::Sh { rs1, rs2, imm, .. } => load("sh", x(rs2), rs1, imm),
+            DecodedInstr::Sw { rs1, rs2, imm, .. } => load("sw", x(rs2), rs1, imm),
+            DecodedInstr::Sd { rs1, rs2, imm, .. } => load("sd", x(rs2), rs1, imm),
+            DecodedInstr::Jal { rd: 0, imm, .. } => format!("j {}", target(imm)),
+            DecodedInstr::Jal { rd: 1, imm, .. } => format!("jal {}", target(imm)),
+            DecodedInstr::Jal { rd, imm, .. } => format!("jal {}, {}", x(rd), target(imm)),
+            DecodedInstr::Jalr {
+                rd: 0,
+                rs1: 1,
+                imm: 0,
+                ..
+            } => "ret".to_string(),
+            DecodedInstr::Jalr {
+                rd: 0, rs1, imm: 0, ..
+            } => format!("jr {}", x(rs1)),
+            DecodedInstr::Jalr {
+                rd: 1, rs1, imm: 0, ..
+            } => format!("jalr {}", x(rs1)),
+            DecodedInstr::Jalr { rd, rs1, imm, .. } => load("jalr", x(rd), rs1, imm),
+            DecodedInstr::Addiw {
+                rd, rs1, imm: 0, ..
+            } => {
+                format!("sext.w {}, {}", x(rd), x(rs1))
+            }
+            DecodedInstr::Addiw { rd, rs1, imm, .. } => {
+                format!("addiw {}, {}, {}", x(rd), x(rs1), imm)
+            }
+            DecodedInstr::Slliw { rd, rs1, shamt, .. } => i("slliw", rd, rs1, shamt as u64),
+            DecodedInstr::Srliw { rd, rs1, shamt, .. } => i("srliw", rd, rs1, shamt as u64),
+            DecodedInstr::Sraiw { rd, rs1, shamt, .. } => i("sraiw", rd, rs1, shamt as u64),
+            DecodedInstr::Beq { rs1, rs2, imm, .. } => branch("beq", rs1, rs2, imm),
+            DecodedInstr::Bne { rs1, rs2, imm, .. } => branch("bne", rs1, rs2, imm),
+            DecodedInstr::Blt { rs1, rs2, imm, .. } => branch("blt", rs1, rs2, imm),
+            DecodedInstr::Bge { rs1, rs2, imm, .. } => branch("bge", rs1, rs2, imm),
+            DecodedInstr::Bltu { rs1, rs2, imm, .. } => branch("bltu", rs1, rs2, imm),
+            DecodedInstr::Bgeu { rs1, rs2, imm, .. } => branch("bgeu", rs1, rs2, imm),
+            DecodedInstr::Addw { rd, rs1, rs2, .. } => r("addw", rd, rs1, rs2),
+            DecodedInstr::Subw {
+                rd, rs1: 0, rs2, ..
+            } => {
+                format!("negw {}, {}", x(rd), x(rs2))
+            }
+            DecodedInstr::Subw { rd, rs1, rs2, .. } => r("subw", rd, rs1, rs2),
+            DecodedInstr::Sllw { rd, rs1, rs2, .. } => r("sllw", rd, rs1, rs2),
+            DecodedInstr::Srlw { rd, rs1, rs2, .. } => r("srlw", rd, rs1, rs2),
+            DecodedInstr::Sraw { rd, rs1, rs2, .. } => r("sraw", rd, rs1, rs2),
+            DecodedInstr::Mulw { rd, rs1, rs2, .. } => r("mulw", rd, rs1, rs2),
+            DecodedInstr::Divw { rd, rs1, rs2, .. } => r("divw", rd, rs1, rs2),
+            DecodedInstr::Divuw { rd, rs1, rs2, .. } => r("divuw", rd, rs1, rs2),
+            DecodedInstr::Remw { rd, rs1, rs2, .. } => r("remw", rd, rs1, rs2),
+            DecodedInstr::Remuw { rd, rs1, rs2, .. } => r("remuw", rd, rs1, rs2),
+            DecodedInstr::Lui { rd, imm, .. } => {
+                format!("lui {}, 0x{:x}", x(rd), (imm >> 12) & 0xfffff)
+            }
+            DecodedInstr::Auipc { rd, imm, .. } => {
+                format!("auipc {}, 0x{:x}", x(rd), (imm >> 12) & 0xfffff)
+            }
+            DecodedInstr::Ecall { .. } => "ecall".to_string(),
+            DecodedInstr::Ebreak { .. } => "ebreak".to_string(),
+            DecodedInstr::Sret { .. } => "sret".to_string(),
+            DecodedInstr::Wfi { .. } => "wfi".to_string(),
+            DecodedInstr::Mret { .. } => "mret".to_string(),
+            // csrrw zero, cycle, zero; the canonical illegal instruction
+            DecodedInstr::Csrrw {
+                raw: 0xc000_1073, ..
+            } => "unimp".to_string(),
+            DecodedInstr::Csrrw {
+                rd: 0,
+                rs1,
+                csr: number,
+                ..
+            } => format!("csrw {}, {}", csr(number), x(rs1)),
+            DecodedInstr::Csrrw {
+                rd,
+                rs1,
+                csr: number,
+                ..
+            } => format!("csrrw {}, {}, {}", x(rd), csr(number), x(rs1)),
+            DecodedInstr::Csrrs {
+                rd,
+                rs1: 0,
+                csr: number,
+                ..
+            } => format!("csrr {}, {}", x(rd), csr(number)),
+            DecodedInstr::Csrrs {
+                rd: 0,
+                rs1,
+                csr: number,
+                ..
+            } => format!("csrs {}, {}", csr(number), x(rs1)),
+            DecodedInstr::Csrrs {
+                rd,
+                rs1,
+                csr: number,
+                ..
+            } => format!("csrrs {}, {}, {}", x(rd), csr(number), x(rs1)),
+            DecodedInstr::Csrrc {
+                rd: 0,
+                rs1,
+                csr: number,
+                ..
+            } => format!("csrc {}, {}", csr(number), x(rs1)),
+            DecodedInstr::Csrrc {
+                rd,
+                rs1,
+                csr: number,
+                ..
+            } => format!("csrrc {}, {}, {}", x(rd), csr(number), x(rs1)),
+            DecodedInstr::Csrrwi {
+                rd,
+                csr: number,
+                uimm,
+                ..
+            } => csr_immediate("csrrwi", "csrwi", rd, number, uimm),
+            DecodedInstr::Csrrsi {
+                rd,
+                csr: number,
+                uimm,
+                ..
+            } => csr_immediate("csrrsi", "csrsi", rd, number, uimm),
+            DecodedInstr::Csrrci {
+                rd,
+                csr: number,
+                uimm,
+                ..
+            } => csr_immediate("csrrci", "csrci", rd, number, uimm),
+            DecodedInstr::Sfence { raw } => {
+                let rs1 = ((raw >> 15) & 0x1f) as usize;
+                let rs2 = ((raw >> 20) & 0x1f) as usize;
+                match (rs1, rs2) {
+                    (0, 0) => "sfence.vma".to_string(),
+                    (_, 0) => format!("sfence.vma {}", x(rs1)),
+                    _ => format!("sfence.vma {}, {}", x(rs1), x(rs2)),
+                }
+            }
+            DecodedInstr::Fence { raw } if (raw >> 12) & 0x7 == 0x1 => "fence.i".to_string(),
+            DecodedInstr::Fence { raw } if raw >> 28 == 0x8 => "fence.tso".to_string(),
+            DecodedInstr::Fence { raw } if (raw >> 20) & 0xff == 0xff => "fence".to_string(),
+            DecodedInstr::Fence { raw } => format!(
+                "fence {}, {}",
+                fence_set((raw >> 24) & 0xf),
+                fence_set((raw >> 20) & 0xf)
+            ),
+            DecodedInstr::Amoswap { raw, rd, rs1, rs2 } => amo("amoswap", raw, rd, rs1, rs2),
+            DecodedInstr::Amoadd { raw, rd, rs1, rs2 } => amo("amoadd", raw, rd, rs1, rs2),
+            DecodedInstr::Amoxor { raw, rd, rs1, rs2 } => amo("amoxor", raw, rd, rs1, rs2),
+            DecodedInstr::Amoand { raw, rd, rs1, rs2 } => amo("amoand", raw, rd, rs1, rs2),
+            DecodedInstr::Amoor { raw, rd, rs1, rs2 } => amo("amoor", raw, rd, rs1, rs2),
+            DecodedInstr::Amomin { raw, rd, rs1, rs2 } => amo("amomin", raw, rd, rs1, rs2),
+            DecodedInstr::Amomax { raw, rd, rs1, rs2 } => amo("amomax", raw, rd, rs1, rs2),
+            DecodedInstr::Amominu { raw, rd, rs1, rs2 } => amo("amominu", raw, rd, rs1, rs2),
+            DecodedInstr::Amomaxu { raw, rd, rs1, rs2 } => amo("amomaxu", raw, rd, rs1, rs2),
+            DecodedInstr::Lrw { raw, rd, rs1 } | DecodedInstr::Lrd { raw, rd, rs1 } => format!(
+                "lr.{}{} {}, ({})",
+                atomic_width(raw),
+                ordering(raw),
+                x(rd),
+                x(rs1)
+            ),
+            DecodedInstr::Scw { raw, rd, rs1, rs2 } | DecodedInstr::Scd { raw, rd, rs1, rs2 } => {
+                amo("sc", raw, rd, rs1, rs2)
+            }
+            DecodedInstr::Flw { rd, rs1, imm, .. } => load("flw", f(rd), rs1, imm),
+            DecodedInstr::Fld { rd, rs1, imm, .. } => load("fld", f(rd), rs1, imm),
+            DecodedInstr::Fsw { rs1, rs2, imm, .. } => load("fsw", f(rs2), rs1, imm),
+            DecodedInstr::Fsd { rs1, rs2, imm, .. } => load("fsd", f(rs2), rs1, imm),
+            DecodedInstr::Fmadd {
+                raw,
+                rd,
+                rs1,
+                rs2,
+                rs3,
+                rm,
+            } => fused("fmadd", raw, [rd, rs1, rs2, rs3], rm),
+            DecodedInstr::Fmsub {
+                raw,
+                rd,
+                rs1,
+                rs2,
+                rs3,
+                rm,
+            } => fused("fmsub", raw, [rd, rs1, rs2, rs3], rm),
+            DecodedInstr::Fnmsub {
+                raw,
+                rd,
+                rs1,
+                rs2,
+                rs3,
+                rm,
+            } => fused("fnmsub", raw, [rd, rs1, rs2, rs3], rm),
+            DecodedInstr::Fnmadd {
+                raw,
+                rd,
+                rs1,
+                rs2,
+                rs3,
+                rm,
+            } => fused("fnmadd", raw, [rd, rs1, rs2, rs3], rm),
+            DecodedInstr::Fadd {
+                raw,
+                rd,
+                rs1,
+                rs2,
+                rm,
+            } => fp3("fadd", raw, rd, rs1, rs2, &rounding(rm)),
+            DecodedInstr::Fsub {
+                raw,
+                rd,
+                rs1,
+                rs2,
+                rm,
+            } => fp3("fsub", raw, rd, rs1, rs2, &rounding(rm)),
+            DecodedInstr::Fmul {
+                raw,
+                rd,
+                rs1,
+                rs2,
+                rm,
+            } => fp3("fmul", raw, rd, rs1, rs2, &rounding(rm)),
+            DecodedInstr::Fdiv {
+                raw,
+                rd,
+                rs1,
+                rs2,
+                rm,
+            } => fp3("fdiv", raw, rd, rs1, rs2, &rounding(rm)),
+            DecodedInstr::Fsqrt { raw, rd, rs1, rm } => format!(
+                "fsqrt.{} {}, {}{}",
+                fmt_suffix(raw),
+                f(rd),
+                f(rs1),
+                rounding(rm)
+            ),
+            DecodedInstr::Fsgnj { raw, rd, rs1, rs2 } if rs1 == rs2 => {
+                format!("fmv.{} {}, {}", fmt_suffix(raw), f(rd), f(rs1))
+            }
+            DecodedInstr::Fsgnjn { raw, rd, rs1, rs2 } if rs1 == rs2 => {
+                format!("fneg.{} {}, {}", fmt_suffix(raw), f(rd), f(rs1))
+            }
+            DecodedInstr::Fsgnjx { raw, rd, rs1, rs2 } if rs1 == rs2 => {
+                format!("fabs.{} {}, {}", fmt_suffix(raw), f(rd), f(rs1))
+            }
+            DecodedInstr::Fsgnj { raw, rd, rs1, rs2 } => fp3("fsgnj", raw, rd, rs1, rs2, ""),
+            DecodedInstr::Fsgnjn { raw, rd, rs1, rs2 } => fp3("fsgnjn", raw, rd, rs1, rs2, ""),
+            DecodedInstr::Fsgnjx { raw, rd, rs1, rs2 } => fp3("fsgnjx", raw, rd, rs1, rs2, ""),
+            DecodedInstr::Fmin { raw, rd, rs1, rs2 } => fp3("fmin", raw, rd, rs1, rs2, ""),
+            DecodedInstr::Fmax { raw, rd, rs1, rs2 } => fp3("fmax", raw, rd, rs1, rs2, ""),
+            DecodedInstr::FcvtW { raw, rd, rs1, rm } => fp_to_int("w", raw, rd, rs1, rm),
+            DecodedInstr::FcvtWu { raw, rd, rs1, rm } => fp_to_int("wu", raw, rd, rs1, rm),
+            DecodedInstr::FcvtL { raw, rd, rs1, rm } => fp_to_int("l", raw, rd, rs1, rm),
+            DecodedInstr::FcvtLu { raw, rd, rs1, rm } => fp_to_int("lu", raw, rd, rs1, rm),
+            DecodedInstr::FcvtFromW { raw, rd, rs1, rm } => int_to_fp("w", raw, rd, rs1, rm),
+            DecodedInstr::FcvtFromWu { raw, rd, rs1, rm } => int_to_fp("wu", raw, rd, rs1, rm),
+            DecodedInstr::FcvtFromL { raw, rd, rs1, rm } => int_to_fp("l", raw, rd, rs1, rm),
+            DecodedInstr::FcvtFromLu { raw, rd, rs1, rm } => int_to_fp("lu", raw, rd, rs1, rm),
+            DecodedInstr::FcvtSD { rd, rs1, rm, .. } => {
+                format!("fcvt.s.d {}, {}{}", f(rd), f(rs1), rounding(rm))
+            }
+            // exact conversions take no rounding mode
+            DecodedInstr::FcvtDS { rd, rs1, .. } => format!("fcvt.d.s {}, {}", f(rd), f(rs1)),
+            DecodedInstr::FmvXW { rd, rs1, .. } => format!("fmv.x.w {}, {}", x(rd), f(rs1)),
+            DecodedInstr::FmvWX { rd, rs1, .. } => format!("fmv.w.x {}, {}", f(rd), x(rs1)),
+            DecodedInstr::FmvXD { rd, rs1, .. } => format!("fmv.x.d {}, {}", x(rd), f(rs1)),
+            DecodedInstr::FmvDX { rd, rs1, .. } => format!("fmv.d.x {}, {}", f(rd), x(rs1)),
+            DecodedInstr::Feq { raw, rd, rs1, rs2 } => fp_compare("feq", raw, rd, rs1, rs2),
+            DecodedInstr::Flt { raw, rd, rs1, rs2 } => fp_compare("flt", raw, rd, rs1, rs2),
+            DecodedInstr::Fle { raw, rd, rs1, rs2 } => fp_compare("fle", raw, rd, rs1, rs2),
+            DecodedInstr::Fclass { raw, rd, rs1 } => {
+                format!("fclass.{} {}, {}", fmt_suffix(raw), x(rd), f(rs1))
+            }
+            DecodedInstr::IllegalInstruction { inst: 0 } => "unimp".to_string(),
+            DecodedInstr::IllegalInstruction { inst } if inst & 0b11 != 0b11 => {
+                format!(".half 0x{:04x}", inst & 0xffff)
+            }
+            DecodedInstr::IllegalInstruction { inst } => format!(".word 0x{:08x}", inst),
+        }
+    }
+}
+
+fn csr_immediate(name: &str, alias: &str, rd: usize, number: usize, uimm: u32) -> String {
+    match rd {
+        0 => format!("{} {}, {}", alias, csr(number), uimm),
+        _ => format!("{} {}, {}, {}", name, x(rd), csr(number), uimm),
+    }
+}
+
+fn fp_to_int(int: &str, raw: u32, rd: usize, rs1: usize, rm: u64) -> String {
+    format!(
+        "fcvt.{}.{} {}, {}{}",
+        int,
+        fmt_suffix(raw),
+        x(rd),
+        f(rs1),
+        rounding(rm)
+    )
+}
+
+fn int_to_fp(int: &str, raw: u32, rd: usize, rs1: usize, rm: u64) -> String {
+    // every 32-bit integer is exact in double precision
+    let exact = fmt_suffix(raw) == "d" && int.starts_with('w');
+    format!(
+        "fcvt.{}.{} {}, {}{}",
+        fmt_suffix(raw),
+        int,
+        f(rd),
+        x(rs1),
+        if exact { String::new() } else { rounding(rm) }
+    )
+}
+
+fn fp_compare(name: &str, raw: u32, rd: usize, rs1: usize, rs2: usize) -> String {
+    format!(
+        "{}.{} {}, {}, {}",
+        name,
+        fmt_suffix(raw),
+        x(rd),
+        f(rs1),
+        f(rs2)
+    )
+}
+
+impl fmt::Display for DecodedInstr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.disassemble(None))
+    }
+}
+
+impl fmt::Display for Disassembly<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.inst.disassemble(Some(self.pc)))
+    }
+}
+
+/// Read instructions from a memory image loaded at `base`. A 16-bit RVC
+/// instruction is returned in the lower half, as `Cpu::fetch` does.
+pub fn fetch_from(memory: &[u8], base: u64) -> impl Fn(u64) -> Option<u32> + '_ {
+    move |addr| {
+        let half = |addr: u64| {
+            let offset = addr.checked_sub(base)? as usize;
+            let bytes = memory.get(offset..offset.checked_add(2)?)?;
+            Some(u16::from_le_bytes([bytes[0], bytes[1]]) as u32)
+        };
+        let low = half(addr)?;
+        if low & 0b11 != 0b11 {
+            return Some(low);
+        }
+        Some(low | (half(addr + 2)? << 16))
+    }
+}
+
+/// An objdump-style listing of the instructions from `start` up to `end`,
+/// with a label wherever a symbol starts. Stops early at an address
+/// `fetch` cannot read.
+pub fn listing(
+    mut fetch: impl FnMut(u64) -> Option<u32>,
+    start: u64,
+    end: u64,
+    symbols: &SymbolTable,
+) -> String {
+    let mut output = String::new();
+    let mut pc = start;
+    while pc < end {
+        if let Some(location) = symbols.lookup(pc).filter(|l| l.offset == 0 || pc == start) {
+            output += &format!("\n{:016x} <{}>:\n", pc, location);
+        }
+        let Some(raw) = fetch(pc) else {
+            output += &format!("{:>16x}:\t<unreadable>\n", pc);
+            break;
+        };
+        let inst = DecodedInstr::decode(raw);
+        let encoding = match inst.inst_len() {
+            2 => format!("{:04x}", raw),
+            _ => format!("{:08x}", raw),
+        };
+        let target = inst
+            .branch_target(pc)
+            .and_then(|target| symbols.lookup(target))
+            .map(|location| format!(" <{}>", location))
+            .unwrap_or_default();
+        output += &format!("{:>16x}:\t{:<8}\t{}{}\n", pc, encoding, inst.at(pc), target);
+        pc += inst.inst_len();
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassembly_matches_assembler_syntax() {
+        // encodings assembled with llvm-mc, placed at 0x80000000
+        let cases: [(u32, &str); 29] = [
+            (0xff010113, "addi sp, sp, -16"),
+            (0x00113423, "sd ra, 8(sp)"),
+            (0xffb00513, "li a0, -5"),
+            (0x00058513, "mv a0, a1"),
+            (0x00000013, "nop"),
+            (0x4215d513, "srai a0, a1, 33"),
+            (0x0005851b, "sext.w a0, a1"),
+            (0x40b00533, "neg a0, a1"),
+            (0x00008067, "ret"),
+            (0x000780e7, "jalr a5"),
+            (0x008782e7, "jalr t0, 8(a5)"),
+            (0x100000ef, "jal 0x80000100"),
+            (0xff9ff06f, "j 0x7ffffff8"),
+            (0xfeb518e3, "bne a0, a1, 0x7ffffff0"),
+            (0x00055863, "bgez a0, 0x80000010"),
+            (0x00a05863, "blez a0, 0x80000010"),
+            (0xfffff537, "lui a0, 0xfffff"),
+            (0x0000b117, "auipc sp, 0xb"),
+            (0xf14025f3, "csrr a1, mhartid"),
+            (0x30551073, "csrw mtvec, a0"),
+            (0x7c02d573, "csrrwi a0, 0x7c0, 5"),
+            (0xc0001073, "unimp"),
+            (0x12b50073, "sfence.vma a0, a1"),
+            (0x0000100f, "fence.i"),
+            (0x66b6352f, "amoand.d.aqrl a0, a1, (a2)"),
+            (0x1405b52f, "lr.d.aq a0, (a1)"),
+            (0x0ad5f553, "fsub.d fa0, fa1, fa3"),
+            (0x0ad58553, "fsub.d fa0, fa1, fa3, rne"),
+            (0xc0051553, "fcvt.w.s a0, fa0, rtz"),
+        ];
+        for (raw, expected) in cases {
+            let inst = DecodedInstr::decode(raw);
+            assert_eq!(inst.at(0x8000_0000).to_string(), expected, "{:#010x}", raw);
+        }
+    }
+
+    #[test]
+    fn test_relative_targets_and_compressed() {
+        assert_eq!(DecodedInstr::decode(0xff9ff06f).to_string(), "j . - 8");
+        assert_eq!(
+            DecodedInstr::decode(0x00b50863).to_string(),
+            "beq a0, a1, . + 16"
+        );
+        // c.mv a0, a1 and c.addi16sp sp, -512
+        assert_eq!(DecodedInstr::decode(0x852e).to_string(), "mv a0, a1");
+        assert_eq!(
+            DecodedInstr::decode(0x7101).to_string(),
+            "addi sp, sp, -512"
+        );
+        assert_eq!(DecodedInstr::decode(0x0000).to_string(), "unimp");
+    }
+
+    #[test]
+    fn test_listing() {
+        // li a0, 1; c.mv a1, a0; j . - 6
+        let memory = [0x13, 0x05, 0x10, 0x00, 0xaa, 0x85, 0xed, 0xbf];
+        let listing = listing(
+            fetch_from(&memory, 0x8000_0000),
+            0x8000_0000,
+            0x8000_000a,
+            &SymbolTable::default(),
+        );
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "        80000000:\t00100513\tli a0, 1",
+                "        80000004:\t85aa    \tmv a1, a0",
+                "        80000006:\tbfed    \tj 0x80000000",
+                "        80000008:\t<unreadable>",
+            ]
+        );
+    }
+}
@@ -0,0 +1,308 @@
+use log::info;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+const SECTOR_SIZE: u64 = 512;
+// an overlay record: the sector number followed by the sector's contents
+const RECORD_SIZE: u64 = 8 + SECTOR_SIZE;
+
+/// Where the virtio disk's contents come from and where guest writes go.
+///
+/// The device keeps the whole image in memory; a backend supplies the initial
+/// contents and decides what happens to the sectors the guest writes.
+pub trait DiskBackend: Send {
+    /// The initial disk contents.
+    fn open(&mut self) -> io::Result<Vec<u8>>;
+    /// Guest writes are rejected with an I/O error and the device reports VIRTIO_BLK_F_RO.
+    fn read_only(&self) -> bool {
+        false
+    }
+    /// The guest wrote `len` bytes at `offset` of `image`, the in-memory
+    /// contents. A backend that keeps the writes stores them before returning,
+    /// so they survive however the emulator exits.
+    fn written(&mut self, _image: &[u8], _offset: u64, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+    /// Make everything written so far durable.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Parse the `--disk-mode` option for the image at `image`:
+/// `memory`, `read-only`, `write-back` or `overlay:PATH`.
+pub fn parse_backend(mode: &str, image: PathBuf) -> Result<Box<dyn DiskBackend>, String> {
+    match mode.split_once(':') {
+        None if mode == "memory" => Ok(Box::new(MemoryDisk::from_file(image))),
+        None if mode == "read-only" => Ok(Box::new(ReadOnlyDisk { image })),
+        None if mode == "write-back" => Ok(Box::new(WriteBackDisk::new(image))),
+        Some(("overlay", overlay)) => Ok(Box::new(OverlayDisk::new(image, PathBuf::from(overlay)))),
+        _ => Err(format!("unknown disk mode: {}", mode)),
+    }
+}
+
+fn sectors(offset: u64, len: u64) -> std::ops::Range<u64> {
+    offset / SECTOR_SIZE..(offset + len).div_ceil(SECTOR_SIZE)
+}
+
+// the bytes of `sector`, which may be short at the end of the image
+fn sector_bytes(image: &[u8], sector: u64) -> &[u8] {
+    let start = sector.saturating_mul(SECTOR_SIZE).min(image.len() as u64) as usize;
+    let end = (start + SECTOR_SIZE as usize).min(image.len());
+    &image[start..end]
+}
+
+/// Contents live only in memory; writes are lost at exit.
+pub struct MemoryDisk {
+    source: Option<PathBuf>,
+    image: Vec<u8>,
+}
+
+impl MemoryDisk {
+    pub fn new(image: Vec<u8>) -> MemoryDisk {
+        Self {
+            source: None,
+            image,
+        }
+    }
+
+    pub fn from_file(path: PathBuf) -> MemoryDisk {
+        Self {
+            source: Some(path),
+            image: Vec::new(),
+        }
+    }
+}
+
+impl DiskBackend for MemoryDisk {
+    fn open(&mut self) -> io::Result<Vec<u8>> {
+        match &self.source {
+            Some(path) => std::fs::read(path),
+            None => Ok(std::mem::take(&mut self.image)),
+        }
+    }
+}
+
+/// The image file as it is; the guest cannot write to it.
+pub struct ReadOnlyDisk {
+    image: PathBuf,
+}
+
+impl DiskBackend for ReadOnlyDisk {
+    fn open(&mut self) -> io::Result<Vec<u8>> {
+        std::fs::read(&self.image)
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+}
+
+/// Guest writes go straight through to the image file; flush requests
+/// sync it to storage.
+pub struct WriteBackDisk {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl WriteBackDisk {
+    pub fn new(path: PathBuf) -> WriteBackDisk {
+        Self { path, file: None }
+    }
+}
+
+impl DiskBackend for WriteBackDisk {
+    fn open(&mut self) -> io::Result<Vec<u8>> {
+        let mut file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        let mut image = Vec::new();
+        file.read_to_end(&mut image)?;
+        self.file = Some(file);
+        Ok(image)
+    }
+
+    fn written(&mut self, image: &[u8], offset: u64, len: u64) -> io::Result<()> {
+        let Some(file) = self.file.as_mut() else {
+            return Ok(());
+        };
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&image[offset as usize..(offset + len) as usize])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &self.file {
+            Some(file) => file.sync_data(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Copy-on-write overlay: the base image is never modified. Every written
+/// sector is stored in the overlay file as a (sector number, contents)
+/// record as soon as the guest writes it, and the records are replayed on
+/// top of the base the next time the overlay is opened. Writing a sector
+/// again overwrites its record, so the overlay holds at most one record per
+/// sector and never grows past the size of the image plus the sector numbers.
+pub struct OverlayDisk {
+    base: PathBuf,
+    path: PathBuf,
+    file: Option<File>,
+    // the offset of each written sector's record in the overlay file
+    records: BTreeMap<u64, u64>,
+    // where the next new record goes
+    end: u64,
+}
+
+impl OverlayDisk {
+    pub fn new(base: PathBuf, path: PathBuf) -> OverlayDisk {
+        Self {
+            base,
+            path,
+            file: None,
+            records: BTreeMap::new(),
+            end: 0,
+        }
+    }
+}
+
+impl DiskBackend for OverlayDisk {
+    fn open(&mut self) -> io::Result<Vec<u8>> {
+        let mut image = std::fs::read(&self.base)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)?;
+        let mut records = Vec::new();
+        file.read_to_end(&mut records)?;
+        // a torn record at the end is dropped and overwritten by the next one
+        for (i, record) in records.chunks_exact(RECORD_SIZE as usize).enumerate() {
+            let sector = u64::from_le_bytes(record[..8].try_into().unwrap());
+            let start = sector
+                .checked_mul(SECTOR_SIZE)
+                .filter(|&start| start < image.len() as u64)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "overlay {}: record {} names sector {} beyond the image",
+                            self.path.display(),
+                            i,
+                            sector
+                        ),
+                    )
+                })? as usize;
+            let end = (start + SECTOR_SIZE as usize).min(image.len());
+            image[start..end].copy_from_slice(&record[8..8 + end - start]);
+            self.records.insert(sector, i as u64 * RECORD_SIZE);
+        }
+        self.end = records.len() as u64 / RECORD_SIZE * RECORD_SIZE;
+        info!(
+            "disk: replayed {} sector(s) from overlay {}",
+            self.records.len(),
+            self.path.display()
+        );
+        self.file = Some(file);
+        Ok(image)
+    }
+
+    fn written(&mut self, image: &[u8], offset: u64, len: u64) -> io::Result<()> {
+        let Some(file) = self.file.as_mut() else {
+            return Ok(());
+        };
+        for sector in sectors(offset, len) {
+            let mut record = [0u8; RECORD_SIZE as usize];
+            let bytes = sector_bytes(image, sector);
+            record[..8].copy_from_slice(&sector.to_le_bytes());
+            record[8..8 + bytes.len()].copy_from_slice(bytes);
+            let end = &mut self.end;
+            let pos = *self.records.entry(sector).or_insert_with(|| {
+                *end += RECORD_SIZE;
+                *end - RECORD_SIZE
+            });
+            file.seek(SeekFrom::Start(pos))?;
+            file.write_all(&record)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &self.file {
+            Some(file) => file.sync_data(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rv-emu-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_write_back_and_overlay() {
+        let base = temp_path("base.img");
+        std::fs::write(&base, vec![0u8; 4 * SECTOR_SIZE as usize]).unwrap();
+
+        // overlay: the base stays pristine and the overlay is replayed on reopen
+        let overlay = temp_path("overlay.cow");
+        let mut disk = OverlayDisk::new(base.clone(), overlay.clone());
+        let mut image = disk.open().unwrap();
+        image[600..700].fill(0x5a);
+        disk.written(&image, 600, 100).unwrap();
+        // rewriting a sector replaces its record instead of adding one
+        image[700..800].fill(0x3c);
+        disk.written(&image, 700, 100).unwrap();
+        drop(disk);
+        assert!(std::fs::read(&base).unwrap().iter().all(|&b| b == 0));
+        assert_eq!(std::fs::metadata(&overlay).unwrap().len(), RECORD_SIZE);
+        let reopened = OverlayDisk::new(base.clone(), overlay.clone())
+            .open()
+            .unwrap();
+        assert_eq!(reopened, image);
+
+        // write-back: writes reach the image file without a flush
+        let mut disk = WriteBackDisk::new(base.clone());
+        let mut image = disk.open().unwrap();
+        image[1500..1600].fill(0xa5);
+        disk.written(&image, 1500, 100).unwrap();
+        assert_eq!(std::fs::read(&base).unwrap(), image);
+
+        assert!(ReadOnlyDisk {
+            image: base.clone()
+        }
+        .read_only());
+        assert!(parse_backend("overlay:x.cow", base.clone()).is_ok());
+        assert!(parse_backend("cow", base.clone()).is_err());
+
+        let _ = std::fs::remove_file(base);
+        let _ = std::fs::remove_file(overlay);
+    }
+
+    #[test]
+    fn test_reject_corrupt_overlay() {
+        let base = temp_path("small.img");
+        std::fs::write(&base, vec![0u8; 2 * SECTOR_SIZE as usize]).unwrap();
+        let overlay = temp_path("corrupt.cow");
+        for sector in [2, u64::MAX / 2] {
+            let mut record = vec![0u8; RECORD_SIZE as usize];
+            record[..8].copy_from_slice(&u64::to_le_bytes(sector));
+            std::fs::write(&overlay, &record).unwrap();
+            let err = OverlayDisk::new(base.clone(), overlay.clone())
+                .open()
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
+        let _ = std::fs::remove_file(base);
+        let _ = std::fs::remove_file(overlay);
+    }
+}
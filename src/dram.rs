@@ -10,6 +10,13 @@ pub const DRAM_SIZE: u64 = 1024 * 1024 * 128;
 pub struct Dram {
     pub dram: Vec<u8>,
     pub dram_base: u64,
+    // one bit per page holding the code of a cached basic block; the CPU's
+    // block cache is not part of snapshots, so neither is this
+    #[serde(skip)]
+    code_pages: Vec<u64>,
+    // addresses of the code pages written since the CPU last looked
+    #[serde(skip)]
+    written_code_pages: Vec<u64>,
 }
 
 impl Dram {
@@ -19,6 +26,47 @@ impl Dram {
         Self {
             dram: dram,
             dram_base: base,
+            code_pages: Vec::new(),
+            written_code_pages: Vec::new(),
+        }
+    }
+
+    /// Watches the page holding `addr` for writes, as it holds code of a
+    /// cached basic block.
+    pub fn mark_code(&mut self, addr: u64) {
+        let Some(offset) = addr.checked_sub(self.dram_base) else {
+            return;
+        };
+        if offset >= self.dram.len() as u64 {
+            return;
+        }
+        let page = (offset >> 12) as usize;
+        if self.code_pages.is_empty() {
+            self.code_pages = vec![0; (self.dram.len() >> 12) / 64 + 1];
+        }
+        self.code_pages[page / 64] |= 1 << (page % 64);
+    }
+
+    /// The addresses of the code pages written since the last call. They are
+    /// no longer watched until marked again.
+    pub fn take_written_code_pages(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.written_code_pages)
+    }
+
+    // `len` bytes were written at offset `index`
+    fn wrote(&mut self, index: usize, len: usize) {
+        if self.code_pages.is_empty() || len == 0 {
+            return;
+        }
+        for page in index >> 12..=(index + len - 1) >> 12 {
+            let Some(bits) = self.code_pages.get_mut(page / 64) else {
+                continue;
+            };
+            if *bits & (1 << (page % 64)) != 0 {
+                *bits &= !(1 << (page % 64));
+                self.written_code_pages
+                    .push(self.dram_base + ((page as u64) << 12));
+            }
         }
     }
 
@@ -74,17 +122,20 @@ impl Dram {
 
     fn store8(&mut self, addr: u64, value: u64) {
         let index = (addr - self.dram_base) as usize;
+        self.wrote(index, 1);
         self.dram[index + 0] = ((value >> 0) & 0xff) as u8;
     }
 
     fn store16(&mut self, addr: u64, value: u64) {
         let index = (addr - self.dram_base) as usize;
+        self.wrote(index, 2);
         self.dram[index + 0] = ((value >> 0) & 0xff) as u8;
         self.dram[index + 1] = ((value >> 8) & 0xff) as u8;
     }
 
     fn store32(&mut self, addr: u64, value: u64) {
         let index = (addr - self.dram_base) as usize;
+        self.wrote(index, 4);
         self.dram[index + 0] = ((value >> 0) & 0xff) as u8;
         self.dram[index + 1] = ((value >> 8) & 0xff) as u8;
         self.dram[index + 2] = ((value >> 16) & 0xff) as u8;
@@ -93,6 +144,7 @@ impl Dram {
 
     fn store64(&mut self, addr: u64, value: u64) {
         let index = (addr - self.dram_base) as usize;
+        self.wrote(index, 8);
         self.dram[index + 0] = ((value >> 0) & 0xff) as u8;
         self.dram[index + 1] = ((value >> 8) & 0xff) as u8;
         self.dram[index + 2] = ((value >> 16) & 0xff) as u8;
@@ -103,6 +155,22 @@ impl Dram {
         self.dram[index + 7] = ((value >> 56) & 0xff) as u8;
     }
 
+    /// Copy `bytes` into DRAM at `addr`; false if they do not fit.
+    pub fn write_bytes(&mut self, addr: u64, bytes: &[u8]) -> bool {
+        let Some(offset) = addr.checked_sub(self.dram_base) else {
+            return false;
+        };
+        let offset = offset as usize;
+        match self.dram.get_mut(offset..offset + bytes.len()) {
+            Some(dest) => {
+                dest.copy_from_slice(bytes);
+                self.wrote(offset, bytes.len());
+                true
+            }
+            None => false,
+        }
+    }
+
     pub fn dump(&self, path: &str) {
         let mut file = File::create(path).expect("Cannot open file");
 
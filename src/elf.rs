@@ -0,0 +1,663 @@
+//! ELF loader for RISC-V executables, 32- and 64-bit little endian.
+
+use crate::dram::DRAM_SIZE;
+use log::{debug, info, warn};
+use std::convert::TryInto;
+use std::path::Path;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHN_UNDEF: u16 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfClass {
+    Elf32,
+    Elf64,
+}
+
+/// A `PT_LOAD` segment; `data` is followed by `mem_size - data.len()` zero bytes.
+#[derive(Debug)]
+pub struct Segment {
+    pub paddr: u64,
+    pub vaddr: u64,
+    pub mem_size: u64,
+    pub data: Vec<u8>,
+    /// PF_X, PF_W and PF_R in bits 0, 1 and 2.
+    pub flags: u32,
+}
+
+#[derive(Debug)]
+pub struct Section {
+    pub name: String,
+    pub addr: u64,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Function,
+    Object,
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u64,
+    pub size: u64,
+    pub kind: SymbolKind,
+}
+
+#[derive(Debug)]
+pub struct ElfImage {
+    pub class: ElfClass,
+    pub entry: u64,
+    /// Loadable segments sorted by physical address.
+    pub segments: Vec<Segment>,
+    pub sections: Vec<Section>,
+    /// Defined function, object and other named symbols, sorted by address.
+    pub symbols: Vec<Symbol>,
+}
+
+// bounds-checked little-endian reads of the file
+struct Reader<'a> {
+    data: &'a [u8],
+    class: ElfClass,
+}
+
+impl Reader<'_> {
+    fn bytes(&self, offset: u64, len: u64) -> Result<&[u8], String> {
+        offset
+            .checked_add(len)
+            .filter(|&end| end <= self.data.len() as u64)
+            .map(|end| &self.data[offset as usize..end as usize])
+            .ok_or_else(|| {
+                format!(
+                    "truncated file: {} bytes at offset 0x{:x} are out of range",
+                    len, offset
+                )
+            })
+    }
+
+    // the offset of entry `index` of a table of `size`-byte entries at
+    // `table`, checked to lie wholly within the file
+    fn entry(&self, table: u64, index: u64, size: u64) -> Result<u64, String> {
+        let offset = index
+            .checked_mul(size)
+            .and_then(|offset| table.checked_add(offset))
+            .ok_or_else(|| {
+                format!(
+                    "entry {} of the table at offset 0x{:x} is out of range",
+                    index, table
+                )
+            })?;
+        self.bytes(offset, size)?;
+        Ok(offset)
+    }
+
+    fn u8(&self, offset: u64) -> Result<u8, String> {
+        Ok(self.bytes(offset, 1)?[0])
+    }
+
+    fn u16(&self, offset: u64) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(
+            self.bytes(offset, 2)?.try_into().unwrap(),
+        ))
+    }
+
+    fn u32(&self, offset: u64) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(
+            self.bytes(offset, 4)?.try_into().unwrap(),
+        ))
+    }
+
+    fn u64(&self, offset: u64) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(
+            self.bytes(offset, 8)?.try_into().unwrap(),
+        ))
+    }
+
+    // an address-sized field: 4 bytes in ELF32, 8 in ELF64
+    fn word(&self, offset: u64) -> Result<u64, String> {
+        match self.class {
+            ElfClass::Elf32 => self.u32(offset).map(u64::from),
+            ElfClass::Elf64 => self.u64(offset),
+        }
+    }
+
+    fn string(&self, table_offset: u64, table_size: u64, index: u32) -> Result<String, String> {
+        let table = self.bytes(table_offset, table_size)?;
+        let start = (index as usize).min(table.len());
+        let len = table[start..]
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or("unterminated string in a string table")?;
+        Ok(String::from_utf8_lossy(&table[start..start + len]).into_owned())
+    }
+}
+
+// offsets of the fields that differ between the two classes
+struct Layout {
+    phoff: u64,
+    shoff: u64,
+    phentsize: u64,
+    phnum: u64,
+    shentsize: u64,
+    shnum: u64,
+    shstrndx: u64,
+    phdr_size: u64,
+    shdr_size: u64,
+    sym_size: u64,
+}
+
+const LAYOUT32: Layout = Layout {
+    phoff: 28,
+    shoff: 32,
+    phentsize: 42,
+    phnum: 44,
+    shentsize: 46,
+    shnum: 48,
+    shstrndx: 50,
+    phdr_size: 32,
+    shdr_size: 40,
+    sym_size: 16,
+};
+
+const LAYOUT64: Layout = Layout {
+    phoff: 32,
+    shoff: 40,
+    phentsize: 54,
+    phnum: 56,
+    shentsize: 58,
+    shnum: 60,
+    shstrndx: 62,
+    phdr_size: 56,
+    shdr_size: 64,
+    sym_size: 24,
+};
+
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+}
+
+impl ElfImage {
+    pub fn from_file(path: &Path) -> Result<ElfImage, String> {
+        let data =
+            std::fs::read(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        Self::parse(&data).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(data: &[u8]) -> Result<ElfImage, String> {
+        if data.len() < 16 || &data[0..4] != ELF_MAGIC {
+            return Err("not an ELF file".to_string());
+        }
+        let class = match data[4] {
+            ELFCLASS32 => ElfClass::Elf32,
+            ELFCLASS64 => ElfClass::Elf64,
+            other => return Err(format!("unknown ELF class {}", other)),
+        };
+        if data[5] != ELFDATA2LSB {
+            return Err("big-endian ELF files are not supported".to_string());
+        }
+        let reader = Reader { data, class };
+        let layout = match class {
+            ElfClass::Elf32 => &LAYOUT32,
+            ElfClass::Elf64 => &LAYOUT64,
+        };
+
+        let machine = reader.u16(18)?;
+        if machine != EM_RISCV {
+            return Err(format!(
+                "not a RISC-V binary (e_machine is {}, expected {})",
+                machine, EM_RISCV
+            ));
+        }
+        let file_type = reader.u16(16)?;
+        if file_type != ET_EXEC && file_type != ET_DYN {
+            return Err(format!(
+                "not an executable (e_type is {}); link the program first",
+                file_type
+            ));
+        }
+        let entry = reader.word(24)?;
+
+        let segments = Self::read_segments(&reader, layout)?;
+        let headers = Self::read_section_headers(&reader, layout)?;
+        let names = headers.get(reader.u16(layout.shstrndx)? as usize);
+        let mut sections = Vec::new();
+        for header in &headers {
+            let name = match names {
+                Some(names) => reader.string(names.offset, names.size, header.name)?,
+                None => String::new(),
+            };
+            sections.push(Section {
+                name,
+                addr: header.addr,
+                size: header.size,
+            });
+        }
+        let symbols = Self::read_symbols(&reader, layout, &headers)?;
+
+        Ok(ElfImage {
+            class,
+            entry,
+            segments,
+            sections,
+            symbols,
+        })
+    }
+
+    fn read_segments(reader: &Reader, layout: &Layout) -> Result<Vec<Segment>, String> {
+        let phoff = reader.word(layout.phoff)?;
+        let phentsize = reader.u16(layout.phentsize)? as u64;
+        let phnum = reader.u16(layout.phnum)? as u64;
+        if phnum > 0 && phentsize < layout.phdr_size {
+            return Err(format!("invalid program header size {}", phentsize));
+        }
+        let mut segments = Vec::new();
+        for index in 0..phnum {
+            let header = reader.entry(phoff, index, phentsize)?;
+            if reader.u32(header)? != PT_LOAD {
+                continue;
+            }
+            let (offset, vaddr, paddr, file_size, mem_size, flags) = match reader.class {
+                ElfClass::Elf32 => (
+                    reader.word(header + 4)?,
+                    reader.word(header + 8)?,
+                    reader.word(header + 12)?,
+                    reader.word(header + 16)?,
+                    reader.word(header + 20)?,
+                    reader.u32(header + 24)?,
+                ),
+                ElfClass::Elf64 => (
+                    reader.word(header + 8)?,
+                    reader.word(header + 16)?,
+                    reader.word(header + 24)?,
+                    reader.word(header + 32)?,
+                    reader.word(header + 40)?,
+                    reader.u32(header + 4)?,
+                ),
+            };
+            if file_size > mem_size {
+                return Err(format!(
+                    "segment {} has a file size larger than its memory size",
+                    index
+                ));
+            }
+            if mem_size == 0 {
+                continue;
+            }
+            let data = reader
+                .bytes(offset, file_size)
+                .map_err(|e| format!("segment {}: {}", index, e))?
+                .to_vec();
+            segments.push(Segment {
+                paddr,
+                vaddr,
+                mem_size,
+                data,
+                flags,
+            });
+        }
+        segments.sort_by_key(|segment| segment.paddr);
+        for pair in segments.windows(2) {
+            if pair[0].paddr.saturating_add(pair[0].mem_size) > pair[1].paddr {
+                return Err(format!(
+                    "segments at 0x{:x} and 0x{:x} overlap",
+                    pair[0].paddr, pair[1].paddr
+                ));
+            }
+        }
+        Ok(segments)
+    }
+
+    fn read_section_headers(
+        reader: &Reader,
+        layout: &Layout,
+    ) -> Result<Vec<SectionHeader>, String> {
+        let shoff = reader.word(layout.shoff)?;
+        let shentsize = reader.u16(layout.shentsize)? as u64;
+        let shnum = reader.u16(layout.shnum)? as u64;
+        if shnum > 0 && shentsize < layout.shdr_size {
+            return Err(format!("invalid section header size {}", shentsize));
+        }
+        let mut headers = Vec::new();
+        for index in 0..shnum {
+            let header = reader.entry(shoff, index, shentsize)?;
+            let (addr, offset, size, link) = match reader.class {
+                ElfClass::Elf32 => (
+                    reader.word(header + 12)?,
+                    reader.word(header + 16)?,
+                    reader.word(header + 20)?,
+                    reader.u32(header + 24)?,
+                ),
+                ElfClass::Elf64 => (
+                    reader.word(header + 16)?,
+                    reader.word(header + 24)?,
+                    reader.word(header + 32)?,
+                    reader.u32(header + 40)?,
+                ),
+            };
+            headers.push(SectionHeader {
+                name: reader.u32(header)?,
+                kind: reader.u32(header + 4)?,
+                addr,
+                offset,
+                size,
+                link,
+            });
+        }
+        Ok(headers)
+    }
+
+    fn read_symbols(
+        reader: &Reader,
+        layout: &Layout,
+        headers: &[SectionHeader],
+    ) -> Result<Vec<Symbol>, String> {
+        let mut symbols = Vec::new();
+        for table in headers.iter().filter(|h| h.kind == SHT_SYMTAB) {
+            let strings = headers
+                .get(table.link as usize)
+                .ok_or("symbol table without a string table")?;
+            for index in 1..table.size / layout.sym_size {
+                let sym = reader.entry(table.offset, index, layout.sym_size)?;
+                let (name, addr, size, info, shndx) = match reader.class {
+                    ElfClass::Elf32 => (
+                        reader.u32(sym)?,
+                        reader.word(sym + 4)?,
+                        reader.word(sym + 8)?,
+                        reader.u8(sym + 12)?,
+                        reader.u16(sym + 14)?,
+                    ),
+                    ElfClass::Elf64 => (
+                        reader.u32(sym)?,
+                        reader.word(sym + 8)?,
+                        reader.word(sym + 16)?,
+                        reader.u8(sym + 4)?,
+                        reader.u16(sym + 6)?,
+                    ),
+                };
+                let kind = match info & 0xf {
+                    STT_FUNC => SymbolKind::Function,
+                    STT_OBJECT => SymbolKind::Object,
+                    0 => SymbolKind::Other,
+                    // sections, files and TLS symbols do not name an address
+                    _ => continue,
+                };
+                if shndx == SHN_UNDEF {
+                    continue;
+                }
+                let name = reader.string(strings.offset, strings.size, name)?;
+                // skip unnamed and assembler-local labels such as .L0
+                if name.is_empty() || name.starts_with(".L") {
+                    continue;
+                }
+                symbols.push(Symbol {
+                    name,
+                    addr,
+                    size,
+                    kind,
+                });
+            }
+        }
+        symbols.sort_by(|a, b| a.addr.cmp(&b.addr).then_with(|| a.name.cmp(&b.name)));
+        Ok(symbols)
+    }
+
+    /// The symbol containing `addr`, preferring functions.
+    pub fn symbol_at(&self, addr: u64) -> Option<&Symbol> {
+        self.symbols
+            .iter()
+            .filter(|s| s.addr <= addr && addr < s.addr.saturating_add(s.size.max(1)))
+            .min_by_key(|s| (s.kind != SymbolKind::Function, addr - s.addr))
+    }
+
+    /// DRAM contents from `base` holding every segment at its physical
+    /// address. Segments below `base` are skipped.
+    pub fn memory_image(&self, base: u64) -> Result<Vec<u8>, String> {
+        let mut memory = Vec::new();
+        for segment in &self.segments {
+            if segment.paddr < base {
+                warn!(
+                    "skipping segment at 0x{:x} below the base address 0x{:x}",
+                    segment.paddr, base
+                );
+                continue;
+            }
+            let start = segment.paddr - base;
+            let Some(end) = start
+                .checked_add(segment.mem_size)
+                .filter(|&end| end <= DRAM_SIZE)
+            else {
+                return Err(format!(
+                    "segment at 0x{:x} ({} bytes) does not fit in DRAM",
+                    segment.paddr, segment.mem_size
+                ));
+            };
+            debug!(
+                "segment paddr 0x{:x} vaddr 0x{:x} filesz 0x{:x} memsz 0x{:x} flags {:03b}",
+                segment.paddr,
+                segment.vaddr,
+                segment.data.len(),
+                segment.mem_size,
+                segment.flags
+            );
+            if memory.len() < end as usize {
+                memory.resize(end as usize, 0);
+            }
+            memory[start as usize..start as usize + segment.data.len()]
+                .copy_from_slice(&segment.data);
+        }
+        info!(
+            "{:?} image: entry 0x{:x}{}, {} segment(s), {} section(s), {} symbol(s)",
+            self.class,
+            self.entry,
+            self.symbol_at(self.entry)
+                .map(|s| format!(" <{}>", s.name))
+                .unwrap_or_default(),
+            self.segments.len(),
+            self.sections.len(),
+            self.symbols.len()
+        );
+        for section in self.sections.iter().filter(|s| s.addr != 0) {
+            debug!(
+                "section {:<16} 0x{:x} size 0x{:x}",
+                section.name, section.addr, section.size
+            );
+        }
+        Ok(memory)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A minimal executable: two loadable segments, a .symtab with two
+    // symbols and the string tables they need.
+    fn build_elf(class: ElfClass, machine: u16, second_segment_at: u64) -> Vec<u8> {
+        let layout = match class {
+            ElfClass::Elf32 => &LAYOUT32,
+            ElfClass::Elf64 => &LAYOUT64,
+        };
+        let word = |out: &mut Vec<u8>, value: u64| match class {
+            ElfClass::Elf32 => out.extend_from_slice(&(value as u32).to_le_bytes()),
+            ElfClass::Elf64 => out.extend_from_slice(&value.to_le_bytes()),
+        };
+        let ehsize = if class == ElfClass::Elf32 { 52 } else { 64 };
+        let phoff = ehsize;
+        let text_offset = phoff + 2 * layout.phdr_size;
+        let text = [0x13u8, 0, 0, 0, 0x6f, 0, 0, 0]; // nop; j .
+        let data_offset = text_offset + text.len() as u64;
+        let data = [1u8, 2, 3, 4];
+        let strtab = b"\0_start\0counter\0";
+        let shstrtab = b"\0.text\0.symtab\0.strtab\0.shstrtab\0";
+        let symtab_offset = data_offset + data.len() as u64;
+        let strtab_offset = symtab_offset + 3 * layout.sym_size;
+        let shstrtab_offset = strtab_offset + strtab.len() as u64;
+        let shoff = shstrtab_offset + shstrtab.len() as u64;
+
+        let mut out = Vec::new();
+        out.extend_from_slice(ELF_MAGIC);
+        out.push(if class == ElfClass::Elf32 {
+            ELFCLASS32
+        } else {
+            ELFCLASS64
+        });
+        out.extend_from_slice(&[ELFDATA2LSB, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        out.extend_from_slice(&ET_EXEC.to_le_bytes());
+        out.extend_from_slice(&machine.to_le_bytes());
+        out.extend_from_slice(&1u32.to_le_bytes());
+        word(&mut out, 0x8000_0000); // entry
+        word(&mut out, phoff);
+        word(&mut out, shoff);
+        out.extend_from_slice(&0u32.to_le_bytes()); // flags
+        for half in [ehsize, layout.phdr_size, 2, layout.shdr_size, 5, 4] {
+            out.extend_from_slice(&(half as u16).to_le_bytes());
+        }
+
+        for (offset, addr, file_size, mem_size, flags) in [
+            (
+                text_offset,
+                0x8000_0000,
+                text.len() as u64,
+                text.len() as u64,
+                5u32,
+            ),
+            (data_offset, second_segment_at, data.len() as u64, 16, 6),
+        ] {
+            out.extend_from_slice(&PT_LOAD.to_le_bytes());
+            if class == ElfClass::Elf64 {
+                out.extend_from_slice(&flags.to_le_bytes());
+            }
+            for value in [offset, addr, addr, file_size, mem_size] {
+                word(&mut out, value);
+            }
+            if class == ElfClass::Elf32 {
+                out.extend_from_slice(&flags.to_le_bytes());
+            }
+            word(&mut out, 4); // align
+        }
+        out.extend_from_slice(&text);
+        out.extend_from_slice(&data);
+
+        for (name, value, size, info) in [
+            (0u32, 0u64, 0u64, 0u8),
+            (1, 0x8000_0000, 8, STT_FUNC | 0x10),
+            (8, second_segment_at, 4, STT_OBJECT | 0x10),
+        ] {
+            out.extend_from_slice(&name.to_le_bytes());
+            if class == ElfClass::Elf32 {
+                word(&mut out, value);
+                word(&mut out, size);
+                out.extend_from_slice(&[info, 0]);
+                out.extend_from_slice(&1u16.to_le_bytes());
+            } else {
+                out.extend_from_slice(&[info, 0]);
+                out.extend_from_slice(&1u16.to_le_bytes());
+                word(&mut out, value);
+                word(&mut out, size);
+            }
+        }
+        out.extend_from_slice(strtab);
+        out.extend_from_slice(shstrtab);
+
+        // null, .text, .symtab, .strtab, .shstrtab
+        for (name, kind, addr, offset, size, link) in [
+            (0u32, 0u32, 0u64, 0u64, 0u64, 0u32),
+            (1, 1, 0x8000_0000, text_offset, text.len() as u64, 0),
+            (7, SHT_SYMTAB, 0, symtab_offset, 3 * layout.sym_size, 3),
+            (15, 3, 0, strtab_offset, strtab.len() as u64, 0),
+            (23, 3, 0, shstrtab_offset, shstrtab.len() as u64, 0),
+        ] {
+            out.extend_from_slice(&name.to_le_bytes());
+            out.extend_from_slice(&kind.to_le_bytes());
+            word(&mut out, 0); // flags
+            word(&mut out, addr);
+            word(&mut out, offset);
+            word(&mut out, size);
+            out.extend_from_slice(&link.to_le_bytes());
+            out.extend_from_slice(&0u32.to_le_bytes()); // info
+            word(&mut out, 1); // addralign
+            word(&mut out, 0); // entsize
+        }
+        out
+    }
+
+    #[test]
+    fn test_parse_and_load() {
+        for class in [ElfClass::Elf32, ElfClass::Elf64] {
+            let image = ElfImage::parse(&build_elf(class, EM_RISCV, 0x8000_1000)).unwrap();
+            assert_eq!(image.class, class);
+            assert_eq!(image.entry, 0x8000_0000);
+            assert_eq!(image.segments.len(), 2);
+            let names: Vec<&str> = image.sections.iter().map(|s| s.name.as_str()).collect();
+            assert_eq!(names, ["", ".text", ".symtab", ".strtab", ".shstrtab"]);
+            assert_eq!(image.symbol_at(0x8000_0004).unwrap().name, "_start");
+            assert_eq!(image.symbol_at(0x8000_1002).unwrap().name, "counter");
+            assert_eq!(image.symbol_at(0x8000_1004), None);
+
+            let memory = image.memory_image(0x8000_0000).unwrap();
+            assert_eq!(memory.len(), 0x1010);
+            assert_eq!(&memory[0..8], &[0x13, 0, 0, 0, 0x6f, 0, 0, 0]);
+            assert_eq!(&memory[0x1000..0x1008], &[1, 2, 3, 4, 0, 0, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn test_reject_malformed() {
+        let x86 = build_elf(ElfClass::Elf64, 62, 0x8000_1000);
+        assert!(ElfImage::parse(&x86).unwrap_err().contains("not a RISC-V"));
+        assert_eq!(
+            ElfImage::parse(b"#!/bin/sh\n").unwrap_err(),
+            "not an ELF file"
+        );
+        let overlapping = build_elf(ElfClass::Elf64, EM_RISCV, 0x8000_0004);
+        assert!(ElfImage::parse(&overlapping)
+            .unwrap_err()
+            .contains("overlap"));
+        let elf = build_elf(ElfClass::Elf64, EM_RISCV, 0x8000_1000);
+        assert!(ElfImage::parse(&elf[..100])
+            .unwrap_err()
+            .contains("truncated"));
+
+        // offsets and sizes near u64::MAX are errors, not overflows
+        let patch = |offset: usize, value: u64| {
+            let mut elf = elf.clone();
+            elf[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+            elf
+        };
+        for (offset, value) in [(32, u64::MAX - 8), (32, u64::MAX / 2), (40, u64::MAX - 8)] {
+            assert!(ElfImage::parse(&patch(offset, value))
+                .unwrap_err()
+                .contains("out of range"));
+        }
+        // the data segment's p_memsz
+        let huge = ElfImage::parse(&patch(64 + 56 + 40, u64::MAX)).unwrap();
+        assert!(huge
+            .memory_image(0x8000_0000)
+            .unwrap_err()
+            .contains("does not fit"));
+        // the st_size of counter, the last symbol: the symbol table is
+        // followed by .strtab, .shstrtab and five section headers
+        let st_size = elf.len() - 5 * 64 - 33 - 16 - 8;
+        let unbounded = ElfImage::parse(&patch(st_size, u64::MAX)).unwrap();
+        assert_eq!(unbounded.symbol_at(u64::MAX - 1).unwrap().addr, 0x8000_1000);
+    }
+}
@@ -1,11 +1,14 @@
 use crate::bus::*;
 use crate::cpu::*;
+use crate::disk::DiskBackend;
 use crate::dram::Dram;
+use crate::fdt::Chosen;
 use crate::instruction::*;
 use crate::interrupt::*;
 use crate::plic::ExternalInterrupt;
 use crate::plic::PlicSnapshot;
 use crate::uart::UartSnapshot;
+use crate::uart_backend::UartBackend;
 use crate::virtio::*;
 
 use bincode;
@@ -74,6 +77,10 @@ impl Emu {
             return Some(Event::Break);
         }
 
+        if self.cpu.system_reset().is_some() {
+            return Some(Event::Halted);
+        }
+
         None
     }
 
@@ -112,6 +119,9 @@ impl Emu {
                     if self.breakpoints.contains(&self.cpu.pc) {
                         return RunEvent::Event(Event::Break);
                     }
+                    if self.cpu.system_reset().is_some() {
+                        return RunEvent::Event(Event::Halted);
+                    }
                 }
                 if poll_incoming_data() {
                     RunEvent::IncomingData
@@ -153,6 +163,9 @@ impl Emu {
                     if self.breakpoints.contains(&self.cpu.pc) {
                         return RunEvent::Event(Event::Break);
                     }
+                    if self.cpu.system_reset().is_some() {
+                        return RunEvent::Event(Event::Halted);
+                    }
                 }
                 RunEvent::Event(Event::DoneStep)
             }
@@ -163,10 +176,43 @@ impl Emu {
         self.cpu.pc = entry_addr;
     }
 
+    /// A device tree describing this machine.
+    pub fn generate_device_tree(&self, chosen: &Chosen) -> Vec<u8> {
+        crate::fdt::generate(&self.cpu, &self.bus, chosen)
+    }
+
+    /// Copy `dtb` to the top of DRAM and pass its address to the program in
+    /// a1, with the hart ID in a0. Returns the address.
+    pub fn load_device_tree(&mut self, dtb: &[u8]) -> Result<u64, String> {
+        if !crate::fdt::is_valid(dtb) {
+            return Err("not a flattened device tree".to_string());
+        }
+        let addr = crate::fdt::placement(&self.bus, dtb.len())?;
+        self.bus.dram.write_bytes(addr, dtb);
+        self.cpu.regs[10] = 0;
+        self.cpu.regs[11] = addr;
+        info!("Device tree ({} bytes) placed at 0x{:x}", dtb.len(), addr);
+        Ok(addr)
+    }
+
+    /// Start the program in S-mode under the built-in SBI firmware.
+    pub fn enable_sbi(&mut self, dtb_addr: u64) {
+        crate::sbi::enter_payload(&mut self.cpu, dtb_addr);
+    }
+
+    #[allow(unused)]
     pub fn set_disk_image(&mut self, disk_image: Vec<u8>) {
         self.bus.virtio.as_mut().unwrap().set_disk_image(disk_image);
     }
 
+    pub fn set_disk_backend(&mut self, backend: Box<dyn DiskBackend>) -> std::io::Result<()> {
+        self.bus.virtio.as_mut().unwrap().set_disk_backend(backend)
+    }
+
+    pub fn set_uart_backend(&mut self, backend: Box<dyn UartBackend>) -> std::io::Result<()> {
+        self.bus.uart.set_backend(backend)
+    }
+
     pub fn to_snapshot(&self) -> EmuSnapshot {
         EmuSnapshot {
             cpu: self.cpu.to_snapshot(),
@@ -181,6 +227,8 @@ impl Emu {
                 .unwrap_or_else(|| VirtioSnapshot {
                     start_addr: 0x10001000,
                     id: 0,
+                    device_features_sel: 0,
+                    driver_features_sel: 0,
                     driver_features: 0,
                     page_size: 0,
                     queue_sel: 0,
@@ -190,6 +238,9 @@ impl Emu {
                     desc_addr: 0,
                     avail_addr: 0,
                     used_addr: 0,
+                    queue_ready: 0,
+                    last_avail_idx: 0,
+                    interrupt_status: 0,
                     status: 0,
                     disk: Vec::new(),
                 }),
@@ -334,6 +385,683 @@ mod tests {
         assert_states_eq(&state_before, &state_after);
     }
 
+    fn words_to_binary(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn test_lr_sc_reservation() {
+        let program = [
+            0x10000513, // li a0, 0x100
+            0x000017b7, // lui a5, 0x1
+            0x2347879b, // addiw a5, a5, 0x234
+            0x18f5272f, // sc.w a4, a5, (a0)    -> no reservation, fails
+            0x100525af, // lr.w a1, (a0)
+            0x00052023, // sw zero, 0(a0)       -> breaks the reservation
+            0x18f5262f, // sc.w a2, a5, (a0)    -> fails
+            0x100535af, // lr.d a1, (a0)
+            0x18f536af, // sc.d a3, a5, (a0)    -> succeeds
+            0x18f5382f, // sc.d a6, a5, (a0)    -> reservation consumed, fails
+            0x00052883, // lw a7, 0(a0)
+            0x0000006f, // j .
+        ];
+        let mut emu = make_emu(words_to_binary(&program), 0);
+        emu.run_for(100);
+
+        assert_eq!(emu.cpu.regs[14], 1, "sc.w without reservation must fail");
+        assert_eq!(emu.cpu.regs[12], 1, "sc.w after a store must fail");
+        assert_eq!(emu.cpu.regs[13], 0, "sc.d after lr.d must succeed");
+        assert_eq!(emu.cpu.regs[16], 1, "sc.d after a successful sc.d must fail");
+        assert_eq!(emu.cpu.regs[17], 0x1234);
+        assert_eq!(emu.cpu.reservation, None);
+    }
+
+    #[test]
+    fn test_amo_faults() {
+        use crate::csr::*;
+
+        let mut emu = make_emu(Vec::new(), 0x8000_0000);
+        let amoadd_w = DecodedInstr::decode(0x00b5262f); // amoadd.w a2, a1, (a0)
+
+        // nothing is mapped at 0x10 on the bus
+        emu.cpu.regs[10] = 0x10;
+        assert!(matches!(
+            emu.cpu.execute(&mut emu.bus, &amoadd_w),
+            Err(Exception::StoreAMOAccessFault)
+        ));
+
+        // an empty Sv39 root table maps nothing
+        emu.cpu.csr.store_csrs(PMPADDR0, u64::MAX);
+        emu.cpu
+            .csr
+            .store_csrs(PMPCFG0, PMP_NAPOT | PMP_X | PMP_W | PMP_R);
+        let satp = (SATP_MODE_SV39 << SATP_MODE_SHIFT) | (0x8010_0000 >> 12);
+        emu.cpu.csr.store_csrs(SATP, satp);
+        emu.cpu.mode = S_MODE;
+        emu.cpu.regs[10] = 0x1000;
+        assert!(matches!(
+            emu.cpu.execute(&mut emu.bus, &amoadd_w),
+            Err(Exception::StoreAMOPageFault(0x1000))
+        ));
+    }
+
+    #[test]
+    fn test_floating_point() {
+        let program = [
+            0x00300293, // li t0, 3
+            0xd222f053, // fcvt.d.l ft0, t0
+            0x00200313, // li t1, 2
+            0xd22370d3, // fcvt.d.l ft1, t1
+            0x1a107153, // fdiv.d ft2, ft0, ft1
+            0x5a00f1d3, // fsqrt.d ft3, ft1
+            0xe2010553, // fmv.x.d a0, ft2
+            0x4011f553, // fcvt.s.d fa0, ft3
+            0xe00505d3, // fmv.x.w a1, fa0
+            0x00102673, // frflags a2
+            0x00a575d3, // fadd.s fa1, fa0, fa0
+            0xa0a5a6d3, // feq.s a3, fa1, fa0
+            0xe2011753, // fclass.d a4, ft2
+            0xc20107d3, // fcvt.w.d a5, ft2, rne
+            0x0000006f, // j .
+        ];
+        let mut emu = make_emu(words_to_binary(&program), 0);
+        emu.run_for(100);
+
+        assert_eq!(emu.cpu.regs[10], 1.5f64.to_bits());
+        assert_eq!(emu.cpu.regs[11], 2f32.sqrt().to_bits() as u64);
+        assert_eq!(emu.cpu.regs[12], 0x1, "sqrt(2) must raise only inexact");
+        assert_eq!(
+            emu.cpu.fregs[11],
+            0xffff_ffff_0000_0000 | (2.0 * 2f32.sqrt()).to_bits() as u64,
+            "singles must be NaN-boxed"
+        );
+        assert_eq!(emu.cpu.regs[13], 0);
+        assert_eq!(emu.cpu.regs[14], 1 << 6, "1.5 is a positive normal number");
+        assert_eq!(emu.cpu.regs[15], 2, "1.5 rounds to even");
+        assert_eq!(
+            emu.cpu
+                .csr
+                .load_csrs(crate::csr::MSTATUS, 0, &emu.cpu.interrupt_list)
+                >> 63,
+            1,
+            "SD must reflect the dirty FP state"
+        );
+    }
+
+    #[test]
+    fn test_clint_timer_and_software_interrupts() {
+        let program = [
+            0x00000297, // auipc t0, 0
+            0x04028293, // addi t0, t0, 64
+            0x30529073, // csrw mtvec, t0
+            0x02004337, // lui t1, 0x2004      (mtimecmp)
+            0x03200393, // li t2, 50
+            0x00733023, // sd t2, 0(t1)
+            0x08000293, // li t0, 0x80         (MTIE)
+            0x30429073, // csrw mie, t0
+            0x30046073, // csrsi mstatus, 0x8  (MIE)
+            0x0000006f, // loop: j loop
+            0x00000013, // nop
+            0x00000013, // nop
+            0x00000013, // nop
+            0x00000013, // nop
+            0x00000013, // nop
+            0x00000013, // nop
+            0x34202e73, // handler: csrr t3, mcause
+            0x02041e63, // bnez s0, second
+            0x000e0513, // mv a0, t3
+            0x0200c337, // lui t1, 0x200c
+            0xff83031b, // addiw t1, t1, -8    (mtime)
+            0x00033603, // ld a2, 0(t1)
+            0x02004337, // lui t1, 0x2004
+            0xfff00393, // li t2, -1
+            0x00733023, // sd t2, 0(t1)        -> clears MTIP
+            0x02000337, // lui t1, 0x2000      (msip)
+            0x00100393, // li t2, 1
+            0x00732023, // sw t2, 0(t1)        -> raises MSIP
+            0x00800293, // li t0, 0x8          (MSIE)
+            0x3042a073, // csrs mie, t0
+            0x00140413, // addi s0, s0, 1
+            0x30200073, // mret
+            0x000e0593, // second: mv a1, t3
+            0x02000337, // lui t1, 0x2000
+            0x00032023, // sw zero, 0(t1)      -> clears MSIP
+            0x344026f3, // csrr a3, mip
+            0x30200073, // mret
+        ];
+        let mut emu = make_emu(words_to_binary(&program), 0);
+        emu.run_for(5000);
+
+        assert_eq!(
+            emu.cpu.regs[10],
+            INTERRUPT_BIT | 7,
+            "machine timer interrupt"
+        );
+        assert_eq!(
+            emu.cpu.regs[11],
+            INTERRUPT_BIT | 3,
+            "machine software interrupt"
+        );
+        assert!(emu.cpu.regs[12] >= 50, "mtime must have reached mtimecmp");
+        assert_eq!(emu.cpu.regs[13] & 0x88, 0, "MTIP and MSIP must be cleared");
+        assert_eq!(emu.cpu.regs[8], 1);
+    }
+
+    #[test]
+    fn test_console_script() {
+        let program = [
+            0x100004b7, // lui s1, 0x10000     (uart)
+            0x03e00293, // li t0, '>'
+            0x00548023, // sb t0, 0(s1)
+            0x02000293, // li t0, ' '
+            0x00548023, // sb t0, 0(s1)
+            0x0054c283, // poll: lbu t0, 5(s1)  (lsr)
+            0x0012f293, // andi t0, t0, 1
+            0xfe028ce3, // beqz t0, poll
+            0x0004c303, // lbu t1, 0(s1)
+            0x00648023, // sb t1, 0(s1)        echo it back
+            0x00a00393, // li t2, '\n'
+            0xfe7314e3, // bne t1, t2, poll
+            0x06f00293, // li t0, 'o'
+            0x00548023, // sb t0, 0(s1)
+            0x06b00293, // li t0, 'k'
+            0x00548023, // sb t0, 0(s1)
+            0x0000006f, // done: j done
+        ];
+        let script = crate::console_script::ConsoleScript::parse(
+            "timeout 10000\n\
+             fail \"error\"\n\
+             expect \"> \"\n\
+             send \"hi\\n\"\n\
+             expect \"hi\\nok\"\n",
+        )
+        .unwrap();
+        let mut emu = make_emu(words_to_binary(&program), 0x8000_0000);
+        assert_eq!(script.run(&mut emu), Ok(()));
+
+        let script = crate::console_script::ConsoleScript::parse("expect \"$ \" 1000").unwrap();
+        let mut emu = make_emu(words_to_binary(&program), 0x8000_0000);
+        let err = script.run(&mut emu).unwrap_err();
+        assert!(err.starts_with("line 1: timed out"), "{}", err);
+
+        let script = crate::console_script::ConsoleScript::parse("fail \">\"\nrun 1000").unwrap();
+        let mut emu = make_emu(words_to_binary(&program), 0x8000_0000);
+        let err = script.run(&mut emu).unwrap_err();
+        assert!(err.starts_with("line 2: console printed"), "{}", err);
+    }
+
+    #[test]
+    fn test_sbi_calls() {
+        let program = [
+            0x01000893, // li a7, 0x10         (base)
+            0x00300813, // li a6, 3            probe_extension
+            0x53525537, // lui a0, 0x53525
+            0x3545051b, // addiw a0, a0, 0x354 (SRST)
+            0x00000073, // ecall
+            0x00058413, // mv s0, a1
+            0x00100893, // li a7, 1            legacy console_putchar
+            0x04f00513, // li a0, 'O'
+            0x00000073, // ecall
+            0x00100893, // li a7, 1
+            0x04b00513, // li a0, 'K'
+            0x00000073, // ecall
+            0x535258b7, // lui a7, 0x53525
+            0x3548889b, // addiw a7, a7, 0x354
+            0x00000813, // li a6, 0            system_reset
+            0x00000513, // li a0, 0            shutdown
+            0x00100593, // li a1, 1            system failure
+            0x00000073, // ecall
+            0x0000006f, // done: j done
+        ];
+        let mut emu = make_emu(words_to_binary(&program), 0x8000_0000);
+        emu.enable_sbi(0x8200_0000);
+        assert_eq!(emu.cpu.mode, S_MODE);
+        assert_eq!(emu.cpu.regs[11], 0x8200_0000);
+        emu.bus.uart.start_capture();
+
+        assert!(matches!(emu.run_for(10000), RunEvent::Event(Event::Halted)));
+        assert_eq!(emu.bus.uart.take_captured(), b"OK");
+        assert_eq!(emu.cpu.regs[8], 1, "SRST should be reported as present");
+        assert_eq!(emu.cpu.regs[10], 0, "system_reset returned an error");
+        assert_eq!(emu.cpu.mode, S_MODE, "ecalls must not trap into M-mode");
+        let reset = emu.cpu.system_reset().unwrap();
+        assert_eq!(reset.reset_type, 0);
+        assert!(reset.is_failure());
+    }
+
+    #[test]
+    fn test_frame_pointer_backtrace() {
+        let program = [
+            0x00010117, // _start: auipc sp, 0x10
+            0x00000413, // li s0, 0
+            0x00000097, // call f
+            0x00c080e7, //
+            0x0000006f, // j .
+            0xff010113, // f: addi sp, sp, -16
+            0x00113423, // sd ra, 8(sp)
+            0x00813023, // sd s0, 0(sp)
+            0x01010413, // addi s0, sp, 16
+            0x00000097, // call g
+            0x00c080e7, //
+            0x0000006f, // j .
+            0xff010113, // g: addi sp, sp, -16
+            0x00113423, // sd ra, 8(sp)
+            0x00813023, // sd s0, 0(sp)
+            0x01010413, // addi s0, sp, 16
+            0x0000006f, // j .
+        ];
+        let mut emu = make_emu(words_to_binary(&program), 0x8000_0000);
+        emu.run_for(1000);
+        assert_eq!(emu.cpu.pc, 0x8000_0040);
+
+        let Emu { cpu, bus, .. } = &mut emu;
+        assert_eq!(cpu.backtrace(bus), [0x8000_002c, 0x8000_0010]);
+        let report = cpu.crash_report(bus);
+        assert!(report.contains("  #0 0x80000040\n  #1 0x8000002c\n  #2 0x80000010\n"));
+    }
+
+    // a writer whose output the test can still read after handing it over
+    struct SharedBuffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl std::io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_commit_log() {
+        let program = [
+            0x00001297, // auipc t0, 0x1
+            0x02a00513, // li a0, 42
+            0x00a2a023, // sw a0, 0(t0)
+            0x0002a583, // lw a1, 0(t0)
+            0x34051073, // csrw mscratch, a0
+            0xa001862e, // c.mv a2, a1; c.j .
+        ];
+        let mut emu = make_emu(words_to_binary(&program), 0x8000_0000);
+        let buffer = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        emu.cpu
+            .set_commit_log(crate::commit_log::CommitLog::new(Box::new(SharedBuffer(
+                buffer.clone(),
+            ))));
+        emu.run_for(8);
+
+        let log = String::from_utf8(buffer.borrow().clone()).unwrap();
+        let expected = "\
+core   0: 3 0x0000000080000000 (0x00001297) x5  0x0000000080001000
+core   0: 3 0x0000000080000004 (0x02a00513) x10 0x000000000000002a
+core   0: 3 0x0000000080000008 (0x00a2a023) mem 0x0000000080001000 0x0000002a
+core   0: 3 0x000000008000000c (0x0002a583) x11 0x000000000000002a mem 0x0000000080001000
+core   0: 3 0x0000000080000010 (0x34051073) c832_mscratch 0x000000000000002a
+core   0: 3 0x0000000080000014 (0x862e) x12 0x000000000000002a
+core   0: 3 0x0000000080000016 (0xa001)
+";
+        assert!(log.starts_with(expected), "{}", log);
+    }
+
+    #[test]
+    fn test_binary_trace() {
+        use crate::trace::{Record, TraceReader, TraceWriter};
+
+        let program = [
+            0x00000297, // auipc t0, 0
+            0x02028293, // addi t0, t0, 32
+            0x30529073, // csrw mtvec, t0
+            0x10000337, // lui t1, 0x10000
+            0x04100393, // li t2, 0x41
+            0x00730023, // sb t2, 0(t1)
+            0x00000073, // ecall
+            0x0000006f, // j .
+            0x0000006f, // handler: j .
+        ];
+        let mut emu = make_emu(words_to_binary(&program), 0x8000_0000);
+        let buffer = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let writer = TraceWriter::new(Box::new(SharedBuffer(buffer.clone()))).unwrap();
+        emu.cpu.set_trace(writer);
+        emu.run_for(10);
+
+        let bytes = buffer.borrow().clone();
+        let records: Vec<Record> = TraceReader::new(&bytes[..])
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            records[..4],
+            [
+                Record::Block {
+                    cycle: 1,
+                    start_pc: 0x8000_0000,
+                    end_pc: 0x8000_0018,
+                    mode: 3,
+                },
+                Record::CsrWrite {
+                    cycle: 3,
+                    csr: 0x305,
+                    value: 0x8000_0020,
+                },
+                Record::Mmio {
+                    cycle: 6,
+                    addr: 0x1000_0000,
+                    size: 8,
+                    write: true,
+                    value: 0x41,
+                },
+                Record::Trap {
+                    cycle: 7,
+                    cause: 11,
+                    epc: 0x8000_0018,
+                    tval: 0,
+                    from_mode: 3,
+                    to_mode: 3,
+                },
+            ]
+        );
+        assert!(matches!(
+            records[4],
+            Record::Block {
+                start_pc: 0x8000_0020,
+                ..
+            }
+        ));
+
+        // with DRAM at 0 device accesses are still told apart from memory
+        let program = [
+            0x02000337, // lui t1, 0x2000       (CLINT)
+            0x00032023, // sw zero, 0(t1)       -> msip
+            0x10002023, // sw zero, 0x100(zero) -> DRAM
+            0x0000006f, // j .
+        ];
+        let mut emu = make_emu(words_to_binary(&program), 0);
+        let buffer = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let writer = TraceWriter::new(Box::new(SharedBuffer(buffer.clone()))).unwrap();
+        emu.cpu.set_trace(writer);
+        emu.run_for(5);
+
+        let bytes = buffer.borrow().clone();
+        let mmio: Vec<Record> = TraceReader::new(&bytes[..])
+            .unwrap()
+            .filter_map(|record| record.ok())
+            .filter(|record| matches!(record, Record::Mmio { .. }))
+            .collect();
+        assert_eq!(
+            mmio,
+            [Record::Mmio {
+                cycle: 2,
+                addr: 0x200_0000,
+                size: 32,
+                write: true,
+                value: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_profiler_call_stacks() {
+        let program = [
+            0x010000ef, // jal ra, f
+            0x0000006f, // j .
+            0x00000000, //
+            0x00000000, //
+            0x00150513, // f: addi a0, a0, 1
+            0x00008067, // ret
+        ];
+        let mut emu = make_emu(words_to_binary(&program), 0x8000_0000);
+        emu.cpu
+            .set_profiler(crate::profiler::Profiler::new(Default::default(), 0, 10));
+        emu.run_for(10);
+
+        let profiler = emu.cpu.profiler.as_ref().unwrap();
+        assert_eq!(
+            profiler.folded(&emu.cpu.symbols),
+            "machine;0x80000000 1\n\
+             machine;0x80000004 3\n\
+             machine;0x80000010 2\n"
+        );
+    }
+
+    #[test]
+    fn test_pmp_denies_user_access() {
+        let program = [
+            0x00000297, // auipc t0, 0
+            0x04028293, // addi t0, t0, 64
+            0x30529073, // csrw mtvec, t0
+            0x20000337, // lui t1, 0x20000
+            0x4003031b, // addiw t1, t1, 0x400
+            0x3b031073, // csrw pmpaddr0, t1     (TOR up to 0x80001000)
+            0x00f00313, // li t1, 0xf            (TOR, RWX)
+            0x3a031073, // csrw pmpcfg0, t1
+            0x00000297, // auipc t0, 0
+            0x01028293, // addi t0, t0, 16
+            0x34129073, // csrw mepc, t0
+            0x30200073, // mret                  -> U-mode
+            0x00000597, // auipc a1, 0
+            0x0005b603, // ld a2, 0(a1)          -> allowed
+            0x00002517, // auipc a0, 2
+            0x00053503, // ld a0, 0(a0)          -> outside entry 0
+            0x342026f3, // handler: csrr a3, mcause
+            0x34102773, // csrr a4, mepc
+            0xff9ff06f, // j handler
+        ];
+        let mut emu = make_emu(words_to_binary(&program), 0x8000_0000);
+        emu.run_for(40);
+
+        assert_eq!(emu.cpu.mode, M_MODE);
+        assert_eq!(emu.cpu.regs[12], 0x0005_b603_0000_0597);
+        assert_eq!(emu.cpu.regs[13], 5, "load access fault");
+        assert_eq!(emu.cpu.regs[14], 0x8000_003c);
+    }
+
+    #[test]
+    fn test_statistics() {
+        let program = [
+            0x00000297, // auipc t0, 0
+            0x01428293, // addi t0, t0, 20
+            0x30529073, // csrw mtvec, t0
+            0x04100393, // li t2, 0x41
+            0x00000073, // ecall
+            0x0000006f, // handler: j .
+        ];
+        let mut emu = make_emu(words_to_binary(&program), 0x8000_0000);
+        emu.cpu.set_stats(crate::stats::Stats::new(false, None));
+        emu.run_for(10);
+
+        let stats = emu.cpu.stats.as_ref().unwrap();
+        assert_eq!(
+            stats.to_json(),
+            "{\"instructions\":{\"user\":0,\"supervisor\":0,\"machine\":7},\
+             \"exceptions\":{\"EnvironmentalCallFromMMode\":1},\"interrupts\":{},\
+             \"sbi_calls\":0,\"page_walks\":0,\"translation_cache_hits\":0,\
+             \"opcodes\":{\"addi\":2,\"jal\":2,\"auipc\":1,\"csrrw\":1,\"ecall\":1}}"
+        );
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        let program = [
+            0x00000297, // auipc t0, 0
+            0x01c000ef, // jal ra, f
+            0x0282a303, // lw t1, 40(t0)
+            0x0262a023, // sw t1, 32(t0)       (no fence.i)
+            0x010000ef, // jal ra, f
+            0x0000006f, // j .
+            0x00000013, // nop
+            0x00000013, // nop
+            0x00150513, // f: addi a0, a0, 1
+            0x00008067, // ret
+            0x06450513, // addi a0, a0, 100
+        ];
+        let mut emu = make_emu(words_to_binary(&program), 0x8000_0000);
+        emu.run_for(20);
+
+        assert_eq!(emu.cpu.regs[10], 101);
+    }
+
+    #[test]
+    fn test_blocks_keyed_by_address_space() {
+        use crate::csr::*;
+
+        let mut emu = make_emu(Vec::new(), 0x8000_0000);
+        // two Sv39 address spaces mapping 0x1000 to different code
+        let spaces = [(1, 0x8010_0000, 0x8020_0000), (2, 0x8011_0000, 0x8021_0000)];
+        for (n, (_, root, code)) in spaces.iter().enumerate() {
+            let (l1, l0) = (root + 0x1000, root + 0x2000);
+            emu.bus.store(*root, 64, ((l1 >> 12) << 10) | 1).unwrap();
+            emu.bus.store(l1, 64, ((l0 >> 12) << 10) | 1).unwrap();
+            // V, R, X and A
+            emu.bus
+                .store(l0 + 8, 64, ((code >> 12) << 10) | 0x4b)
+                .unwrap();
+            let addi = 0x00050513 | ((n as u64 + 1) << 20); // addi a0, a0, n + 1
+            emu.bus.store(*code, 32, addi).unwrap();
+            emu.bus.store(code + 4, 32, 0x0000006f).unwrap(); // j .
+        }
+        emu.cpu.csr.store_csrs(PMPADDR0, u64::MAX);
+        emu.cpu
+            .csr
+            .store_csrs(PMPCFG0, PMP_NAPOT | PMP_X | PMP_W | PMP_R);
+        emu.cpu.mode = S_MODE;
+
+        let run_in = |emu: &mut Emu, (asid, root, _): (u64, u64, u64)| {
+            let satp =
+                (SATP_MODE_SV39 << SATP_MODE_SHIFT) | (asid << SATP_ASID_SHIFT) | (root >> 12);
+            emu.cpu.csr.store_csrs(SATP, satp);
+            emu.cpu.pc = 0x1000;
+            let block = emu.cpu.build_basic_block(&mut emu.bus).unwrap();
+            emu.cpu.run_block(&mut emu.bus, &block);
+        };
+        run_in(&mut emu, spaces[0]);
+        run_in(&mut emu, spaces[1]);
+        assert_eq!(emu.cpu.regs[10], 1 + 2);
+        run_in(&mut emu, spaces[0]);
+        assert_eq!(emu.cpu.regs[10], 1 + 2 + 1);
+        // finding the cached block is not counted as a translation: only the
+        // first look at each page, which misses, and the fetches are
+        assert!(emu.cpu.tlb.report().contains("; 6 lookups, 4 hits"));
+
+        // a DMA-style write over the code of a cached block
+        emu.bus
+            .dram
+            .write_bytes(spaces[0].2, &0x06450513u32.to_le_bytes()); // addi a0, a0, 100
+        run_in(&mut emu, spaces[0]);
+        assert_eq!(emu.cpu.regs[10], 1 + 2 + 1 + 100);
+
+        // taking execute permission away drops the cached blocks
+        emu.cpu.mode = M_MODE;
+        let csrw_pmpcfg0 = DecodedInstr::decode(0x3a001073); // csrw pmpcfg0, zero
+        emu.cpu.execute(&mut emu.bus, &csrw_pmpcfg0).unwrap();
+        emu.cpu.mode = S_MODE;
+        emu.cpu.pc = 0x1000;
+        assert!(matches!(
+            emu.cpu.build_basic_block(&mut emu.bus),
+            Err(Exception::InstructionAccessFault)
+        ));
+    }
+
+    #[test]
+    fn test_block_spanning_pages() {
+        let mut emu = make_emu(Vec::new(), 0x8000_0000);
+        for (i, inst) in [0x00150513, 0x00150513, 0x00150513, 0x0000006f]
+            .iter()
+            .enumerate()
+        {
+            // addi a0, a0, 1 three times and j ., across a page boundary
+            emu.bus
+                .store(0x8000_0ff8 + 4 * i as u64, 32, *inst)
+                .unwrap();
+        }
+        emu.cpu.pc = 0x8000_0ff8;
+        emu.cpu.build_basic_block(&mut emu.bus).unwrap();
+        assert_eq!(emu.cpu.code_pages[&0x8000_1000].len(), 1);
+
+        // a write to the first page forgets the block in the second one too
+        emu.bus.store(0x8000_0000, 32, 0).unwrap();
+        emu.cpu.pc = 0x8000_2000;
+        emu.cpu.build_basic_block(&mut emu.bus).unwrap();
+        assert_eq!(emu.cpu.block_cache.len(), 1);
+        assert!(emu.cpu.code_pages[&0x8000_1000].is_empty());
+    }
+
+    #[test]
+    fn test_linux_boot_protocol() {
+        let image = [
+            0x0400006f, // j entry
+            0x00000000, 0x00200000, // text_offset
+            0x00000000, 0x00001000, // image_size
+            0x00000000, 0x00000000, // flags
+            0x00000000, 0x00000002, // version
+            0x00000000, 0x00000000, 0x00000000, 0x43534952, // "RISCV\0\0\0"
+            0x00000056, 0x05435352, // "RSC\x05"
+            0x00000000, 0x0005e403, // entry: lwu s0, 0(a1)  (dtb magic)
+            0x00050493, // mv s1, a0
+            0x535258b7, // lui a7, 0x53525
+            0x3548889b, // addiw a7, a7, 0x354 (SRST)
+            0x00000813, // li a6, 0
+            0x00000513, // li a0, 0
+            0x00000593, // li a1, 0
+            0x00000073, // ecall
+            0x0000006f, // j .
+        ];
+        let kernel = crate::linux::KernelImage::parse(words_to_binary(&image)).unwrap();
+        assert!(crate::linux::KernelImage::parse(vec![0; 64]).is_err());
+
+        let mut emu = make_emu(Vec::new(), 0x8000_0000);
+        let initrd = b"070701 initramfs".to_vec();
+        let config = crate::linux::BootConfig {
+            initrd: Some(initrd.clone()),
+            bootargs: Some("console=ttyS0 earlycon=sbi".to_string()),
+            dtb: None,
+        };
+        let dtb = crate::linux::boot(&mut emu, &kernel, config).unwrap();
+        assert_eq!(emu.cpu.pc, 0x8020_0000);
+        assert_eq!(emu.cpu.mode, S_MODE);
+        let dtb_addr = emu.cpu.regs[11];
+        let initrd_start = (dtb_addr - initrd.len() as u64) & !0xfff;
+        assert!(dtb
+            .windows(b"console=ttyS0 earlycon=sbi\0".len())
+            .any(|w| w == b"console=ttyS0 earlycon=sbi\0"));
+        assert!(dtb.windows(8).any(|w| w == initrd_start.to_be_bytes()));
+        let offset = (initrd_start - 0x8000_0000) as usize;
+        assert_eq!(
+            &emu.bus.dram.dram[offset..offset + initrd.len()],
+            &initrd[..]
+        );
+
+        assert!(matches!(emu.run_for(10000), RunEvent::Event(Event::Halted)));
+        assert_eq!(emu.cpu.regs[8], 0xedfe0dd0, "a1 should point at the DTB");
+        assert_eq!(emu.cpu.regs[9], 0, "a0 should hold the hart ID");
+    }
+
+    #[test]
+    #[ignore]
+    fn test_xv6_usertests() {
+        const BASE_ADDR: u64 = 0x8000_0000;
+
+        let kernel =
+            crate::elf::ElfImage::from_file(std::path::Path::new("apps/xv6-riscv/kernel/kernel"))
+                .expect("failed to load xv6 kernel ELF");
+        let code = kernel
+            .memory_image(BASE_ADDR)
+            .expect("xv6 kernel must fit in DRAM");
+        let entry = kernel.entry;
+        let disk_image =
+            std::fs::read("apps/xv6-riscv/fs.img").expect("apps/xv6-riscv/fs.img must exist");
+
+        let mut emu = Emu::new(code, BASE_ADDR, 0, u64::MAX);
+        emu.set_entry_point(entry);
+        emu.set_disk_image(disk_image);
+        emu.exec_mode = ExecMode::Continue;
+
+        let script = crate::console_script::ConsoleScript::from_file(std::path::Path::new(
+            "tool/xv6-usertests.script",
+        ))
+        .unwrap();
+        script.run(&mut emu).unwrap();
+    }
+
     #[test]
     #[ignore]
     fn test_xv6_snapshot_reproducible() {
@@ -341,11 +1069,13 @@ mod tests {
         const SNAPSHOT_AT: u64 = 1_000_000;
         const RUN_TOTAL: u64 = 3_000_000;
 
-        let mut code: Vec<u8> = Vec::new();
-        let mut kernel_file = std::fs::File::open("apps/xv6-riscv/kernel/kernel")
-            .expect("apps/xv6-riscv/kernel/kernel must exist");
-        let entry = crate::load_elf(&mut code, &mut kernel_file, BASE_ADDR as usize)
-            .expect("failed to load xv6 kernel ELF");
+        let kernel =
+            crate::elf::ElfImage::from_file(std::path::Path::new("apps/xv6-riscv/kernel/kernel"))
+                .expect("failed to load xv6 kernel ELF");
+        let code = kernel
+            .memory_image(BASE_ADDR)
+            .expect("xv6 kernel must fit in DRAM");
+        let entry = kernel.entry;
 
         let disk_image =
             std::fs::read("apps/xv6-riscv/fs.img").expect("apps/xv6-riscv/fs.img must exist");
@@ -0,0 +1,392 @@
+//! Flattened device tree describing the emulated machine.
+//!
+//! The tree is generated from the devices actually present on the `Bus`, so
+//! the guest finds DRAM, the CLINT, the PLIC, the UART and the virtio disk
+//! at the addresses and with the interrupt numbers the emulator uses.
+
+use crate::bus::Bus;
+use crate::cpu::{Cpu, CPU_FREQUENCY};
+use crate::csr::TIMER_FREQ;
+use crate::dram::DRAM_SIZE;
+use crate::plic::{ExternalInterrupt, NUM_SOURCES, PLIC_SIZE};
+use crate::uart::UART_SIZE;
+use crate::virtio::VIRTIO_SIZE;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+const ISA: &str = "rv64imafdc_zicsr_zifencei_sstc";
+const ISA_EXTENSIONS: [&str; 9] = ["i", "m", "a", "f", "d", "c", "zicsr", "zifencei", "sstc"];
+// the 16550A reference clock
+const UART_CLOCK_FREQUENCY: u32 = 3_686_400;
+
+const CPU_INTC_PHANDLE: u32 = 1;
+const PLIC_PHANDLE: u32 = 2;
+
+// local interrupt numbers used by interrupts-extended
+const IRQ_M_SOFT: u32 = 3;
+const IRQ_M_TIMER: u32 = 7;
+const IRQ_S_EXT: u32 = 9;
+const IRQ_M_EXT: u32 = 11;
+
+/// Builds the structure and strings blocks of a device tree blob.
+pub struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    depth: usize,
+}
+
+impl FdtWriter {
+    pub fn new() -> FdtWriter {
+        Self {
+            structure: Vec::new(),
+            strings: Vec::new(),
+            depth: 0,
+        }
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn align(&mut self) {
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+
+    // offset of `name` in the strings block, adding it if needed
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let mut offset = 0;
+        while offset < self.strings.len() {
+            let end = offset + self.strings[offset..].iter().position(|&b| b == 0).unwrap();
+            if &self.strings[offset..end] == name.as_bytes() {
+                return offset as u32;
+            }
+            offset = end + 1;
+        }
+        let offset = self.strings.len();
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        offset as u32
+    }
+
+    pub fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        self.push_u32(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let name_offset = self.string_offset(name);
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(name_offset);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    pub fn property_null(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_u64(&mut self, name: &str, value: u64) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        self.property_strings(name, &[value]);
+    }
+
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) {
+        let mut value = Vec::new();
+        for s in values {
+            value.extend_from_slice(s.as_bytes());
+            value.push(0);
+        }
+        self.property(name, &value);
+    }
+
+    /// A `reg` property with two address and two size cells.
+    pub fn property_reg(&mut self, base: u64, size: u64) {
+        self.property_cells(
+            "reg",
+            &[
+                (base >> 32) as u32,
+                base as u32,
+                (size >> 32) as u32,
+                size as u32,
+            ],
+        );
+    }
+
+    /// Assemble the blob: header, empty memory reservation map, structure block
+    /// and strings block.
+    pub fn finish(mut self) -> Vec<u8> {
+        assert_eq!(self.depth, 0, "unbalanced device tree nodes");
+        self.push_u32(FDT_END);
+
+        let reserve_offset = FDT_HEADER_SIZE;
+        let reserve_size = 16; // just the terminating entry
+        let struct_offset = reserve_offset + reserve_size;
+        let strings_offset = struct_offset + self.structure.len();
+        let total_size = strings_offset + self.strings.len();
+
+        let mut blob = Vec::with_capacity(total_size);
+        for field in [
+            FDT_MAGIC,
+            total_size as u32,
+            struct_offset as u32,
+            strings_offset as u32,
+            reserve_offset as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            0, // boot_cpuid_phys
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ] {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+        blob.extend_from_slice(&[0; 16]);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+/// Boot parameters for the `/chosen` node.
+#[derive(Default)]
+pub struct Chosen {
+    pub bootargs: Option<String>,
+    /// Start and end address of the initial ramdisk.
+    pub initrd: Option<(u64, u64)>,
+}
+
+/// Describe the machine made of `cpu` and `bus`.
+pub fn generate(cpu: &Cpu, bus: &Bus, chosen: &Chosen) -> Vec<u8> {
+    let mut fdt = FdtWriter::new();
+    let uart = bus.uart.start_addr();
+
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "riscv-virtio");
+    fdt.property_string("model", "rv-emu");
+
+    fdt.begin_node("chosen");
+    fdt.property_string("stdout-path", &format!("/soc/serial@{:x}", uart));
+    if let Some(bootargs) = &chosen.bootargs {
+        fdt.property_string("bootargs", bootargs);
+    }
+    if let Some((start, end)) = chosen.initrd {
+        fdt.property_u64("linux,initrd-start", start);
+        fdt.property_u64("linux,initrd-end", end);
+    }
+    fdt.end_node();
+
+    let dram_base = bus.dram.dram_base;
+    fdt.begin_node(&format!("memory@{:x}", dram_base));
+    fdt.property_string("device_type", "memory");
+    fdt.property_reg(dram_base, DRAM_SIZE);
+    fdt.end_node();
+
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", TIMER_FREQ as u32);
+    fdt.begin_node("cpu@0");
+    fdt.property_string("device_type", "cpu");
+    fdt.property_u32("reg", 0);
+    fdt.property_string("status", "okay");
+    fdt.property_string("compatible", "riscv");
+    let mut isa = ISA.to_string();
+    let mut extensions = ISA_EXTENSIONS.to_vec();
+    for (enabled, name) in [
+        (cpu.paging.svade, "svade"),
+        (!cpu.paging.svade, "svadu"),
+        (cpu.paging.svnapot, "svnapot"),
+        (cpu.paging.svpbmt, "svpbmt"),
+    ] {
+        if enabled {
+            isa = isa + "_" + name;
+            extensions.push(name);
+        }
+    }
+    fdt.property_string("riscv,isa", &isa);
+    fdt.property_string("riscv,isa-base", "rv64i");
+    fdt.property_strings("riscv,isa-extensions", &extensions);
+    fdt.property_string("mmu-type", "riscv,sv57");
+    fdt.property_u32("clock-frequency", CPU_FREQUENCY as u32);
+    fdt.begin_node("interrupt-controller");
+    fdt.property_u32("#interrupt-cells", 1);
+    fdt.property_null("interrupt-controller");
+    fdt.property_string("compatible", "riscv,cpu-intc");
+    fdt.property_u32("phandle", CPU_INTC_PHANDLE);
+    fdt.end_node();
+    fdt.end_node();
+    fdt.end_node();
+
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "simple-bus");
+    fdt.property_null("ranges");
+
+    let clint = cpu.clint.start_addr();
+    fdt.begin_node(&format!("clint@{:x}", clint));
+    fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+    fdt.property_reg(clint, cpu.clint.size());
+    fdt.property_cells(
+        "interrupts-extended",
+        &[CPU_INTC_PHANDLE, IRQ_M_SOFT, CPU_INTC_PHANDLE, IRQ_M_TIMER],
+    );
+    fdt.end_node();
+
+    // context 0 is the hart's M-mode, context 1 its S-mode
+    let plic = bus.plic.start_addr();
+    fdt.begin_node(&format!("plic@{:x}", plic));
+    fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+    fdt.property_reg(plic, PLIC_SIZE);
+    fdt.property_u32("#address-cells", 0);
+    fdt.property_u32("#interrupt-cells", 1);
+    fdt.property_null("interrupt-controller");
+    fdt.property_cells(
+        "interrupts-extended",
+        &[CPU_INTC_PHANDLE, IRQ_M_EXT, CPU_INTC_PHANDLE, IRQ_S_EXT],
+    );
+    fdt.property_u32("riscv,ndev", NUM_SOURCES as u32 - 1);
+    fdt.property_u32("phandle", PLIC_PHANDLE);
+    fdt.end_node();
+
+    fdt.begin_node(&format!("serial@{:x}", uart));
+    fdt.property_string("compatible", "ns16550a");
+    fdt.property_reg(uart, UART_SIZE);
+    fdt.property_u32("clock-frequency", UART_CLOCK_FREQUENCY);
+    fdt.property_u32("interrupt-parent", PLIC_PHANDLE);
+    fdt.property_u32("interrupts", ExternalInterrupt::UartInput.id() as u32);
+    fdt.end_node();
+
+    if let Some(virtio) = bus.virtio.as_ref() {
+        fdt.begin_node(&format!("virtio_mmio@{:x}", virtio.start_addr()));
+        fdt.property_string("compatible", "virtio,mmio");
+        fdt.property_reg(virtio.start_addr(), VIRTIO_SIZE);
+        fdt.property_u32("interrupt-parent", PLIC_PHANDLE);
+        fdt.property_u32("interrupts", ExternalInterrupt::VirtioDiskIO.id() as u32);
+        fdt.end_node();
+    }
+
+    fdt.end_node(); // soc
+    fdt.end_node(); // root
+    fdt.finish()
+}
+
+/// Where to place a blob of `len` bytes: the top of DRAM, page aligned.
+pub fn placement(bus: &Bus, len: usize) -> Result<u64, String> {
+    let len = (len as u64 + 0xfff) & !0xfff;
+    if len > DRAM_SIZE {
+        return Err(format!(
+            "the device tree does not fit in DRAM ({} bytes)",
+            len
+        ));
+    }
+    Ok(bus.dram.dram_base + DRAM_SIZE - len)
+}
+
+/// Whether `blob` starts with a device tree header.
+pub fn is_valid(blob: &[u8]) -> bool {
+    blob.len() >= FDT_HEADER_SIZE && blob[..4] == FDT_MAGIC.to_be_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    fn be32(blob: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_fdt_layout() {
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        fdt.property_u32("#size-cells", 2);
+        fdt.begin_node("cpus");
+        fdt.property_u32("#size-cells", 0);
+        fdt.end_node();
+        fdt.end_node();
+        let blob = fdt.finish();
+
+        assert_eq!(be32(&blob, 0), FDT_MAGIC);
+        assert_eq!(be32(&blob, 4) as usize, blob.len());
+        let struct_offset = be32(&blob, 8) as usize;
+        let strings_offset = be32(&blob, 12) as usize;
+        // the property name is stored once and shared
+        assert_eq!(&blob[strings_offset..], b"#size-cells\0");
+        let words: Vec<u32> = (struct_offset..strings_offset)
+            .step_by(4)
+            .map(|offset| be32(&blob, offset))
+            .collect();
+        assert_eq!(
+            words,
+            vec![
+                FDT_BEGIN_NODE,
+                0,
+                FDT_PROP,
+                4,
+                0,
+                2,
+                FDT_BEGIN_NODE,
+                u32::from_be_bytes(*b"cpus"),
+                0,
+                FDT_PROP,
+                4,
+                0,
+                0,
+                FDT_END_NODE,
+                FDT_END_NODE,
+                FDT_END,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_isa_names_the_ad_update_scheme() {
+        let bus = Bus::new(Vec::new(), 0x8000_0000);
+        let mut cpu = Cpu::new(0x8000_0000, 0);
+        let contains = |blob: &[u8], name: &[u8]| blob.windows(name.len()).any(|w| w == name);
+
+        let blob = generate(&cpu, &bus, &Chosen::default());
+        assert!(contains(&blob, b"_svadu\0") && contains(&blob, b"\0svadu\0"));
+        assert!(!contains(&blob, b"svade"));
+
+        cpu.set_paging_extensions(crate::cpu::PagingExtensions {
+            svade: true,
+            ..Default::default()
+        });
+        let blob = generate(&cpu, &bus, &Chosen::default());
+        assert!(contains(&blob, b"_svade\0") && !contains(&blob, b"svadu"));
+    }
+}
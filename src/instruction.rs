@@ -480,6 +480,253 @@ pub enum DecodedInstr {
         rs1: usize,
         rs2: usize,
     },
+    Lrw {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+    },
+    Lrd {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+    },
+    Scw {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+    },
+    Scd {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+    },
+    // F and D extensions. Arithmetic variants are shared between single and
+    // double precision; the fmt field of `raw` (bits 25-26) selects the format.
+    Flw {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        imm: u64,
+    },
+    Fld {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        imm: u64,
+    },
+    Fsw {
+        raw: u32,
+        rs1: usize,
+        rs2: usize,
+        imm: u64,
+    },
+    Fsd {
+        raw: u32,
+        rs1: usize,
+        rs2: usize,
+        imm: u64,
+    },
+    Fmadd {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+        rs3: usize,
+        rm: u64,
+    },
+    Fmsub {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+        rs3: usize,
+        rm: u64,
+    },
+    Fnmsub {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+        rs3: usize,
+        rm: u64,
+    },
+    Fnmadd {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+        rs3: usize,
+        rm: u64,
+    },
+    Fadd {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+        rm: u64,
+    },
+    Fsub {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+        rm: u64,
+    },
+    Fmul {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+        rm: u64,
+    },
+    Fdiv {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+        rm: u64,
+    },
+    Fsqrt {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rm: u64,
+    },
+    Fsgnj {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+    },
+    Fsgnjn {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+    },
+    Fsgnjx {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+    },
+    Fmin {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+    },
+    Fmax {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+    },
+    FcvtW {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rm: u64,
+    },
+    FcvtWu {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rm: u64,
+    },
+    FcvtL {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rm: u64,
+    },
+    FcvtLu {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rm: u64,
+    },
+    FcvtFromW {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rm: u64,
+    },
+    FcvtFromWu {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rm: u64,
+    },
+    FcvtFromL {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rm: u64,
+    },
+    FcvtFromLu {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rm: u64,
+    },
+    FcvtSD {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rm: u64,
+    },
+    FcvtDS {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rm: u64,
+    },
+    FmvXW {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+    },
+    FmvWX {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+    },
+    FmvXD {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+    },
+    FmvDX {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+    },
+    Feq {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+    },
+    Flt {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+    },
+    Fle {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+    },
+    Fclass {
+        raw: u32,
+        rd: usize,
+        rs1: usize,
+    },
     IllegalInstruction {
         inst: u32,
     },
@@ -487,6 +734,9 @@ pub enum DecodedInstr {
 
 impl DecodedInstr {
     pub fn decode(inst: u32) -> Self {
+        if inst & 0b11 != 0b11 {
+            return Self::decode_compressed(inst as u16);
+        }
         let opcode = inst & 0x7f;
         let rd = ((inst >> 7) & 0x1f) as usize;
         let rs1 = ((inst >> 15) & 0x1f) as usize;
@@ -1018,116 +1268,315 @@ impl DecodedInstr {
             0x0f => DecodedInstr::Fence { raw: inst },
             0x2f => {
                 // Atomic Operation instructions
+                // funct3 selects the width: 0x2 for .w, 0x3 for .d
                 let funct5 = funct7 >> 2;
-                match (funct3, funct5) {
-                    (0x2, 0x1) => DecodedInstr::Amoswap {
+                match (funct5, funct3) {
+                    (0x2, 0x2) if rs2 == 0 => DecodedInstr::Lrw { raw: inst, rd, rs1 },
+                    (0x2, 0x3) if rs2 == 0 => DecodedInstr::Lrd { raw: inst, rd, rs1 },
+                    (0x3, 0x2) => DecodedInstr::Scw {
+                        raw: inst,
+                        rd,
+                        rs1,
+                        rs2,
+                    },
+                    (0x3, 0x3) => DecodedInstr::Scd {
+                        raw: inst,
+                        rd,
+                        rs1,
+                        rs2,
+                    },
+                    (0x1, 0x2 | 0x3) => DecodedInstr::Amoswap {
+                        raw: inst,
+                        rd,
+                        rs1,
+                        rs2,
+                    },
+                    (0x0, 0x2 | 0x3) => DecodedInstr::Amoadd {
+                        raw: inst,
+                        rd,
+                        rs1,
+                        rs2,
+                    },
+                    (0x4, 0x2 | 0x3) => DecodedInstr::Amoxor {
+                        raw: inst,
+                        rd,
+                        rs1,
+                        rs2,
+                    },
+                    (0xc, 0x2 | 0x3) => DecodedInstr::Amoand {
+                        raw: inst,
+                        rd,
+                        rs1,
+                        rs2,
+                    },
+                    (0x8, 0x2 | 0x3) => DecodedInstr::Amoor {
                         raw: inst,
                         rd,
                         rs1,
                         rs2,
                     },
-                    (0x0, 0x1) => DecodedInstr::Amoadd {
+                    (0x10, 0x2 | 0x3) => DecodedInstr::Amomin {
                         raw: inst,
                         rd,
                         rs1,
                         rs2,
                     },
-                    (0x4, 0x1) => DecodedInstr::Amoxor {
+                    (0x14, 0x2 | 0x3) => DecodedInstr::Amomax {
                         raw: inst,
                         rd,
                         rs1,
                         rs2,
                     },
-                    (0xc, 0x1) => DecodedInstr::Amoand {
+                    (0x18, 0x2 | 0x3) => DecodedInstr::Amominu {
                         raw: inst,
                         rd,
                         rs1,
                         rs2,
                     },
-                    (0x8, 0x1) => DecodedInstr::Amoor {
+                    (0x1c, 0x2 | 0x3) => DecodedInstr::Amomaxu {
                         raw: inst,
                         rd,
                         rs1,
                         rs2,
                     },
-                    (0x10, 0x1) => DecodedInstr::Amomin {
+                    _ => DecodedInstr::IllegalInstruction { inst },
+                }
+            }
+            0x07 => {
+                // fp loads
+                let imm = ((inst as i32 as i64) >> 20) as u64;
+                match funct3 {
+                    0x2 => DecodedInstr::Flw {
+                        raw: inst,
+                        rd,
+                        rs1,
+                        imm,
+                    },
+                    0x3 => DecodedInstr::Fld {
                         raw: inst,
                         rd,
                         rs1,
+                        imm,
+                    },
+                    _ => DecodedInstr::IllegalInstruction { inst },
+                }
+            }
+            0x27 => {
+                // fp stores
+                let imm = (((inst & 0xfe000000) as i32 as i64 >> 20) as u64)
+                    | ((inst >> 7) & 0x1f) as u64;
+                match funct3 {
+                    0x2 => DecodedInstr::Fsw {
+                        raw: inst,
+                        rs1,
                         rs2,
+                        imm,
+                    },
+                    0x3 => DecodedInstr::Fsd {
+                        raw: inst,
+                        rs1,
+                        rs2,
+                        imm,
                     },
-                    (0x14, 0x1) => DecodedInstr::Amomax {
+                    _ => DecodedInstr::IllegalInstruction { inst },
+                }
+            }
+            0x43 | 0x47 | 0x4b | 0x4f => {
+                // fused multiply-add (R4-type), only single and double formats
+                let rs3 = (inst >> 27) as usize;
+                let rm = funct3 as u64;
+                match (opcode, funct7 & 0x3) {
+                    (_, 0x2 | 0x3) => DecodedInstr::IllegalInstruction { inst },
+                    (0x43, _) => DecodedInstr::Fmadd {
+                        raw: inst,
+                        rd,
+                        rs1,
+                        rs2,
+                        rs3,
+                        rm,
+                    },
+                    (0x47, _) => DecodedInstr::Fmsub {
+                        raw: inst,
+                        rd,
+                        rs1,
+                        rs2,
+                        rs3,
+                        rm,
+                    },
+                    (0x4b, _) => DecodedInstr::Fnmsub {
+                        raw: inst,
+                        rd,
+                        rs1,
+                        rs2,
+                        rs3,
+                        rm,
+                    },
+                    _ => DecodedInstr::Fnmadd {
+                        raw: inst,
+                        rd,
+                        rs1,
+                        rs2,
+                        rs3,
+                        rm,
+                    },
+                }
+            }
+            0x53 => {
+                // fp computational instructions: funct7 = funct5 | fmt
+                let funct5 = funct7 >> 2;
+                let fmt = funct7 & 0x3;
+                let rm = funct3 as u64;
+                if fmt > 0x1 {
+                    return DecodedInstr::IllegalInstruction { inst };
+                }
+                match (funct5, funct3, rs2) {
+                    (0x00, _, _) => DecodedInstr::Fadd {
                         raw: inst,
                         rd,
                         rs1,
                         rs2,
+                        rm,
                     },
-                    (0x18, 0x1) => DecodedInstr::Amominu {
+                    (0x01, _, _) => DecodedInstr::Fsub {
                         raw: inst,
                         rd,
                         rs1,
                         rs2,
+                        rm,
                     },
-                    (0x1c, 0x1) => DecodedInstr::Amomaxu {
+                    (0x02, _, _) => DecodedInstr::Fmul {
                         raw: inst,
                         rd,
                         rs1,
                         rs2,
+                        rm,
                     },
-                    (0x2, 0x3) => DecodedInstr::Amoswap {
+                    (0x03, _, _) => DecodedInstr::Fdiv {
                         raw: inst,
                         rd,
                         rs1,
                         rs2,
+                        rm,
                     },
-                    (0x0, 0x3) => DecodedInstr::Amoadd {
+                    (0x0b, _, 0x0) => DecodedInstr::Fsqrt {
+                        raw: inst,
+                        rd,
+                        rs1,
+                        rm,
+                    },
+                    (0x04, 0x0, _) => DecodedInstr::Fsgnj {
                         raw: inst,
                         rd,
                         rs1,
                         rs2,
                     },
-                    (0x4, 0x3) => DecodedInstr::Amoxor {
+                    (0x04, 0x1, _) => DecodedInstr::Fsgnjn {
                         raw: inst,
                         rd,
                         rs1,
                         rs2,
                     },
-                    (0xc, 0x3) => DecodedInstr::Amoand {
+                    (0x04, 0x2, _) => DecodedInstr::Fsgnjx {
                         raw: inst,
                         rd,
                         rs1,
                         rs2,
                     },
-                    (0x8, 0x3) => DecodedInstr::Amoor {
+                    (0x05, 0x0, _) => DecodedInstr::Fmin {
                         raw: inst,
                         rd,
                         rs1,
                         rs2,
                     },
-                    (0x10, 0x3) => DecodedInstr::Amomin {
+                    (0x05, 0x1, _) => DecodedInstr::Fmax {
                         raw: inst,
                         rd,
                         rs1,
                         rs2,
                     },
-                    (0x14, 0x3) => DecodedInstr::Amomax {
+                    (0x08, _, 0x1) if fmt == 0x0 => DecodedInstr::FcvtSD {
+                        raw: inst,
+                        rd,
+                        rs1,
+                        rm,
+                    },
+                    (0x08, _, 0x0) if fmt == 0x1 => DecodedInstr::FcvtDS {
+                        raw: inst,
+                        rd,
+                        rs1,
+                        rm,
+                    },
+                    (0x14, 0x2, _) => DecodedInstr::Feq {
                         raw: inst,
                         rd,
                         rs1,
                         rs2,
                     },
-                    (0x18, 0x3) => DecodedInstr::Amominu {
+                    (0x14, 0x1, _) => DecodedInstr::Flt {
                         raw: inst,
                         rd,
                         rs1,
                         rs2,
                     },
-                    (0x1c, 0x3) => DecodedInstr::Amomaxu {
+                    (0x14, 0x0, _) => DecodedInstr::Fle {
                         raw: inst,
                         rd,
                         rs1,
                         rs2,
                     },
+                    (0x18, _, 0x0) => DecodedInstr::FcvtW {
+                        raw: inst,
+                        rd,
+                        rs1,
+                        rm,
+                    },
+                    (0x18, _, 0x1) => DecodedInstr::FcvtWu {
+                        raw: inst,
+                        rd,
+                        rs1,
+                        rm,
+                    },
+                    (0x18, _, 0x2) => DecodedInstr::FcvtL {
+                        raw: inst,
+                        rd,
+                        rs1,
+                        rm,
+                    },
+                    (0x18, _, 0x3) => DecodedInstr::FcvtLu {
+                        raw: inst,
+                        rd,
+                        rs1,
+                        rm,
+                    },
+                    (0x1a, _, 0x0) => DecodedInstr::FcvtFromW {
+                        raw: inst,
+                        rd,
+                        rs1,
+                        rm,
+                    },
+                    (0x1a, _, 0x1) => DecodedInstr::FcvtFromWu {
+                        raw: inst,
+                        rd,
+                        rs1,
+                        rm,
+                    },
+                    (0x1a, _, 0x2) => DecodedInstr::FcvtFromL {
+                        raw: inst,
+                        rd,
+                        rs1,
+                        rm,
+                    },
+                    (0x1a, _, 0x3) => DecodedInstr::FcvtFromLu {
+                        raw: inst,
+                        rd,
+                        rs1,
+                        rm,
+                    },
+                    (0x1c, 0x0, 0x0) if fmt == 0x0 => DecodedInstr::FmvXW { raw: inst, rd, rs1 },
+                    (0x1c, 0x0, 0x0) => DecodedInstr::FmvXD { raw: inst, rd, rs1 },
+                    (0x1c, 0x1, 0x0) => DecodedInstr::Fclass { raw: inst, rd, rs1 },
+                    (0x1e, 0x0, 0x0) if fmt == 0x0 => DecodedInstr::FmvWX { raw: inst, rd, rs1 },
+                    (0x1e, 0x0, 0x0) => DecodedInstr::FmvDX { raw: inst, rd, rs1 },
                     _ => DecodedInstr::IllegalInstruction { inst },
                 }
             }
@@ -1140,6 +1589,641 @@ impl DecodedInstr {
         }
     }
 
+    /// Expand a 16-bit RVC instruction into the equivalent 32-bit instruction.
+    /// The `raw` field keeps the original 16-bit encoding so that the
+    /// instruction length can be recovered with `inst_len`.
+    pub fn decode_compressed(inst: u16) -> Self {
+        let raw = inst as u32;
+        let op = inst & 0b11;
+        let funct3 = (inst >> 13) & 0x7;
+        // full register fields (CR/CI/CSS formats)
+        let rd = ((inst >> 7) & 0x1f) as usize;
+        let rs2 = ((inst >> 2) & 0x1f) as usize;
+        // compressed register fields x8-x15 (CIW/CL/CS/CA/CB formats)
+        let rd_p = (((inst >> 2) & 0x7) + 8) as usize;
+        let rs1_p = (((inst >> 7) & 0x7) + 8) as usize;
+        // sign-extended 6-bit immediate used by CI format
+        let ci_imm = (((((inst >> 12) & 0x1) << 5) | ((inst >> 2) & 0x1f)) as u64) << 58;
+        let ci_imm = ((ci_imm as i64) >> 58) as u64;
+        let ci_shamt = ((((inst >> 12) & 0x1) << 5) | ((inst >> 2) & 0x1f)) as u64;
+
+        match (op, funct3) {
+            (0b00, 0b000) => {
+                // c.addi4spn
+                let nzuimm = ((inst >> 7) & 0x30)
+                    | ((inst >> 1) & 0x3c0)
+                    | ((inst >> 4) & 0x4)
+                    | ((inst >> 2) & 0x8);
+                if nzuimm == 0 {
+                    return DecodedInstr::IllegalInstruction { inst: raw };
+                }
+                DecodedInstr::Addi {
+                    raw,
+                    rd: rd_p,
+                    rs1: 2,
+                    imm: nzuimm as u64,
+                }
+            }
+            (0b00, 0b001) => {
+                // c.fld
+                let imm = ((inst >> 7) & 0x38) | ((inst << 1) & 0xc0);
+                DecodedInstr::Fld {
+                    raw,
+                    rd: rd_p,
+                    rs1: rs1_p,
+                    imm: imm as u64,
+                }
+            }
+            (0b00, 0b010) => {
+                // c.lw
+                let imm = ((inst >> 7) & 0x38) | ((inst >> 4) & 0x4) | ((inst << 1) & 0x40);
+                DecodedInstr::Lw {
+                    raw,
+                    rd: rd_p,
+                    rs1: rs1_p,
+                    imm: imm as u64,
+                }
+            }
+            (0b00, 0b011) => {
+                // c.ld
+                let imm = ((inst >> 7) & 0x38) | ((inst << 1) & 0xc0);
+                DecodedInstr::Ld {
+                    raw,
+                    rd: rd_p,
+                    rs1: rs1_p,
+                    imm: imm as u64,
+                }
+            }
+            (0b00, 0b101) => {
+                // c.fsd
+                let imm = ((inst >> 7) & 0x38) | ((inst << 1) & 0xc0);
+                DecodedInstr::Fsd {
+                    raw,
+                    rs1: rs1_p,
+                    rs2: rd_p,
+                    imm: imm as u64,
+                }
+            }
+            (0b00, 0b110) => {
+                // c.sw
+                let imm = ((inst >> 7) & 0x38) | ((inst >> 4) & 0x4) | ((inst << 1) & 0x40);
+                DecodedInstr::Sw {
+                    raw,
+                    rs1: rs1_p,
+                    rs2: rd_p,
+                    imm: imm as u64,
+                }
+            }
+            (0b00, 0b111) => {
+                // c.sd
+                let imm = ((inst >> 7) & 0x38) | ((inst << 1) & 0xc0);
+                DecodedInstr::Sd {
+                    raw,
+                    rs1: rs1_p,
+                    rs2: rd_p,
+                    imm: imm as u64,
+                }
+            }
+            (0b01, 0b000) => {
+                // c.addi, c.nop
+                DecodedInstr::Addi {
+                    raw,
+                    rd,
+                    rs1: rd,
+                    imm: ci_imm,
+                }
+            }
+            (0b01, 0b001) => {
+                // c.addiw
+                if rd == 0 {
+                    return DecodedInstr::IllegalInstruction { inst: raw };
+                }
+                DecodedInstr::Addiw {
+                    raw,
+                    rd,
+                    rs1: rd,
+                    imm: ci_imm as i32,
+                }
+            }
+            (0b01, 0b010) => {
+                // c.li
+                DecodedInstr::Addi {
+                    raw,
+                    rd,
+                    rs1: 0,
+                    imm: ci_imm,
+                }
+            }
+            (0b01, 0b011) => {
+                if rd == 2 {
+                    // c.addi16sp
+                    let nzimm = ((inst >> 3) & 0x200)
+                        | ((inst >> 2) & 0x10)
+                        | ((inst << 1) & 0x40)
+                        | ((inst << 4) & 0x180)
+                        | ((inst << 3) & 0x20);
+                    if nzimm == 0 {
+                        return DecodedInstr::IllegalInstruction { inst: raw };
+                    }
+                    let imm = (((nzimm as u64) << 54) as i64 >> 54) as u64;
+                    DecodedInstr::Addi {
+                        raw,
+                        rd: 2,
+                        rs1: 2,
+                        imm,
+                    }
+                } else {
+                    // c.lui
+                    if ci_imm == 0 {
+                        return DecodedInstr::IllegalInstruction { inst: raw };
+                    }
+                    DecodedInstr::Lui {
+                        raw,
+                        rd,
+                        imm: ci_imm << 12,
+                    }
+                }
+            }
+            (0b01, 0b100) => match ((inst >> 10) & 0x3, (inst >> 12) & 0x1, (inst >> 5) & 0x3) {
+                (0b00, _, _) => DecodedInstr::Srli {
+                    // c.srli
+                    raw,
+                    rd: rs1_p,
+                    rs1: rs1_p,
+                    imm: ci_shamt,
+                },
+                (0b01, _, _) => DecodedInstr::Srli {
+                    // c.srai, encoded like the funct7=0x20 I-type immediate
+                    raw,
+                    rd: rs1_p,
+                    rs1: rs1_p,
+                    imm: 0x400 | ci_shamt,
+                },
+                (0b10, _, _) => DecodedInstr::Andi {
+                    // c.andi
+                    raw,
+                    rd: rs1_p,
+                    rs1: rs1_p,
+                    imm: ci_imm,
+                },
+                (0b11, 0b0, 0b00) => DecodedInstr::Sub {
+                    raw,
+                    rd: rs1_p,
+                    rs1: rs1_p,
+                    rs2: rd_p,
+                },
+                (0b11, 0b0, 0b01) => DecodedInstr::Xor {
+                    raw,
+                    rd: rs1_p,
+                    rs1: rs1_p,
+                    rs2: rd_p,
+                },
+                (0b11, 0b0, 0b10) => DecodedInstr::Or {
+                    raw,
+                    rd: rs1_p,
+                    rs1: rs1_p,
+                    rs2: rd_p,
+                },
+                (0b11, 0b0, 0b11) => DecodedInstr::And {
+                    raw,
+                    rd: rs1_p,
+                    rs1: rs1_p,
+                    rs2: rd_p,
+                },
+                (0b11, 0b1, 0b00) => DecodedInstr::Subw {
+                    raw,
+                    rd: rs1_p,
+                    rs1: rs1_p,
+                    rs2: rd_p,
+                },
+                (0b11, 0b1, 0b01) => DecodedInstr::Addw {
+                    raw,
+                    rd: rs1_p,
+                    rs1: rs1_p,
+                    rs2: rd_p,
+                },
+                _ => DecodedInstr::IllegalInstruction { inst: raw },
+            },
+            (0b01, 0b101) => {
+                // c.j
+                let offset = ((inst >> 1) & 0x800)
+                    | ((inst >> 7) & 0x10)
+                    | ((inst >> 1) & 0x300)
+                    | ((inst << 2) & 0x400)
+                    | ((inst >> 1) & 0x40)
+                    | ((inst << 1) & 0x80)
+                    | ((inst >> 2) & 0xe)
+                    | ((inst << 3) & 0x20);
+                let imm = (((offset as u64) << 52) as i64 >> 52) as u64;
+                DecodedInstr::Jal { raw, rd: 0, imm }
+            }
+            (0b01, 0b110) | (0b01, 0b111) => {
+                // c.beqz, c.bnez
+                let offset = ((inst >> 4) & 0x100)
+                    | ((inst >> 7) & 0x18)
+                    | ((inst << 1) & 0xc0)
+                    | ((inst >> 2) & 0x6)
+                    | ((inst << 3) & 0x20);
+                let imm = (((offset as u64) << 55) as i64 >> 55) as u64;
+                if funct3 == 0b110 {
+                    DecodedInstr::Beq {
+                        raw,
+                        rs1: rs1_p,
+                        rs2: 0,
+                        imm,
+                    }
+                } else {
+                    DecodedInstr::Bne {
+                        raw,
+                        rs1: rs1_p,
+                        rs2: 0,
+                        imm,
+                    }
+                }
+            }
+            (0b10, 0b000) => {
+                // c.slli
+                DecodedInstr::Slli {
+                    raw,
+                    rd,
+                    rs1: rd,
+                    imm: ci_shamt,
+                }
+            }
+            (0b10, 0b001) => {
+                // c.fldsp
+                let imm = ((inst >> 7) & 0x20) | ((inst >> 2) & 0x18) | ((inst << 4) & 0x1c0);
+                DecodedInstr::Fld {
+                    raw,
+                    rd,
+                    rs1: 2,
+                    imm: imm as u64,
+                }
+            }
+            (0b10, 0b010) => {
+                // c.lwsp
+                if rd == 0 {
+                    return DecodedInstr::IllegalInstruction { inst: raw };
+                }
+                let imm = ((inst >> 7) & 0x20) | ((inst >> 2) & 0x1c) | ((inst << 4) & 0xc0);
+                DecodedInstr::Lw {
+                    raw,
+                    rd,
+                    rs1: 2,
+                    imm: imm as u64,
+                }
+            }
+            (0b10, 0b011) => {
+                // c.ldsp
+                if rd == 0 {
+                    return DecodedInstr::IllegalInstruction { inst: raw };
+                }
+                let imm = ((inst >> 7) & 0x20) | ((inst >> 2) & 0x18) | ((inst << 4) & 0x1c0);
+                DecodedInstr::Ld {
+                    raw,
+                    rd,
+                    rs1: 2,
+                    imm: imm as u64,
+                }
+            }
+            (0b10, 0b100) => match ((inst >> 12) & 0x1, rd, rs2) {
+                (0b0, 0, 0) => DecodedInstr::IllegalInstruction { inst: raw },
+                (0b0, _, 0) => DecodedInstr::Jalr {
+                    // c.jr
+                    raw,
+                    rd: 0,
+                    rs1: rd,
+                    imm: 0,
+                },
+                (0b0, _, _) => DecodedInstr::Add {
+                    // c.mv
+                    raw,
+                    rd,
+                    rs1: 0,
+                    rs2,
+                },
+                (0b1, 0, 0) => DecodedInstr::Ebreak { raw },
+                (0b1, _, 0) => DecodedInstr::Jalr {
+                    // c.jalr
+                    raw,
+                    rd: 1,
+                    rs1: rd,
+                    imm: 0,
+                },
+                (_, _, _) => DecodedInstr::Add {
+                    // c.add
+                    raw,
+                    rd,
+                    rs1: rd,
+                    rs2,
+                },
+            },
+            (0b10, 0b101) => {
+                // c.fsdsp
+                let imm = ((inst >> 7) & 0x38) | ((inst >> 1) & 0x1c0);
+                DecodedInstr::Fsd {
+                    raw,
+                    rs1: 2,
+                    rs2,
+                    imm: imm as u64,
+                }
+            }
+            (0b10, 0b110) => {
+                // c.swsp
+                let imm = ((inst >> 7) & 0x3c) | ((inst >> 1) & 0xc0);
+                DecodedInstr::Sw {
+                    raw,
+                    rs1: 2,
+                    rs2,
+                    imm: imm as u64,
+                }
+            }
+            (0b10, 0b111) => {
+                // c.sdsp
+                let imm = ((inst >> 7) & 0x38) | ((inst >> 1) & 0x1c0);
+                DecodedInstr::Sd {
+                    raw,
+                    rs1: 2,
+                    rs2,
+                    imm: imm as u64,
+                }
+            }
+            _ => {
+                error!("Unsupported compressed instruction!");
+                error!("inst:{inst:016b}");
+                DecodedInstr::IllegalInstruction { inst: raw }
+            }
+        }
+    }
+
+    /// The raw encoding the instruction was decoded from.
+    pub fn raw(&self) -> u32 {
+        match self {
+            DecodedInstr::Add { raw, .. }
+            | DecodedInstr::Sub { raw, .. }
+            | DecodedInstr::Sll { raw, .. }
+            | DecodedInstr::Slt { raw, .. }
+            | DecodedInstr::Sltu { raw, .. }
+            | DecodedInstr::Xor { raw, .. }
+            | DecodedInstr::Srl { raw, .. }
+            | DecodedInstr::Sra { raw, .. }
+            | DecodedInstr::Or { raw, .. }
+            | DecodedInstr::And { raw, .. }
+            | DecodedInstr::Mul { raw, .. }
+            | DecodedInstr::Mulh { raw, .. }
+            | DecodedInstr::Mulhsu { raw, .. }
+            | DecodedInstr::Mulhu { raw, .. }
+            | DecodedInstr::Div { raw, .. }
+            | DecodedInstr::Divu { raw, .. }
+            | DecodedInstr::Rem { raw, .. }
+            | DecodedInstr::Remu { raw, .. }
+            | DecodedInstr::Addi { raw, .. }
+            | DecodedInstr::Slti { raw, .. }
+            | DecodedInstr::Sltiu { raw, .. }
+            | DecodedInstr::Xori { raw, .. }
+            | DecodedInstr::Ori { raw, .. }
+            | DecodedInstr::Andi { raw, .. }
+            | DecodedInstr::Slli { raw, .. }
+            | DecodedInstr::Srli { raw, .. }
+            | DecodedInstr::Lb { raw, .. }
+            | DecodedInstr::Lh { raw, .. }
+            | DecodedInstr::Lw { raw, .. }
+            | DecodedInstr::Ld { raw, .. }
+            | DecodedInstr::Lbu { raw, .. }
+            | DecodedInstr::Lhu { raw, .. }
+            | DecodedInstr::Lwu { raw, .. }
+            | DecodedInstr::Sb { raw, .. }
+            | DecodedInstr::Sh { raw, .. }
+            | DecodedInstr::Sw { raw, .. }
+            | DecodedInstr::Sd { raw, .. }
+            | DecodedInstr::Jal { raw, .. }
+            | DecodedInstr::Jalr { raw, .. }
+            | DecodedInstr::Addiw { raw, .. }
+            | DecodedInstr::Slliw { raw, .. }
+            | DecodedInstr::Srliw { raw, .. }
+            | DecodedInstr::Sraiw { raw, .. }
+            | DecodedInstr::Beq { raw, .. }
+            | DecodedInstr::Bne { raw, .. }
+            | DecodedInstr::Blt { raw, .. }
+            | DecodedInstr::Bge { raw, .. }
+            | DecodedInstr::Bltu { raw, .. }
+            | DecodedInstr::Bgeu { raw, .. }
+            | DecodedInstr::Addw { raw, .. }
+            | DecodedInstr::Subw { raw, .. }
+            | DecodedInstr::Sllw { raw, .. }
+            | DecodedInstr::Srlw { raw, .. }
+            | DecodedInstr::Sraw { raw, .. }
+            | DecodedInstr::Mulw { raw, .. }
+            | DecodedInstr::Divw { raw, .. }
+            | DecodedInstr::Divuw { raw, .. }
+            | DecodedInstr::Remw { raw, .. }
+            | DecodedInstr::Remuw { raw, .. }
+            | DecodedInstr::Lui { raw, .. }
+            | DecodedInstr::Auipc { raw, .. }
+            | DecodedInstr::Ecall { raw, .. }
+            | DecodedInstr::Ebreak { raw, .. }
+            | DecodedInstr::Sret { raw, .. }
+            | DecodedInstr::Wfi { raw, .. }
+            | DecodedInstr::Mret { raw, .. }
+            | DecodedInstr::Csrrw { raw, .. }
+            | DecodedInstr::Csrrs { raw, .. }
+            | DecodedInstr::Csrrc { raw, .. }
+            | DecodedInstr::Csrrwi { raw, .. }
+            | DecodedInstr::Csrrsi { raw, .. }
+            | DecodedInstr::Csrrci { raw, .. }
+            | DecodedInstr::Sfence { raw, .. }
+            | DecodedInstr::Fence { raw, .. }
+            | DecodedInstr::Amoswap { raw, .. }
+            | DecodedInstr::Amoadd { raw, .. }
+            | DecodedInstr::Amoxor { raw, .. }
+            | DecodedInstr::Amoand { raw, .. }
+            | DecodedInstr::Amoor { raw, .. }
+            | DecodedInstr::Amomin { raw, .. }
+            | DecodedInstr::Amomax { raw, .. }
+            | DecodedInstr::Amominu { raw, .. }
+            | DecodedInstr::Amomaxu { raw, .. }
+            | DecodedInstr::Lrw { raw, .. }
+            | DecodedInstr::Lrd { raw, .. }
+            | DecodedInstr::Scw { raw, .. }
+            | DecodedInstr::Scd { raw, .. }
+            | DecodedInstr::Flw { raw, .. }
+            | DecodedInstr::Fld { raw, .. }
+            | DecodedInstr::Fsw { raw, .. }
+            | DecodedInstr::Fsd { raw, .. }
+            | DecodedInstr::Fmadd { raw, .. }
+            | DecodedInstr::Fmsub { raw, .. }
+            | DecodedInstr::Fnmsub { raw, .. }
+            | DecodedInstr::Fnmadd { raw, .. }
+            | DecodedInstr::Fadd { raw, .. }
+            | DecodedInstr::Fsub { raw, .. }
+            | DecodedInstr::Fmul { raw, .. }
+            | DecodedInstr::Fdiv { raw, .. }
+            | DecodedInstr::Fsqrt { raw, .. }
+            | DecodedInstr::Fsgnj { raw, .. }
+            | DecodedInstr::Fsgnjn { raw, .. }
+            | DecodedInstr::Fsgnjx { raw, .. }
+            | DecodedInstr::Fmin { raw, .. }
+            | DecodedInstr::Fmax { raw, .. }
+            | DecodedInstr::FcvtW { raw, .. }
+            | DecodedInstr::FcvtWu { raw, .. }
+            | DecodedInstr::FcvtL { raw, .. }
+            | DecodedInstr::FcvtLu { raw, .. }
+            | DecodedInstr::FcvtFromW { raw, .. }
+            | DecodedInstr::FcvtFromWu { raw, .. }
+            | DecodedInstr::FcvtFromL { raw, .. }
+            | DecodedInstr::FcvtFromLu { raw, .. }
+            | DecodedInstr::FcvtSD { raw, .. }
+            | DecodedInstr::FcvtDS { raw, .. }
+            | DecodedInstr::FmvXW { raw, .. }
+            | DecodedInstr::FmvWX { raw, .. }
+            | DecodedInstr::FmvXD { raw, .. }
+            | DecodedInstr::FmvDX { raw, .. }
+            | DecodedInstr::Feq { raw, .. }
+            | DecodedInstr::Flt { raw, .. }
+            | DecodedInstr::Fle { raw, .. }
+            | DecodedInstr::Fclass { raw, .. } => *raw,
+            DecodedInstr::IllegalInstruction { inst } => *inst,
+        }
+    }
+
+    /// The register the instruction writes its result to, if any. Writes to
+    /// x0 are reported too; they are simply discarded.
+    pub fn destination(&self) -> Option<RegDest> {
+        match *self {
+            DecodedInstr::Add { rd, .. }
+            | DecodedInstr::Sub { rd, .. }
+            | DecodedInstr::Sll { rd, .. }
+            | DecodedInstr::Slt { rd, .. }
+            | DecodedInstr::Sltu { rd, .. }
+            | DecodedInstr::Xor { rd, .. }
+            | DecodedInstr::Srl { rd, .. }
+            | DecodedInstr::Sra { rd, .. }
+            | DecodedInstr::Or { rd, .. }
+            | DecodedInstr::And { rd, .. }
+            | DecodedInstr::Mul { rd, .. }
+            | DecodedInstr::Mulh { rd, .. }
+            | DecodedInstr::Mulhsu { rd, .. }
+            | DecodedInstr::Mulhu { rd, .. }
+            | DecodedInstr::Div { rd, .. }
+            | DecodedInstr::Divu { rd, .. }
+            | DecodedInstr::Rem { rd, .. }
+            | DecodedInstr::Remu { rd, .. }
+            | DecodedInstr::Addi { rd, .. }
+            | DecodedInstr::Slti { rd, .. }
+            | DecodedInstr::Sltiu { rd, .. }
+            | DecodedInstr::Xori { rd, .. }
+            | DecodedInstr::Ori { rd, .. }
+            | DecodedInstr::Andi { rd, .. }
+            | DecodedInstr::Slli { rd, .. }
+            | DecodedInstr::Srli { rd, .. }
+            | DecodedInstr::Lb { rd, .. }
+            | DecodedInstr::Lh { rd, .. }
+            | DecodedInstr::Lw { rd, .. }
+            | DecodedInstr::Ld { rd, .. }
+            | DecodedInstr::Lbu { rd, .. }
+            | DecodedInstr::Lhu { rd, .. }
+            | DecodedInstr::Lwu { rd, .. }
+            | DecodedInstr::Jal { rd, .. }
+            | DecodedInstr::Jalr { rd, .. }
+            | DecodedInstr::Addiw { rd, .. }
+            | DecodedInstr::Slliw { rd, .. }
+            | DecodedInstr::Srliw { rd, .. }
+            | DecodedInstr::Sraiw { rd, .. }
+            | DecodedInstr::Addw { rd, .. }
+            | DecodedInstr::Subw { rd, .. }
+            | DecodedInstr::Sllw { rd, .. }
+            | DecodedInstr::Srlw { rd, .. }
+            | DecodedInstr::Sraw { rd, .. }
+            | DecodedInstr::Mulw { rd, .. }
+            | DecodedInstr::Divw { rd, .. }
+            | DecodedInstr::Divuw { rd, .. }
+            | DecodedInstr::Remw { rd, .. }
+            | DecodedInstr::Remuw { rd, .. }
+            | DecodedInstr::Lui { rd, .. }
+            | DecodedInstr::Auipc { rd, .. }
+            | DecodedInstr::Csrrw { rd, .. }
+            | DecodedInstr::Csrrs { rd, .. }
+            | DecodedInstr::Csrrc { rd, .. }
+            | DecodedInstr::Csrrwi { rd, .. }
+            | DecodedInstr::Csrrsi { rd, .. }
+            | DecodedInstr::Csrrci { rd, .. }
+            | DecodedInstr::Amoswap { rd, .. }
+            | DecodedInstr::Amoadd { rd, .. }
+            | DecodedInstr::Amoxor { rd, .. }
+            | DecodedInstr::Amoand { rd, .. }
+            | DecodedInstr::Amoor { rd, .. }
+            | DecodedInstr::Amomin { rd, .. }
+            | DecodedInstr::Amomax { rd, .. }
+            | DecodedInstr::Amominu { rd, .. }
+            | DecodedInstr::Amomaxu { rd, .. }
+            | DecodedInstr::Lrw { rd, .. }
+            | DecodedInstr::Lrd { rd, .. }
+            | DecodedInstr::Scw { rd, .. }
+            | DecodedInstr::Scd { rd, .. }
+            | DecodedInstr::FcvtW { rd, .. }
+            | DecodedInstr::FcvtWu { rd, .. }
+            | DecodedInstr::FcvtL { rd, .. }
+            | DecodedInstr::FcvtLu { rd, .. }
+            | DecodedInstr::FmvXW { rd, .. }
+            | DecodedInstr::FmvXD { rd, .. }
+            | DecodedInstr::Feq { rd, .. }
+            | DecodedInstr::Flt { rd, .. }
+            | DecodedInstr::Fle { rd, .. }
+            | DecodedInstr::Fclass { rd, .. } => Some(RegDest::X(rd)),
+            DecodedInstr::Flw { rd, .. }
+            | DecodedInstr::Fld { rd, .. }
+            | DecodedInstr::Fmadd { rd, .. }
+            | DecodedInstr::Fmsub { rd, .. }
+            | DecodedInstr::Fnmsub { rd, .. }
+            | DecodedInstr::Fnmadd { rd, .. }
+            | DecodedInstr::Fadd { rd, .. }
+            | DecodedInstr::Fsub { rd, .. }
+            | DecodedInstr::Fmul { rd, .. }
+            | DecodedInstr::Fdiv { rd, .. }
+            | DecodedInstr::Fsqrt { rd, .. }
+            | DecodedInstr::Fsgnj { rd, .. }
+            | DecodedInstr::Fsgnjn { rd, .. }
+            | DecodedInstr::Fsgnjx { rd, .. }
+            | DecodedInstr::Fmin { rd, .. }
+            | DecodedInstr::Fmax { rd, .. }
+            | DecodedInstr::FcvtFromW { rd, .. }
+            | DecodedInstr::FcvtFromWu { rd, .. }
+            | DecodedInstr::FcvtFromL { rd, .. }
+            | DecodedInstr::FcvtFromLu { rd, .. }
+            | DecodedInstr::FcvtSD { rd, .. }
+            | DecodedInstr::FcvtDS { rd, .. }
+            | DecodedInstr::FmvWX { rd, .. }
+            | DecodedInstr::FmvDX { rd, .. } => Some(RegDest::F(rd)),
+            _ => None,
+        }
+    }
+
+    /// The CSR a Zicsr instruction writes. csrrs/csrrc and their immediate
+    /// forms only write when rs1 (or uimm) is nonzero.
+    pub fn csr_write(&self) -> Option<usize> {
+        match *self {
+            DecodedInstr::Csrrw { csr, .. } | DecodedInstr::Csrrwi { csr, .. } => Some(csr),
+            DecodedInstr::Csrrs { rs1, csr, .. }
+            | DecodedInstr::Csrrc { rs1, csr, .. }
+            | DecodedInstr::Csrrsi { rs1, csr, .. }
+            | DecodedInstr::Csrrci { rs1, csr, .. }
+                if rs1 != 0 =>
+            {
+                Some(csr)
+            }
+            _ => None,
+        }
+    }
+
+    /// Instruction length in bytes: 2 for RVC encodings, 4 otherwise.
+    pub fn inst_len(&self) -> u64 {
+        if self.raw() & 0b11 == 0b11 {
+            4
+        } else {
+            2
+        }
+    }
+
     fn is_branch(&self) -> bool {
         matches!(
             self,
@@ -1181,9 +2265,100 @@ impl DecodedInstr {
     }
 }
 
+/// A register an instruction writes: an integer or a floating-point one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegDest {
+    X(usize),
+    F(usize),
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BasicBlock {
     pub start_pc: u64,
     pub end_pc: u64,
     pub instrs: Vec<DecodedInstr>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn without_raw(inst: &DecodedInstr) -> String {
+        format!("{:?}", inst).replace(&format!("raw: {}", inst.raw()), "raw: _")
+    }
+
+    #[test]
+    fn test_compressed_expands_to_base_instruction() {
+        // (RVC encoding, equivalent 32-bit encoding) pairs assembled with llvm-mc
+        let pairs: [(u16, u32); 40] = [
+            (0x1fe8, 0x3fc10513), // c.addi4spn a0, sp, 1020
+            (0x5e6c, 0x07c62583), // c.lw a1, 124(a2)
+            (0x7f74, 0x0f873683), // c.ld a3, 248(a4)
+            (0xc03c, 0x04f42023), // c.sw a5, 64(s0)
+            (0xfd64, 0x0e953c23), // c.sd s1, 248(a0)
+            (0x0001, 0x00000013), // c.nop
+            (0x1501, 0xfe050513), // c.addi a0, -32
+            (0x25fd, 0x01f5859b), // c.addiw a1, 31
+            (0x52fd, 0xfff00293), // c.li t0, -1
+            (0x7101, 0xe0010113), // c.addi16sp sp, -512
+            (0x617d, 0x1f010113), // c.addi16sp sp, 496
+            (0x7601, 0xfffe0637), // c.lui a2, 0xfffe0
+            (0x667d, 0x0001f637), // c.lui a2, 0x1f
+            (0x917d, 0x03f55513), // c.srli a0, 63
+            (0x9585, 0x4215d593), // c.srai a1, 33
+            (0x9a3d, 0xfef67613), // c.andi a2, -17
+            (0x8c05, 0x40940433), // c.sub s0, s1
+            (0x8d2d, 0x00b54533), // c.xor a0, a1
+            (0x8e55, 0x00d66633), // c.or a2, a3
+            (0x8f7d, 0x00f77733), // c.and a4, a5
+            (0x9c1d, 0x40f4043b), // c.subw s0, a5
+            (0x9d25, 0x0095053b), // c.addw a0, s1
+            (0xb001, 0x801ff06f), // c.j -2048
+            (0xaffd, 0x7fe0006f), // c.j 2046
+            (0xd101, 0xf00500e3), // c.beqz a0, -256
+            (0xedfd, 0x0e059f63), // c.bnez a1, 254
+            (0x137e, 0x03f31313), // c.slli t1, 63
+            (0x50fe, 0x0fc12083), // c.lwsp ra, 252(sp)
+            (0x73fe, 0x1f813383), // c.ldsp t2, 504(sp)
+            (0x8082, 0x00008067), // c.jr ra
+            (0x852e, 0x00b00533), // c.mv a0, a1
+            (0x9002, 0x00100073), // c.ebreak
+            (0x9282, 0x000280e7), // c.jalr t0
+            (0x952e, 0x00b50533), // c.add a0, a1
+            (0xdfca, 0x0f212e23), // c.swsp s2, 252(sp)
+            (0xffce, 0x1f313c23), // c.sdsp s3, 504(sp)
+            (0x2510, 0x00853607), // c.fld fa2, 8(a0)
+            (0xa09c, 0x00f4b027), // c.fsd fa5, 0(s1)
+            (0x34fe, 0x1f813487), // c.fldsp fs1, 504(sp)
+            (0xa0a2, 0x04813027), // c.fsdsp fs0, 64(sp)
+        ];
+        for (compressed, expanded) in pairs {
+            let c = DecodedInstr::decode(compressed as u32);
+            let e = DecodedInstr::decode(expanded);
+            assert_eq!(c.inst_len(), 2, "{:#06x} is not 2 bytes long", compressed);
+            assert_eq!(e.inst_len(), 4);
+            assert_eq!(
+                without_raw(&c),
+                without_raw(&e),
+                "{:#06x} expanded differently from {:#010x}",
+                compressed,
+                expanded
+            );
+        }
+    }
+
+    #[test]
+    fn test_compressed_reserved_encodings_are_illegal() {
+        // all-zero, c.addi16sp with zero immediate, c.lwsp with rd=x0, c.jr with rs1=x0
+        for inst in [0x0000u32, 0x6101, 0x4002, 0x8002] {
+            assert!(
+                matches!(
+                    DecodedInstr::decode(inst),
+                    DecodedInstr::IllegalInstruction { .. }
+                ),
+                "{:#06x} should be illegal",
+                inst
+            );
+        }
+    }
+}
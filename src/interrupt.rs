@@ -1,5 +1,7 @@
+use crate::bus::Bus;
 use crate::cpu::*;
 use crate::csr::*;
+use crate::trace::Record;
 use core::panic;
 use log::{debug, error, info};
 use serde::{Deserialize, Serialize};
@@ -46,6 +48,19 @@ impl Interrupt {
     pub fn take_trap(&mut self, cpu: &mut Cpu) {
         let cause = INTERRUPT_BIT | self.code();
         let target_mode = self.get_trap_mode(cpu);
+        cpu.reservation = None;
+        if let Some(stats) = &mut cpu.stats {
+            stats.interrupt(self);
+        }
+        if let Ok(to_mode) = target_mode {
+            cpu.record(Record::Interrupt {
+                cycle: cpu.cycle,
+                cause,
+                epc: cpu.pc,
+                from_mode: cpu.mode as u8,
+                to_mode: to_mode as u8,
+            });
+        }
         debug!(
             "Taking trap for interrupt: {:?}, cause: 0x{:x}, target mode: {}",
             self,
@@ -200,9 +215,9 @@ pub enum Exception {
     EnvironmentalCallFromUMode,
     EnvironmentalCallFromSMode,
     EnvironmentalCallFromMMode,
-    InstructionPageFault(u32),
-    LoadPageFault(u32),
-    StoreAMOPageFault(u32),
+    InstructionPageFault(u64),
+    LoadPageFault(u64),
+    StoreAMOPageFault(u64),
 }
 
 impl Exception {
@@ -230,14 +245,27 @@ impl Exception {
     }
 
     pub fn take_trap(&self, cpu: &mut Cpu) {
+        let epc = cpu.pc;
         let cause = self.code();
         let target_mode = self.get_target_mode(cpu);
+        cpu.reservation = None;
         let xtval = match self {
             Exception::InstructionPageFault(v) => *v,
             Exception::LoadPageFault(v) => *v,
             Exception::StoreAMOPageFault(v) => *v,
             _ => 0,
-        } as u64;
+        };
+        if let Some(stats) = &mut cpu.stats {
+            stats.exception(self);
+        }
+        cpu.record(Record::Trap {
+            cycle: cpu.cycle,
+            cause,
+            epc,
+            tval: xtval,
+            from_mode: cpu.mode as u8,
+            to_mode: target_mode as u8,
+        });
         match target_mode {
             M_MODE => {
                 cpu.csr.store_csrs(MEPC, cpu.pc);
@@ -299,7 +327,34 @@ impl Exception {
                 panic!("Exception Error, this should not be reached!");
             }
         }
-        info!("Exception:{} occurred!", self.code());
+        info!(
+            "Exception:{} occurred at {}!",
+            self.code(),
+            cpu.symbols.describe(epc)
+        );
+    }
+
+    /// Take the trap, unless it is an ecall from S-mode and the built-in SBI
+    /// firmware is enabled, in which case the call is serviced in place.
+    pub fn take_trap_or_call_sbi(&self, cpu: &mut Cpu, bus: &mut Bus) {
+        if matches!(self, Exception::EnvironmentalCallFromSMode) && cpu.sbi.is_some() {
+            if let Some(stats) = &mut cpu.stats {
+                stats.sbi_call();
+            }
+            crate::sbi::handle_ecall(cpu, bus);
+        } else {
+            self.take_trap(cpu);
+        }
+    }
+
+    /// Whether the mode the trap goes to has no trap vector set up, so
+    /// taking it would jump to address 0.
+    pub fn is_unhandled(&self, cpu: &mut Cpu) -> bool {
+        let tvec = match self.get_target_mode(cpu) {
+            M_MODE => MTVEC,
+            _ => STVEC,
+        };
+        cpu.csr.load_csrs(tvec, cpu.cycle, &cpu.interrupt_list) & !0x3 == 0
     }
 
     fn get_target_mode(&self, cpu: &mut Cpu) -> u64 {
@@ -0,0 +1,126 @@
+//! Booting Linux with the RISC-V kernel `Image` boot protocol.
+//!
+//! The kernel is placed `text_offset` bytes into DRAM as its header asks,
+//! the initramfs and the device tree go to the top of DRAM, and the kernel
+//! is entered in S-mode under the built-in SBI firmware with the hart ID in
+//! a0, the device tree address in a1 and translation off.
+
+use crate::emu::Emu;
+use crate::fdt::Chosen;
+use log::{info, warn};
+use std::convert::TryInto;
+
+const HEADER_SIZE: usize = 64;
+// "RSC\x05" at offset 56; the older "RISCV\0\0\0" at offset 48 is deprecated
+const IMAGE_MAGIC2: &[u8; 4] = b"RSC\x05";
+const IMAGE_MAGIC: &[u8; 8] = b"RISCV\0\0\0";
+const PAGE_SIZE: u64 = 0x1000;
+
+/// A kernel `Image` and the fields of its header the loader needs.
+pub struct KernelImage {
+    pub data: Vec<u8>,
+    /// Where in DRAM the image must be loaded, relative to its start.
+    pub text_offset: u64,
+    /// Memory the kernel occupies from its load address, bss included.
+    pub image_size: u64,
+}
+
+impl KernelImage {
+    pub fn parse(data: Vec<u8>) -> Result<KernelImage, String> {
+        if data.len() < HEADER_SIZE {
+            return Err("the kernel image is too small for its header".to_string());
+        }
+        if &data[56..60] != IMAGE_MAGIC2 && &data[48..56] != IMAGE_MAGIC {
+            return Err("not a RISC-V kernel Image (bad magic)".to_string());
+        }
+        let field =
+            |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+        let text_offset = field(8);
+        let image_size = field(16).max(data.len() as u64);
+        Ok(KernelImage {
+            text_offset,
+            image_size,
+            data,
+        })
+    }
+}
+
+/// Everything `boot` puts into the guest besides the kernel.
+#[derive(Default)]
+pub struct BootConfig {
+    pub initrd: Option<Vec<u8>>,
+    pub bootargs: Option<String>,
+    /// A device tree to use as is instead of the generated one.
+    pub dtb: Option<Vec<u8>>,
+}
+
+fn align_down(value: u64, align: u64) -> u64 {
+    value & !(align - 1)
+}
+
+/// Load `kernel` and the boot data into `emu` and prepare the hart to enter
+/// the kernel. Returns the device tree passed to the kernel.
+pub fn boot(emu: &mut Emu, kernel: &KernelImage, config: BootConfig) -> Result<Vec<u8>, String> {
+    let dram_base = emu.bus.dram.dram_base;
+    let entry = dram_base + kernel.text_offset;
+    if !emu.bus.dram.write_bytes(entry, &kernel.data) {
+        return Err(format!(
+            "the kernel ({} bytes at 0x{:x}) does not fit in DRAM",
+            kernel.data.len(),
+            entry
+        ));
+    }
+    let kernel_end = entry + kernel.image_size;
+    info!(
+        "Linux kernel loaded at 0x{:x}, occupying up to 0x{:x}",
+        entry, kernel_end
+    );
+
+    let mut chosen = Chosen {
+        bootargs: config.bootargs,
+        initrd: None,
+    };
+    let dtb = match config.dtb {
+        Some(dtb) => {
+            if chosen.bootargs.is_some() || config.initrd.is_some() {
+                warn!("the supplied device tree is used as is; pass the command line and initrd in it");
+            }
+            dtb
+        }
+        None => {
+            // the placeholder range keeps the size of the final tree
+            chosen.initrd = config.initrd.as_ref().map(|_| (0, 0));
+            emu.generate_device_tree(&chosen)
+        }
+    };
+    let dtb_addr = crate::fdt::placement(&emu.bus, dtb.len())?;
+
+    let dtb = match config.initrd {
+        Some(initrd) => {
+            let start = dtb_addr
+                .checked_sub(initrd.len() as u64)
+                .map_or(0, |start| align_down(start, PAGE_SIZE));
+            if start < kernel_end {
+                return Err(format!(
+                    "the initrd ({} bytes) does not fit between the kernel and the device tree",
+                    initrd.len()
+                ));
+            }
+            emu.bus.dram.write_bytes(start, &initrd);
+            let end = start + initrd.len() as u64;
+            info!("initrd placed at 0x{:x}-0x{:x}", start, end);
+            if chosen.initrd.is_some() {
+                chosen.initrd = Some((start, end));
+                emu.generate_device_tree(&chosen)
+            } else {
+                dtb
+            }
+        }
+        None => dtb,
+    };
+
+    let dtb_addr = emu.load_device_tree(&dtb)?;
+    emu.set_entry_point(entry);
+    emu.enable_sbi(dtb_addr);
+    Ok(dtb)
+}
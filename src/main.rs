@@ -1,14 +1,28 @@
 mod bus;
 mod clint;
+mod commit_log;
+mod console_script;
 mod cpu;
 mod csr;
 mod debugger;
+mod disasm;
+mod disk;
 mod dram;
+mod elf;
 mod emu;
+mod fdt;
 mod instruction;
 mod interrupt;
+mod linux;
 mod plic;
+mod profiler;
+mod sbi;
+mod softfloat;
+mod stats;
+mod symbols;
+mod trace;
 mod uart;
+mod uart_backend;
 mod virtio;
 use clap::Parser; // command-line option parser
 
@@ -20,7 +34,6 @@ use gdbstub::conn::ConnectionExt;
 use gdbstub::stub::DisconnectReason;
 use gdbstub::stub::GdbStub;
 use log::{error, info};
-use std::convert::TryInto;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
@@ -28,9 +41,13 @@ use std::io::prelude::*;
 /// Search for a pattern in a file and display the lines that contain it.
 /// c.f. https://rust-cli.github.io/book/tutorial/cli-args.html
 #[derive(Parser)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
     /// The path to the file to read
-    bin: std::path::PathBuf,
+    #[clap(required = true)]
+    bin: Option<std::path::PathBuf>,
     #[clap(short, long)]
     dump: Option<i64>,
     #[clap(short, long)]
@@ -51,8 +68,240 @@ struct Cli {
     snapshot_interval: u64,
     #[clap(long)]
     image: Option<std::path::PathBuf>,
+    /// What happens to guest writes to --image: memory (discarded at exit),
+    /// read-only, write-back or overlay:PATH (copy-on-write into PATH)
+    #[clap(long, default_value = "memory")]
+    disk_mode: String,
     #[clap(long)]
     test_result_addr: Option<u64>,
+    /// Host side of the UART: stdio, stdio-cooked, null, pty, tcp:ADDR,
+    /// file:OUT[,IN] or script:TEXT
+    #[clap(long, default_value = "stdio")]
+    uart: String,
+    /// Drive the console with an expect-style script; the exit code reports
+    /// whether it passed
+    #[clap(long)]
+    script: Option<std::path::PathBuf>,
+    /// Run the program in S-mode under the built-in SBI firmware
+    #[clap(long)]
+    sbi: bool,
+    /// Pass this device tree blob to the guest instead of generating one.
+    /// Without --sbi, --dtb or --dump-dtb no device tree is passed
+    #[clap(long)]
+    dtb: Option<std::path::PathBuf>,
+    /// Write the device tree blob passed to the guest to this file
+    #[clap(long)]
+    dump_dtb: Option<std::path::PathBuf>,
+    /// Boot the file as a Linux kernel Image under the built-in SBI firmware;
+    /// the base address defaults to 0x80000000
+    #[clap(long)]
+    linux: bool,
+    /// Initial ramdisk for --linux
+    #[clap(long)]
+    initrd: Option<std::path::PathBuf>,
+    /// Kernel command line for --linux
+    #[clap(long)]
+    append: Option<String>,
+    /// Also resolve addresses in traces and crash reports with the symbols of
+    /// this ELF file, e.g. a user program; may be repeated
+    #[clap(long)]
+    symbols: Vec<std::path::PathBuf>,
+    /// Write a trace of retired instructions in the format of Spike's
+    /// --log-commits to this file
+    #[clap(long)]
+    commit_log: Option<std::path::PathBuf>,
+    /// Write a binary trace of executed blocks, traps, interrupts, MMIO
+    /// accesses and CSR writes to this file; read it with trace-convert
+    #[clap(long)]
+    trace: Option<std::path::PathBuf>,
+    /// Profile the guest and write folded call stacks, for flamegraph
+    /// tools, to this file; a table of the hottest functions is printed at
+    /// exit
+    #[clap(long)]
+    profile: Option<std::path::PathBuf>,
+    /// Sample the guest every N instructions instead of counting each one
+    #[clap(long, default_value_t = 0)]
+    profile_interval: u64,
+    /// Number of functions in the profile report
+    #[clap(long, default_value_t = 20)]
+    profile_top: usize,
+    /// Count instructions per privilege mode and opcode, traps per cause and
+    /// page walks, and print the counts at exit; under --gdb they are also
+    /// shown by `monitor stats`
+    #[clap(long)]
+    stats: bool,
+    /// Write the statistics as JSON to this file at exit
+    #[clap(long)]
+    stats_json: Option<std::path::PathBuf>,
+    /// Support 64 KiB NAPOT page mappings (Svnapot)
+    #[clap(long)]
+    svnapot: bool,
+    /// Support page-based memory types (Svpbmt), used while menvcfg.PBMTE
+    /// is set, as it is at reset
+    #[clap(long)]
+    svpbmt: bool,
+    /// Raise a page fault on a clear A bit, or a clear D bit on a store,
+    /// instead of setting it in the page table (Svade). Without it the hart
+    /// has Svadu: the bits are set while menvcfg.ADUE is, as it is at reset
+    #[clap(long)]
+    svade: bool,
+    /// Number of TLB entries [default: 1024]
+    #[clap(long)]
+    tlb_entries: Option<usize>,
+    /// TLB associativity; must divide the number of entries [default: 4]
+    #[clap(long)]
+    tlb_ways: Option<usize>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Disassemble a range of a binary, an ELF file or a snapshot's memory
+    Disasm {
+        /// A raw binary, or an ELF file or snapshot with --elf or --snapshot
+        file: std::path::PathBuf,
+        #[clap(short, long)]
+        elf: bool,
+        /// Read the file as a snapshot; addresses are translated like the
+        /// hart's instruction fetches
+        #[clap(long)]
+        snapshot: bool,
+        /// Load address of a raw binary
+        #[clap(long, default_value = "0x80000000", parse(try_from_str = parse_addr))]
+        base_addr: u64,
+        /// First address to disassemble; defaults to the start of the image,
+        /// or the pc of a snapshot
+        #[clap(long, parse(try_from_str = parse_addr))]
+        start: Option<u64>,
+        /// Address to stop at; defaults to the end of the image, or 64
+        /// bytes past the start for a snapshot
+        #[clap(long, parse(try_from_str = parse_addr))]
+        end: Option<u64>,
+        /// Label the listing with the symbols of this ELF file; may be repeated
+        #[clap(long)]
+        symbols: Vec<std::path::PathBuf>,
+    },
+    /// Convert a --trace file to JSON lines, or to SQL to pipe into sqlite3
+    TraceConvert {
+        file: std::path::PathBuf,
+        /// jsonl or sql
+        #[clap(long, default_value = "jsonl")]
+        format: String,
+        /// Output file; defaults to stdout
+        #[clap(short, long)]
+        output: Option<std::path::PathBuf>,
+        /// Label blocks with the symbols of this ELF file; may be repeated
+        #[clap(long)]
+        symbols: Vec<std::path::PathBuf>,
+    },
+}
+
+fn parse_addr(s: &str) -> Result<u64, String> {
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|e| format!("invalid address {}: {}", s, e))
+}
+
+fn disassemble(command: Command) -> io::Result<()> {
+    let Command::Disasm {
+        file,
+        elf,
+        snapshot,
+        base_addr,
+        start,
+        end,
+        symbols: symbol_files,
+    } = command
+    else {
+        unreachable!("not a disasm command");
+    };
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidInput, e);
+    let mut symbols = symbols::SymbolTable::default();
+    for path in &symbol_files {
+        symbols.add_file(path).map_err(invalid)?;
+    }
+
+    let listing = if snapshot {
+        let mut emu = Emu::load_snapshot(file)?;
+        let start = start.unwrap_or(emu.cpu.pc);
+        let end = end.unwrap_or(start + 64);
+        let Emu { cpu, bus, .. } = &mut emu;
+        let fetch = |addr| cpu.fetch(bus, addr).ok();
+        disasm::listing(fetch, start, end, &symbols)
+    } else {
+        let (memory, base) = if elf {
+            let image = elf::ElfImage::from_file(&file).map_err(invalid)?;
+            let base = image.segments.first().map_or(0, |segment| segment.paddr);
+            symbols.add_image(&image);
+            (image.memory_image(base).map_err(invalid)?, base)
+        } else {
+            (std::fs::read(&file)?, base_addr)
+        };
+        let start = start.unwrap_or(base);
+        let end = end.unwrap_or(base + memory.len() as u64);
+        disasm::listing(disasm::fetch_from(&memory, base), start, end, &symbols)
+    };
+    print!("{}", listing);
+    Ok(())
+}
+
+fn convert_trace(command: Command) -> io::Result<()> {
+    let Command::TraceConvert {
+        file,
+        format,
+        output,
+        symbols: symbol_files,
+    } = command
+    else {
+        unreachable!("not a trace-convert command");
+    };
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidInput, e);
+    let mut symbols = symbols::SymbolTable::default();
+    for path in &symbol_files {
+        symbols.add_file(path).map_err(invalid)?;
+    }
+    let to_sql = match format.as_str() {
+        "jsonl" => false,
+        "sql" => true,
+        _ => return Err(invalid(format!("unknown trace format {}", format))),
+    };
+
+    let reader =
+        trace::TraceReader::new(io::BufReader::new(File::open(&file)?)).map_err(invalid)?;
+    let mut out: Box<dyn Write> = match output {
+        Some(path) => Box::new(io::BufWriter::new(File::create(path)?)),
+        None => Box::new(io::BufWriter::new(io::stdout().lock())),
+    };
+    if to_sql {
+        writeln!(out, "BEGIN TRANSACTION;")?;
+        write!(out, "{}", trace::SQL_SCHEMA)?;
+    }
+    for record in reader {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                // keep what was read from a trace cut short
+                error!("{}: {}", file.display(), e);
+                break;
+            }
+        };
+        let name = match record {
+            trace::Record::Block { start_pc, .. } => symbols
+                .lookup(start_pc)
+                .map(|location| location.to_string()),
+            _ => None,
+        };
+        if to_sql {
+            writeln!(out, "{}", record.to_sql(name.as_deref()))?;
+        } else {
+            writeln!(out, "{}", record.to_json(name.as_deref()))?;
+        }
+    }
+    if to_sql {
+        writeln!(out, "COMMIT;")?;
+    }
+    out.flush()
 }
 
 fn main() -> io::Result<()> {
@@ -60,25 +309,72 @@ fn main() -> io::Result<()> {
     env_logger::init();
 
     let cli = Cli::parse();
-    let mut file = File::open(&cli.bin)?;
+    match cli.command {
+        Some(command @ Command::Disasm { .. }) => return disassemble(command),
+        Some(command @ Command::TraceConvert { .. }) => return convert_trace(command),
+        None => {}
+    }
+    let bin = cli.bin.expect("the binary is a required argument");
+    let mut file = File::open(&bin)?;
     let mut code = Vec::new();
     let mut entry_address = 0 as u64;
-    let base_addr = cli.base_addr.unwrap_or(0) as u64;
+    let mut symbols = symbols::SymbolTable::default();
+    let default_base_addr = if cli.linux { 0x8000_0000 } else { 0 };
+    let base_addr = cli.base_addr.map_or(default_base_addr, |addr| addr as u64);
 
     if cli.elf != false {
-        entry_address = load_elf(&mut code, &mut file, base_addr as usize).unwrap();
+        let image = elf::ElfImage::from_file(&bin)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        code = image
+            .memory_image(base_addr)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        entry_address = image.entry;
+        symbols.add_image(&image);
     } else {
         file.read_to_end(&mut code)?;
     }
+    for path in &cli.symbols {
+        symbols
+            .add_file(path)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    }
 
     let reg_dump_count = cli.dump.unwrap_or(0);
+    let paging = cpu::PagingExtensions {
+        svnapot: cli.svnapot,
+        svpbmt: cli.svpbmt,
+        svade: cli.svade,
+    };
 
     let mut emu = if cli.snapshot.is_some() {
         let path = cli.snapshot.unwrap();
         let mut emu = Emu::load_snapshot(path).unwrap();
         emu.cpu.set_dump_count(reg_dump_count as u64);
+        emu.cpu.set_paging_extensions(paging);
         emu.snapshot_interval = cli.snapshot_interval;
         emu
+    } else if cli.linux {
+        let kernel = linux::KernelImage::parse(code)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut emu = Emu::new(
+            Vec::new(),
+            base_addr,
+            reg_dump_count as u64,
+            cli.snapshot_interval,
+        );
+        emu.cpu.set_paging_extensions(paging);
+        let config = linux::BootConfig {
+            initrd: cli.initrd.as_ref().map(std::fs::read).transpose()?,
+            bootargs: cli.append.clone(),
+            dtb: cli.dtb.as_ref().map(std::fs::read).transpose()?,
+        };
+        let dtb = linux::boot(&mut emu, &kernel, config)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        if let Some(path) = &cli.dump_dtb {
+            std::fs::write(path, &dtb)?;
+            info!("Device tree written to {}", path.display());
+        }
+        emu
     } else {
         let mut emu = Emu::new(
             code,
@@ -86,13 +382,70 @@ fn main() -> io::Result<()> {
             reg_dump_count as u64,
             cli.snapshot_interval,
         );
+        emu.cpu.set_paging_extensions(paging);
         emu.set_entry_point(entry_address);
+
+        // bare-metal programs keep the whole of DRAM and their initial
+        // registers unless a device tree is asked for
+        if cli.sbi || cli.dtb.is_some() || cli.dump_dtb.is_some() {
+            let dtb = match &cli.dtb {
+                Some(path) => std::fs::read(path)?,
+                None => emu.generate_device_tree(&fdt::Chosen::default()),
+            };
+            if let Some(path) = &cli.dump_dtb {
+                std::fs::write(path, &dtb)?;
+                info!("Device tree written to {}", path.display());
+            }
+            let dtb_addr = emu
+                .load_device_tree(&dtb)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            if cli.sbi {
+                emu.enable_sbi(dtb_addr);
+            }
+        }
         emu
     };
 
-    if cli.image.is_some() {
-        let disk_image = std::fs::read(cli.image.unwrap()).expect("Failed to read disk image");
-        emu.set_disk_image(disk_image);
+    emu.cpu.symbols = symbols;
+    if let Some(path) = &cli.commit_log {
+        let out = io::BufWriter::new(File::create(path)?);
+        emu.cpu
+            .set_commit_log(commit_log::CommitLog::new(Box::new(out)));
+    }
+    if let Some(path) = &cli.trace {
+        let out = io::BufWriter::new(File::create(path)?);
+        emu.cpu.set_trace(trace::TraceWriter::new(Box::new(out))?);
+    }
+    if let Some(path) = &cli.profile {
+        emu.cpu.set_profiler(profiler::Profiler::new(
+            path.clone(),
+            cli.profile_interval,
+            cli.profile_top,
+        ));
+    }
+    if cli.stats || cli.stats_json.is_some() {
+        emu.cpu
+            .set_stats(stats::Stats::new(cli.stats, cli.stats_json.clone()));
+    }
+
+    // a snapshot keeps its own TLB unless a geometry is given
+    if cli.tlb_entries.is_some() || cli.tlb_ways.is_some() {
+        let tlb = cpu::Tlb::new(
+            cli.tlb_entries.unwrap_or(cpu::tlb::DEFAULT_ENTRIES),
+            cli.tlb_ways.unwrap_or(cpu::tlb::DEFAULT_WAYS),
+        )
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        emu.cpu.set_tlb(tlb);
+    }
+
+    let uart_backend = uart_backend::parse_backend(&cli.uart)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    emu.set_uart_backend(uart_backend)?;
+
+    if let Some(image) = cli.image {
+        let disk_backend = disk::parse_backend(&cli.disk_mode, image)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        emu.set_disk_backend(disk_backend)?;
     }
 
     if cli.gdb {
@@ -132,16 +485,42 @@ fn main() -> io::Result<()> {
                 }
             }
         }
+        emu.cpu.flush_logs();
         Ok(())
     } else {
         info!("No GDB");
         emu.exec_mode = emu::ExecMode::Continue;
 
+        if let Some(path) = cli.script {
+            let script = console_script::ConsoleScript::from_file(&path)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let result = script.run(&mut emu);
+            emu.cpu.flush_logs();
+            return match result {
+                Ok(()) => {
+                    info!("SCRIPT PASSED");
+                    Ok(())
+                }
+                Err(e) => {
+                    error!("script {} failed: {}", path.display(), e);
+                    Err(io::Error::other(e))
+                }
+            };
+        }
+
         if let Some(count) = cli.count {
             emu.run_for(count as u64);
         } else {
             emu.run(|| false);
         }
+        emu.cpu.flush_logs();
+
+        if let Some(reset) = emu.cpu.system_reset() {
+            info!("Guest requested a system reset: {:?}", reset);
+            if reset.is_failure() {
+                return Err(io::Error::other("guest shut down with a system failure"));
+            }
+        }
 
         if cli.test_result_addr.is_some() {
             let addr = cli.test_result_addr.unwrap();
@@ -184,83 +563,3 @@ fn main() -> io::Result<()> {
 //         }
 //     }
 // }
-
-pub const E_ENTRY_POS: usize = 0x18; // 64bit
-pub const PH_POS: usize = 0x20; // 64bit
-pub const PH_ENTRIES_POS: usize = 0x38; // 64bit
-pub const PH_ENTRY_SIZE_POS: usize = 0x36; // 64bit
-
-fn u8_slice_to_u16(barry: &[u8]) -> u16 {
-    u16::from_le_bytes(barry.try_into().expect("slice with incorrect length"))
-}
-
-fn u8_slice_to_u32(barry: &[u8]) -> u32 {
-    u32::from_le_bytes(barry.try_into().expect("slice with incorrect length"))
-}
-
-fn u8_slice_to_u64(barry: &[u8]) -> u64 {
-    u64::from_le_bytes(barry.try_into().expect("slice with incorrect length"))
-}
-
-pub(crate) fn load_elf(code: &mut Vec<u8>, file: &mut File, base_addr: usize) -> io::Result<u64> {
-    let mut elf = Vec::new();
-    file.read_to_end(&mut elf)?;
-    let entry = u8_slice_to_u64(&elf[E_ENTRY_POS..E_ENTRY_POS + 8]) as u64;
-    let ph_offset = u8_slice_to_u64(&elf[PH_POS..PH_POS + 8]) as usize;
-    let ph_entries = u8_slice_to_u16(&elf[PH_ENTRIES_POS..PH_ENTRIES_POS + 2]) as usize;
-    let ph_entry_size = u8_slice_to_u16(&elf[PH_ENTRY_SIZE_POS..PH_ENTRY_SIZE_POS + 2]) as usize;
-    info!(
-        "Prog Header Entries:{}, Offset:{:>#x}, size:{:>#x}, entry:{:>#x}",
-        ph_entries, ph_offset, ph_entry_size, entry
-    );
-
-    for entry in 0..ph_entries {
-        let entry_offset = ph_offset + entry * ph_entry_size;
-        let p_type_offset = entry_offset + 0x0;
-        let va_offset = entry_offset + 0x10;
-        let segment_offset = entry_offset + 0x8;
-        let filesize_offset = entry_offset + 0x20;
-        let memsize_offset = entry_offset + 0x28;
-        let p_type = u8_slice_to_u32(&elf[p_type_offset..p_type_offset + 4]) as usize;
-        let segment = u8_slice_to_u64(&elf[segment_offset..segment_offset + 8]) as usize;
-        let va = u8_slice_to_u64(&elf[va_offset..va_offset + 8]) as usize;
-        let filesize = u8_slice_to_u64(&elf[filesize_offset..filesize_offset + 8]) as usize;
-        let memsize = u8_slice_to_u64(&elf[memsize_offset..memsize_offset + 8]) as usize;
-        info!("Offset:{:>#x}, Entry:{}, segment offset: {:>#x}, va:{:>#x}, filesize:{:>#x}, memsize:{:>#x}",
-                 entry_offset, entry, segment, va, filesize, memsize);
-        info!("Code length: {}", code.len());
-        // if p_type != 0x1 {
-        //     continue;
-        // }
-        if base_addr > va {
-            info!(
-                "Skipping segment: {}, Base address {:>#x} is larger than virtual address {:>#x}\n",
-                entry, base_addr, va
-            );
-            continue;
-        }
-        if code.len() <= (va - base_addr) {
-            code.extend(vec![0; va - base_addr - code.len()].iter());
-            // extend for .text and .data sections
-            code.extend(&elf[segment..segment + filesize]);
-            // extend for .bss section, filling with zeros
-            code.extend(
-                std::iter::repeat(0)
-                    .take(memsize - filesize)
-                    .collect::<Vec<u8>>(),
-            );
-        } else if code.len() > va - base_addr + memsize {
-            code[va - base_addr..va - base_addr + memsize]
-                .copy_from_slice(&elf[segment..segment + memsize]);
-        } else {
-            panic!("Code must have been loaded wrong!");
-        }
-        info!(
-            "Loaded segment at virtual address {:>#x}, code length: {}({:>#x})",
-            va,
-            code.len(),
-            code.len()
-        );
-    }
-    Ok(entry)
-}
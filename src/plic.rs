@@ -5,23 +5,41 @@ use std::sync::{Arc, Mutex};
 use crate::interrupt::{Exception, Interrupt};
 use serde::{Deserialize, Serialize};
 
-use log::info;
+use log::{debug, info, warn};
 
-const PLIC_SIZE: u64 = 0x4000000;
+pub const PLIC_SIZE: u64 = 0x4000000;
 
 const INTERRUPT_SOURCE_PRIORITIES: u64 = 0x000000;
 const INTERRUPT_PENDING_BITS: u64 = 0x001000;
-#[allow(unused)]
 const INTERRUPT_ENABLES: u64 = 0x002000;
-#[allow(unused)]
 const PRIORITY_THRESHOLDS: u64 = 0x200000;
-const CLAIM_COMPLETE: u64 = 0x201004;
+const CLAIM_COMPLETE: u64 = 0x200004;
+
+// per-context strides of the enable and threshold/claim register blocks
+const ENABLES_STRIDE: u64 = 0x80;
+const CONTEXT_STRIDE: u64 = 0x1000;
+
+// source 0 is reserved and means "no interrupt"
+pub const NUM_SOURCES: usize = 96;
+const SOURCE_WORDS: usize = NUM_SOURCES / 32;
+const MAX_PRIORITY: u32 = 7;
+
+/// Hart 0 has two contexts: context 0 drives MEIP and context 1 drives SEIP.
+const CONTEXT_INTERRUPTS: [Interrupt; 2] = [
+    Interrupt::MachineExternalInterrupt,
+    Interrupt::SupervisorExternalInterrupt,
+];
+const NUM_CONTEXTS: usize = CONTEXT_INTERRUPTS.len();
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct PlicSnapshot {
     pub start_addr: u64,
-    pub regs: Vec<u32>,
-    pub external_interrupt_list: BTreeSet<ExternalInterrupt>,
+    pub priorities: Vec<u32>,
+    pub pending: Vec<u32>,
+    pub in_service: Vec<u32>,
+    pub deferred: Vec<u32>,
+    pub enables: Vec<Vec<u32>>,
+    pub thresholds: Vec<u32>,
 }
 
 #[derive(Clone, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd, Debug, Hash, Copy)]
@@ -39,22 +57,49 @@ impl ExternalInterrupt {
     }
 }
 
+fn test_bit(bits: &[u32], id: usize) -> bool {
+    bits[id / 32] & (1 << (id % 32)) != 0
+}
+
+fn set_bit(bits: &mut [u32], id: usize, value: bool) {
+    if value {
+        bits[id / 32] |= 1 << (id % 32);
+    } else {
+        bits[id / 32] &= !(1 << (id % 32));
+    }
+}
+
+/// Platform-level interrupt controller.
+///
+/// Each source passes through a gateway: while a source is claimed and not yet
+/// completed, further requests from it are held back, and a single held
+/// request is forwarded once the claim is completed.
 pub struct Plic {
     start_addr: u64,
-    regs: Vec<u32>,
+    priorities: Vec<u32>,
+    pending: Vec<u32>,
+    // claimed but not yet completed
+    in_service: Vec<u32>,
+    // requests that arrived while the source was in service
+    deferred: Vec<u32>,
+    enables: Vec<Vec<u32>>,
+    thresholds: Vec<u32>,
     pending_queue: Arc<Mutex<Vec<ExternalInterrupt>>>,
     has_pending: Arc<AtomicBool>,
-    external_interrupt_list: BTreeSet<ExternalInterrupt>,
 }
 
 impl Plic {
     pub fn new(start_addr: u64) -> Plic {
         Self {
             start_addr,
-            regs: vec![0; PLIC_SIZE as usize / 8],
+            priorities: vec![0; NUM_SOURCES],
+            pending: vec![0; SOURCE_WORDS],
+            in_service: vec![0; SOURCE_WORDS],
+            deferred: vec![0; SOURCE_WORDS],
+            enables: vec![vec![0; SOURCE_WORDS]; NUM_CONTEXTS],
+            thresholds: vec![0; NUM_CONTEXTS],
             pending_queue: Arc::new(Mutex::new(Vec::new())),
             has_pending: Arc::new(AtomicBool::new(false)),
-            external_interrupt_list: BTreeSet::new(),
         }
     }
 
@@ -74,76 +119,273 @@ impl Plic {
         }
         let pending: Vec<ExternalInterrupt> =
             self.pending_queue.lock().unwrap().drain(..).collect();
-        if pending.is_empty() {
-            return;
-        }
         self.has_pending.store(false, Ordering::Relaxed);
         for interrupt in pending {
             info!("Processing interrupt ID: {:?}", interrupt);
-            interrupts.insert(Interrupt::SupervisorExternalInterrupt);
-            self.regs[INTERRUPT_PENDING_BITS as usize / 4] |= 1 << interrupt.id();
-            info!("Updated pending bits for interrupt ID: {:?}", interrupt);
-            self.external_interrupt_list.insert(interrupt);
+            self.raise(interrupt.id() as usize);
+        }
+        self.update_interrupt_lines(interrupts);
+    }
+
+    /// Gateway: forward a request from `id` unless it is in service.
+    fn raise(&mut self, id: usize) {
+        if test_bit(&self.in_service, id) {
+            set_bit(&mut self.deferred, id, true);
+        } else {
+            set_bit(&mut self.pending, id, true);
+        }
+    }
+
+    /// The highest-priority source that is pending, enabled for `context` and
+    /// above its threshold. Ties go to the lowest ID.
+    fn best_candidate(&self, context: usize) -> Option<usize> {
+        let mut best: Option<usize> = None;
+        for id in 1..NUM_SOURCES {
+            if !test_bit(&self.pending, id)
+                || !test_bit(&self.enables[context], id)
+                || self.priorities[id] <= self.thresholds[context]
+            {
+                continue;
+            }
+            if best.is_none_or(|b| self.priorities[id] > self.priorities[b]) {
+                best = Some(id);
+            }
+        }
+        best
+    }
+
+    /// Drive MEIP/SEIP in the CPU's interrupt list from the state of each context.
+    fn update_interrupt_lines(&self, interrupts: &mut BTreeSet<Interrupt>) {
+        for (context, interrupt) in CONTEXT_INTERRUPTS.iter().enumerate() {
+            if self.best_candidate(context).is_some() {
+                interrupts.insert(*interrupt);
+            } else {
+                interrupts.remove(interrupt);
+            }
+        }
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best_candidate(context) {
+            Some(id) => {
+                set_bit(&mut self.pending, id, false);
+                set_bit(&mut self.in_service, id, true);
+                debug!("plic: context {} claimed source {}", context, id);
+                id as u32
+            }
+            None => 0,
         }
     }
 
+    fn complete(&mut self, context: usize, id: usize) {
+        // completions for sources not enabled on this context are ignored
+        if id == 0 || id >= NUM_SOURCES || !test_bit(&self.enables[context], id) {
+            return;
+        }
+        debug!("plic: context {} completed source {}", context, id);
+        set_bit(&mut self.in_service, id, false);
+        if test_bit(&self.deferred, id) {
+            set_bit(&mut self.deferred, id, false);
+            set_bit(&mut self.pending, id, true);
+        }
+    }
+
+    pub fn start_addr(&self) -> u64 {
+        self.start_addr
+    }
+
     pub fn is_accessible(&self, addr: u64) -> bool {
         (addr >= self.start_addr) && (addr < self.start_addr + PLIC_SIZE)
     }
 
+    /// Split a context-local offset into (context, offset within the block).
+    fn context_of(offset: u64, base: u64, stride: u64) -> Option<(usize, u64)> {
+        let context = ((offset - base) / stride) as usize;
+        if context < NUM_CONTEXTS {
+            Some((context, (offset - base) % stride))
+        } else {
+            None
+        }
+    }
+
     pub fn load(
         &mut self,
         addr: u64,
-        _size: u64,
+        size: u64,
         interrupts: &mut BTreeSet<Interrupt>,
     ) -> Result<u64, Exception> {
-        let relative_addr = addr - self.start_addr;
-        match relative_addr {
-            CLAIM_COMPLETE => {
-                let max_interrupt = self
-                    .external_interrupt_list
-                    .iter()
-                    .max_by_key(|&interrupt| {
-                        self.regs
-                            [INTERRUPT_SOURCE_PRIORITIES as usize / 4 + interrupt.id() as usize]
-                    })
-                    .cloned();
-                if let Some(interrupt) = max_interrupt {
-                    let id = interrupt.id();
-                    self.regs[INTERRUPT_PENDING_BITS as usize / 4] &= !(1 << id);
-                    self.external_interrupt_list.remove(&interrupt);
-                    if self.external_interrupt_list.is_empty() {
-                        interrupts.remove(&Interrupt::SupervisorExternalInterrupt);
-                    }
-                    Ok(id as u64)
-                } else {
-                    Ok(0)
+        let offset = addr - self.start_addr;
+        if size != 32 {
+            return Err(Exception::LoadAccessFault);
+        }
+        if !offset.is_multiple_of(4) {
+            return Err(Exception::LoadAddressMissaligned);
+        }
+        let value = match offset {
+            INTERRUPT_SOURCE_PRIORITIES..INTERRUPT_PENDING_BITS => self
+                .priorities
+                .get((offset / 4) as usize)
+                .copied()
+                .unwrap_or(0),
+            INTERRUPT_PENDING_BITS..INTERRUPT_ENABLES => self
+                .pending
+                .get(((offset - INTERRUPT_PENDING_BITS) / 4) as usize)
+                .copied()
+                .unwrap_or(0),
+            INTERRUPT_ENABLES..PRIORITY_THRESHOLDS => {
+                match Self::context_of(offset, INTERRUPT_ENABLES, ENABLES_STRIDE) {
+                    Some((context, word)) => self.enables[context]
+                        .get((word / 4) as usize)
+                        .copied()
+                        .unwrap_or(0),
+                    None => 0,
                 }
             }
-            _ => Ok(self.regs[(relative_addr / 4) as usize] as u64),
-        }
+            _ => match Self::context_of(offset, PRIORITY_THRESHOLDS, CONTEXT_STRIDE) {
+                Some((context, 0)) => self.thresholds[context],
+                Some((context, reg)) if reg == CLAIM_COMPLETE - PRIORITY_THRESHOLDS => {
+                    let id = self.claim(context);
+                    self.update_interrupt_lines(interrupts);
+                    id
+                }
+                _ => 0,
+            },
+        };
+        Ok(value as u64)
     }
 
-    pub fn store(&mut self, _addr: u64, _size: u64, _value: u64) -> Result<(), Exception> {
+    pub fn store(
+        &mut self,
+        addr: u64,
+        size: u64,
+        value: u64,
+        interrupts: &mut BTreeSet<Interrupt>,
+    ) -> Result<(), Exception> {
+        let offset = addr - self.start_addr;
+        if size != 32 {
+            return Err(Exception::StoreAMOAccessFault);
+        }
+        if !offset.is_multiple_of(4) {
+            return Err(Exception::StoreAMOAddressMisaligned);
+        }
+        let value = value as u32;
+        match offset {
+            INTERRUPT_SOURCE_PRIORITIES..INTERRUPT_PENDING_BITS => {
+                let id = (offset / 4) as usize;
+                if id != 0 && id < NUM_SOURCES {
+                    self.priorities[id] = value.min(MAX_PRIORITY);
+                }
+            }
+            // pending bits are read-only
+            INTERRUPT_PENDING_BITS..INTERRUPT_ENABLES => {}
+            INTERRUPT_ENABLES..PRIORITY_THRESHOLDS => {
+                if let Some((context, word)) =
+                    Self::context_of(offset, INTERRUPT_ENABLES, ENABLES_STRIDE)
+                {
+                    let word = (word / 4) as usize;
+                    if word < SOURCE_WORDS {
+                        // source 0 does not exist
+                        self.enables[context][word] = if word == 0 { value & !1 } else { value };
+                    }
+                }
+            }
+            _ => match Self::context_of(offset, PRIORITY_THRESHOLDS, CONTEXT_STRIDE) {
+                Some((context, 0)) => self.thresholds[context] = value.min(MAX_PRIORITY),
+                Some((context, reg)) if reg == CLAIM_COMPLETE - PRIORITY_THRESHOLDS => {
+                    self.complete(context, value as usize)
+                }
+                _ => warn!("plic: store to unimplemented register {:#x}", offset),
+            },
+        }
+        self.update_interrupt_lines(interrupts);
         Ok(())
     }
 
     pub fn from_snapshot(snapshot: PlicSnapshot) -> Plic {
-        let has_pending = !snapshot.external_interrupt_list.is_empty();
         Plic {
             start_addr: snapshot.start_addr,
-            regs: snapshot.regs,
+            priorities: snapshot.priorities,
+            pending: snapshot.pending,
+            in_service: snapshot.in_service,
+            deferred: snapshot.deferred,
+            enables: snapshot.enables,
+            thresholds: snapshot.thresholds,
             pending_queue: Arc::new(Mutex::new(Vec::new())),
-            has_pending: Arc::new(AtomicBool::new(has_pending)),
-            external_interrupt_list: snapshot.external_interrupt_list,
+            has_pending: Arc::new(AtomicBool::new(false)),
         }
     }
 
     pub fn to_snapshot(&self) -> PlicSnapshot {
         PlicSnapshot {
             start_addr: self.start_addr,
-            regs: self.regs.clone(),
-            external_interrupt_list: self.external_interrupt_list.clone(),
+            priorities: self.priorities.clone(),
+            pending: self.pending.clone(),
+            in_service: self.in_service.clone(),
+            deferred: self.deferred.clone(),
+            enables: self.enables.clone(),
+            thresholds: self.thresholds.clone(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: u64 = 0xc000000;
+    const S_CONTEXT: u64 = 1;
+
+    fn write(plic: &mut Plic, offset: u64, value: u64, interrupts: &mut BTreeSet<Interrupt>) {
+        plic.store(BASE + offset, 32, value, interrupts).unwrap();
+    }
+
+    fn read(plic: &mut Plic, offset: u64, interrupts: &mut BTreeSet<Interrupt>) -> u64 {
+        plic.load(BASE + offset, 32, interrupts).unwrap()
+    }
+
+    #[test]
+    fn test_enable_threshold_and_gateway() {
+        let mut plic = Plic::new(BASE);
+        let mut interrupts = BTreeSet::new();
+        let uart = plic.get_interrupt_notificator(ExternalInterrupt::UartInput);
+        let uart_id = ExternalInterrupt::UartInput.id();
+        let enables = INTERRUPT_ENABLES + ENABLES_STRIDE * S_CONTEXT;
+        let threshold = PRIORITY_THRESHOLDS + CONTEXT_STRIDE * S_CONTEXT;
+        let claim = CLAIM_COMPLETE + CONTEXT_STRIDE * S_CONTEXT;
+        write(&mut plic, uart_id * 4, 1, &mut interrupts);
+
+        // pending but masked: no line raised, nothing to claim
+        uart();
+        plic.process_pending_interrupts(&mut interrupts);
+        assert!(interrupts.is_empty());
+        assert_eq!(
+            read(&mut plic, INTERRUPT_PENDING_BITS, &mut interrupts),
+            1 << uart_id
+        );
+        assert_eq!(read(&mut plic, claim, &mut interrupts), 0);
+
+        // enabling it on the S-mode context raises SEIP only
+        write(&mut plic, enables, 1 << uart_id, &mut interrupts);
+        assert!(interrupts.contains(&Interrupt::SupervisorExternalInterrupt));
+        assert!(!interrupts.contains(&Interrupt::MachineExternalInterrupt));
+
+        // a threshold at the source priority masks it again
+        write(&mut plic, threshold, 1, &mut interrupts);
+        assert!(interrupts.is_empty());
+        write(&mut plic, threshold, 0, &mut interrupts);
+
+        assert_eq!(read(&mut plic, claim, &mut interrupts), uart_id);
+        assert!(interrupts.is_empty());
+
+        // a request while in service is held until completion
+        uart();
+        plic.process_pending_interrupts(&mut interrupts);
+        assert!(interrupts.is_empty());
+        assert_eq!(read(&mut plic, claim, &mut interrupts), 0);
+        write(&mut plic, claim, uart_id, &mut interrupts);
+        assert!(interrupts.contains(&Interrupt::SupervisorExternalInterrupt));
+        assert_eq!(read(&mut plic, claim, &mut interrupts), uart_id);
+        write(&mut plic, claim, uart_id, &mut interrupts);
+        assert!(interrupts.is_empty());
+    }
+}
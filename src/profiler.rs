@@ -0,0 +1,274 @@
+//! Guest profiler.
+//!
+//! Every completed basic block adds its instruction count (or, when sampling,
+//! the samples that fell inside it) to the call stack it ran under. Call
+//! stacks are shadow stacks rebuilt from the link register convention: a
+//! `jal`/`jalr` that writes ra (or t0) is a call, a `jalr x0` through ra (or
+//! t0) is a return. Each privilege mode keeps its own stack, so a trap into
+//! the kernel and the `sret` back leave the user program's stack intact.
+//!
+//! At exit the counts are written as folded stacks, one
+//! `mode;caller;...;function count` line per stack, which flamegraph.pl and
+//! inferno read directly, and a table of the hottest functions is printed.
+
+use crate::symbols::SymbolTable;
+use fxhash::FxHashMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::path::PathBuf;
+
+// deeper stacks lose their outermost frames; calls that never return, like a
+// kernel's scheduler loop, would otherwise grow the stack without bound
+const MAX_DEPTH: usize = 256;
+
+const MODE_NAMES: [&str; 4] = ["user", "supervisor", "hypervisor", "machine"];
+
+struct Frame {
+    entry: u64,
+    return_addr: u64,
+}
+
+pub struct Profiler {
+    path: PathBuf,
+    // sample every this many instructions, or count every one
+    interval: Option<u64>,
+    top: usize,
+    stacks: [Vec<Frame>; 4],
+    // interned (mode, callee entry addresses) so counting a block is a
+    // single map update
+    stack_ids: FxHashMap<(usize, Vec<u64>), usize>,
+    interned: Vec<(usize, Vec<u64>)>,
+    current: [Option<usize>; 4],
+    // (stack id, block start pc) -> instructions or samples
+    counts: FxHashMap<(usize, u64), u64>,
+}
+
+impl Profiler {
+    /// An `interval` of 0 counts every instruction instead of sampling.
+    pub fn new(path: PathBuf, interval: u64, top: usize) -> Self {
+        Self {
+            path,
+            interval: Some(interval).filter(|&n| n != 0),
+            top,
+            stacks: Default::default(),
+            stack_ids: FxHashMap::default(),
+            interned: Vec::new(),
+            current: [None; 4],
+            counts: FxHashMap::default(),
+        }
+    }
+
+    /// Account for `instructions` retired from the block at `start_pc`,
+    /// `cycle` being the cycle count after the last of them.
+    pub(crate) fn block(&mut self, mode: u64, start_pc: u64, instructions: u64, cycle: u64) {
+        let weight = match self.interval {
+            None => instructions,
+            Some(n) => cycle / n - (cycle - instructions) / n,
+        };
+        if weight == 0 {
+            return;
+        }
+        let mode = mode as usize & 3;
+        let id = match self.current[mode] {
+            Some(id) => id,
+            None => self.intern(mode),
+        };
+        *self.counts.entry((id, start_pc)).or_insert(0) += weight;
+    }
+
+    fn intern(&mut self, mode: usize) -> usize {
+        let key = (mode, self.stacks[mode].iter().map(|f| f.entry).collect());
+        let id = match self.stack_ids.get(&key) {
+            Some(&id) => id,
+            None => {
+                let id = self.interned.len();
+                self.interned.push(key.clone());
+                self.stack_ids.insert(key, id);
+                id
+            }
+        };
+        self.current[mode] = Some(id);
+        id
+    }
+
+    pub(crate) fn call(&mut self, mode: u64, entry: u64, return_addr: u64) {
+        let mode = mode as usize & 3;
+        let stack = &mut self.stacks[mode];
+        if stack.len() == MAX_DEPTH {
+            stack.remove(0);
+        }
+        stack.push(Frame { entry, return_addr });
+        self.current[mode] = None;
+    }
+
+    /// A return to `target` unwinds to the frame that called from there.
+    /// Returns matching no frame, as after a context switch, are ignored.
+    pub(crate) fn ret(&mut self, mode: u64, target: u64) {
+        let mode = mode as usize & 3;
+        let stack = &mut self.stacks[mode];
+        if let Some(depth) = stack.iter().rposition(|f| f.return_addr == target) {
+            stack.truncate(depth);
+            self.current[mode] = None;
+        }
+    }
+
+    fn name(symbols: &SymbolTable, addr: u64) -> String {
+        match symbols.lookup(addr) {
+            Some(location) => location.name.to_string(),
+            None => format!("0x{:x}", addr),
+        }
+    }
+
+    // The functions on a stack, outermost first. The innermost is the
+    // function the block belongs to: the callee on top of the stack, unless
+    // the block's symbol says otherwise, as after a tail call.
+    fn frames(&self, symbols: &SymbolTable, id: usize, start_pc: u64) -> Vec<String> {
+        let (_, entries) = &self.interned[id];
+        let mut frames: Vec<String> = entries.iter().map(|&e| Self::name(symbols, e)).collect();
+        let leaf = match symbols.lookup(start_pc) {
+            Some(location) => location.name.to_string(),
+            None if !frames.is_empty() => return frames,
+            None => format!("0x{:x}", start_pc),
+        };
+        if frames.last() != Some(&leaf) {
+            frames.push(leaf);
+        }
+        frames
+    }
+
+    /// `mode;caller;...;function count` lines, sorted.
+    pub fn folded(&self, symbols: &SymbolTable) -> String {
+        let mut stacks: BTreeMap<String, u64> = BTreeMap::new();
+        for (&(id, start_pc), &count) in &self.counts {
+            let mut frames = vec![MODE_NAMES[self.interned[id].0].to_string()];
+            frames.extend(self.frames(symbols, id, start_pc));
+            *stacks.entry(frames.join(";")).or_insert(0) += count;
+        }
+        stacks
+            .iter()
+            .map(|(stack, count)| format!("{} {}\n", stack, count))
+            .collect()
+    }
+
+    /// The `top` functions by self count, with their inclusive counts.
+    pub fn report(&self, symbols: &SymbolTable) -> String {
+        let mut self_counts: FxHashMap<String, u64> = FxHashMap::default();
+        let mut total_counts: FxHashMap<String, u64> = FxHashMap::default();
+        let mut total = 0;
+        for (&(id, start_pc), &count) in &self.counts {
+            let mut frames = self.frames(symbols, id, start_pc);
+            let leaf = frames.pop().unwrap_or_default();
+            // a recursive function counts once towards its total
+            let mut on_stack: BTreeSet<String> = frames.into_iter().collect();
+            on_stack.insert(leaf.clone());
+            for name in on_stack {
+                *total_counts.entry(name).or_insert(0) += count;
+            }
+            *self_counts.entry(leaf).or_insert(0) += count;
+            total += count;
+        }
+
+        let mut hot: Vec<(String, u64)> = self_counts.into_iter().collect();
+        hot.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        let unit = match self.interval {
+            None => "instructions".to_string(),
+            Some(n) => format!("samples, one every {} instructions", n),
+        };
+        let percent = |count: u64| 100.0 * count as f64 / total.max(1) as f64;
+        let mut report = format!("guest profile: {} {}\n", total, unit);
+        report += &format!(
+            "{:>7} {:>12} {:>7} {:>12}  function\n",
+            "self%", "self", "total%", "total"
+        );
+        for (name, count) in hot.into_iter().take(self.top) {
+            let inclusive = total_counts[&name];
+            report += &format!(
+                "{:>6.2}% {:>12} {:>6.2}% {:>12}  {}\n",
+                percent(count),
+                count,
+                percent(inclusive),
+                inclusive,
+                name
+            );
+        }
+        report
+    }
+
+    /// Writes the folded stacks and prints the report to stderr.
+    pub fn write(&self, symbols: &SymbolTable) -> io::Result<()> {
+        std::fs::write(&self.path, self.folded(symbols))?;
+        eprint!("{}", self.report(symbols));
+        eprintln!("folded stacks written to {}", self.path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::{ElfClass, ElfImage, Symbol, SymbolKind};
+
+    fn symbols() -> SymbolTable {
+        let symbol = |name: &str, addr, size| Symbol {
+            name: name.to_string(),
+            addr,
+            size,
+            kind: SymbolKind::Function,
+        };
+        let mut table = SymbolTable::default();
+        table.add_image(&ElfImage {
+            class: ElfClass::Elf64,
+            entry: 0x1000,
+            segments: Vec::new(),
+            sections: Vec::new(),
+            symbols: vec![
+                symbol("main", 0x1000, 0x100),
+                symbol("f", 0x2000, 0x100),
+                symbol("g", 0x3000, 0x100),
+            ],
+        });
+        table
+    }
+
+    #[test]
+    fn test_exact_profile() {
+        let mut profiler = Profiler::new(PathBuf::new(), 0, 2);
+        profiler.block(0, 0x1000, 4, 4);
+        profiler.call(0, 0x2000, 0x1010);
+        profiler.block(0, 0x2000, 10, 14);
+        profiler.call(0, 0x3000, 0x2024);
+        profiler.block(0, 0x3000, 30, 44);
+        profiler.ret(0, 0x2024);
+        profiler.block(0, 0x2024, 2, 46);
+        // a trap runs on the supervisor's own stack
+        profiler.block(1, 0x8000_0000, 5, 51);
+        profiler.ret(0, 0x1010);
+        profiler.block(0, 0x1010, 1, 52);
+        // a return to nowhere on the stack leaves it alone
+        profiler.ret(0, 0x4000);
+        profiler.block(0, 0x1014, 1, 53);
+
+        let symbols = symbols();
+        assert_eq!(
+            profiler.folded(&symbols),
+            "supervisor;0x80000000 5\n\
+             user;f 12\n\
+             user;f;g 30\n\
+             user;main 6\n"
+        );
+        let report = profiler.report(&symbols);
+        assert!(report.starts_with("guest profile: 53 instructions\n"));
+        assert!(report.contains(" 56.60%           30  56.60%           30  g\n"));
+        assert!(report.contains(" 22.64%           12  79.25%           42  f\n"));
+        assert!(!report.contains("main"));
+    }
+
+    #[test]
+    fn test_sampling() {
+        let mut profiler = Profiler::new(PathBuf::new(), 10, 10);
+        profiler.block(0, 0x1000, 4, 4);
+        profiler.block(0, 0x1000, 8, 12);
+        profiler.block(0, 0x2000, 25, 37);
+        assert_eq!(profiler.folded(&symbols()), "user;f 2\nuser;main 1\n");
+    }
+}
@@ -0,0 +1,284 @@
+//! Built-in SBI firmware.
+//!
+//! With `--sbi` the emulator takes the place of M-mode firmware such as
+//! OpenSBI: the payload starts in S-mode and its `ecall`s are serviced here
+//! instead of trapping into M-mode. Implements the base, TIME, IPI, RFENCE,
+//! HSM and SRST extensions of SBI v2.0 and the legacy extensions for a
+//! single hart.
+
+use crate::bus::Bus;
+use crate::cpu::*;
+use crate::csr::*;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+const SPEC_VERSION: u64 = 2 << 24; // v2.0
+const IMPL_ID: u64 = 0x7276_656d; // "rvem", not a registered implementation ID
+const IMPL_VERSION: u64 = 1;
+
+// extension IDs
+const EXT_LEGACY_SET_TIMER: u64 = 0x00;
+const EXT_LEGACY_CONSOLE_PUTCHAR: u64 = 0x01;
+const EXT_LEGACY_CONSOLE_GETCHAR: u64 = 0x02;
+const EXT_LEGACY_CLEAR_IPI: u64 = 0x03;
+const EXT_LEGACY_SEND_IPI: u64 = 0x04;
+const EXT_LEGACY_REMOTE_FENCE_I: u64 = 0x05;
+const EXT_LEGACY_REMOTE_SFENCE_VMA: u64 = 0x06;
+const EXT_LEGACY_REMOTE_SFENCE_VMA_ASID: u64 = 0x07;
+const EXT_LEGACY_SHUTDOWN: u64 = 0x08;
+const EXT_BASE: u64 = 0x10;
+const EXT_TIME: u64 = 0x5449_4d45;
+const EXT_IPI: u64 = 0x73_5049;
+const EXT_RFENCE: u64 = 0x5246_4e43;
+const EXT_HSM: u64 = 0x48_534d;
+const EXT_SRST: u64 = 0x5352_5354;
+
+// error codes
+const SUCCESS: i64 = 0;
+const ERR_FAILED: i64 = -1;
+const ERR_NOT_SUPPORTED: i64 = -2;
+const ERR_INVALID_PARAM: i64 = -3;
+const ERR_ALREADY_AVAILABLE: i64 = -6;
+
+// HSM
+const HART_STATE_STARTED: u64 = 0;
+const SUSPEND_DEFAULT_RETENTIVE: u64 = 0x0000_0000;
+const SUSPEND_DEFAULT_NON_RETENTIVE: u64 = 0x8000_0000;
+
+// SRST
+const RESET_TYPE_SHUTDOWN: u64 = 0;
+const RESET_TYPE_WARM_REBOOT: u64 = 2;
+const RESET_REASON_SYSTEM_FAILURE: u64 = 1;
+
+const HART_ID: u64 = 0;
+
+// exceptions the payload handles itself: everything but ecalls from S- and M-mode
+const DELEGATED_EXCEPTIONS: u64 = 0xb1ff;
+const DELEGATED_INTERRUPTS: u64 = (1 << 1) | (1 << 5) | (1 << 9); // SSIP, STIP, SEIP
+
+/// A system reset requested by the payload; the emulator stops running.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SystemReset {
+    pub reset_type: u32,
+    pub reason: u32,
+}
+
+impl SystemReset {
+    pub fn is_failure(&self) -> bool {
+        self.reason as u64 == RESET_REASON_SYSTEM_FAILURE
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Sbi {
+    pub reset: Option<SystemReset>,
+}
+
+/// Prepare the machine like M-mode firmware would and enter the payload at
+/// the current pc in S-mode with the hart ID in a0 and the DTB address in a1.
+pub fn enter_payload(cpu: &mut Cpu, dtb_addr: u64) {
+    cpu.sbi = Some(Sbi::default());
+    cpu.csr.store_csrs(MEDELEG, DELEGATED_EXCEPTIONS);
+    cpu.csr.store_csrs(MIDELEG, DELEGATED_INTERRUPTS);
+    // like OpenSBI, open all of memory to S- and U-mode
+    cpu.csr.store_csrs(PMPADDR0, u64::MAX);
+    cpu.csr
+        .store_csrs(PMPCFG0, PMP_NAPOT | PMP_X | PMP_W | PMP_R);
+    cpu.regs[10] = HART_ID;
+    cpu.regs[11] = dtb_addr;
+    cpu.mode = S_MODE;
+    info!(
+        "SBI: entering the payload at 0x{:x} in S-mode, dtb at 0x{:x}",
+        cpu.pc, dtb_addr
+    );
+}
+
+/// Service an `ecall` from S-mode. The extension ID is in a7, the function
+/// ID in a6 and the arguments in a0-a5; the error code is returned in a0
+/// and the value in a1. Legacy extensions return their result in a0 only.
+pub fn handle_ecall(cpu: &mut Cpu, bus: &mut Bus) {
+    let eid = cpu.regs[17];
+    let fid = cpu.regs[16];
+    let args = [
+        cpu.regs[10],
+        cpu.regs[11],
+        cpu.regs[12],
+        cpu.regs[13],
+        cpu.regs[14],
+        cpu.regs[15],
+    ];
+    debug!("SBI: eid=0x{:x} fid={} args={:x?}", eid, fid, args);
+
+    if eid <= EXT_LEGACY_SHUTDOWN {
+        cpu.regs[10] = legacy_call(cpu, bus, eid, args) as u64;
+        return;
+    }
+    let (error, value) = match eid {
+        EXT_BASE => base_call(fid, args),
+        EXT_TIME if fid == 0 => {
+            set_timer(cpu, args[0]);
+            (SUCCESS, 0)
+        }
+        EXT_IPI if fid == 0 => match targets_hart(args[0], args[1]) {
+            Ok(true) => {
+                raise_software_interrupt(cpu);
+                (SUCCESS, 0)
+            }
+            Ok(false) => (SUCCESS, 0),
+            Err(error) => (error, 0),
+        },
+        // with a single hart every remote fence is a local one
+        EXT_RFENCE if fid <= 6 => match targets_hart(args[0], args[1]) {
+            Ok(true) => {
+                flush_caches(cpu);
+                (SUCCESS, 0)
+            }
+            Ok(false) => (SUCCESS, 0),
+            Err(error) => (error, 0),
+        },
+        EXT_HSM => hsm_call(cpu, fid, args),
+        EXT_SRST if fid == 0 => system_reset(cpu, args[0], args[1]),
+        _ => {
+            warn!("SBI: unsupported call eid=0x{:x} fid={}", eid, fid);
+            (ERR_NOT_SUPPORTED, 0)
+        }
+    };
+    cpu.regs[10] = error as u64;
+    if error == SUCCESS {
+        cpu.regs[11] = value;
+    }
+}
+
+fn is_supported(eid: u64) -> bool {
+    eid <= EXT_LEGACY_SHUTDOWN
+        || matches!(
+            eid,
+            EXT_BASE | EXT_TIME | EXT_IPI | EXT_RFENCE | EXT_HSM | EXT_SRST
+        )
+}
+
+fn base_call(fid: u64, args: [u64; 6]) -> (i64, u64) {
+    match fid {
+        0 => (SUCCESS, SPEC_VERSION),
+        1 => (SUCCESS, IMPL_ID),
+        2 => (SUCCESS, IMPL_VERSION),
+        3 => (SUCCESS, is_supported(args[0]) as u64),
+        // mvendorid, marchid and mimpid are all 0
+        4..=6 => (SUCCESS, 0),
+        _ => (ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+fn legacy_call(cpu: &mut Cpu, bus: &mut Bus, eid: u64, args: [u64; 6]) -> i64 {
+    match eid {
+        EXT_LEGACY_SET_TIMER => {
+            set_timer(cpu, args[0]);
+            SUCCESS
+        }
+        EXT_LEGACY_CONSOLE_PUTCHAR => {
+            bus.uart.transmit(args[0] as u8);
+            SUCCESS
+        }
+        EXT_LEGACY_CONSOLE_GETCHAR => bus.uart.receive().map_or(-1, |ch| ch as i64),
+        EXT_LEGACY_CLEAR_IPI => {
+            let pending = cpu.csr.software_pending_bits();
+            cpu.csr.store_csrs(MIP, pending & !MIP_SSIP);
+            SUCCESS
+        }
+        // the hart mask is passed by address; 0 means every hart
+        EXT_LEGACY_SEND_IPI
+        | EXT_LEGACY_REMOTE_FENCE_I
+        | EXT_LEGACY_REMOTE_SFENCE_VMA
+        | EXT_LEGACY_REMOTE_SFENCE_VMA_ASID => {
+            let mask = if args[0] == 0 {
+                1 << HART_ID
+            } else {
+                match cpu.load(bus, args[0], 64) {
+                    Ok(mask) => mask,
+                    Err(_) => return ERR_INVALID_PARAM,
+                }
+            };
+            if mask & (1 << HART_ID) != 0 {
+                if eid == EXT_LEGACY_SEND_IPI {
+                    raise_software_interrupt(cpu);
+                } else {
+                    flush_caches(cpu);
+                }
+            }
+            SUCCESS
+        }
+        EXT_LEGACY_SHUTDOWN => system_reset(cpu, RESET_TYPE_SHUTDOWN, 0).0,
+        _ => ERR_NOT_SUPPORTED,
+    }
+}
+
+/// Whether a hart mask selects hart 0. A base of all ones selects every hart.
+fn targets_hart(mask: u64, base: u64) -> Result<bool, i64> {
+    if base == u64::MAX {
+        return Ok(true);
+    }
+    if mask == 0 {
+        return Ok(false);
+    }
+    if base != HART_ID || mask & !1 != 0 {
+        return Err(ERR_INVALID_PARAM);
+    }
+    Ok(true)
+}
+
+fn set_timer(cpu: &mut Cpu, stime_value: u64) {
+    // STIP follows stimecmp, so programming it also clears a pending timer
+    cpu.csr.store_csrs(STIMECMP, stime_value);
+}
+
+fn raise_software_interrupt(cpu: &mut Cpu) {
+    let pending = cpu.csr.software_pending_bits();
+    cpu.csr.store_csrs(MIP, pending | MIP_SSIP);
+}
+
+fn flush_caches(cpu: &mut Cpu) {
+    cpu.tlb.flush(None, None);
+    cpu.clear_block_cache();
+}
+
+fn hsm_call(cpu: &mut Cpu, fid: u64, args: [u64; 6]) -> (i64, u64) {
+    match fid {
+        // hart_start: the only hart is already running
+        0 if args[0] == HART_ID => (ERR_ALREADY_AVAILABLE, 0),
+        // hart_stop: the last running hart cannot stop
+        1 => (ERR_FAILED, 0),
+        2 if args[0] == HART_ID => (SUCCESS, HART_STATE_STARTED),
+        0 | 2 => (ERR_INVALID_PARAM, 0),
+        // hart_suspend: with no other hart to wait for, wake up at once
+        3 => match args[0] {
+            SUSPEND_DEFAULT_RETENTIVE => (SUCCESS, 0),
+            SUSPEND_DEFAULT_NON_RETENTIVE => {
+                // resume at resume_addr as if the hart had just been started
+                cpu.csr.store_csrs(SATP, 0);
+                cpu.csr.set_sstatus_bit(0, MASK_SIE, BIT_SIE);
+                cpu.pc = args[1].wrapping_sub(4);
+                cpu.regs[11] = args[2];
+                (SUCCESS, args[2])
+            }
+            _ => (ERR_INVALID_PARAM, 0),
+        },
+        _ => (ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+fn system_reset(cpu: &mut Cpu, reset_type: u64, reason: u64) -> (i64, u64) {
+    if reset_type > RESET_TYPE_WARM_REBOOT || reason > RESET_REASON_SYSTEM_FAILURE {
+        return (ERR_INVALID_PARAM, 0);
+    }
+    info!(
+        "SBI: system reset requested, type {} reason {}",
+        reset_type, reason
+    );
+    if let Some(sbi) = cpu.sbi.as_mut() {
+        sbi.reset = Some(SystemReset {
+            reset_type: reset_type as u32,
+            reason: reason as u32,
+        });
+    }
+    (SUCCESS, 0)
+}
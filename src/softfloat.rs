@@ -0,0 +1,682 @@
+//! Software IEEE-754 arithmetic for the F and D extensions.
+//!
+//! Values are passed around as raw bit patterns and every operation ORs its
+//! exception flags (in `fflags` layout) into `flags`, so results do not depend
+//! on the host FPU or its rounding state.
+
+pub const FLAG_NX: u64 = 1 << 0; // inexact
+pub const FLAG_UF: u64 = 1 << 1; // underflow
+pub const FLAG_OF: u64 = 1 << 2; // overflow
+pub const FLAG_DZ: u64 = 1 << 3; // divide by zero
+pub const FLAG_NV: u64 = 1 << 4; // invalid operation
+
+pub const RM_RNE: u64 = 0b000;
+pub const RM_RTZ: u64 = 0b001;
+pub const RM_RDN: u64 = 0b010;
+pub const RM_RUP: u64 = 0b011;
+pub const RM_RMM: u64 = 0b100;
+pub const RM_DYN: u64 = 0b111;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Format {
+    exp_bits: u32,
+    frac_bits: u32,
+}
+
+pub const F32: Format = Format {
+    exp_bits: 8,
+    frac_bits: 23,
+};
+pub const F64: Format = Format {
+    exp_bits: 11,
+    frac_bits: 52,
+};
+
+enum Kind {
+    Nan,
+    Inf,
+    // value = sig * 2^exp, zero when sig == 0
+    Finite(i32, u64),
+}
+
+fn shift_right_jam(x: u128, s: u32) -> u128 {
+    if s == 0 {
+        x
+    } else if s >= 128 {
+        (x != 0) as u128
+    } else {
+        (x >> s) | ((x & ((1u128 << s) - 1)) != 0) as u128
+    }
+}
+
+/// Shift `sig` right by `s` bits rounding according to `rm`.
+/// `sig` must be below 2^126 so that a clamped shift still rounds correctly.
+/// Returns the rounded value and whether any bits were lost.
+fn round_shift(sig: u128, s: i32, sign: bool, rm: u64) -> (u128, bool) {
+    if s <= 0 {
+        return (sig << (-s) as u32, false);
+    }
+    let s = s.min(127) as u32;
+    let m = sig >> s;
+    let lost = sig & ((1u128 << s) - 1);
+    let half = 1u128 << (s - 1);
+    let inc = match rm {
+        RM_RNE => lost > half || (lost == half && m & 1 == 1),
+        RM_RDN => sign && lost != 0,
+        RM_RUP => !sign && lost != 0,
+        RM_RMM => lost >= half,
+        _ => false,
+    };
+    (m + inc as u128, lost != 0)
+}
+
+fn isqrt(n: u128) -> (u128, u128) {
+    let mut rem = n;
+    let mut root = 0u128;
+    let mut bit = 1u128 << 126;
+    while bit > n {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if rem >= root + bit {
+            rem -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    (root, rem)
+}
+
+/// Shift `sig` left so that its leading one sits at bit `pos`.
+fn normalize(exp: i32, sig: u128, pos: u32) -> (i32, u128) {
+    let msb = 127 - sig.leading_zeros();
+    let s = pos - msb;
+    (exp - s as i32, sig << s)
+}
+
+impl Format {
+    fn bias(self) -> i32 {
+        (1 << (self.exp_bits - 1)) - 1
+    }
+
+    fn emin(self) -> i32 {
+        1 - self.bias()
+    }
+
+    fn exp_max(self) -> u64 {
+        (1 << self.exp_bits) - 1
+    }
+
+    fn sign_bit(self) -> u64 {
+        1 << (self.exp_bits + self.frac_bits)
+    }
+
+    fn frac_mask(self) -> u64 {
+        (1 << self.frac_bits) - 1
+    }
+
+    fn quiet_bit(self) -> u64 {
+        1 << (self.frac_bits - 1)
+    }
+
+    pub fn canonical_nan(self) -> u64 {
+        (self.exp_max() << self.frac_bits) | self.quiet_bit()
+    }
+
+    fn inf(self, sign: bool) -> u64 {
+        self.zero(sign) | (self.exp_max() << self.frac_bits)
+    }
+
+    fn zero(self, sign: bool) -> u64 {
+        if sign {
+            self.sign_bit()
+        } else {
+            0
+        }
+    }
+
+    fn max_finite(self, sign: bool) -> u64 {
+        self.inf(sign) - 1
+    }
+
+    fn sign(self, a: u64) -> bool {
+        a & self.sign_bit() != 0
+    }
+
+    pub fn negate(self, a: u64) -> u64 {
+        a ^ self.sign_bit()
+    }
+
+    fn exp_field(self, a: u64) -> u64 {
+        (a >> self.frac_bits) & self.exp_max()
+    }
+
+    pub fn is_nan(self, a: u64) -> bool {
+        self.exp_field(a) == self.exp_max() && a & self.frac_mask() != 0
+    }
+
+    pub fn is_snan(self, a: u64) -> bool {
+        self.is_nan(a) && a & self.quiet_bit() == 0
+    }
+
+    fn is_zero(self, a: u64) -> bool {
+        a & !self.sign_bit() == 0
+    }
+
+    fn unpack(self, a: u64) -> (bool, Kind) {
+        let sign = self.sign(a);
+        let exp = self.exp_field(a);
+        let frac = a & self.frac_mask();
+        let kind = if exp == self.exp_max() {
+            if frac == 0 {
+                Kind::Inf
+            } else {
+                Kind::Nan
+            }
+        } else if exp == 0 {
+            Kind::Finite(self.emin() - self.frac_bits as i32, frac)
+        } else {
+            Kind::Finite(
+                exp as i32 - self.bias() - self.frac_bits as i32,
+                frac | (1 << self.frac_bits),
+            )
+        };
+        (sign, kind)
+    }
+
+    fn propagate_nan(self, operands: &[u64], flags: &mut u64) -> u64 {
+        if operands.iter().any(|&x| self.is_snan(x)) {
+            *flags |= FLAG_NV;
+        }
+        self.canonical_nan()
+    }
+
+    fn invalid(self, flags: &mut u64) -> u64 {
+        *flags |= FLAG_NV;
+        self.canonical_nan()
+    }
+
+    /// Round the exact value `(-1)^sign * sig * 2^exp` to this format.
+    fn round_pack(self, sign: bool, exp: i32, sig: u128, rm: u64, flags: &mut u64) -> u64 {
+        if sig == 0 {
+            return self.zero(sign);
+        }
+        // put the leading one at bit 125, folding anything shifted out into a sticky bit
+        let msb = 127 - sig.leading_zeros();
+        let (exp, sig) = if msb > 125 {
+            (exp + (msb - 125) as i32, shift_right_jam(sig, msb - 125))
+        } else {
+            normalize(exp, sig, 125)
+        };
+        let fb = self.frac_bits as i32;
+        let e = exp + 125;
+        let mut q = e.max(self.emin()) - fb;
+        let (mut m, inexact) = round_shift(sig, q - exp, sign, rm);
+        if m >> (fb + 1) != 0 {
+            m >>= 1;
+            q += 1;
+        }
+        if inexact {
+            *flags |= FLAG_NX;
+            // tininess is detected after rounding: a value just below the
+            // smallest normal that rounds up to it is not tiny
+            if e < self.emin() {
+                let (unbounded, _) = round_shift(sig, e - fb - exp, sign, rm);
+                if e < self.emin() - 1 || unbounded >> (fb + 1) == 0 {
+                    *flags |= FLAG_UF;
+                }
+            }
+        }
+        let m = m as u64;
+        if m >> fb == 0 {
+            return self.zero(sign) | m;
+        }
+        let biased = (q + fb + self.bias()) as u64;
+        if biased >= self.exp_max() {
+            *flags |= FLAG_OF | FLAG_NX;
+            let to_inf = match rm {
+                RM_RTZ => false,
+                RM_RDN => sign,
+                RM_RUP => !sign,
+                _ => true,
+            };
+            return if to_inf {
+                self.inf(sign)
+            } else {
+                self.max_finite(sign)
+            };
+        }
+        self.zero(sign) | (biased << fb) | (m & self.frac_mask())
+    }
+
+    /// Exact sum of two finite values (significands below 2^107), then rounded.
+    fn add_finite(
+        self,
+        (sa, ea, ma): (bool, i32, u128),
+        (sb, eb, mb): (bool, i32, u128),
+        rm: u64,
+        flags: &mut u64,
+    ) -> u64 {
+        if ma == 0 && mb == 0 {
+            return self.zero(if sa == sb { sa } else { rm == RM_RDN });
+        }
+        if ma == 0 {
+            return self.round_pack(sb, eb, mb, rm, flags);
+        }
+        if mb == 0 {
+            return self.round_pack(sa, ea, ma, rm, flags);
+        }
+        let (ea, ma) = normalize(ea, ma, 106);
+        let (eb, mb) = normalize(eb, mb, 106);
+        let ((sa, ea, ma), (sb, eb, mb)) = if ea >= eb {
+            ((sa, ea, ma), (sb, eb, mb))
+        } else {
+            ((sb, eb, mb), (sa, ea, ma))
+        };
+        // give the larger operand 20 bits of headroom and align the smaller one to it
+        let (ea, ma) = (ea - 20, ma << 20);
+        let d = eb - ea;
+        let mb = if d >= 0 {
+            mb << d
+        } else {
+            shift_right_jam(mb, (-d) as u32)
+        };
+        if sa == sb {
+            self.round_pack(sa, ea, ma + mb, rm, flags)
+        } else if ma > mb {
+            self.round_pack(sa, ea, ma - mb, rm, flags)
+        } else if mb > ma {
+            self.round_pack(sb, ea, mb - ma, rm, flags)
+        } else {
+            self.zero(rm == RM_RDN)
+        }
+    }
+
+    pub fn add(self, a: u64, b: u64, rm: u64, flags: &mut u64) -> u64 {
+        match (self.unpack(a), self.unpack(b)) {
+            ((_, Kind::Nan), _) | (_, (_, Kind::Nan)) => self.propagate_nan(&[a, b], flags),
+            ((sa, Kind::Inf), (sb, Kind::Inf)) => {
+                if sa == sb {
+                    a
+                } else {
+                    self.invalid(flags)
+                }
+            }
+            ((_, Kind::Inf), _) => a,
+            (_, (_, Kind::Inf)) => b,
+            ((sa, Kind::Finite(ea, ma)), (sb, Kind::Finite(eb, mb))) => {
+                self.add_finite((sa, ea, ma as u128), (sb, eb, mb as u128), rm, flags)
+            }
+        }
+    }
+
+    pub fn sub(self, a: u64, b: u64, rm: u64, flags: &mut u64) -> u64 {
+        self.add(a, self.negate(b), rm, flags)
+    }
+
+    pub fn mul(self, a: u64, b: u64, rm: u64, flags: &mut u64) -> u64 {
+        let sign = self.sign(a) != self.sign(b);
+        match (self.unpack(a), self.unpack(b)) {
+            ((_, Kind::Nan), _) | (_, (_, Kind::Nan)) => self.propagate_nan(&[a, b], flags),
+            ((_, Kind::Inf), (_, Kind::Finite(_, 0)))
+            | ((_, Kind::Finite(_, 0)), (_, Kind::Inf)) => self.invalid(flags),
+            ((_, Kind::Inf), _) | (_, (_, Kind::Inf)) => self.inf(sign),
+            ((_, Kind::Finite(ea, ma)), (_, Kind::Finite(eb, mb))) => {
+                self.round_pack(sign, ea + eb, ma as u128 * mb as u128, rm, flags)
+            }
+        }
+    }
+
+    pub fn div(self, a: u64, b: u64, rm: u64, flags: &mut u64) -> u64 {
+        let sign = self.sign(a) != self.sign(b);
+        match (self.unpack(a), self.unpack(b)) {
+            ((_, Kind::Nan), _) | (_, (_, Kind::Nan)) => self.propagate_nan(&[a, b], flags),
+            ((_, Kind::Inf), (_, Kind::Inf)) => self.invalid(flags),
+            ((_, Kind::Inf), _) => self.inf(sign),
+            (_, (_, Kind::Inf)) => self.zero(sign),
+            ((_, Kind::Finite(_, 0)), (_, Kind::Finite(_, 0))) => self.invalid(flags),
+            ((_, Kind::Finite(_, 0)), _) => self.zero(sign),
+            (_, (_, Kind::Finite(_, 0))) => {
+                *flags |= FLAG_DZ;
+                self.inf(sign)
+            }
+            ((_, Kind::Finite(ea, ma)), (_, Kind::Finite(eb, mb))) => {
+                let (ea, ma) = normalize(ea, ma as u128, 63);
+                let (eb, mb) = normalize(eb, mb as u128, 63);
+                let dividend = ma << 64;
+                let quot = dividend / mb;
+                let sticky = (dividend % mb != 0) as u128;
+                self.round_pack(sign, ea - eb - 65, (quot << 1) | sticky, rm, flags)
+            }
+        }
+    }
+
+    pub fn sqrt(self, a: u64, rm: u64, flags: &mut u64) -> u64 {
+        match self.unpack(a) {
+            (_, Kind::Nan) => self.propagate_nan(&[a], flags),
+            (_, Kind::Finite(_, 0)) => a,
+            (true, _) => self.invalid(flags),
+            (false, Kind::Inf) => a,
+            (false, Kind::Finite(exp, sig)) => {
+                let (mut exp, mut sig) = normalize(exp, sig as u128, 124);
+                if exp & 1 != 0 {
+                    exp -= 1;
+                    sig <<= 1;
+                }
+                let (root, rem) = isqrt(sig);
+                self.round_pack(
+                    false,
+                    exp / 2 - 1,
+                    (root << 1) | (rem != 0) as u128,
+                    rm,
+                    flags,
+                )
+            }
+        }
+    }
+
+    /// Fused `a * b + c` with a single rounding.
+    pub fn mul_add(self, a: u64, b: u64, c: u64, rm: u64, flags: &mut u64) -> u64 {
+        let (sa, ka) = self.unpack(a);
+        let (sb, kb) = self.unpack(b);
+        let (sc, kc) = self.unpack(c);
+        let sp = sa != sb;
+        let inf_times_zero = matches!(
+            (&ka, &kb),
+            (Kind::Inf, Kind::Finite(_, 0)) | (Kind::Finite(_, 0), Kind::Inf)
+        );
+        if matches!(ka, Kind::Nan) || matches!(kb, Kind::Nan) || matches!(kc, Kind::Nan) {
+            // inf * 0 is invalid even when the addend is a quiet NaN
+            if inf_times_zero {
+                *flags |= FLAG_NV;
+            }
+            return self.propagate_nan(&[a, b, c], flags);
+        }
+        if inf_times_zero {
+            return self.invalid(flags);
+        }
+        match (ka, kb, kc) {
+            (Kind::Inf, _, Kind::Inf) | (_, Kind::Inf, Kind::Inf) if sp != sc => {
+                self.invalid(flags)
+            }
+            (Kind::Inf, _, _) | (_, Kind::Inf, _) => self.inf(sp),
+            (_, _, Kind::Inf) => c,
+            (Kind::Finite(ea, ma), Kind::Finite(eb, mb), Kind::Finite(ec, mc)) => self.add_finite(
+                (sp, ea + eb, ma as u128 * mb as u128),
+                (sc, ec, mc as u128),
+                rm,
+                flags,
+            ),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Ordering used by min/max, where -0 is below +0. Neither operand may be NaN.
+    fn less_signed_zero(self, a: u64, b: u64) -> bool {
+        let (sa, sb) = (self.sign(a), self.sign(b));
+        if sa != sb {
+            sa
+        } else if sa {
+            a > b
+        } else {
+            a < b
+        }
+    }
+
+    fn min_max(self, a: u64, b: u64, want_min: bool, flags: &mut u64) -> u64 {
+        if self.is_snan(a) || self.is_snan(b) {
+            *flags |= FLAG_NV;
+        }
+        match (self.is_nan(a), self.is_nan(b)) {
+            (true, true) => self.canonical_nan(),
+            (true, false) => b,
+            (false, true) => a,
+            (false, false) => {
+                if self.less_signed_zero(a, b) == want_min {
+                    a
+                } else {
+                    b
+                }
+            }
+        }
+    }
+
+    pub fn min(self, a: u64, b: u64, flags: &mut u64) -> u64 {
+        self.min_max(a, b, true, flags)
+    }
+
+    pub fn max(self, a: u64, b: u64, flags: &mut u64) -> u64 {
+        self.min_max(a, b, false, flags)
+    }
+
+    /// Quiet comparison: only signaling NaNs raise the invalid flag.
+    pub fn eq(self, a: u64, b: u64, flags: &mut u64) -> bool {
+        if self.is_nan(a) || self.is_nan(b) {
+            if self.is_snan(a) || self.is_snan(b) {
+                *flags |= FLAG_NV;
+            }
+            return false;
+        }
+        a == b || (self.is_zero(a) && self.is_zero(b))
+    }
+
+    /// Signaling comparison: any NaN raises the invalid flag.
+    pub fn lt(self, a: u64, b: u64, flags: &mut u64) -> bool {
+        if self.is_nan(a) || self.is_nan(b) {
+            *flags |= FLAG_NV;
+            return false;
+        }
+        !(self.is_zero(a) && self.is_zero(b)) && self.less_signed_zero(a, b)
+    }
+
+    /// Signaling comparison: any NaN raises the invalid flag.
+    pub fn le(self, a: u64, b: u64, flags: &mut u64) -> bool {
+        if self.is_nan(a) || self.is_nan(b) {
+            *flags |= FLAG_NV;
+            return false;
+        }
+        a == b || (self.is_zero(a) && self.is_zero(b)) || self.less_signed_zero(a, b)
+    }
+
+    /// The 10-bit mask written by fclass.s/fclass.d.
+    pub fn classify(self, a: u64) -> u64 {
+        let sign = self.sign(a);
+        let bit = match self.unpack(a) {
+            (_, Kind::Nan) => {
+                if self.is_snan(a) {
+                    8
+                } else {
+                    9
+                }
+            }
+            (_, Kind::Inf) => 0,
+            (_, Kind::Finite(_, 0)) => 3,
+            _ if self.exp_field(a) == 0 => 2,
+            _ => 1,
+        };
+        let bit = if sign || bit >= 8 { bit } else { 7 - bit };
+        1 << bit
+    }
+
+    /// Convert to a `width`-bit integer. Out-of-range values and NaNs saturate
+    /// and raise the invalid flag; 32-bit results are sign-extended to 64 bits.
+    pub fn to_int(self, a: u64, signed: bool, width: u32, rm: u64, flags: &mut u64) -> u64 {
+        let max_pos: u128 = if signed {
+            (1 << (width - 1)) - 1
+        } else {
+            (1 << width) - 1
+        };
+        let max_neg: u128 = if signed { 1 << (width - 1) } else { 0 };
+        let extend = |v: u64| {
+            if width == 32 {
+                v as i32 as i64 as u64
+            } else {
+                v
+            }
+        };
+        let mut saturate = |negative: bool| {
+            *flags |= FLAG_NV;
+            if negative {
+                extend((max_neg as u64).wrapping_neg())
+            } else {
+                extend(max_pos as u64)
+            }
+        };
+        let (sign, exp, sig) = match self.unpack(a) {
+            (_, Kind::Nan) => return saturate(false),
+            (sign, Kind::Inf) => return saturate(sign),
+            (sign, Kind::Finite(exp, sig)) => (sign, exp, sig),
+        };
+        if exp > 64 {
+            return saturate(sign);
+        }
+        let (mag, inexact) = round_shift(sig as u128, -exp, sign, rm);
+        if (sign && mag > max_neg) || (!sign && mag > max_pos) {
+            return saturate(sign);
+        }
+        if inexact {
+            *flags |= FLAG_NX;
+        }
+        let mag = mag as u64;
+        extend(if sign { mag.wrapping_neg() } else { mag })
+    }
+
+    /// Convert a signed or unsigned 64-bit integer given as sign and magnitude.
+    pub fn int_to_float(self, negative: bool, magnitude: u64, rm: u64, flags: &mut u64) -> u64 {
+        self.round_pack(negative, 0, magnitude as u128, rm, flags)
+    }
+
+    /// Convert a value of format `from` into this format.
+    pub fn convert_from(self, from: Format, a: u64, rm: u64, flags: &mut u64) -> u64 {
+        match from.unpack(a) {
+            (_, Kind::Nan) => {
+                if from.is_snan(a) {
+                    *flags |= FLAG_NV;
+                }
+                self.canonical_nan()
+            }
+            (sign, Kind::Inf) => self.inf(sign),
+            (sign, Kind::Finite(exp, sig)) => self.round_pack(sign, exp, sig as u128, rm, flags),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn f32_op(
+        op: fn(Format, u64, u64, u64, &mut u64) -> u64,
+        a: f32,
+        b: f32,
+        rm: u64,
+    ) -> (u32, u64) {
+        let mut flags = 0;
+        let r = op(F32, a.to_bits() as u64, b.to_bits() as u64, rm, &mut flags);
+        (r as u32, flags)
+    }
+
+    #[test]
+    fn test_arithmetic_matches_host_in_round_to_nearest() {
+        let values = [
+            0.0f64,
+            -0.0,
+            1.0,
+            -1.5,
+            3.0,
+            0.1,
+            1e308,
+            -1e-310,
+            5e-324,
+            2.5e-308,
+            123456.789,
+            f64::MAX,
+            f64::MIN_POSITIVE,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ];
+        for &a in &values {
+            for &b in &values {
+                let mut flags = 0;
+                let (x, y) = (a.to_bits(), b.to_bits());
+                let check = |got: u64, want: f64| {
+                    if want.is_nan() {
+                        assert_eq!(got, F64.canonical_nan(), "{} {}", a, b);
+                    } else {
+                        assert_eq!(got, want.to_bits(), "{} {} -> {}", a, b, want);
+                    }
+                };
+                check(F64.add(x, y, RM_RNE, &mut flags), a + b);
+                check(F64.sub(x, y, RM_RNE, &mut flags), a - b);
+                check(F64.mul(x, y, RM_RNE, &mut flags), a * b);
+                check(F64.div(x, y, RM_RNE, &mut flags), a / b);
+                check(F64.mul_add(x, y, y, RM_RNE, &mut flags), a.mul_add(b, b));
+                check(F64.sqrt(x, RM_RNE, &mut flags), a.sqrt());
+            }
+        }
+    }
+
+    #[test]
+    fn test_rounding_modes_and_flags() {
+        // 1 + 2^-24 is not representable in binary32
+        let tiny = f32::from_bits(0x3380_0000);
+        assert_eq!(
+            f32_op(Format::add, 1.0, tiny, RM_RNE),
+            (0x3f80_0000, FLAG_NX)
+        );
+        assert_eq!(
+            f32_op(Format::add, 1.0, tiny, RM_RUP),
+            (0x3f80_0001, FLAG_NX)
+        );
+        assert_eq!(
+            f32_op(Format::add, -1.0, -tiny, RM_RDN),
+            (0xbf80_0001, FLAG_NX)
+        );
+        assert_eq!(
+            f32_op(Format::add, -1.0, -tiny, RM_RTZ),
+            (0xbf80_0000, FLAG_NX)
+        );
+        assert_eq!(
+            f32_op(Format::mul, f32::MAX, 2.0, RM_RTZ),
+            (f32::MAX.to_bits(), FLAG_OF | FLAG_NX)
+        );
+        assert_eq!(
+            f32_op(Format::div, 1.0, 0.0, RM_RNE),
+            (f32::INFINITY.to_bits(), FLAG_DZ)
+        );
+        assert_eq!(f32_op(Format::sub, 1.0, 1.0, RM_RDN), (0x8000_0000, 0));
+        assert_eq!(
+            f32_op(Format::mul, f32::MIN_POSITIVE, 0.5, RM_RNE),
+            (0x0040_0000, 0)
+        );
+        assert_eq!(
+            f32_op(Format::mul, f32::MIN_POSITIVE, 1.0 / 3.0, RM_RNE).1,
+            FLAG_UF | FLAG_NX
+        );
+    }
+
+    #[test]
+    fn test_conversions_saturate() {
+        let mut flags = 0;
+        let nan = F32.canonical_nan();
+        assert_eq!(F32.to_int(nan, true, 32, RM_RTZ, &mut flags), 0x7fff_ffff);
+        assert_eq!(flags, FLAG_NV);
+        flags = 0;
+        let m = (-1.0f32).to_bits() as u64;
+        assert_eq!(F32.to_int(m, false, 64, RM_RTZ, &mut flags), 0);
+        assert_eq!(flags, FLAG_NV);
+        flags = 0;
+        let x = (-2.5f64).to_bits();
+        assert_eq!(F64.to_int(x, true, 32, RM_RNE, &mut flags), (-2i64) as u64);
+        assert_eq!(F64.to_int(x, true, 32, RM_RMM, &mut flags), (-3i64) as u64);
+        assert_eq!(flags, FLAG_NX);
+        flags = 0;
+        assert_eq!(
+            F32.convert_from(F64, 0.1f64.to_bits(), RM_RNE, &mut flags),
+            0.1f32.to_bits() as u64
+        );
+        assert_eq!(flags, FLAG_NX);
+    }
+}
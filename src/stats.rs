@@ -0,0 +1,242 @@
+//! Execution statistics.
+//!
+//! Counts the instructions retired in each privilege mode and per opcode, the
+//! traps and interrupts taken per cause, SBI calls serviced in place, and how
+//! often address translation walked the page table rather than hitting the
+//! TLB. The counts are printed at exit, written as JSON, or
+//! shown from gdb with `monitor stats`.
+
+use crate::instruction::DecodedInstr;
+use crate::interrupt::{Exception, Interrupt};
+use fxhash::FxHashMap;
+use std::collections::BTreeMap;
+use std::io;
+use std::mem::Discriminant;
+use std::path::PathBuf;
+
+// indexed by privilege mode; there is no hypervisor mode
+const MODES: [(usize, &str); 3] = [(0, "user"), (1, "supervisor"), (3, "machine")];
+
+#[derive(Default)]
+pub struct Stats {
+    // print the report to stderr at exit
+    print: bool,
+    json: Option<PathBuf>,
+    instructions: [u64; 4],
+    // opcode -> (mnemonic, count), named after the first instruction seen
+    opcodes: FxHashMap<Discriminant<DecodedInstr>, (String, u64)>,
+    // cause code -> (name, count)
+    exceptions: BTreeMap<u64, (String, u64)>,
+    interrupts: BTreeMap<u64, (String, u64)>,
+    sbi_calls: u64,
+    page_walks: u64,
+    translation_cache_hits: u64,
+}
+
+// the variant name of an instruction or trap cause, without its fields
+fn variant_name<T: std::fmt::Debug>(value: &T) -> String {
+    let name = format!("{:?}", value);
+    let end = name
+        .find(|c: char| !c.is_ascii_alphanumeric())
+        .unwrap_or(name.len());
+    name[..end].to_string()
+}
+
+fn count(map: &mut BTreeMap<u64, (String, u64)>, code: u64, name: impl FnOnce() -> String) {
+    map.entry(code).or_insert_with(|| (name(), 0)).1 += 1;
+}
+
+fn percent(count: u64, total: u64) -> f64 {
+    100.0 * count as f64 / total.max(1) as f64
+}
+
+impl Stats {
+    pub fn new(print: bool, json: Option<PathBuf>) -> Self {
+        Self {
+            print,
+            json,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn instruction(&mut self, mode: u64, inst: &DecodedInstr) {
+        self.instructions[mode as usize & 3] += 1;
+        let opcode = std::mem::discriminant(inst);
+        match self.opcodes.get_mut(&opcode) {
+            Some((_, count)) => *count += 1,
+            None => {
+                let name = variant_name(inst).to_lowercase();
+                self.opcodes.insert(opcode, (name, 1));
+            }
+        }
+    }
+
+    pub(crate) fn exception(&mut self, exception: &Exception) {
+        count(&mut self.exceptions, exception.code(), || {
+            variant_name(exception)
+        });
+    }
+
+    pub(crate) fn interrupt(&mut self, interrupt: &Interrupt) {
+        count(&mut self.interrupts, interrupt.code(), || {
+            variant_name(interrupt)
+        });
+    }
+
+    pub(crate) fn sbi_call(&mut self) {
+        self.sbi_calls += 1;
+    }
+
+    pub(crate) fn page_walk(&mut self) {
+        self.page_walks += 1;
+    }
+
+    pub(crate) fn translation_cache_hit(&mut self) {
+        self.translation_cache_hits += 1;
+    }
+
+    fn total_instructions(&self) -> u64 {
+        self.instructions.iter().sum()
+    }
+
+    // opcodes by descending count, then name
+    fn opcodes(&self) -> Vec<(&str, u64)> {
+        let mut opcodes: Vec<(&str, u64)> = self
+            .opcodes
+            .values()
+            .map(|(name, count)| (name.as_str(), *count))
+            .collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        opcodes
+    }
+
+    pub fn report(&self) -> String {
+        let total = self.total_instructions();
+        let mut report = format!("instructions: {}\n", total);
+        for &(mode, name) in &MODES {
+            let count = self.instructions[mode];
+            report += &format!(
+                "  {:<12} {:>14} {:>6.2}%\n",
+                name,
+                count,
+                percent(count, total)
+            );
+        }
+        for (title, causes) in [
+            ("exceptions", &self.exceptions),
+            ("interrupts", &self.interrupts),
+        ] {
+            let taken: u64 = causes.values().map(|(_, count)| count).sum();
+            report += &format!("{}: {}\n", title, taken);
+            for (code, (name, count)) in causes {
+                report += &format!("  {:>2} {:<30} {:>10}\n", code, name, count);
+            }
+        }
+        report += &format!("sbi calls: {}\n", self.sbi_calls);
+        let translations = self.page_walks + self.translation_cache_hits;
+        report += &format!(
+            "address translations: {}\n  {:<12} {:>14} {:>6.2}%\n  {:<12} {:>14} {:>6.2}%\n",
+            translations,
+            "page walks",
+            self.page_walks,
+            percent(self.page_walks, translations),
+            "cached",
+            self.translation_cache_hits,
+            percent(self.translation_cache_hits, translations)
+        );
+        report += "opcodes:\n";
+        for (name, count) in self.opcodes() {
+            report += &format!(
+                "  {:<12} {:>14} {:>6.2}%\n",
+                name,
+                count,
+                percent(count, total)
+            );
+        }
+        report
+    }
+
+    pub fn to_json(&self) -> String {
+        let object = |fields: Vec<String>| format!("{{{}}}", fields.join(","));
+        let causes = |causes: &BTreeMap<u64, (String, u64)>| {
+            object(
+                causes
+                    .values()
+                    .map(|(name, count)| format!("\"{}\":{}", name, count))
+                    .collect(),
+            )
+        };
+        let instructions = object(
+            MODES
+                .iter()
+                .map(|&(mode, name)| format!("\"{}\":{}", name, self.instructions[mode]))
+                .collect(),
+        );
+        let opcodes = object(
+            self.opcodes()
+                .into_iter()
+                .map(|(name, count)| format!("\"{}\":{}", name, count))
+                .collect(),
+        );
+        object(vec![
+            format!("\"instructions\":{}", instructions),
+            format!("\"exceptions\":{}", causes(&self.exceptions)),
+            format!("\"interrupts\":{}", causes(&self.interrupts)),
+            format!("\"sbi_calls\":{}", self.sbi_calls),
+            format!("\"page_walks\":{}", self.page_walks),
+            format!("\"translation_cache_hits\":{}", self.translation_cache_hits),
+            format!("\"opcodes\":{}", opcodes),
+        ])
+    }
+
+    /// Prints the report and writes the JSON file, as configured.
+    pub fn write(&self) -> io::Result<()> {
+        if self.print {
+            eprint!("{}", self.report());
+        }
+        if let Some(path) = &self.json {
+            std::fs::write(path, self.to_json() + "\n")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts() {
+        let mut stats = Stats::default();
+        let addi = DecodedInstr::decode(0x00150513); // addi a0, a0, 1
+        let add = DecodedInstr::decode(0x00b50533); // add a0, a0, a1
+        stats.instruction(0, &addi);
+        stats.instruction(0, &addi);
+        stats.instruction(3, &add);
+        stats.instruction(1, &DecodedInstr::decode(0x00c58533)); // add a0, a1, a2
+        stats.exception(&Exception::IllegalInstruction(0));
+        stats.exception(&Exception::EnvironmentalCallFromUMode);
+        stats.exception(&Exception::IllegalInstruction(0x13));
+        stats.interrupt(&Interrupt::SupervisorTimerInterrupt);
+        stats.page_walk();
+        stats.translation_cache_hit();
+        stats.translation_cache_hit();
+        stats.translation_cache_hit();
+
+        assert_eq!(
+            stats.to_json(),
+            "{\"instructions\":{\"user\":2,\"supervisor\":1,\"machine\":1},\
+             \"exceptions\":{\"IllegalInstruction\":2,\"EnvironmentalCallFromUMode\":1},\
+             \"interrupts\":{\"SupervisorTimerInterrupt\":1},\
+             \"sbi_calls\":0,\"page_walks\":1,\"translation_cache_hits\":3,\
+             \"opcodes\":{\"add\":2,\"addi\":2}}"
+        );
+        let report = stats.report();
+        assert!(report.starts_with("instructions: 4\n  user                      2  50.00%\n"));
+        assert!(report.contains("   2 IllegalInstruction                      2\n"));
+        assert!(report.contains("  page walks                1  25.00%\n"));
+        assert!(report.ends_with(
+            "  add                       2  50.00%\n  addi                      2  50.00%\n"
+        ));
+    }
+}
@@ -0,0 +1,122 @@
+//! Symbol lookup for traces and crash reports.
+//!
+//! Symbols come from the ELF files the emulator is told about: the kernel it
+//! loaded plus any extra executables, such as user programs, given with
+//! `--symbols`. Tables are searched in the order they were added, so the
+//! kernel takes precedence where address ranges overlap.
+
+use crate::elf::{ElfImage, Symbol, SymbolKind};
+use std::fmt;
+use std::path::Path;
+
+#[derive(Default)]
+pub struct SymbolTable {
+    // one table per ELF file, each sorted by address
+    tables: Vec<Vec<Symbol>>,
+}
+
+/// A resolved address: the symbol it belongs to and the offset into it.
+pub struct Location<'a> {
+    pub name: &'a str,
+    pub offset: u64,
+}
+
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.offset == 0 {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{}+0x{:x}", self.name, self.offset)
+        }
+    }
+}
+
+impl SymbolTable {
+    pub fn add_image(&mut self, image: &ElfImage) {
+        if !image.symbols.is_empty() {
+            self.tables.push(image.symbols.clone());
+        }
+    }
+
+    pub fn add_file(&mut self, path: &Path) -> Result<(), String> {
+        self.add_image(&ElfImage::from_file(path)?);
+        Ok(())
+    }
+
+    /// The symbol `addr` falls in. A symbol without a size, such as an
+    /// assembly label, extends up to the next symbol.
+    pub fn lookup(&self, addr: u64) -> Option<Location<'_>> {
+        self.tables.iter().find_map(|symbols| {
+            let end = symbols.partition_point(|s| s.addr <= addr);
+            let candidates = symbols[..end]
+                .iter()
+                .rev()
+                .filter(|s| s.kind != SymbolKind::Object || s.size != 0);
+            let mut best: Option<&Symbol> = None;
+            for symbol in candidates {
+                if best.is_some_and(|b| b.addr != symbol.addr) {
+                    break;
+                }
+                // among aliases at the same address, prefer a function
+                if best.is_none_or(|b| {
+                    b.kind != SymbolKind::Function && symbol.kind == SymbolKind::Function
+                }) {
+                    best = Some(symbol);
+                }
+            }
+            let symbol = best?;
+            if symbol.size != 0 && addr - symbol.addr >= symbol.size {
+                return None;
+            }
+            Some(Location {
+                name: &symbol.name,
+                offset: addr - symbol.addr,
+            })
+        })
+    }
+
+    /// `addr` in hex followed by `<symbol+offset>` when it resolves.
+    pub fn describe(&self, addr: u64) -> String {
+        match self.lookup(addr) {
+            Some(location) => format!("0x{:x} <{}>", addr, location),
+            None => format!("0x{:x}", addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol(name: &str, addr: u64, size: u64, kind: SymbolKind) -> Symbol {
+        Symbol {
+            name: name.to_string(),
+            addr,
+            size,
+            kind,
+        }
+    }
+
+    #[test]
+    fn test_lookup() {
+        let mut table = SymbolTable::default();
+        table.tables.push(vec![
+            symbol("_entry", 0x8000_0000, 0, SymbolKind::Other),
+            symbol("start", 0x8000_0020, 0x40, SymbolKind::Function),
+            symbol("ticks", 0x8000_0080, 8, SymbolKind::Object),
+            symbol("trampoline", 0x8000_1000, 0, SymbolKind::Other),
+            symbol("uservec", 0x8000_1000, 0, SymbolKind::Function),
+        ]);
+        table
+            .tables
+            .push(vec![symbol("main", 0x0, 0x20, SymbolKind::Function)]);
+
+        assert_eq!(table.describe(0x8000_0004), "0x80000004 <_entry+0x4>");
+        assert_eq!(table.describe(0x8000_0020), "0x80000020 <start>");
+        assert_eq!(table.describe(0x8000_0064), "0x80000064");
+        assert_eq!(table.describe(0x8000_0084), "0x80000084 <ticks+0x4>");
+        assert_eq!(table.describe(0x8000_1010), "0x80001010 <uservec+0x10>");
+        assert_eq!(table.describe(0x10), "0x10 <main+0x10>");
+        assert_eq!(table.describe(0x7fff_ffff), "0x7fffffff");
+    }
+}
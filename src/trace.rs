@@ -0,0 +1,511 @@
+//! Compact binary execution trace.
+//!
+//! With `--trace PATH` the emulator records executed blocks, traps,
+//! interrupts, MMIO accesses and CSR writes, each stamped with the cycle it
+//! happened at. The file starts with an 8-byte magic and a version, followed
+//! by records: a one-byte tag and little-endian fields. `TraceReader` reads
+//! them back, and `trace-convert` turns a trace into JSON lines or SQL for
+//! sqlite3.
+
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 8] = b"RVTRACE\0";
+const VERSION: u32 = 1;
+
+const TAG_BLOCK: u8 = 1;
+const TAG_TRAP: u8 = 2;
+const TAG_INTERRUPT: u8 = 3;
+const TAG_MMIO: u8 = 4;
+const TAG_CSR_WRITE: u8 = 5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    /// A basic block about to run, `start_pc..=end_pc`.
+    Block {
+        cycle: u64,
+        start_pc: u64,
+        end_pc: u64,
+        mode: u8,
+    },
+    /// An exception taken at `epc`.
+    Trap {
+        cycle: u64,
+        cause: u64,
+        epc: u64,
+        tval: u64,
+        from_mode: u8,
+        to_mode: u8,
+    },
+    /// An interrupt taken before the instruction at `epc`.
+    Interrupt {
+        cycle: u64,
+        cause: u64,
+        epc: u64,
+        from_mode: u8,
+        to_mode: u8,
+    },
+    /// A load from or store to a device register; `size` is in bits.
+    Mmio {
+        cycle: u64,
+        addr: u64,
+        size: u8,
+        write: bool,
+        value: u64,
+    },
+    /// A CSR written by a Zicsr instruction.
+    CsrWrite { cycle: u64, csr: u16, value: u64 },
+}
+
+pub struct TraceWriter {
+    out: Box<dyn Write>,
+}
+
+impl TraceWriter {
+    pub fn new(mut out: Box<dyn Write>) -> io::Result<Self> {
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        Ok(Self { out })
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(48);
+        match *record {
+            Record::Block {
+                cycle,
+                start_pc,
+                end_pc,
+                mode,
+            } => {
+                buf.push(TAG_BLOCK);
+                buf.extend_from_slice(&cycle.to_le_bytes());
+                buf.extend_from_slice(&start_pc.to_le_bytes());
+                buf.extend_from_slice(&end_pc.to_le_bytes());
+                buf.push(mode);
+            }
+            Record::Trap {
+                cycle,
+                cause,
+                epc,
+                tval,
+                from_mode,
+                to_mode,
+            } => {
+                buf.push(TAG_TRAP);
+                buf.extend_from_slice(&cycle.to_le_bytes());
+                buf.extend_from_slice(&cause.to_le_bytes());
+                buf.extend_from_slice(&epc.to_le_bytes());
+                buf.extend_from_slice(&tval.to_le_bytes());
+                buf.push(from_mode);
+                buf.push(to_mode);
+            }
+            Record::Interrupt {
+                cycle,
+                cause,
+                epc,
+                from_mode,
+                to_mode,
+            } => {
+                buf.push(TAG_INTERRUPT);
+                buf.extend_from_slice(&cycle.to_le_bytes());
+                buf.extend_from_slice(&cause.to_le_bytes());
+                buf.extend_from_slice(&epc.to_le_bytes());
+                buf.push(from_mode);
+                buf.push(to_mode);
+            }
+            Record::Mmio {
+                cycle,
+                addr,
+                size,
+                write,
+                value,
+            } => {
+                buf.push(TAG_MMIO);
+                buf.extend_from_slice(&cycle.to_le_bytes());
+                buf.extend_from_slice(&addr.to_le_bytes());
+                buf.push(size);
+                buf.push(write as u8);
+                buf.extend_from_slice(&value.to_le_bytes());
+            }
+            Record::CsrWrite { cycle, csr, value } => {
+                buf.push(TAG_CSR_WRITE);
+                buf.extend_from_slice(&cycle.to_le_bytes());
+                buf.extend_from_slice(&csr.to_le_bytes());
+                buf.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        self.out.write_all(&buf)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Iterates over the records of a trace. A record cut short, as when the
+/// emulator was killed mid-write, ends the iteration with an error.
+pub struct TraceReader<R: Read> {
+    input: R,
+    done: bool,
+}
+
+impl<R: Read> TraceReader<R> {
+    pub fn new(mut input: R) -> Result<Self, String> {
+        let mut header = [0; 12];
+        input
+            .read_exact(&mut header)
+            .map_err(|_| "not a trace file: too short".to_string())?;
+        if &header[..8] != MAGIC {
+            return Err("not a trace file: bad magic".to_string());
+        }
+        let version = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+        if version != VERSION {
+            return Err(format!(
+                "unsupported trace version {} (expected {})",
+                version, VERSION
+            ));
+        }
+        Ok(Self { input, done: false })
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let mut buf = [0; N];
+        self.input
+            .read_exact(&mut buf)
+            .map_err(|_| "truncated record".to_string())?;
+        Ok(buf)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.bytes()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.bytes()?))
+    }
+
+    fn record(&mut self, tag: u8) -> Result<Record, String> {
+        let cycle = self.u64()?;
+        Ok(match tag {
+            TAG_BLOCK => Record::Block {
+                cycle,
+                start_pc: self.u64()?,
+                end_pc: self.u64()?,
+                mode: self.u8()?,
+            },
+            TAG_TRAP => Record::Trap {
+                cycle,
+                cause: self.u64()?,
+                epc: self.u64()?,
+                tval: self.u64()?,
+                from_mode: self.u8()?,
+                to_mode: self.u8()?,
+            },
+            TAG_INTERRUPT => Record::Interrupt {
+                cycle,
+                cause: self.u64()?,
+                epc: self.u64()?,
+                from_mode: self.u8()?,
+                to_mode: self.u8()?,
+            },
+            TAG_MMIO => Record::Mmio {
+                cycle,
+                addr: self.u64()?,
+                size: self.u8()?,
+                write: self.u8()? != 0,
+                value: self.u64()?,
+            },
+            TAG_CSR_WRITE => Record::CsrWrite {
+                cycle,
+                csr: self.u16()?,
+                value: self.u64()?,
+            },
+            _ => return Err(format!("unknown record tag {}", tag)),
+        })
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = Result<Record, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut tag = [0; 1];
+        match self.input.read(&mut tag) {
+            Ok(0) => {
+                self.done = true;
+                None
+            }
+            Ok(_) => {
+                let record = self.record(tag[0]);
+                self.done = record.is_err();
+                Some(record)
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e.to_string()))
+            }
+        }
+    }
+}
+
+impl Record {
+    /// One JSON object. Addresses and register values are hex strings, as
+    /// they do not all fit in a double; causes are numbers, without the
+    /// interrupt bit of mcause; `symbol` labels a block's start.
+    pub fn to_json(&self, symbol: Option<&str>) -> String {
+        match *self {
+            Record::Block {
+                cycle,
+                start_pc,
+                end_pc,
+                mode,
+            } => {
+                let symbol = match symbol {
+                    Some(name) => format!(",\"symbol\":\"{}\"", escape_json(name)),
+                    None => String::new(),
+                };
+                format!(
+                    "{{\"kind\":\"block\",\"cycle\":{},\"start_pc\":\"0x{:x}\",\"end_pc\":\"0x{:x}\",\"mode\":{}{}}}",
+                    cycle, start_pc, end_pc, mode, symbol
+                )
+            }
+            Record::Trap {
+                cycle,
+                cause,
+                epc,
+                tval,
+                from_mode,
+                to_mode,
+            } => format!(
+                "{{\"kind\":\"trap\",\"cycle\":{},\"cause\":{},\"epc\":\"0x{:x}\",\"tval\":\"0x{:x}\",\"from_mode\":{},\"to_mode\":{}}}",
+                cycle, cause, epc, tval, from_mode, to_mode
+            ),
+            Record::Interrupt {
+                cycle,
+                cause,
+                epc,
+                from_mode,
+                to_mode,
+            } => format!(
+                "{{\"kind\":\"interrupt\",\"cycle\":{},\"cause\":{},\"epc\":\"0x{:x}\",\"from_mode\":{},\"to_mode\":{}}}",
+                cycle,
+                cause & !(1 << 63),
+                epc,
+                from_mode,
+                to_mode
+            ),
+            Record::Mmio {
+                cycle,
+                addr,
+                size,
+                write,
+                value,
+            } => format!(
+                "{{\"kind\":\"mmio\",\"cycle\":{},\"addr\":\"0x{:x}\",\"size\":{},\"write\":{},\"value\":\"0x{:x}\"}}",
+                cycle, addr, size, write, value
+            ),
+            Record::CsrWrite { cycle, csr, value } => format!(
+                "{{\"kind\":\"csr_write\",\"cycle\":{},\"csr\":\"0x{:x}\",\"value\":\"0x{:x}\"}}",
+                cycle, csr, value
+            ),
+        }
+    }
+
+    /// An INSERT into the table for the record's kind (see `SQL_SCHEMA`).
+    /// SQLite integers are signed, so 64-bit values above i64::MAX wrap.
+    pub fn to_sql(&self, symbol: Option<&str>) -> String {
+        match *self {
+            Record::Block {
+                cycle,
+                start_pc,
+                end_pc,
+                mode,
+            } => {
+                let symbol = match symbol {
+                    Some(name) => format!("'{}'", name.replace('\'', "''")),
+                    None => "NULL".to_string(),
+                };
+                format!(
+                    "INSERT INTO blocks VALUES ({}, {}, {}, {}, {});",
+                    cycle, start_pc as i64, end_pc as i64, mode, symbol
+                )
+            }
+            Record::Trap {
+                cycle,
+                cause,
+                epc,
+                tval,
+                from_mode,
+                to_mode,
+            } => format!(
+                "INSERT INTO traps VALUES ({}, 0, {}, {}, {}, {}, {});",
+                cycle, cause as i64, epc as i64, tval as i64, from_mode, to_mode
+            ),
+            Record::Interrupt {
+                cycle,
+                cause,
+                epc,
+                from_mode,
+                to_mode,
+            } => format!(
+                "INSERT INTO traps VALUES ({}, 1, {}, {}, NULL, {}, {});",
+                cycle,
+                cause & !(1 << 63),
+                epc as i64,
+                from_mode,
+                to_mode
+            ),
+            Record::Mmio {
+                cycle,
+                addr,
+                size,
+                write,
+                value,
+            } => format!(
+                "INSERT INTO mmio VALUES ({}, {}, {}, {}, {});",
+                cycle, addr as i64, size, write as u8, value as i64
+            ),
+            Record::CsrWrite { cycle, csr, value } => format!(
+                "INSERT INTO csr_writes VALUES ({}, {}, {});",
+                cycle, csr, value as i64
+            ),
+        }
+    }
+}
+
+/// Tables filled by `Record::to_sql`.
+pub const SQL_SCHEMA: &str = "\
+DROP TABLE IF EXISTS blocks;
+DROP TABLE IF EXISTS traps;
+DROP TABLE IF EXISTS mmio;
+DROP TABLE IF EXISTS csr_writes;
+CREATE TABLE blocks (cycle INTEGER, start_pc INTEGER, end_pc INTEGER, mode INTEGER, symbol TEXT);
+CREATE TABLE traps (cycle INTEGER, interrupt INTEGER, cause INTEGER, epc INTEGER, tval INTEGER, from_mode INTEGER, to_mode INTEGER);
+CREATE TABLE mmio (cycle INTEGER, addr INTEGER, size INTEGER, write INTEGER, value INTEGER);
+CREATE TABLE csr_writes (cycle INTEGER, csr INTEGER, value INTEGER);
+";
+
+fn escape_json(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let records = vec![
+            Record::Block {
+                cycle: 1,
+                start_pc: 0x8000_0000,
+                end_pc: 0x8000_0010,
+                mode: 3,
+            },
+            Record::CsrWrite {
+                cycle: 3,
+                csr: 0x305,
+                value: 0x8000_0100,
+            },
+            Record::Mmio {
+                cycle: 5,
+                addr: 0x1000_0000,
+                size: 8,
+                write: true,
+                value: 0x41,
+            },
+            Record::Trap {
+                cycle: 9,
+                cause: 8,
+                epc: 0x1004,
+                tval: 0,
+                from_mode: 0,
+                to_mode: 1,
+            },
+            Record::Interrupt {
+                cycle: 12,
+                cause: (1 << 63) | 5,
+                epc: 0x8000_0200,
+                from_mode: 1,
+                to_mode: 1,
+            },
+        ];
+        let buffer = Rc::new(RefCell::new(Vec::new()));
+        let mut writer = TraceWriter::new(Box::new(SharedBuffer(buffer.clone()))).unwrap();
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+        let bytes = buffer.borrow().clone();
+        let decoded: Vec<Record> = TraceReader::new(&bytes[..])
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(decoded, records);
+
+        // a record cut short ends the iteration with an error
+        let mut reader = TraceReader::new(&bytes[..bytes.len() - 3]).unwrap();
+        assert_eq!(reader.by_ref().filter(|r| r.is_ok()).count(), 4);
+        assert!(reader.next().is_none());
+        let last = TraceReader::new(&bytes[..bytes.len() - 3]).unwrap().last();
+        assert_eq!(last, Some(Err("truncated record".to_string())));
+
+        assert_eq!(
+            TraceReader::new(&b"RVTRACE\0\x02\0\0\0"[..]).err(),
+            Some("unsupported trace version 2 (expected 1)".to_string())
+        );
+        assert!(TraceReader::new(&b"\x7fELF"[..]).is_err());
+    }
+
+    #[test]
+    fn test_convert() {
+        let block = Record::Block {
+            cycle: 7,
+            start_pc: 0x8000_0000,
+            end_pc: 0x8000_0010,
+            mode: 1,
+        };
+        assert_eq!(
+            block.to_json(Some("main")),
+            "{\"kind\":\"block\",\"cycle\":7,\"start_pc\":\"0x80000000\",\"end_pc\":\"0x80000010\",\"mode\":1,\"symbol\":\"main\"}"
+        );
+        assert_eq!(
+            block.to_sql(None),
+            "INSERT INTO blocks VALUES (7, 2147483648, 2147483664, 1, NULL);"
+        );
+        let interrupt = Record::Interrupt {
+            cycle: 12,
+            cause: (1 << 63) | 5,
+            epc: 0x8000_0200,
+            from_mode: 0,
+            to_mode: 1,
+        };
+        assert_eq!(
+            interrupt.to_json(None),
+            "{\"kind\":\"interrupt\",\"cycle\":12,\"cause\":5,\"epc\":\"0x80000200\",\"from_mode\":0,\"to_mode\":1}"
+        );
+        assert_eq!(
+            interrupt.to_sql(None),
+            "INSERT INTO traps VALUES (12, 1, 5, 2147484160, NULL, 0, 1);"
+        );
+    }
+}
@@ -1,78 +1,345 @@
 use crate::interrupt::*;
-use log::info;
+use crate::uart_backend::*;
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
-use std::io::Read as _;
+use std::io::{self, Read as _, Write as _};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
-const UART_SIZE: u64 = 0x100; // size of the UART memory-mapped region
+pub const UART_SIZE: u64 = 0x100; // size of the UART memory-mapped region
+const FIFO_SIZE: usize = 16;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct UartSnapshot {
     start_addr: u64,
+    regs: UartRegisters,
     // recv_buf is intentionally excluded: pending input is transient and
     // cannot be serialised meaningfully.
 }
 
+/// Guest-visible 16550A register state.
+#[derive(Clone, Serialize, Deserialize)]
+struct UartRegisters {
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    // only the delta bits (0-3); the status bits are derived from mcr
+    msr_deltas: u8,
+    scr: u8,
+    dll: u8,
+    dlm: u8,
+    // THR-empty interrupt condition, cleared by reading IIR or writing THR
+    thre_pending: bool,
+}
+
+impl Default for UartRegisters {
+    fn default() -> Self {
+        Self {
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            msr_deltas: 0,
+            scr: 0,
+            dll: 0x0c,
+            dlm: 0,
+            thre_pending: false,
+        }
+    }
+}
+
+/// State shared between the emulator and the input thread.
+struct UartState {
+    regs: UartRegisters,
+    // Receive buffer written by the input thread, read by the emulator via RHR.
+    recv_buf: VecDeque<u8>,
+}
+
+/// A 16550A UART with an infinitely fast transmitter.
+///
+/// The interrupt output is a level: it is sampled after every register
+/// access and every received chunk of input, and the PLIC is notified while
+/// it is asserted. The PLIC gateway turns that into a single pending request.
 pub struct Uart {
     start_addr: u64,
     interrupt_notifier: Arc<Box<dyn Fn() + Send + Sync>>,
-    // Receive buffer written by the input thread, read by the emulator via RHR.
-    recv_buf: Arc<Mutex<VecDeque<u8>>>,
-    input_thread: std::thread::JoinHandle<()>,
+    state: Arc<Mutex<UartState>>,
+    output: UartOutput,
+    // copy of the transmitted bytes kept for console scripts
+    captured: Option<Vec<u8>>,
+    input_thread: Option<std::thread::JoinHandle<()>>,
 }
 
-#[allow(unused)]
+// register offsets; the meaning of 0 and 1 depends on LCR.DLAB
 const REG_RHR_THR: u64 = 0;
-#[allow(unused)]
 const REG_IER: u64 = 1;
-#[allow(unused)]
 const REG_FCR_ISR: u64 = 2;
-#[allow(unused)]
 const REG_LCR: u64 = 3;
-#[allow(unused)]
 const REG_MCR: u64 = 4;
 const REG_LSR: u64 = 5;
-#[allow(unused)]
 const REG_MSR: u64 = 6;
-#[allow(unused)]
 const REG_SPR: u64 = 7;
 
-const RECEIVE_DATA_READY: u64 = 1 << 0;
+// IER
+const IER_RECEIVE_DATA: u8 = 1 << 0;
+const IER_TRANSMIT_EMPTY: u8 = 1 << 1;
+#[allow(unused)]
+const IER_LINE_STATUS: u8 = 1 << 2;
+const IER_MODEM_STATUS: u8 = 1 << 3;
+
+// IIR interrupt identification, highest priority first
+const IIR_NO_INTERRUPT: u8 = 0x1;
+#[allow(unused)]
+const IIR_LINE_STATUS: u8 = 0x6;
+const IIR_RECEIVE_DATA: u8 = 0x4;
+const IIR_CHAR_TIMEOUT: u8 = 0xc;
+const IIR_TRANSMIT_EMPTY: u8 = 0x2;
+const IIR_MODEM_STATUS: u8 = 0x0;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+// FCR
+const FCR_FIFO_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RECEIVE: u8 = 1 << 1;
+#[allow(unused)]
+const FCR_CLEAR_TRANSMIT: u8 = 1 << 2;
+
+// LCR
+const LCR_DLAB: u8 = 1 << 7;
+
+// MCR
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+const MCR_OUT1: u8 = 1 << 2;
+const MCR_OUT2: u8 = 1 << 3;
+const MCR_LOOPBACK: u8 = 1 << 4;
+
+// LSR
+const RECEIVE_DATA_READY: u8 = 1 << 0;
 #[allow(unused)]
-const OVERRUN_ERROR: u64 = 1 << 1;
+const OVERRUN_ERROR: u8 = 1 << 1;
 #[allow(unused)]
-const PARITY_ERROR: u64 = 1 << 2;
+const PARITY_ERROR: u8 = 1 << 2;
 #[allow(unused)]
-const FRAMING_ERROR: u64 = 1 << 3;
+const FRAMING_ERROR: u8 = 1 << 3;
 #[allow(unused)]
-const BREAK_INTERRUPT: u64 = 1 << 4;
-const TRANSMIT_HOLDING_EMPTY: u64 = 1 << 5;
-const TRANSMIT_EMPTY: u64 = 1 << 6;
+const BREAK_INTERRUPT: u8 = 1 << 4;
+const TRANSMIT_HOLDING_EMPTY: u8 = 1 << 5;
+const TRANSMIT_EMPTY: u8 = 1 << 6;
 #[allow(unused)]
-const FIFO_ERROR: u64 = 1 << 7;
+const FIFO_ERROR: u8 = 1 << 7;
+
+// MSR
+const MSR_DELTA_CTS: u8 = 1 << 0;
+const MSR_DELTA_DSR: u8 = 1 << 1;
+const MSR_TRAILING_EDGE_RI: u8 = 1 << 2;
+const MSR_DELTA_DCD: u8 = 1 << 3;
+const MSR_CTS: u8 = 1 << 4;
+const MSR_DSR: u8 = 1 << 5;
+const MSR_RI: u8 = 1 << 6;
+const MSR_DCD: u8 = 1 << 7;
+
+impl UartState {
+    fn new(regs: UartRegisters) -> Self {
+        Self {
+            regs,
+            recv_buf: VecDeque::new(),
+        }
+    }
+
+    fn dlab(&self) -> bool {
+        self.regs.lcr & LCR_DLAB != 0
+    }
+
+    fn fifo_enabled(&self) -> bool {
+        self.regs.fcr & FCR_FIFO_ENABLE != 0
+    }
+
+    fn receive_trigger_level(&self) -> usize {
+        if !self.fifo_enabled() {
+            return 1;
+        }
+        match self.regs.fcr >> 6 {
+            0 => 1,
+            1 => 4,
+            2 => 8,
+            _ => 14,
+        }
+    }
+
+    /// Modem input lines. In loopback mode they follow the MCR outputs;
+    /// otherwise the other end is always present and ready.
+    fn modem_status(&self) -> u8 {
+        let mcr = self.regs.mcr;
+        if mcr & MCR_LOOPBACK == 0 {
+            return MSR_DCD | MSR_DSR | MSR_CTS;
+        }
+        let mut status = 0;
+        if mcr & MCR_RTS != 0 {
+            status |= MSR_CTS;
+        }
+        if mcr & MCR_DTR != 0 {
+            status |= MSR_DSR;
+        }
+        if mcr & MCR_OUT1 != 0 {
+            status |= MSR_RI;
+        }
+        if mcr & MCR_OUT2 != 0 {
+            status |= MSR_DCD;
+        }
+        status
+    }
+
+    /// The pending interrupt with the highest priority, as reported in IIR bits 0-3.
+    fn interrupt_id(&self) -> u8 {
+        let ier = self.regs.ier;
+        let buffered = self.recv_buf.len().min(FIFO_SIZE);
+        if ier & IER_RECEIVE_DATA != 0 && buffered > 0 {
+            // without a bit clock, data below the trigger level times out at once
+            if buffered >= self.receive_trigger_level() {
+                return IIR_RECEIVE_DATA;
+            }
+            return IIR_CHAR_TIMEOUT;
+        }
+        if ier & IER_TRANSMIT_EMPTY != 0 && self.regs.thre_pending {
+            return IIR_TRANSMIT_EMPTY;
+        }
+        if ier & IER_MODEM_STATUS != 0 && self.regs.msr_deltas != 0 {
+            return IIR_MODEM_STATUS;
+        }
+        IIR_NO_INTERRUPT
+    }
+
+    fn interrupt_asserted(&self) -> bool {
+        self.interrupt_id() != IIR_NO_INTERRUPT
+    }
+
+    fn line_status(&self) -> u8 {
+        // the transmitter is infinitely fast, so it is always empty
+        let rx_ready = if self.recv_buf.is_empty() {
+            0
+        } else {
+            RECEIVE_DATA_READY
+        };
+        TRANSMIT_EMPTY | TRANSMIT_HOLDING_EMPTY | rx_ready
+    }
+
+    fn read(&mut self, offset: u64) -> u8 {
+        match offset {
+            REG_RHR_THR if self.dlab() => self.regs.dll,
+            REG_RHR_THR => {
+                // Pop the oldest character from the receive buffer.
+                let ch = self.recv_buf.pop_front().unwrap_or(0);
+                info!("UART RHR read: 0x{:02x} ('{}')", ch, ch as char);
+                ch
+            }
+            REG_IER if self.dlab() => self.regs.dlm,
+            REG_IER => self.regs.ier,
+            REG_FCR_ISR => {
+                let id = self.interrupt_id();
+                // reading IIR acknowledges a THR-empty interrupt
+                if id == IIR_TRANSMIT_EMPTY {
+                    self.regs.thre_pending = false;
+                }
+                let fifo = if self.fifo_enabled() {
+                    IIR_FIFO_ENABLED
+                } else {
+                    0
+                };
+                fifo | id
+            }
+            REG_LCR => self.regs.lcr,
+            REG_MCR => self.regs.mcr,
+            REG_LSR => self.line_status(),
+            REG_MSR => {
+                let msr = self.modem_status() | self.regs.msr_deltas;
+                self.regs.msr_deltas = 0;
+                msr
+            }
+            REG_SPR => self.regs.scr,
+            _ => 0,
+        }
+    }
+
+    /// Returns the byte to transmit, if any.
+    fn write(&mut self, offset: u64, value: u8) -> Option<u8> {
+        match offset {
+            REG_RHR_THR if self.dlab() => self.regs.dll = value,
+            REG_RHR_THR => {
+                // the byte leaves at once, so THR is empty again straight away
+                self.regs.thre_pending = true;
+                if self.regs.mcr & MCR_LOOPBACK != 0 {
+                    self.recv_buf.push_back(value);
+                    return None;
+                }
+                return Some(value);
+            }
+            REG_IER if self.dlab() => self.regs.dlm = value,
+            REG_IER => {
+                let enabling_thre =
+                    self.regs.ier & IER_TRANSMIT_EMPTY == 0 && value & IER_TRANSMIT_EMPTY != 0;
+                self.regs.ier = value & 0x0f;
+                // THR is always empty, so enabling the interrupt raises it
+                if enabling_thre {
+                    self.regs.thre_pending = true;
+                }
+            }
+            REG_FCR_ISR => {
+                if value & FCR_CLEAR_RECEIVE != 0 {
+                    self.recv_buf.clear();
+                }
+                // FCR_CLEAR_TRANSMIT is a no-op: nothing is ever left to transmit
+                self.regs.fcr = value & (FCR_FIFO_ENABLE | 0xc0);
+            }
+            REG_LCR => self.regs.lcr = value,
+            REG_MCR => {
+                let old = self.modem_status();
+                self.regs.mcr = value & 0x1f;
+                let new = self.modem_status();
+                let changed = old ^ new;
+                if changed & MSR_CTS != 0 {
+                    self.regs.msr_deltas |= MSR_DELTA_CTS;
+                }
+                if changed & MSR_DSR != 0 {
+                    self.regs.msr_deltas |= MSR_DELTA_DSR;
+                }
+                if old & MSR_RI != 0 && new & MSR_RI == 0 {
+                    self.regs.msr_deltas |= MSR_TRAILING_EDGE_RI;
+                }
+                if changed & MSR_DCD != 0 {
+                    self.regs.msr_deltas |= MSR_DELTA_DCD;
+                }
+            }
+            // LSR and MSR are read-only
+            REG_LSR | REG_MSR => {}
+            REG_SPR => self.regs.scr = value,
+            _ => {}
+        }
+        None
+    }
+}
 
 fn spawn_input_thread(
-    recv_buf: Arc<Mutex<VecDeque<u8>>>,
+    mut input: UartInput,
+    state: Arc<Mutex<UartState>>,
     interrupt_notifier: Arc<Box<dyn Fn() + Send + Sync>>,
 ) -> thread::JoinHandle<()> {
     thread::spawn(move || {
         info!("UART input thread started");
-        let stdin = std::io::stdin();
-        let mut raw = stdin.lock();
         let mut buf = [0u8; 256];
         loop {
-            match raw.read(&mut buf) {
+            match input.read(&mut buf) {
                 Ok(0) => break, // EOF – stop spinning
                 Ok(n) => {
-                    {
-                        let mut q = recv_buf.lock().unwrap();
-                        for &b in &buf[..n] {
-                            q.push_back(b);
-                        }
+                    let asserted = {
+                        let mut state = state.lock().unwrap();
+                        state.recv_buf.extend(&buf[..n]);
+                        state.interrupt_asserted()
+                    };
+                    if asserted {
+                        (interrupt_notifier)();
                     }
-                    (interrupt_notifier)();
                     info!("UART: queued {} byte(s)", n);
                 }
                 Err(_) => break,
@@ -83,85 +350,214 @@ fn spawn_input_thread(
 }
 
 impl Uart {
-    pub fn new(_start_addr: u64, interrupt_notifier: Box<dyn Fn() + Send + Sync>) -> Uart {
-        let interrupt_notifier = Arc::new(interrupt_notifier);
-        let recv_buf = Arc::new(Mutex::new(VecDeque::<u8>::new()));
-
-        let input_thread =
-            spawn_input_thread(Arc::clone(&recv_buf), Arc::clone(&interrupt_notifier));
+    pub fn new(start_addr: u64, interrupt_notifier: Box<dyn Fn() + Send + Sync>) -> Uart {
+        Self::with_registers(start_addr, UartRegisters::default(), interrupt_notifier)
+    }
 
+    fn with_registers(
+        start_addr: u64,
+        regs: UartRegisters,
+        interrupt_notifier: Box<dyn Fn() + Send + Sync>,
+    ) -> Uart {
         Self {
-            start_addr: _start_addr,
-            interrupt_notifier,
-            recv_buf,
-            input_thread,
+            start_addr,
+            interrupt_notifier: Arc::new(interrupt_notifier),
+            state: Arc::new(Mutex::new(UartState::new(regs))),
+            output: Box::new(io::sink()),
+            captured: None,
+            input_thread: None,
         }
     }
 
+    /// Start keeping a copy of everything the guest transmits, in addition
+    /// to sending it to the backend.
+    pub fn start_capture(&mut self) {
+        self.captured.get_or_insert_with(Vec::new);
+    }
+
+    /// The bytes transmitted since the last call.
+    pub fn take_captured(&mut self) -> Vec<u8> {
+        self.captured
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Queue bytes as if they had arrived from the backend.
+    pub fn send_input(&mut self, bytes: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        state.recv_buf.extend(bytes);
+        self.notify_if_asserted(&state);
+    }
+
+    /// Connect the host side of the UART. Until this is called output is
+    /// discarded and no input arrives. Meant to be called once: an input
+    /// thread of a previous backend cannot be stopped.
+    pub fn set_backend(&mut self, backend: Box<dyn UartBackend>) -> io::Result<()> {
+        let (input, output) = backend.connect()?;
+        self.output = output;
+        if let Some(input) = input {
+            if self.input_thread.is_some() {
+                warn!("UART: the previous backend's input thread keeps running");
+            }
+            self.input_thread = Some(spawn_input_thread(
+                input,
+                Arc::clone(&self.state),
+                Arc::clone(&self.interrupt_notifier),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Send a byte to the backend, bypassing the register file.
+    pub fn transmit(&mut self, ch: u8) {
+        if let Some(captured) = self.captured.as_mut() {
+            captured.push(ch);
+        }
+        if let Err(e) = self.output.write_all(&[ch]) {
+            warn!("UART: failed to write to the backend: {}", e);
+        }
+    }
+
+    /// Take the next received byte, bypassing the register file.
+    pub fn receive(&mut self) -> Option<u8> {
+        self.state.lock().unwrap().recv_buf.pop_front()
+    }
+
+    pub fn start_addr(&self) -> u64 {
+        self.start_addr
+    }
+
     pub fn is_accessible(&self, addr: u64) -> bool {
         (addr >= self.start_addr) && (addr < self.start_addr + UART_SIZE)
     }
 
-    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
-        if size != 8 {
-            return Err(Exception::LoadAccessFault);
+    // registers are one byte wide; wider accesses see them zero-extended
+    fn is_valid_size(size: u64) -> bool {
+        matches!(size, 8 | 16 | 32)
+    }
+
+    fn notify_if_asserted(&self, state: &UartState) {
+        if state.interrupt_asserted() {
+            (self.interrupt_notifier)();
         }
-        let actual_addr = addr - self.start_addr;
-        match actual_addr {
-            REG_RHR_THR => {
-                // Pop the oldest character from the receive buffer.
-                let ch = self.recv_buf.lock().unwrap().pop_front().unwrap_or(0);
-                info!("UART RHR read: 0x{:02x} ('{}')", ch, ch as char);
-                Ok(ch as u64)
-            }
-            REG_LSR => {
-                // Bit 0 – RECEIVE_DATA_READY: set when there is data in the receive buffer.
-                // Bits 5-6 – transmit side: always ready (infinitely fast transmitter).
-                let has_data = !self.recv_buf.lock().unwrap().is_empty();
-                let rx_ready = if has_data { RECEIVE_DATA_READY } else { 0 };
-                Ok(TRANSMIT_EMPTY | TRANSMIT_HOLDING_EMPTY | rx_ready)
-            }
-            _ => Ok(0x0),
+    }
+
+    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if !Self::is_valid_size(size) {
+            return Err(Exception::LoadAccessFault);
         }
+        let mut state = self.state.lock().unwrap();
+        let value = state.read(addr - self.start_addr);
+        self.notify_if_asserted(&state);
+        Ok(value as u64)
     }
 
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        if size != 8 {
-            return Err(Exception::LoadAccessFault);
+        if !Self::is_valid_size(size) {
+            return Err(Exception::StoreAMOAccessFault);
         }
-        let actual_addr = addr - self.start_addr;
-        match actual_addr {
-            REG_RHR_THR => {
-                use std::io::Write as _;
-                print!("{}", value as u8 as char);
-                let _ = std::io::stdout().flush();
-                Ok(())
-            }
-            _ => Ok(()),
+        let offset = addr - self.start_addr;
+        let state = Arc::clone(&self.state);
+        let mut state = state.lock().unwrap();
+        if let Some(ch) = state.write(offset, value as u8) {
+            self.transmit(ch);
+        } else {
+            debug!("UART write: offset {}, value 0x{:02x}", offset, value as u8);
         }
+        self.notify_if_asserted(&state);
+        Ok(())
     }
 
     pub fn from_snapshot(
         snapshot: UartSnapshot,
         interrupt_notifier: Box<dyn Fn() + Send + Sync + 'static>,
     ) -> Self {
-        let interrupt_notifier = Arc::new(interrupt_notifier);
-        let recv_buf = Arc::new(Mutex::new(VecDeque::<u8>::new()));
-
-        let input_thread =
-            spawn_input_thread(Arc::clone(&recv_buf), Arc::clone(&interrupt_notifier));
-
-        Self {
-            start_addr: snapshot.start_addr,
-            interrupt_notifier,
-            recv_buf,
-            input_thread,
-        }
+        Self::with_registers(snapshot.start_addr, snapshot.regs, interrupt_notifier)
     }
 
     pub fn to_snapshot(&self) -> UartSnapshot {
         UartSnapshot {
             start_addr: self.start_addr,
+            regs: self.state.lock().unwrap().regs.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_16550a_registers() {
+        let mut state = UartState::new(UartRegisters::default());
+
+        // divisor latch and scratch registers
+        state.write(REG_LCR, LCR_DLAB | 0x3);
+        state.write(REG_RHR_THR, 0x01);
+        state.write(REG_IER, 0x00);
+        assert_eq!(state.read(REG_RHR_THR), 0x01);
+        state.write(REG_LCR, 0x3);
+        assert_eq!(state.read(REG_IER), 0);
+        state.write(REG_SPR, 0x5a);
+        assert_eq!(state.read(REG_SPR), 0x5a);
+
+        // FIFO mode shows in IIR; nothing is pending yet
+        state.write(REG_FCR_ISR, FCR_FIFO_ENABLE | FCR_CLEAR_RECEIVE | 0x80);
+        assert_eq!(state.read(REG_FCR_ISR), IIR_FIFO_ENABLED | IIR_NO_INTERRUPT);
+
+        // enabling the THR-empty interrupt raises it; reading IIR clears it
+        state.write(REG_IER, IER_TRANSMIT_EMPTY | IER_RECEIVE_DATA);
+        assert!(state.interrupt_asserted());
+        assert_eq!(
+            state.read(REG_FCR_ISR),
+            IIR_FIFO_ENABLED | IIR_TRANSMIT_EMPTY
+        );
+        assert!(!state.interrupt_asserted());
+        assert_eq!(state.write(REG_RHR_THR, b'x'), Some(b'x'));
+        assert_eq!(state.interrupt_id(), IIR_TRANSMIT_EMPTY);
+        state.read(REG_FCR_ISR);
+
+        // received data below the trigger level of 8 reports a timeout
+        state.recv_buf.extend(b"abc");
+        assert_eq!(state.interrupt_id(), IIR_CHAR_TIMEOUT);
+        assert_eq!(state.read(REG_LSR) & RECEIVE_DATA_READY, RECEIVE_DATA_READY);
+        state.recv_buf.extend(b"defgh");
+        assert_eq!(state.interrupt_id(), IIR_RECEIVE_DATA);
+        while state.read(REG_LSR) & RECEIVE_DATA_READY != 0 {
+            state.read(REG_RHR_THR);
+        }
+        assert!(!state.interrupt_asserted());
+
+        // loopback: modem lines follow MCR and THR feeds RHR
+        assert_eq!(state.read(REG_MSR), MSR_DCD | MSR_DSR | MSR_CTS);
+        state.write(REG_MCR, MCR_LOOPBACK | MCR_OUT2 | MCR_RTS);
+        assert_eq!(state.read(REG_MSR), MSR_DCD | MSR_CTS | MSR_DELTA_DSR);
+        assert_eq!(state.write(REG_RHR_THR, b'y'), None);
+        assert_eq!(state.read(REG_RHR_THR), b'y');
+    }
+
+    #[test]
+    fn test_scripted_backend() {
+        let base = 0x10000000;
+        let mut uart = Uart::new(base, Box::new(|| {}));
+        let backend = ScriptedBackend::new(b"ls\n".to_vec());
+        let transcript = backend.transcript();
+        uart.set_backend(Box::new(backend)).unwrap();
+
+        let mut received = Vec::new();
+        while received.len() < 3 {
+            if uart.load(base + REG_LSR, 8).unwrap() as u8 & RECEIVE_DATA_READY != 0 {
+                received.push(uart.load(base + REG_RHR_THR, 8).unwrap() as u8);
+            } else {
+                thread::yield_now();
+            }
+        }
+        assert_eq!(received, b"ls\n");
+
+        for &ch in b"$ " {
+            uart.store(base + REG_RHR_THR, 8, ch as u64).unwrap();
         }
+        assert_eq!(*transcript.lock().unwrap(), b"$ ");
     }
 }
@@ -0,0 +1,366 @@
+use log::info;
+use once_cell::sync::OnceCell;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Write};
+use std::net::TcpListener;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// Bytes received by the UART; read on the UART's input thread until EOF.
+pub type UartInput = Box<dyn Read + Send>;
+/// Bytes transmitted by the guest through THR.
+pub type UartOutput = Box<dyn Write + Send>;
+
+/// The host side of the UART.
+pub trait UartBackend {
+    /// Open the backend and split it into its input and output halves.
+    /// A backend without input returns `None` for the first half.
+    fn connect(self: Box<Self>) -> io::Result<(Option<UartInput>, UartOutput)>;
+}
+
+/// Parse the `--uart` option:
+/// `stdio`, `stdio-cooked`, `null`, `pty`, `tcp:ADDR`, `file:OUT[,IN]` or `script:TEXT`.
+pub fn parse_backend(spec: &str) -> Result<Box<dyn UartBackend>, String> {
+    let (kind, arg) = match spec.split_once(':') {
+        Some((kind, arg)) => (kind, Some(arg)),
+        None => (spec, None),
+    };
+    match (kind, arg) {
+        ("stdio", None) => Ok(Box::new(StdioBackend { raw: true })),
+        ("stdio-cooked", None) => Ok(Box::new(StdioBackend { raw: false })),
+        ("null", None) => Ok(Box::new(NullBackend)),
+        ("pty", None) => Ok(Box::new(PtyBackend)),
+        ("tcp", Some(addr)) => Ok(Box::new(TcpBackend {
+            addr: addr.to_string(),
+        })),
+        ("file", Some(paths)) => {
+            let (output, input) = match paths.split_once(',') {
+                Some((output, input)) => (output, Some(PathBuf::from(input))),
+                None => (paths, None),
+            };
+            Ok(Box::new(FileBackend {
+                output: PathBuf::from(output),
+                input,
+            }))
+        }
+        ("script", Some(text)) => {
+            let mut backend = ScriptedBackend::new(unescape(text));
+            backend.echo = true;
+            Ok(Box::new(backend))
+        }
+        _ => Err(format!("unknown UART backend: {}", spec)),
+    }
+}
+
+// \n, \r, \t and \\ in scripted input given on the command line
+fn unescape(text: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let c = if c == '\\' {
+            match chars.next() {
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('t') => '\t',
+                Some(other) => other,
+                None => '\\',
+            }
+        } else {
+            c
+        };
+        let mut buf = [0; 4];
+        bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+    }
+    bytes
+}
+
+/// Discards output and never receives input.
+pub struct NullBackend;
+
+impl UartBackend for NullBackend {
+    fn connect(self: Box<Self>) -> io::Result<(Option<UartInput>, UartOutput)> {
+        Ok((None, Box::new(io::sink())))
+    }
+}
+
+/// The emulator's own stdin and stdout. With `raw`, a terminal on stdin is
+/// switched to non-canonical, no-echo mode until the output half is dropped.
+pub struct StdioBackend {
+    pub raw: bool,
+}
+
+// terminal settings to restore on exit or SIGINT
+static SAVED_TERMIOS: OnceCell<libc::termios> = OnceCell::new();
+
+extern "C" fn restore_terminal_and_exit(_signal: libc::c_int) {
+    if let Some(termios) = SAVED_TERMIOS.get() {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, termios) };
+    }
+    unsafe { libc::_exit(130) };
+}
+
+/// Keeps stdin in raw mode while alive.
+struct RawModeGuard;
+
+impl RawModeGuard {
+    fn enable() -> io::Result<Option<RawModeGuard>> {
+        if unsafe { libc::isatty(libc::STDIN_FILENO) } == 0 {
+            return Ok(None);
+        }
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let _ = SAVED_TERMIOS.set(termios);
+        // keep ISIG and output processing so that ^C still stops the
+        // emulator and the guest's bare '\n' still returns the carriage
+        termios.c_lflag &= !(libc::ICANON | libc::ECHO | libc::IEXTEN);
+        termios.c_iflag &= !(libc::ICRNL | libc::IXON);
+        termios.c_cc[libc::VMIN] = 1;
+        termios.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        unsafe {
+            libc::signal(
+                libc::SIGINT,
+                restore_terminal_and_exit as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            )
+        };
+        Ok(Some(RawModeGuard))
+    }
+}
+
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        if let Some(termios) = SAVED_TERMIOS.get() {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, termios) };
+        }
+    }
+}
+
+struct StdoutOutput {
+    _raw_mode: Option<RawModeGuard>,
+}
+
+impl Write for StdoutOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut stdout = io::stdout();
+        let n = stdout.write(buf)?;
+        stdout.flush()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+impl UartBackend for StdioBackend {
+    fn connect(self: Box<Self>) -> io::Result<(Option<UartInput>, UartOutput)> {
+        let raw_mode = if self.raw {
+            RawModeGuard::enable()?
+        } else {
+            None
+        };
+        Ok((
+            Some(Box::new(io::stdin())),
+            Box::new(StdoutOutput {
+                _raw_mode: raw_mode,
+            }),
+        ))
+    }
+}
+
+/// A new Linux pseudo-terminal; its path is logged so that a terminal
+/// program such as `screen` can be attached to it.
+pub struct PtyBackend;
+
+impl UartBackend for PtyBackend {
+    fn connect(self: Box<Self>) -> io::Result<(Option<UartInput>, UartOutput)> {
+        let master = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY) };
+        if master < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let master = unsafe { File::from_raw_fd(master) };
+        if unsafe { libc::grantpt(master.as_raw_fd()) } != 0
+            || unsafe { libc::unlockpt(master.as_raw_fd()) } != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        let mut name = [0 as libc::c_char; 128];
+        if unsafe { libc::ptsname_r(master.as_raw_fd(), name.as_mut_ptr(), name.len()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let path = unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+
+        // Hold the slave open so that reads from the master block instead of
+        // failing while nobody is attached, and make it raw so the guest sees
+        // exactly what the user types.
+        let slave = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+        unsafe {
+            libc::tcgetattr(slave.as_raw_fd(), &mut termios);
+            libc::cfmakeraw(&mut termios);
+            libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios);
+        }
+        info!("UART connected to {}", path);
+        eprintln!("UART connected to {}", path);
+
+        let input = master.try_clone()?;
+        Ok((
+            Some(Box::new(input)),
+            Box::new(PtyOutput {
+                master,
+                _slave: slave,
+            }),
+        ))
+    }
+}
+
+struct PtyOutput {
+    master: File,
+    _slave: File,
+}
+
+impl Write for PtyOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.master.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.master.flush()
+    }
+}
+
+/// Listens on `addr` and waits for a single client before the guest starts.
+pub struct TcpBackend {
+    pub addr: String,
+}
+
+impl UartBackend for TcpBackend {
+    fn connect(self: Box<Self>) -> io::Result<(Option<UartInput>, UartOutput)> {
+        let listener = TcpListener::bind(&self.addr)?;
+        info!(
+            "UART waiting for a connection on {}",
+            listener.local_addr()?
+        );
+        eprintln!(
+            "UART waiting for a connection on {}",
+            listener.local_addr()?
+        );
+        let (stream, peer) = listener.accept()?;
+        info!("UART connected to {}", peer);
+        stream.set_nodelay(true)?;
+        Ok((Some(Box::new(stream.try_clone()?)), Box::new(stream)))
+    }
+}
+
+/// Writes output to a file and optionally reads input from another one.
+pub struct FileBackend {
+    pub output: PathBuf,
+    pub input: Option<PathBuf>,
+}
+
+impl UartBackend for FileBackend {
+    fn connect(self: Box<Self>) -> io::Result<(Option<UartInput>, UartOutput)> {
+        let output = File::create(&self.output)?;
+        let input = match self.input {
+            Some(path) => Some(Box::new(File::open(path)?) as UartInput),
+            None => None,
+        };
+        Ok((input, Box::new(output)))
+    }
+}
+
+/// Feeds fixed input to the guest and records everything it transmits.
+pub struct ScriptedBackend {
+    input: Vec<u8>,
+    transcript: Arc<Mutex<Vec<u8>>>,
+    /// Also copy the output to stdout.
+    pub echo: bool,
+}
+
+impl ScriptedBackend {
+    pub fn new(input: Vec<u8>) -> ScriptedBackend {
+        Self {
+            input,
+            transcript: Arc::new(Mutex::new(Vec::new())),
+            echo: false,
+        }
+    }
+
+    /// A handle to the recorded output that stays valid after `connect`.
+    #[allow(unused)]
+    pub fn transcript(&self) -> Arc<Mutex<Vec<u8>>> {
+        Arc::clone(&self.transcript)
+    }
+}
+
+struct TranscriptOutput {
+    transcript: Arc<Mutex<Vec<u8>>>,
+    echo: bool,
+}
+
+impl Write for TranscriptOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.transcript.lock().unwrap().extend_from_slice(buf);
+        if self.echo {
+            let mut stdout = io::stdout();
+            stdout.write_all(buf)?;
+            stdout.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl UartBackend for ScriptedBackend {
+    fn connect(self: Box<Self>) -> io::Result<(Option<UartInput>, UartOutput)> {
+        let input = if self.input.is_empty() {
+            None
+        } else {
+            Some(Box::new(Cursor::new(self.input)) as UartInput)
+        };
+        Ok((
+            input,
+            Box::new(TranscriptOutput {
+                transcript: self.transcript,
+                echo: self.echo,
+            }),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_backend() {
+        for spec in [
+            "stdio",
+            "stdio-cooked",
+            "null",
+            "pty",
+            "tcp:127.0.0.1:4444",
+            "file:out.txt",
+            "file:out.txt,in.txt",
+            "script:ls\\n",
+        ] {
+            assert!(parse_backend(spec).is_ok(), "{}", spec);
+        }
+        assert!(parse_backend("tcp").is_err());
+        assert!(parse_backend("serial").is_err());
+        assert_eq!(unescape("echo hi\\n\\\\"), b"echo hi\n\\");
+    }
+}
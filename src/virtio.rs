@@ -1,12 +1,16 @@
+use crate::disk::{DiskBackend, MemoryDisk};
 use crate::dram::Dram;
 use crate::interrupt::*;
-use log::info;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
 
-const VIRTIO_SIZE: u64 = 0x1000; // size of virtio mmio device
+pub const VIRTIO_SIZE: u64 = 0x1000; // size of virtio mmio device
 const VRING_DESC_SIZE: u64 = 16;
-/// The number of virtio descriptors. It must be a power of two.
-const DESC_NUM: u64 = 8;
+/// The largest queue the driver may configure through QUEUE_NUM.
+const QUEUE_NUM_MAX: u64 = 256;
+const VRING_DESC_F_NEXT: u64 = 1;
+const VRING_DESC_F_WRITE: u64 = 2;
 
 // virtio mmio control registers, mapped starting at 0x10001000.
 // from qemu virtio_mmio.h
@@ -15,19 +19,17 @@ const VIRTIO_MMIO_VERSION: usize = 0x004; // version; 1 is legacy
 const VIRTIO_MMIO_DEVICE_ID: usize = 0x008; // device type; 1 is net, 2 is disk
 const VIRTIO_MMIO_VENDOR_ID: usize = 0x00c; // 0x554d4551
 const VIRTIO_MMIO_DEVICE_FEATURES: usize = 0x010;
+const VIRTIO_MMIO_DEVICE_FEATURES_SEL: usize = 0x014;
 const VIRTIO_MMIO_DRIVER_FEATURES: usize = 0x020;
+const VIRTIO_MMIO_DRIVER_FEATURES_SEL: usize = 0x024;
 const VIRTIO_MMIO_GUEST_PAGE_SIZE: usize = 0x028; // page size for PFN, write-only
 const VIRTIO_MMIO_QUEUE_SEL: usize = 0x030; // select queue, write-only
 const VIRTIO_MMIO_QUEUE_NUM_MAX: usize = 0x034; // max size of current queue, read-only
 const VIRTIO_MMIO_QUEUE_NUM: usize = 0x038; // size of current queue, write-only
 const VIRTIO_MMIO_QUEUE_PFN: usize = 0x040; // physical page number for queue, read/write
-#[allow(dead_code)]
 const VIRTIO_MMIO_QUEUE_READY: usize = 0x044; // ready bit
-#[allow(dead_code)]
 const VIRTIO_MMIO_QUEUE_NOTIFY: usize = 0x050; // write-only
-#[allow(dead_code)]
 const VIRTIO_MMIO_INTERRUPT_STATUS: usize = 0x060; // read-only
-#[allow(dead_code)]
 const VIRTIO_MMIO_INTERRUPT_ACK: usize = 0x064; // write-only
 const VIRTIO_MMIO_STATUS: usize = 0x070; // read/write
 const VIRTIO_MMIO_QUEUE_DESC_LOW: usize = 0x080; // physical address for descriptor table, write-only
@@ -36,11 +38,38 @@ const VIRTIO_MMIO_DRIVER_DESC_LOW: usize = 0x090; // physical address for availa
 const VIRTIO_MMIO_DRIVER_DESC_HIGH: usize = 0x094;
 const VIRTIO_MMIO_DEVICE_DESC_LOW: usize = 0x0a0; // physical address for used ring, write-only
 const VIRTIO_MMIO_DEVICE_DESC_HIGH: usize = 0x0a4;
+const VIRTIO_MMIO_CONFIG_GENERATION: usize = 0x0fc;
+const VIRTIO_MMIO_CONFIG: usize = 0x100; // struct virtio_blk_config
+const VIRTIO_MMIO_CONFIG_CAPACITY_HIGH: usize = 0x104;
+
+// interrupt status bits
+const VIRTIO_MMIO_INT_VRING: u64 = 1 << 0;
+
+// feature bits
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+const DEVICE_FEATURES: u64 = VIRTIO_BLK_F_FLUSH | VIRTIO_F_VERSION_1;
+
+// virtio-blk requests
+const SECTOR_SIZE: u64 = 512;
+const VIRTIO_BLK_OUTHDR_SIZE: usize = 16; // type, reserved, sector
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+const VIRTIO_BLK_ID_BYTES: usize = 20;
+const VIRTIO_BLK_ID: &[u8] = b"rv-emu-virtio-blk";
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct VirtioSnapshot {
     pub start_addr: u64,
     pub id: u8,
+    pub device_features_sel: u64,
+    pub driver_features_sel: u64,
     pub driver_features: u64,
     pub page_size: u64,
     pub queue_sel: u64,
@@ -50,14 +79,74 @@ pub struct VirtioSnapshot {
     pub desc_addr: u64,
     pub avail_addr: u64,
     pub used_addr: u64,
+    pub queue_ready: u64,
+    pub last_avail_idx: u16,
+    pub interrupt_status: u64,
     pub status: u64,
     pub disk: Vec<u8>,
 }
 
+/// A descriptor chain as (guest address, length) segments.
+struct DescriptorChain {
+    readable: Vec<(u64, u64)>,
+    writable: Vec<(u64, u64)>,
+}
+
+fn dram_range(dram: &Dram, addr: u64, len: u64) -> Option<std::ops::Range<usize>> {
+    let start = addr.checked_sub(dram.dram_base)?;
+    let end = start.checked_add(len)?;
+    if end > dram.dram.len() as u64 {
+        return None;
+    }
+    Some(start as usize..end as usize)
+}
+
+fn dram_load(dram: &Dram, addr: u64, size: u64) -> Option<u64> {
+    dram_range(dram, addr, size / 8)?;
+    dram.load(addr, size).ok()
+}
+
+fn dram_store(dram: &mut Dram, addr: u64, size: u64, value: u64) -> bool {
+    dram_range(dram, addr, size / 8).is_some() && dram.store(addr, size, value).is_ok()
+}
+
+/// Concatenate the contents of `segments`.
+fn gather(dram: &Dram, segments: &[(u64, u64)]) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for &(addr, len) in segments {
+        bytes.extend_from_slice(&dram.dram[dram_range(dram, addr, len)?]);
+    }
+    Some(bytes)
+}
+
+/// Write `data` to the start of the chain's writable part and the status to
+/// its last byte. Returns the number of bytes written.
+fn finish(dram: &mut Dram, chain: &DescriptorChain, data: &[u8], status: u8) -> u64 {
+    let mut remaining = data;
+    for &(addr, len) in &chain.writable {
+        if remaining.is_empty() {
+            break;
+        }
+        let n = (len as usize).min(remaining.len());
+        // through write_bytes, so that DMA over code drops its decoded blocks
+        if !dram.write_bytes(addr, &remaining[..n]) {
+            return 0;
+        }
+        remaining = &remaining[n..];
+    }
+    let &(addr, len) = chain.writable.last().unwrap();
+    if !dram_store(dram, addr + len - 1, 8, status as u64) {
+        return 0;
+    }
+    data.len() as u64 + 1
+}
+
 pub struct Virtio {
     start_addr: u64,
     notificator: Box<dyn Fn() + Send + Sync>,
     id: u8,
+    device_features_sel: u64,
+    driver_features_sel: u64,
     driver_features: u64,
     page_size: u64,
     queue_sel: u64,
@@ -66,9 +155,14 @@ pub struct Virtio {
     desc_addr: u64,
     avail_addr: u64,
     used_addr: u64,
+    queue_ready: u64,
     queue_notify: u64,
+    // the next avail ring entry to serve
+    last_avail_idx: u16,
+    interrupt_status: u64,
     status: u64,
     disk: Vec<u8>,
+    disk_backend: Box<dyn DiskBackend>,
 }
 
 impl Virtio {
@@ -77,6 +171,8 @@ impl Virtio {
             start_addr,
             notificator,
             id: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
             driver_features: 0,
             page_size: 0,
             queue_sel: 0,
@@ -85,12 +181,20 @@ impl Virtio {
             desc_addr: 0,
             avail_addr: 0,
             used_addr: 0,
+            queue_ready: 0,
             queue_notify: 9999,
+            last_avail_idx: 0,
+            interrupt_status: 0,
             status: 0,
             disk: Vec::new(),
+            disk_backend: Box::new(MemoryDisk::new(Vec::new())),
         }
     }
 
+    pub fn start_addr(&self) -> u64 {
+        self.start_addr
+    }
+
     pub fn is_accessible(&self, addr: u64) -> bool {
         (addr >= self.start_addr) && (addr < self.start_addr + VIRTIO_SIZE)
     }
@@ -102,9 +206,21 @@ impl Virtio {
             VIRTIO_MMIO_VERSION => 0x2,
             VIRTIO_MMIO_DEVICE_ID => 0x2,
             VIRTIO_MMIO_VENDOR_ID => 0x554d4551,
-            VIRTIO_MMIO_DEVICE_FEATURES => 0,
+            VIRTIO_MMIO_DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.device_features() & 0xffff_ffff,
+                1 => self.device_features() >> 32,
+                _ => 0,
+            },
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => self.device_features_sel,
             VIRTIO_MMIO_DRIVER_FEATURES => self.driver_features,
-            VIRTIO_MMIO_QUEUE_NUM_MAX => 8,
+            VIRTIO_MMIO_DRIVER_FEATURES_SEL => self.driver_features_sel,
+            VIRTIO_MMIO_QUEUE_NUM_MAX => QUEUE_NUM_MAX,
+            VIRTIO_MMIO_QUEUE_READY => self.queue_ready,
+            VIRTIO_MMIO_INTERRUPT_STATUS => self.interrupt_status,
+            VIRTIO_MMIO_CONFIG_GENERATION => 0,
+            // capacity in 512-byte sectors
+            VIRTIO_MMIO_CONFIG => self.disk.len() as u64 / SECTOR_SIZE,
+            VIRTIO_MMIO_CONFIG_CAPACITY_HIGH => (self.disk.len() as u64 / SECTOR_SIZE) >> 32,
             VIRTIO_MMIO_QUEUE_PFN => self.queue_pfn,
             VIRTIO_MMIO_STATUS => self.status,
             VIRTIO_MMIO_QUEUE_SEL => self.queue_sel,
@@ -133,19 +249,34 @@ impl Virtio {
         );
         let relative_addr = (addr - self.start_addr) as usize;
         match relative_addr {
-            VIRTIO_MMIO_DEVICE_FEATURES => self.driver_features = value,
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            VIRTIO_MMIO_DRIVER_FEATURES => {
+                let shift = if self.driver_features_sel == 1 { 32 } else { 0 };
+                self.driver_features = (self.driver_features & !(0xffff_ffff << shift))
+                    | ((value & 0xffff_ffff & (self.device_features() >> shift)) << shift);
+            }
+            VIRTIO_MMIO_DRIVER_FEATURES_SEL => self.driver_features_sel = value,
             VIRTIO_MMIO_GUEST_PAGE_SIZE => self.page_size = value,
             VIRTIO_MMIO_QUEUE_SEL => self.queue_sel = value,
-            VIRTIO_MMIO_QUEUE_NUM => self.queue_num = value,
+            VIRTIO_MMIO_QUEUE_NUM => {
+                if value > QUEUE_NUM_MAX {
+                    warn!("virtio: queue size {} exceeds the maximum", value);
+                }
+                self.queue_num = value.min(QUEUE_NUM_MAX);
+            }
             VIRTIO_MMIO_QUEUE_PFN => self.queue_pfn = value,
+            VIRTIO_MMIO_QUEUE_READY => self.queue_ready = value & 1,
             VIRTIO_MMIO_QUEUE_NOTIFY => {
                 self.queue_notify = value;
                 info!("virtio: queue notify called with value: {}", value);
-                if value != 9999 {
-                    (self.notificator)();
+            }
+            VIRTIO_MMIO_INTERRUPT_ACK => self.interrupt_status &= !value,
+            VIRTIO_MMIO_STATUS => {
+                self.status = value;
+                if value == 0 {
+                    self.reset();
                 }
             }
-            VIRTIO_MMIO_STATUS => self.status = value,
             VIRTIO_MMIO_QUEUE_DESC_LOW => {
                 self.desc_addr = value & 0xFFFFFFFF;
             }
@@ -169,125 +300,222 @@ impl Virtio {
         Ok(())
     }
 
+    /// Writing 0 to the status register resets the device but keeps the disk.
+    fn reset(&mut self) {
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.queue_sel = 0;
+        self.queue_num = 0;
+        self.queue_ready = 0;
+        self.queue_notify = 9999;
+        self.last_avail_idx = 0;
+        self.interrupt_status = 0;
+        self.desc_addr = 0;
+        self.avail_addr = 0;
+        self.used_addr = 0;
+    }
+
     /// Set the binary in the virtio disk.
     pub fn set_disk_image(&mut self, binary: Vec<u8>) {
         self.disk.extend(binary.iter().cloned());
     }
 
-    fn read_disk(&self, addr: u64) -> u8 {
-        self.disk[addr as usize]
+    /// Replace the disk contents with those of `backend`, which from now on
+    /// receives the guest's writes.
+    pub fn set_disk_backend(&mut self, mut backend: Box<dyn DiskBackend>) -> std::io::Result<()> {
+        self.disk = backend.open()?;
+        self.disk_backend = backend;
+        Ok(())
+    }
+
+    fn device_features(&self) -> u64 {
+        if self.disk_backend.read_only() {
+            DEVICE_FEATURES | VIRTIO_BLK_F_RO
+        } else {
+            DEVICE_FEATURES
+        }
     }
 
     pub fn has_pending_work(&self) -> bool {
         self.queue_notify != 9999
     }
 
-    /// Access the disk via virtio. This function performs DMA against *guest physical memory*.
+    /// Process every request the driver has made available since the last
+    /// call. This function performs DMA against *guest physical memory*.
     /// Takes a mutable reference to Dram to read/write guest memory directly.
     pub fn disk_access(&mut self, dram: &mut Dram) {
         if self.queue_notify == 9999 {
             return;
         }
         self.queue_notify = 9999;
+        if self.queue_ready == 0 || self.queue_num == 0 {
+            return;
+        }
 
-        let desc_addr = self.desc_addr;
-        let avail_addr = self.avail_addr;
-        let used_addr = self.used_addr;
-
-        let avail_idx = match dram.load(avail_addr + 2, 16) {
-            Ok(v) => v as u16,
-            Err(_) => return,
+        let avail_idx = match dram_load(dram, self.avail_addr + 2, 16) {
+            Some(v) => v as u16,
+            None => return,
         };
-        if avail_idx == 0 {
-            return;
+        let mut completed = 0;
+        while self.last_avail_idx != avail_idx {
+            let ring_pos = (self.last_avail_idx as u64) % self.queue_num;
+            self.last_avail_idx = self.last_avail_idx.wrapping_add(1);
+            let Some(head) = dram_load(dram, self.avail_addr + 4 + ring_pos * 2, 16) else {
+                warn!("virtio: avail ring is outside of memory");
+                return;
+            };
+            let head = head as u16;
+            let written = match self.read_chain(dram, head) {
+                Some(chain) => self.serve_request(dram, &chain),
+                None => {
+                    warn!("virtio: malformed descriptor chain at head {}", head);
+                    0
+                }
+            };
+            self.push_used(dram, head, written);
+            completed += 1;
         }
 
-        let ring_pos = ((avail_idx - 1) as u64) % (DESC_NUM as u64);
-        let head = dram
-            .load(avail_addr + 4 + ring_pos * 2, 16)
-            .expect("failed to read avail.ring entry") as u16;
-
-        let desc0 = desc_addr + VRING_DESC_SIZE * (head as u64);
-        let addr0 = dram.load(desc0 + 0, 64).expect("failed to read desc0.addr");
-        let _len0 = dram.load(desc0 + 8, 32).expect("failed to read desc0.len") as u32;
-        let _flags0 = dram
-            .load(desc0 + 12, 16)
-            .expect("failed to read desc0.flags") as u16;
-        let next0 = dram
-            .load(desc0 + 14, 16)
-            .expect("failed to read desc0.next") as u16;
-
-        let desc1 = desc_addr + VRING_DESC_SIZE * (next0 as u64);
-        let addr1 = dram.load(desc1 + 0, 64).expect("failed to read desc1.addr");
-        let len1 = dram.load(desc1 + 8, 32).expect("failed to read desc1.len") as u32;
-        let flags1 = dram
-            .load(desc1 + 12, 16)
-            .expect("failed to read desc1.flags") as u16;
-        let next1 = dram
-            .load(desc1 + 14, 16)
-            .expect("failed to read desc1.next") as u16;
-
-        let desc2 = desc_addr + VRING_DESC_SIZE * (next1 as u64);
-        let addr2 = dram.load(desc2 + 0, 64).expect("failed to read desc2.addr");
-        let _len2 = dram.load(desc2 + 8, 32).expect("failed to read desc2.len") as u32;
-        let _flags2 = dram
-            .load(desc2 + 12, 16)
-            .expect("failed to read desc2.flags") as u16;
-
-        let blk_sector = dram.load(addr0 + 8, 64).expect(&format!(
-            "failed to read virtio_blk_outhdr.sector: addr0=0x{:x} (sector@0x{:x})",
-            addr0,
-            addr0 + 8
-        ));
+        // VIRTQ_AVAIL_F_NO_INTERRUPT
+        let avail_flags = dram_load(dram, self.avail_addr, 16).unwrap_or(0);
+        if completed > 0 && avail_flags & 1 == 0 {
+            self.interrupt_status |= VIRTIO_MMIO_INT_VRING;
+            (self.notificator)();
+        }
+    }
 
+    /// Split the chain starting at `head` into its device-readable and
+    /// device-writable parts. `None` if it leaves the table or loops.
+    fn read_chain(&self, dram: &Dram, head: u16) -> Option<DescriptorChain> {
+        let mut chain = DescriptorChain {
+            readable: Vec::new(),
+            writable: Vec::new(),
+        };
+        let mut index = head as u64;
+        for _ in 0..self.queue_num {
+            if index >= self.queue_num {
+                return None;
+            }
+            let desc = self.desc_addr + VRING_DESC_SIZE * index;
+            let addr = dram_load(dram, desc, 64)?;
+            let len = dram_load(dram, desc + 8, 32)?;
+            let flags = dram_load(dram, desc + 12, 16)?;
+            let next = dram_load(dram, desc + 14, 16)?;
+            if flags & VRING_DESC_F_WRITE != 0 {
+                chain.writable.push((addr, len));
+            } else {
+                // every device-readable descriptor precedes the writable ones
+                if !chain.writable.is_empty() {
+                    return None;
+                }
+                chain.readable.push((addr, len));
+            }
+            if flags & VRING_DESC_F_NEXT == 0 {
+                return Some(chain);
+            }
+            index = next;
+        }
+        None
+    }
+
+    /// Serve one virtio-blk request and return the number of bytes written
+    /// to the chain's writable part.
+    fn serve_request(&mut self, dram: &mut Dram, chain: &DescriptorChain) -> u64 {
+        let writable_len: u64 = chain.writable.iter().map(|&(_, len)| len).sum();
+        if writable_len == 0 {
+            warn!("virtio: request without room for a status byte");
+            return 0;
+        }
+        let Some(readable) = gather(dram, &chain.readable) else {
+            return finish(dram, chain, &[], VIRTIO_BLK_S_IOERR);
+        };
+        if readable.len() < VIRTIO_BLK_OUTHDR_SIZE {
+            return finish(dram, chain, &[], VIRTIO_BLK_S_IOERR);
+        }
+        let req_type = u32::from_le_bytes(readable[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(readable[8..16].try_into().unwrap());
+        let data_len = writable_len - 1;
         info!(
-            "virtio: head={} desc=0x{:x} avail=0x{:x} used=0x{:x}",
-            head, desc_addr, avail_addr, used_addr
-        );
-        info!(
-            "virtio: addr0=0x{:x} addr1=0x{:x} len1=0x{:x} flags1=0x{:x} addr2=0x{:x} sector={}",
-            addr0, addr1, len1, flags1, addr2, blk_sector
+            "virtio: request type={} sector={} readable={} writable={}",
+            req_type,
+            sector,
+            readable.len(),
+            writable_len
         );
 
-        let device_writes = (flags1 & 2) != 0;
-
-        if !device_writes {
-            let mut buffer = Vec::with_capacity(len1 as usize);
-            for i in 0..(len1 as u64) {
-                let b = dram
-                    .load(addr1 + i, 8)
-                    .expect(&format!("failed DMA read: guest addr=0x{:x}", addr1 + i))
-                    as u8;
-                buffer.push(b);
+        match req_type {
+            VIRTIO_BLK_T_IN => match self.disk_range(sector, data_len) {
+                Some(range) => {
+                    let data = self.disk[range].to_vec();
+                    finish(dram, chain, &data, VIRTIO_BLK_S_OK)
+                }
+                None => finish(dram, chain, &[], VIRTIO_BLK_S_IOERR),
+            },
+            VIRTIO_BLK_T_OUT if self.disk_backend.read_only() => {
+                finish(dram, chain, &[], VIRTIO_BLK_S_IOERR)
             }
-            for (i, b) in buffer.into_iter().enumerate() {
-                let disk_index = blk_sector * 512 + (i as u64);
-                self.disk[disk_index as usize] = b;
+            VIRTIO_BLK_T_OUT => {
+                let data = &readable[VIRTIO_BLK_OUTHDR_SIZE..];
+                match self.disk_range(sector, data.len() as u64) {
+                    Some(range) => {
+                        let start = range.start as u64;
+                        self.disk[range].copy_from_slice(data);
+                        match self
+                            .disk_backend
+                            .written(&self.disk, start, data.len() as u64)
+                        {
+                            Ok(()) => finish(dram, chain, &[], VIRTIO_BLK_S_OK),
+                            Err(e) => {
+                                warn!("virtio: write failed: {}", e);
+                                finish(dram, chain, &[], VIRTIO_BLK_S_IOERR)
+                            }
+                        }
+                    }
+                    None => finish(dram, chain, &[], VIRTIO_BLK_S_IOERR),
+                }
             }
-        } else {
-            info!("Reading from disk sector: {}", blk_sector);
-            for i in 0..(len1 as u64) {
-                let b = self.read_disk(blk_sector * 512 + i) as u64;
-                dram.store(addr1 + i, 8, b)
-                    .expect("failed DMA write to guest memory");
+            VIRTIO_BLK_T_FLUSH => match self.disk_backend.flush() {
+                Ok(()) => finish(dram, chain, &[], VIRTIO_BLK_S_OK),
+                Err(e) => {
+                    warn!("virtio: flush failed: {}", e);
+                    finish(dram, chain, &[], VIRTIO_BLK_S_IOERR)
+                }
+            },
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = [0u8; VIRTIO_BLK_ID_BYTES];
+                id[..VIRTIO_BLK_ID.len()].copy_from_slice(VIRTIO_BLK_ID);
+                let len = (data_len as usize).min(VIRTIO_BLK_ID_BYTES);
+                finish(dram, chain, &id[..len], VIRTIO_BLK_S_OK)
             }
+            _ => finish(dram, chain, &[], VIRTIO_BLK_S_UNSUPP),
         }
+    }
 
-        dram.store(addr2, 8, 0)
-            .expect("failed to write status byte");
-
-        let used_idx = dram.load(used_addr + 2, 16).unwrap_or(0) as u16;
-
-        let used_pos = (used_idx as u64) % (DESC_NUM as u64);
-        let used_elem = used_addr + 4 + used_pos * 8;
-
-        dram.store(used_elem + 0, 32, head as u64)
-            .expect("failed to write used.elems[].id");
-        dram.store(used_elem + 4, 32, len1 as u64)
-            .expect("failed to write used.elems[].len");
+    // the `len` bytes from the start of `sector`, if they all lie on the disk
+    fn disk_range(&self, sector: u64, len: u64) -> Option<std::ops::Range<usize>> {
+        let start = sector.checked_mul(SECTOR_SIZE)?;
+        let end = start.checked_add(len)?;
+        if end > self.disk.len() as u64 {
+            return None;
+        }
+        Some(start as usize..end as usize)
+    }
 
-        dram.store(used_addr + 2, 16, (used_idx.wrapping_add(1)) as u64)
-            .expect("failed to write used.idx");
+    fn push_used(&self, dram: &mut Dram, head: u16, written: u64) {
+        let used_idx = dram_load(dram, self.used_addr + 2, 16).unwrap_or(0) as u16;
+        let used_elem = self.used_addr + 4 + (used_idx as u64 % self.queue_num) * 8;
+        if !dram_store(dram, used_elem, 32, head as u64)
+            || !dram_store(dram, used_elem + 4, 32, written)
+            || !dram_store(
+                dram,
+                self.used_addr + 2,
+                16,
+                used_idx.wrapping_add(1) as u64,
+            )
+        {
+            warn!("virtio: used ring is outside of memory");
+        }
     }
 
     /// Returns a clone of the disk image (used in tests to verify preservation).
@@ -299,6 +527,8 @@ impl Virtio {
         VirtioSnapshot {
             start_addr: self.start_addr,
             id: self.id,
+            device_features_sel: self.device_features_sel,
+            driver_features_sel: self.driver_features_sel,
             driver_features: self.driver_features,
             page_size: self.page_size,
             queue_sel: self.queue_sel,
@@ -308,6 +538,9 @@ impl Virtio {
             desc_addr: self.desc_addr,
             avail_addr: self.avail_addr,
             used_addr: self.used_addr,
+            queue_ready: self.queue_ready,
+            last_avail_idx: self.last_avail_idx,
+            interrupt_status: self.interrupt_status,
             status: self.status,
             disk: self.disk.clone(),
         }
@@ -321,6 +554,8 @@ impl Virtio {
             start_addr: snapshot.start_addr,
             notificator,
             id: snapshot.id,
+            device_features_sel: snapshot.device_features_sel,
+            driver_features_sel: snapshot.driver_features_sel,
             driver_features: snapshot.driver_features,
             page_size: snapshot.page_size,
             queue_sel: snapshot.queue_sel,
@@ -330,8 +565,218 @@ impl Virtio {
             desc_addr: snapshot.desc_addr,
             avail_addr: snapshot.avail_addr,
             used_addr: snapshot.used_addr,
+            queue_ready: snapshot.queue_ready,
+            last_avail_idx: snapshot.last_avail_idx,
+            interrupt_status: snapshot.interrupt_status,
             status: snapshot.status,
             disk: snapshot.disk,
+            disk_backend: Box::new(MemoryDisk::new(Vec::new())),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::WriteBackDisk;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const BASE: u64 = 0x10001000;
+    const DESC: u64 = 0x8000_1000;
+    const AVAIL: u64 = 0x8000_2000;
+    const USED: u64 = 0x8000_3000;
+    const BUF: u64 = 0x8000_4000;
+
+    struct Queue {
+        next_desc: u64,
+        avail_idx: u64,
+    }
+
+    impl Queue {
+        /// Add a chain of (addr, len, device-writable) segments to the avail ring.
+        fn submit(&mut self, dram: &mut Dram, segments: &[(u64, u64, bool)]) {
+            let head = self.next_desc;
+            for (i, &(addr, len, writable)) in segments.iter().enumerate() {
+                let desc = DESC + VRING_DESC_SIZE * self.next_desc;
+                let last = i == segments.len() - 1;
+                let flags = if writable { VRING_DESC_F_WRITE } else { 0 }
+                    | if last { 0 } else { VRING_DESC_F_NEXT };
+                dram.store(desc, 64, addr).unwrap();
+                dram.store(desc + 8, 32, len).unwrap();
+                dram.store(desc + 12, 16, flags).unwrap();
+                dram.store(desc + 14, 16, self.next_desc + 1).unwrap();
+                self.next_desc += 1;
+            }
+            dram.store(AVAIL + 4 + self.avail_idx * 2, 16, head)
+                .unwrap();
+            self.avail_idx += 1;
+            dram.store(AVAIL + 2, 16, self.avail_idx).unwrap();
+        }
+    }
+
+    fn header(dram: &mut Dram, addr: u64, req_type: u32, sector: u64) {
+        dram.store(addr, 32, req_type as u64).unwrap();
+        dram.store(addr + 4, 32, 0).unwrap();
+        dram.store(addr + 8, 64, sector).unwrap();
+    }
+
+    fn set_up_queue(virtio: &mut Virtio) {
+        for (reg, value) in [
+            (VIRTIO_MMIO_QUEUE_NUM, 32),
+            (VIRTIO_MMIO_QUEUE_DESC_LOW, DESC),
+            (VIRTIO_MMIO_DRIVER_DESC_LOW, AVAIL),
+            (VIRTIO_MMIO_DEVICE_DESC_LOW, USED),
+            (VIRTIO_MMIO_QUEUE_READY, 1),
+        ] {
+            virtio.store(BASE + reg as u64, 32, value).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_batched_multi_segment_requests() {
+        let interrupts = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&interrupts);
+        let mut virtio = Virtio::new(
+            BASE,
+            Box::new(move || {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }),
+        );
+        virtio.set_disk_image(vec![0; 4 * SECTOR_SIZE as usize]);
+        let mut dram = Dram::new(Vec::new(), 0x8000_0000);
+        set_up_queue(&mut virtio);
+        let mut queue = Queue {
+            next_desc: 0,
+            avail_idx: 0,
+        };
+
+        // write sector 1 from two data segments
+        header(&mut dram, BUF, VIRTIO_BLK_T_OUT, 1);
+        dram.dram[0x5000..0x5100].fill(0xaa);
+        dram.dram[0x6000..0x6100].fill(0xbb);
+        queue.submit(
+            &mut dram,
+            &[
+                (BUF, 16, false),
+                (0x8000_5000, 0x100, false),
+                (0x8000_6000, 0x100, false),
+                (BUF + 0x10, 1, true),
+            ],
+        );
+        // read it back into two segments
+        header(&mut dram, BUF + 0x20, VIRTIO_BLK_T_IN, 1);
+        queue.submit(
+            &mut dram,
+            &[
+                (BUF + 0x20, 16, false),
+                (0x8000_7000, 0x80, true),
+                (0x8000_8000, 0x180, true),
+                (BUF + 0x30, 1, true),
+            ],
+        );
+        header(&mut dram, BUF + 0x40, VIRTIO_BLK_T_GET_ID, 0);
+        queue.submit(
+            &mut dram,
+            &[(BUF + 0x40, 16, false), (0x8000_9000, 21, true)],
+        );
+        header(&mut dram, BUF + 0x60, 0x1234, 0);
+        queue.submit(&mut dram, &[(BUF + 0x60, 16, false), (BUF + 0x70, 1, true)]);
+        // reading past the end of the disk
+        header(&mut dram, BUF + 0x80, VIRTIO_BLK_T_IN, 4);
+        queue.submit(
+            &mut dram,
+            &[
+                (BUF + 0x80, 16, false),
+                (0x8000_a000, 0x200, true),
+                (BUF + 0x90, 1, true),
+            ],
+        );
+        // a sector whose byte offset overflows
+        header(&mut dram, BUF + 0xa0, VIRTIO_BLK_T_IN, 1 << 60);
+        queue.submit(
+            &mut dram,
+            &[
+                (BUF + 0xa0, 16, false),
+                (0x8000_b000, 0x200, true),
+                (BUF + 0xb0, 1, true),
+            ],
+        );
+
+        virtio
+            .store(BASE + VIRTIO_MMIO_QUEUE_NOTIFY as u64, 32, 0)
+            .unwrap();
+        virtio.disk_access(&mut dram);
+
+        assert_eq!(dram.load(USED + 2, 16).unwrap(), 6, "used.idx");
+        assert_eq!(
+            dram.load(USED + 4 + 8 + 4, 32).unwrap(),
+            0x201,
+            "read length"
+        );
+        assert_eq!(&dram.dram[0x7000..0x7080], &[0xaa; 0x80][..]);
+        assert_eq!(&dram.dram[0x8000..0x8080], &[0xaa; 0x80][..]);
+        assert_eq!(&dram.dram[0x8080..0x8180], &[0xbb; 0x100][..]);
+        assert_eq!(
+            &dram.dram[0x9000..0x9000 + VIRTIO_BLK_ID.len()],
+            VIRTIO_BLK_ID
+        );
+        let status = |dram: &Dram, addr: u64| dram.load(addr, 8).unwrap() as u8;
+        assert_eq!(status(&dram, BUF + 0x10), VIRTIO_BLK_S_OK);
+        assert_eq!(status(&dram, BUF + 0x30), VIRTIO_BLK_S_OK);
+        assert_eq!(status(&dram, 0x8000_9000 + 20), VIRTIO_BLK_S_OK);
+        assert_eq!(status(&dram, BUF + 0x70), VIRTIO_BLK_S_UNSUPP);
+        assert_eq!(status(&dram, BUF + 0x90), VIRTIO_BLK_S_IOERR);
+        assert_eq!(status(&dram, BUF + 0xb0), VIRTIO_BLK_S_IOERR);
+
+        // one interrupt for the whole batch, cleared by INTERRUPT_ACK
+        assert_eq!(interrupts.load(Ordering::Relaxed), 1);
+        let isr = BASE + VIRTIO_MMIO_INTERRUPT_STATUS as u64;
+        assert_eq!(virtio.load(isr, 32).unwrap(), VIRTIO_MMIO_INT_VRING);
+        virtio
+            .store(
+                BASE + VIRTIO_MMIO_INTERRUPT_ACK as u64,
+                32,
+                VIRTIO_MMIO_INT_VRING,
+            )
+            .unwrap();
+        assert_eq!(virtio.load(isr, 32).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_writes_reach_the_image_without_flush() {
+        let path = std::env::temp_dir().join(format!("rv-emu-{}-virtio.img", std::process::id()));
+        std::fs::write(&path, vec![0u8; 2 * SECTOR_SIZE as usize]).unwrap();
+        let mut virtio = Virtio::new(BASE, Box::new(|| {}));
+        virtio
+            .set_disk_backend(Box::new(WriteBackDisk::new(path.clone())))
+            .unwrap();
+        let mut dram = Dram::new(Vec::new(), 0x8000_0000);
+        set_up_queue(&mut virtio);
+        let mut queue = Queue {
+            next_desc: 0,
+            avail_idx: 0,
+        };
+
+        header(&mut dram, BUF, VIRTIO_BLK_T_OUT, 1);
+        dram.dram[0x5000..0x5200].fill(0xcd);
+        queue.submit(
+            &mut dram,
+            &[
+                (BUF, 16, false),
+                (0x8000_5000, 0x200, false),
+                (BUF + 0x10, 1, true),
+            ],
+        );
+        virtio
+            .store(BASE + VIRTIO_MMIO_QUEUE_NOTIFY as u64, 32, 0)
+            .unwrap();
+        virtio.disk_access(&mut dram);
+
+        // the device is still alive and the guest never sent a flush
+        let image = std::fs::read(&path).unwrap();
+        assert_eq!(&image[SECTOR_SIZE as usize..], &[0xcd; 0x200][..]);
+        drop(virtio);
+        let _ = std::fs::remove_file(path);
+    }
+}
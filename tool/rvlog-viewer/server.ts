@@ -13,12 +13,13 @@ const ROOT_DIR = path.join(__dirname, "..");
 const PUBLIC_DIR = path.join(ROOT_DIR, "public");
 
 const DISASM_PATH = process.env.DISASM_PATH ?? path.join(ROOT_DIR, "disasm.txt");
-const LOG_PATH = process.env.LOG_PATH ?? path.join(ROOT_DIR, "emu.log");
+// JSON lines from `rv-emu trace-convert --symbols <kernel> <trace>`
+const TRACE_PATH = process.env.TRACE_PATH ?? path.join(ROOT_DIR, "trace.jsonl");
 
 app.use(express.static(PUBLIC_DIR));
 
 console.log("DISASM_PATH=", DISASM_PATH);
-console.log("LOG_PATH=", LOG_PATH);
+console.log("TRACE_PATH=", TRACE_PATH);
 console.log("PUBLIC_DIR=", PUBLIC_DIR);
 
 // --- helpers -------------------------------------------------------------
@@ -111,28 +112,46 @@ app.use((req, res, next) => {
 
 app.use(express.static(path.join(__dirname, "public")));
 
-app.get("/api/logs", (req, res) => {
-  const text = readFileOrEmpty(LOG_PATH);
-
-  // 「Block execution:」を含む行を拾う + start/end は取れれば取る
-  const re = /Block execution:\s*(0x[0-9A-Fa-f]+)?\s*(?:to\s*(0x[0-9A-Fa-f]+))?/;
+type TraceRecord = {
+  kind: string;
+  cycle: number;
+  start_pc?: string;
+  end_pc?: string;
+  symbol?: string;
+  cause?: number | string;
+  epc?: string;
+};
 
-  const items = text
+function parseTrace(text: string): TraceRecord[] {
+  return text
     .split(/\r?\n/)
-    .map((line) => line.trimEnd())
-    .map((line) => {
-      if (!line.includes("Block execution:")) return null;
-      const m = line.match(re);
-      return { line, start: m?.[1] ?? "", end: m?.[2] ?? "" };
-    })
-    .filter((x): x is { line: string; start: string; end: string } => x !== null);
+    .filter((line) => line.trim() !== "")
+    .flatMap((line) => {
+      try {
+        return [JSON.parse(line) as TraceRecord];
+      } catch {
+        return [];
+      }
+    });
+}
+
+app.get("/api/logs", (req, res) => {
+  // blocks highlight their range; traps and interrupts the instruction at epc
+  const items = parseTrace(readFileOrEmpty(TRACE_PATH)).flatMap((r) => {
+    if (r.kind === "block" && r.start_pc && r.end_pc) {
+      const label = `[${r.cycle}] Block execution: ${r.start_pc} to ${r.end_pc}${r.symbol ? " " + r.symbol : ""}`;
+      return [{ label, start: r.start_pc, end: r.end_pc }];
+    }
+    if ((r.kind === "trap" || r.kind === "interrupt") && r.epc) {
+      const label = `[${r.cycle}] ${r.kind === "trap" ? "Exception" : "Interrupt"} ${r.cause} at ${r.epc}`;
+      return [{ label, start: r.epc, end: r.epc }];
+    }
+    return [];
+  });
 
   const html = items
     .map((it) => {
-      const url =
-        it.start && it.end
-          ? `/api/disasm?start=${encodeURIComponent(it.start)}&end=${encodeURIComponent(it.end)}`
-          : `/api/disasm`;
+      const url = `/api/disasm?start=${encodeURIComponent(it.start)}&end=${encodeURIComponent(it.end)}`;
 
       return `
 <button
@@ -142,12 +161,12 @@ app.get("/api/logs", (req, res) => {
   hx-target="#disasmPanel"
   hx-swap="innerHTML"
 >
-  ${escapeHtml(it.line)}
+  ${escapeHtml(it.label)}
 </button>`.trim();
     })
     .join("\n");
 
-  res.type("text/html").send(html || `<div class="muted">No matching log lines.</div>`);
+  res.type("text/html").send(html || `<div class="muted">No trace records.</div>`);
 });
 
 app.get("/api/disasm", (req, res) => {
@@ -176,5 +195,5 @@ app.get("/api/disasm", (req, res) => {
 app.listen(PORT, () => {
   console.log(`open http://localhost:${PORT}`);
   console.log(`DISASM_PATH=${DISASM_PATH}`);
-  console.log(`LOG_PATH=${LOG_PATH}`);
+  console.log(`TRACE_PATH=${TRACE_PATH}`);
 });
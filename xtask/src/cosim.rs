@@ -0,0 +1,347 @@
+//! Lockstep comparison of the emulator against Spike.
+//!
+//! Both simulators write a commit log (Spike with `--log-commits`, rv-emu with
+//! `--commit-log`): one line per retired instruction with its pc, encoding
+//! and the registers and memory it wrote. The logs are aligned on the
+//! emulator's first pc, which skips Spike's boot ROM, and walked together
+//! until the first instruction that differs.
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+#[derive(Parser, Debug)]
+pub struct CosimArgs {
+    /// ELF to run on both simulators. Without it, --spike-log and --emu-log
+    /// name existing logs to compare.
+    elf: Option<PathBuf>,
+
+    /// Path to your emulator binary
+    #[arg(long, default_value = "target/release/rv-emu")]
+    emulator: PathBuf,
+
+    /// Path to the Spike binary
+    #[arg(long, default_value = "spike")]
+    spike: PathBuf,
+
+    /// ISA string passed to Spike
+    #[arg(long, default_value = "rv64imafdc")]
+    isa: String,
+
+    /// Spike commit log (written when an ELF is given)
+    #[arg(long, default_value = "target/xtask-logs/cosim/spike.log")]
+    spike_log: PathBuf,
+
+    /// Emulator commit log (written when an ELF is given)
+    #[arg(long, default_value = "target/xtask-logs/cosim/emu.log")]
+    emu_log: PathBuf,
+
+    /// Instructions the emulator runs for
+    #[arg(long, default_value_t = 100000)]
+    count: u64,
+
+    /// Matching instructions shown before the divergence
+    #[arg(long, default_value_t = 10)]
+    context: usize,
+
+    /// Also compare CSR writes. Off by default: Spike logs implicit CSR
+    /// updates, such as fflags and mstatus.FS, that the emulator does not.
+    #[arg(long, default_value_t = false)]
+    csrs: bool,
+
+    /// Everything after `--` is passed to emulator verbatim.
+    #[arg(trailing_var_arg = true)]
+    emu_args: Vec<String>,
+}
+
+pub fn cosim(args: CosimArgs) -> Result<()> {
+    if let Some(elf) = &args.elf {
+        run_both(&args, elf)?;
+    }
+    let spike = fs::read_to_string(&args.spike_log)
+        .with_context(|| format!("failed to read {:?}", args.spike_log))?;
+    let emu = fs::read_to_string(&args.emu_log)
+        .with_context(|| format!("failed to read {:?}", args.emu_log))?;
+
+    match compare(&spike, &emu, args.csrs, args.context)? {
+        Outcome::Match { compared, note } => {
+            eprintln!("[xtask] no divergence in {compared} instructions ({note})");
+            Ok(())
+        }
+        Outcome::Diverged { index, report } => {
+            println!("{report}");
+            bail!("traces diverge at instruction #{index}");
+        }
+    }
+}
+
+fn run_both(args: &CosimArgs, elf: &Path) -> Result<()> {
+    for log in [&args.spike_log, &args.emu_log] {
+        if let Some(dir) = log.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create log dir {:?}", dir))?;
+        }
+    }
+
+    let mut spike = Command::new(&args.spike);
+    spike
+        .arg(format!("--isa={}", args.isa))
+        .arg("--log-commits")
+        .arg(format!("--log={}", args.spike_log.display()))
+        .arg(elf);
+    eprintln!("[xtask] cmd: {:?}", spike);
+    // a failing test still leaves a usable log, so only spawn errors matter
+    spike
+        .status()
+        .with_context(|| format!("failed to run: {:?}", spike))?;
+
+    let mut emu = Command::new(&args.emulator);
+    emu.arg("--elf")
+        .arg(elf)
+        .arg("--commit-log")
+        .arg(&args.emu_log)
+        .arg("--count")
+        .arg(args.count.to_string())
+        .args(&args.emu_args);
+    eprintln!("[xtask] cmd: {:?}", emu);
+    emu.status()
+        .with_context(|| format!("failed to run: {:?}", emu))?;
+    Ok(())
+}
+
+/// One line of a commit log.
+#[derive(Debug, PartialEq)]
+struct Commit<'a> {
+    line: usize,
+    text: &'a str,
+    mode: u8,
+    pc: u64,
+    inst: u32,
+    // x and f registers, keyed "x5", "f10"
+    regs: Vec<(&'a str, u128)>,
+    // keyed by number, "c768"; the name after '_' varies between simulators
+    csrs: Vec<(&'a str, u128)>,
+    // address, and the value for a store
+    mem: Vec<(u64, Option<u128>)>,
+}
+
+fn hex(token: &str) -> Option<u128> {
+    u128::from_str_radix(token.strip_prefix("0x")?, 16).ok()
+}
+
+/// Parses `core   0: 3 0x... (0x...) x5  0x... mem 0x...`. Other lines
+/// Spike prints, such as traps and disassembly, give None.
+fn parse_line(line: usize, text: &str) -> Option<Commit<'_>> {
+    let rest = text.strip_prefix("core")?;
+    let (_, rest) = rest.split_once(": ")?;
+    let mut tokens = rest.split_whitespace().peekable();
+    let mode = tokens.next()?.parse().ok()?;
+    let pc = hex(tokens.next()?)? as u64;
+    let inst = tokens.next()?.strip_prefix('(')?.strip_suffix(')')?;
+    let mut commit = Commit {
+        line,
+        text,
+        mode,
+        pc,
+        inst: hex(inst)? as u32,
+        regs: Vec::new(),
+        csrs: Vec::new(),
+        mem: Vec::new(),
+    };
+    while let Some(token) = tokens.next() {
+        if token == "mem" {
+            let addr = hex(tokens.next()?)? as u64;
+            let value = match tokens.peek() {
+                Some(next) if next.starts_with("0x") => hex(tokens.next()?),
+                _ => None,
+            };
+            commit.mem.push((addr, value));
+        } else if let Some(csr) = token.strip_prefix('c') {
+            let number = csr.split('_').next()?;
+            commit
+                .csrs
+                .push((&token[..number.len() + 1], hex(tokens.next()?)?));
+        } else if token.starts_with('x') || token.starts_with('f') {
+            let value = hex(tokens.next()?)?;
+            if token != "x0" {
+                commit.regs.push((token, value));
+            }
+        } else {
+            // vector state and other extras we do not model
+            tokens.next();
+        }
+    }
+    commit.regs.sort();
+    commit.csrs.sort();
+    Some(commit)
+}
+
+fn parse(log: &str) -> Vec<Commit<'_>> {
+    log.lines()
+        .enumerate()
+        .filter_map(|(i, text)| parse_line(i + 1, text))
+        .collect()
+}
+
+fn differences(spike: &Commit, emu: &Commit, csrs: bool) -> Vec<String> {
+    let mut diffs = Vec::new();
+    if spike.pc != emu.pc {
+        diffs.push(format!("pc: spike {:#x}, emulator {:#x}", spike.pc, emu.pc));
+    }
+    if spike.inst != emu.inst {
+        diffs.push(format!(
+            "instruction: spike {:#x}, emulator {:#x}",
+            spike.inst, emu.inst
+        ));
+    }
+    if spike.mode != emu.mode {
+        diffs.push(format!(
+            "privilege: spike {}, emulator {}",
+            spike.mode, emu.mode
+        ));
+    }
+    compare_writes(&mut diffs, &spike.regs, &emu.regs);
+    if csrs {
+        compare_writes(&mut diffs, &spike.csrs, &emu.csrs);
+    }
+    if spike.mem != emu.mem {
+        diffs.push(format!(
+            "memory: spike {:x?}, emulator {:x?}",
+            spike.mem, emu.mem
+        ));
+    }
+    diffs
+}
+
+fn compare_writes(diffs: &mut Vec<String>, spike: &[(&str, u128)], emu: &[(&str, u128)]) {
+    for (name, value) in spike {
+        match emu.iter().find(|(n, _)| n == name) {
+            Some((_, v)) if v == value => {}
+            Some((_, v)) => diffs.push(format!("{name}: spike {value:#x}, emulator {v:#x}")),
+            None => diffs.push(format!("{name}: spike {value:#x}, not written by emulator")),
+        }
+    }
+    for (name, value) in emu {
+        if !spike.iter().any(|(n, _)| n == name) {
+            diffs.push(format!("{name}: emulator {value:#x}, not written by spike"));
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Outcome {
+    Match { compared: usize, note: String },
+    Diverged { index: usize, report: String },
+}
+
+fn compare(spike_log: &str, emu_log: &str, csrs: bool, context: usize) -> Result<Outcome> {
+    let spike = parse(spike_log);
+    let emu = parse(emu_log);
+    let Some(first) = emu.first() else {
+        bail!("the emulator log has no commits");
+    };
+    let Some(start) = spike.iter().position(|c| c.pc == first.pc) else {
+        bail!(
+            "spike never reaches the emulator's first pc {:#x}",
+            first.pc
+        );
+    };
+    let spike = &spike[start..];
+
+    for (index, (s, e)) in spike.iter().zip(&emu).enumerate() {
+        let diffs = differences(s, e, csrs);
+        if diffs.is_empty() {
+            continue;
+        }
+        let mut report = format!(
+            "first divergence at instruction #{index} (spike line {}, emulator line {})\n",
+            s.line, e.line
+        );
+        for c in &spike[index.saturating_sub(context)..index] {
+            report += &format!("           {}\n", c.text);
+        }
+        report += &format!("- spike    {}\n", s.text);
+        report += &format!("+ emulator {}\n", e.text);
+        for diff in diffs {
+            report += &format!("  {diff}\n");
+        }
+        return Ok(Outcome::Diverged { index, report });
+    }
+
+    let compared = spike.len().min(emu.len());
+    let note = if spike.len() == emu.len() {
+        "both logs end together".to_string()
+    } else if spike.len() > emu.len() {
+        format!("spike ran {} more", spike.len() - emu.len())
+    } else {
+        format!("the emulator ran {} more", emu.len() - spike.len())
+    };
+    Ok(Outcome::Match { compared, note })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPIKE: &str = "\
+core   0: 0x0000000000001000 (0x00000297) auipc   t0, 0x0
+core   0: 3 0x0000000000001000 (0x00000297) x5  0x0000000000001000
+core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000
+core   0: 3 0x0000000080000004 (0x30529073) c773_mtvec 0x0000000080000000
+core   0: 3 0x0000000080000008 (0xc04a) mem 0x0000000080001000 0x00000000
+core   0: exception trap_illegal_instruction, epc 0x000000008000000a
+core   0: 3 0x000000008000000c (0x0002b503) x10 0x0000000000000002 mem 0x0000000080000000
+";
+
+    #[test]
+    fn test_parse_line() {
+        let commit = parse_line(
+            3,
+            "core   0: 3 0x0000000080000014 (0x30051573) x10 0x0000000000001800 \
+             c768_mstatus 0x000000000000000a mem 0x0000000080001000 0x0000002a",
+        )
+        .unwrap();
+        assert_eq!(commit.mode, 3);
+        assert_eq!(commit.pc, 0x8000_0014);
+        assert_eq!(commit.inst, 0x3005_1573);
+        assert_eq!(commit.regs, vec![("x10", 0x1800)]);
+        assert_eq!(commit.csrs, vec![("c768", 0xa)]);
+        assert_eq!(commit.mem, vec![(0x8000_1000, Some(0x2a))]);
+        assert!(parse_line(1, "core   0: 0x0000000000001000 (0x00000297) auipc").is_none());
+    }
+
+    #[test]
+    fn test_compare() {
+        let emu = "\
+core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000
+core   0: 3 0x0000000080000004 (0x30529073) c773_mtvec 0x0000000080000004
+core   0: 3 0x0000000080000008 (0xc04a) mem 0x0000000080001000 0x00000000
+core   0: 3 0x000000008000000c (0x0002b503) x10 0x0000000000000003 mem 0x0000000080000000
+";
+        // CSR writes are only compared on request
+        let Outcome::Diverged { index, report } = compare(SPIKE, emu, false, 1).unwrap() else {
+            panic!("x10 differs");
+        };
+        assert_eq!(index, 3);
+        assert!(report.contains("spike line 7, emulator line 4"));
+        assert!(report.contains("           core   0: 3 0x0000000080000008"));
+        assert!(report.contains("  x10: spike 0x2, emulator 0x3\n"));
+
+        let Outcome::Diverged { index, report } = compare(SPIKE, emu, true, 1).unwrap() else {
+            panic!("mtvec differs");
+        };
+        assert_eq!(index, 1);
+        assert!(report.contains("c773: spike 0x80000000, emulator 0x80000004"));
+
+        let prefix: String = emu.lines().take(3).map(|l| format!("{l}\n")).collect();
+        let Outcome::Match { compared, note } = compare(SPIKE, &prefix, false, 1).unwrap() else {
+            panic!("the common prefix matches");
+        };
+        assert_eq!(compared, 3);
+        assert_eq!(note, "spike ran 1 more");
+    }
+}
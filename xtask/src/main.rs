@@ -9,6 +9,8 @@ use std::{
 };
 use walkdir::WalkDir;
 
+mod cosim;
+
 #[derive(Parser)]
 #[command(author, version, about)]
 struct Cli {
@@ -20,6 +22,9 @@ struct Cli {
 enum Cmd {
     /// Build riscv-tests and run them on your emulator
     TestRiscv(TestRiscvArgs),
+    /// Run an ELF on Spike and your emulator and report the first
+    /// instruction where their commit logs differ
+    Cosim(cosim::CosimArgs),
 }
 
 #[derive(Parser, Debug)]
@@ -93,6 +98,7 @@ fn main() -> Result<()> {
     let cli = Cli::parse();
     match cli.cmd {
         Cmd::TestRiscv(args) => test_riscv(args),
+        Cmd::Cosim(args) => cosim::cosim(args),
     }
 }
 